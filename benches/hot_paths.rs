@@ -0,0 +1,223 @@
+/*
+Benchmarks for the hot paths of the node: VM execution, block verification, the mem_pool, block encoding,
+block propagation and hashing. Run with cargo bench, criterion compares every run with the previous one.
+*/
+
+use std::io::Cursor;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use projectx_rs::{
+    core::{
+        assemble, BincodeDecoder, BincodeEncoder, Block, BlockHasher, Decoder, Encoder,
+        ExecutionContext, Hasher, Header, State, Transaction, TxHasher, VM,
+    },
+    crypto::PrivateKey,
+    network::{
+        default_rpc_decode_fn, LocalTransport, Message, MessageType, Transport, TxPool, RPC,
+    },
+    types::Hash,
+};
+
+fn signed_tx(i: u32) -> Transaction {
+    let mut tx =
+        Transaction::new(assemble(&format!("push {}; push 2; mul; return", i % 200)).unwrap());
+    tx.sign(&PrivateKey::generate());
+    tx.set_first_seen(i as u128);
+    tx
+}
+
+fn block_with_txs(n: u32) -> Block {
+    let txx = (0..n).map(signed_tx).collect();
+    let mut b = Block::from_prev_header(Block::genesis().header, txx).unwrap();
+    b.sign(&PrivateKey::generate()).unwrap();
+    b
+}
+
+fn bench_vm(c: &mut Criterion) {
+    // counts down from 100
+    let code =
+        assemble("push 100; loop: push 1; swap; sub; dup; push @loop; jumpif; return").unwrap();
+
+    c.bench_function("vm_run_loop", |b| {
+        b.iter(|| {
+            let mut state = State::new();
+            let mut vm = VM::new(
+                black_box(code.clone()),
+                &mut state,
+                ExecutionContext::default(),
+            );
+            black_box(vm.run())
+        })
+    });
+
+    let store = assemble("push 1; store A; push 2; store B; get A; get B; add; return").unwrap();
+    c.bench_function("vm_run_storage", |b| {
+        b.iter(|| {
+            let mut state = State::new();
+            let mut vm = VM::new(
+                black_box(store.clone()),
+                &mut state,
+                ExecutionContext::default(),
+            );
+            black_box(vm.run())
+        })
+    });
+}
+
+fn bench_block_verify(c: &mut Criterion) {
+    let block = block_with_txs(10);
+
+    c.bench_function("block_verify_10_txs", |b| {
+        b.iter_batched(
+            || block.clone(),
+            |mut block| block.verify().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_tx_pool(c: &mut Criterion) {
+    let txx: Vec<Transaction> = (0..500).map(signed_tx).collect();
+
+    c.bench_function("tx_pool_add_500", |b| {
+        b.iter_batched(
+            || txx.clone(),
+            |txx| {
+                let mut pool = TxPool::new(1000);
+                for tx in txx {
+                    pool.add(tx).unwrap();
+                }
+                pool
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let mut pool = TxPool::new(1000);
+    for tx in txx {
+        pool.add(tx).unwrap();
+    }
+    c.bench_function("tx_pool_pending_500", |b| {
+        b.iter(|| black_box(pool.pending().len()))
+    });
+    c.bench_function("tx_pool_pending_cloned_500", |b| {
+        b.iter(|| black_box(pool.pending_cloned()))
+    });
+}
+
+fn bench_encoding(c: &mut Criterion) {
+    let block = block_with_txs(100);
+    let mut encoded = vec![];
+    BincodeEncoder::new(&mut encoded).encode(&block).unwrap();
+
+    c.bench_function("block_encode_100_txs", |b| {
+        b.iter(|| {
+            let mut buf = Vec::with_capacity(encoded.len());
+            BincodeEncoder::new(&mut buf).encode(&block).unwrap();
+            buf
+        })
+    });
+
+    c.bench_function("block_decode_100_txs", |b| {
+        b.iter(|| {
+            let mut decoded = Block::new(Header::default(), vec![]);
+            BincodeDecoder::new(&mut Cursor::new(&encoded))
+                .decode(&mut decoded)
+                .unwrap();
+            decoded
+        })
+    });
+}
+
+fn bench_propagation(c: &mut Criterion) {
+    const PEERS: usize = 20;
+
+    let mut buf = vec![];
+    BincodeEncoder::new(&mut buf)
+        .encode(&block_with_txs(100))
+        .unwrap();
+    let payload = Message::new(MessageType::Block, buf).bytes().unwrap();
+
+    // payloads used to be a Vec<u8> copied for every peer, now every peer shares one buffer
+    let copied = payload.to_vec();
+    c.bench_function("propagate_block_copy_20_peers", |b| {
+        b.iter(|| {
+            (0..PEERS)
+                .map(|_| black_box(copied.clone()))
+                .collect::<Vec<_>>()
+        })
+    });
+    c.bench_function("propagate_block_shared_20_peers", |b| {
+        b.iter(|| {
+            (0..PEERS)
+                .map(|_| black_box(payload.clone()))
+                .collect::<Vec<_>>()
+        })
+    });
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let tr = LocalTransport::new("A".into());
+    let peers: Vec<LocalTransport> = (0..PEERS)
+        .map(|i| LocalTransport::new(format!("P{i}").into()))
+        .collect();
+    rt.block_on(async {
+        for peer in &peers {
+            tr.connect(Box::new(peer.clone())).await.unwrap();
+        }
+    });
+
+    c.bench_function("broadcast_block_20_peers", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                tr.broadcast(payload.clone()).await.unwrap();
+                for peer in &peers {
+                    black_box(peer.recv().await);
+                }
+            })
+        })
+    });
+
+    // the message data is sliced out of the payload before the block is decoded
+    c.bench_function("decode_block_rpc_100_txs", |b| {
+        b.iter(|| {
+            default_rpc_decode_fn(RPC {
+                from: "A".into(),
+                payload: payload.clone(),
+            })
+            .unwrap()
+        })
+    });
+}
+
+fn bench_hash(c: &mut Criterion) {
+    let block = block_with_txs(1);
+    let tx = signed_tx(1);
+    let hash = Hash::random();
+    let bytes = hash.into_bytes();
+
+    c.bench_function("hash_header", |b| {
+        b.iter(|| BlockHasher.hash(black_box(&block.header)).unwrap())
+    });
+    c.bench_function("hash_tx", |b| {
+        b.iter(|| TxHasher.hash(black_box(&tx)).unwrap())
+    });
+    c.bench_function("hash_from_bytes", |b| {
+        b.iter(|| Hash::try_from_bytes(black_box(&bytes)))
+    });
+    c.bench_function("hash_is_zero", |b| b.iter(|| black_box(hash).is_zero()));
+    c.bench_function("hash_to_string", |b| b.iter(|| black_box(hash).to_string()));
+}
+
+criterion_group!(
+    benches,
+    bench_vm,
+    bench_block_verify,
+    bench_tx_pool,
+    bench_encoding,
+    bench_propagation,
+    bench_hash
+);
+criterion_main!(benches);
@@ -0,0 +1,164 @@
+// Admin methods, operators inspect and manage a running node with them. See mod.rs for the authentication.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{net::SocketAddr, time::Instant};
+
+use super::{parse_params, tx_json, Api, RpcError, INVALID_PARAMS, SERVER_ERROR};
+use crate::{
+    core::NodeMode,
+    network::{Direction, NetAddr, SyncStatus},
+};
+
+const ADMIN_METHODS: [&str; 7] = [
+    "node_info",
+    "sync_status",
+    "peers",
+    "add_peer",
+    "remove_peer",
+    "mempool_content",
+    "set_log_level",
+];
+
+#[derive(Deserialize)]
+struct AddrParams {
+    addr: String,
+}
+
+#[derive(Deserialize)]
+struct LogLevelParams {
+    level: String,
+}
+
+pub(super) fn is_admin_method(method: &str) -> bool {
+    ADMIN_METHODS.contains(&method)
+}
+
+pub(super) async fn call(api: &Api, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "node_info" => node_info(api).await,
+        "sync_status" => Ok(match api.handle.get_sync_status().await? {
+            SyncStatus::Synced => json!({ "status": "synced" }),
+            SyncStatus::Syncing {
+                current,
+                target,
+                peers,
+            } => json!({
+                "status": "syncing",
+                "current": current,
+                "target": target,
+                "peers": peers,
+            }),
+        }),
+        "peers" => peers(api).await,
+        "add_peer" => {
+            let AddrParams { addr } = parse_params(params)?;
+            let addr: SocketAddr = addr
+                .parse()
+                .map_err(|err| RpcError::new(INVALID_PARAMS, format!("invalid address: {err}")))?;
+            api.handle.add_peer(addr.into()).await?;
+            Ok(Value::Null)
+        }
+        "remove_peer" => {
+            let AddrParams { addr } = parse_params(params)?;
+            api.handle.remove_peer(NetAddr::from(addr.as_str())).await?;
+            Ok(Value::Null)
+        }
+        "mempool_content" => mempool_content(api).await,
+        "set_log_level" => {
+            let LogLevelParams { level } = parse_params(params)?;
+            let set_log_level = api
+                .opts
+                .set_log_level
+                .as_ref()
+                .ok_or_else(|| RpcError::new(SERVER_ERROR, "the log level can't be changed"))?;
+            set_log_level(&level).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+            Ok(Value::Null)
+        }
+        _ => unreachable!("{method} is not an admin method"),
+    }
+}
+
+async fn node_info(api: &Api) -> Result<Value, RpcError> {
+    let info = api.handle.get_node_info().await?;
+    let tasks: serde_json::Map<String, Value> = info
+        .tasks
+        .iter()
+        .map(|(name, health)| {
+            let health = json!({
+                "running": health.running,
+                "failures": health.failures,
+                "restarts": health.restarts,
+                "last_error": health.last_error,
+            });
+            (name.clone(), health)
+        })
+        .collect();
+    Ok(json!({
+        "id": info.id,
+        "peer_id": info.peer_id.to_string(),
+        "protocol_version": info.protocol_version,
+        "height": info.height,
+        "head": info.head.to_string(),
+        "validator": info.validator,
+        "external_addr": info.external_addr.map(|addr| addr.to_string()),
+        "peer_count": info.peer_count,
+        "mempool_size": info.mempool_size,
+        "mempool_age_ms": info.mempool_ages.map(|ages| json!({
+            "p50": ages.p50.as_millis() as u64,
+            "p90": ages.p90.as_millis() as u64,
+            "p99": ages.p99.as_millis() as u64,
+        })),
+        "chain_lag": info.chain_lag,
+        "block_time_average_ms": info
+            .block_time_average
+            .map(|average| average.as_millis() as u64),
+        "mode": match info.mode {
+            NodeMode::Archival => json!({ "kind": "archival" }),
+            NodeMode::Pruned { keep_blocks } => json!({ "kind": "pruned", "keep_blocks": keep_blocks }),
+        },
+        "lowest_block": info.lowest_block,
+        "dropped_messages": {
+            "consensus": info.dropped_messages.consensus,
+            "sync": info.dropped_messages.sync,
+            "tx": info.dropped_messages.tx,
+        },
+        "tasks": tasks,
+    }))
+}
+
+async fn peers(api: &Api) -> Result<Value, RpcError> {
+    let now = Instant::now();
+    let mut peers = api.handle.get_peers().await?;
+    peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+
+    let peers: Vec<Value> = peers
+        .into_iter()
+        .map(|peer| {
+            json!({
+                "addr": peer.addr.to_string(),
+                "direction": match peer.direction {
+                    Direction::Inbound => "inbound",
+                    Direction::Outbound => "outbound",
+                },
+                "last_seen_secs_ago": now.saturating_duration_since(peer.last_seen).as_secs(),
+                "latency_ms": peer.latency.map(|latency| latency.as_millis() as u64),
+                "id": peer.id.map(|id| id.to_string()),
+                "version": peer.version,
+                "features": peer.features,
+            })
+        })
+        .collect();
+    Ok(Value::Array(peers))
+}
+
+async fn mempool_content(api: &Api) -> Result<Value, RpcError> {
+    let txx: Vec<Value> = api
+        .handle
+        .get_mempool()
+        .await?
+        .into_iter()
+        .map(|tx| tx_json(&tx))
+        .collect();
+    Ok(Value::Array(txx))
+}
@@ -0,0 +1,91 @@
+// Methods looking up transactions on the chain, e.g. for wallets showing when a transaction confirmed
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+use super::{parse_params, Api, RpcError, HISTORY_UNAVAILABLE, INVALID_PARAMS};
+use crate::{core::TxStatus, types::Hash};
+
+const EXPLORER_METHODS: [&str; 2] = ["tx_status", "tx_receipt"];
+
+#[derive(Deserialize)]
+struct HashParams {
+    hash: String,
+}
+
+pub(super) fn is_explorer_method(method: &str) -> bool {
+    EXPLORER_METHODS.contains(&method)
+}
+
+pub(super) async fn call(api: &Api, method: &str, params: Value) -> Result<Value, RpcError> {
+    let HashParams { hash } = parse_params(params)?;
+    let hash =
+        Hash::from_str(&hash).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+
+    match method {
+        "tx_status" => match api.handle.get_tx_status(hash).await? {
+            TxStatus::Unknown => {
+                check_history(api).await?;
+                Ok(status_json(TxStatus::Unknown))
+            }
+            status => Ok(status_json(status)),
+        },
+        "tx_receipt" => {
+            let Some(receipt) = api.handle.get_receipt(hash).await? else {
+                // pending and dropped transactions have no receipt on any node
+                if api.handle.get_tx_status(hash).await? == TxStatus::Unknown {
+                    check_history(api).await?;
+                }
+                return Ok(Value::Null);
+            };
+            Ok(json!({
+                "tx_hash": receipt.tx_hash.to_string(),
+                "block_height": receipt.block_height,
+                "timestamp_ms": timestamp_ms(receipt.timestamp),
+                "success": receipt.success,
+                "error": receipt.error,
+                "gas_used": receipt.gas_used,
+            }))
+        }
+        _ => unreachable!("{method} is not an explorer method"),
+    }
+}
+
+// A transaction a pruned node doesn't know may be in one of the blocks it dropped
+async fn check_history(api: &Api) -> Result<(), RpcError> {
+    let info = api.handle.get_node_info().await?;
+    if info.lowest_block > 0 {
+        return Err(RpcError::new(
+            HISTORY_UNAVAILABLE,
+            format!(
+                "the node only keeps the blocks from {}, ask an archival node",
+                info.lowest_block
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn status_json(status: TxStatus) -> Value {
+    match status {
+        TxStatus::Unknown => json!({ "status": "unknown" }),
+        TxStatus::Pending => json!({ "status": "pending" }),
+        TxStatus::InBlock {
+            height,
+            index,
+            timestamp,
+        } => json!({
+            "status": "in_block",
+            "height": height,
+            "index": index,
+            "timestamp_ms": timestamp_ms(timestamp),
+        }),
+        TxStatus::Dropped { reason } => json!({ "status": "dropped", "reason": reason }),
+    }
+}
+
+// Block timestamps are nanoseconds since the unix epoch
+fn timestamp_ms(timestamp: u128) -> u64 {
+    u64::try_from(timestamp / 1_000_000).unwrap_or(u64::MAX)
+}
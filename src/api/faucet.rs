@@ -0,0 +1,369 @@
+/*
+The faucet of a test network hands out the tokens of a funded key: a POST to /faucet with a body like
+{"address": "3f1a...c2"} sends FaucetOpts::amount to the address with a Transfer transaction, submitted through the
+ServerHandle like any other transaction. The answer is {"tx": "<hash>"} or {"error": "<message>"}.
+
+An address and an IP get tokens once per cooldown, so a single client can't drain the faucet. The faucet expects
+to be the only one spending from its key: while its last transfer is pooled the next one gets the nonce after it,
+otherwise, e.g. once the transfer was mined or dropped from the pool, the account nonce is read from the chain tip.
+*/
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::Instant};
+use tracing::info;
+
+use crate::{
+    core::{Transaction, TxHasher, TxKind, TxStatus},
+    crypto::PrivateKey,
+    network::ServerHandle,
+    types::{Address, Hash},
+};
+
+pub const DEFAULT_FAUCET_AMOUNT: u64 = 1_000;
+pub const DEFAULT_FAUCET_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug)]
+pub struct FaucetOpts {
+    // the funded key the tokens are sent from
+    pub key: PrivateKey,
+    // sent to every address asking
+    pub amount: u64,
+    pub fee: u64,
+    // time an address or IP has to wait before it gets tokens again
+    pub cooldown: Duration,
+}
+
+impl FaucetOpts {
+    pub fn new(key: PrivateKey) -> Self {
+        Self {
+            key,
+            amount: DEFAULT_FAUCET_AMOUNT,
+            fee: 0,
+            cooldown: DEFAULT_FAUCET_COOLDOWN,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FaucetError {
+    // the address or the IP got tokens less than the cooldown ago, they can ask again after the duration
+    RateLimited(Duration),
+    // the node didn't accept the transfer, e.g. because the faucet ran dry
+    Rejected(anyhow::Error),
+}
+
+impl fmt::Display for FaucetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited(wait) => write!(f, "try again in {} seconds", wait.as_secs().max(1)),
+            Self::Rejected(err) => write!(f, "the transfer was rejected: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FaucetError {}
+
+pub struct Faucet {
+    handle: ServerHandle,
+    opts: FaucetOpts,
+    state: Mutex<FaucetState>,
+}
+
+#[derive(Default)]
+struct FaucetState {
+    // the nonce and the hash of the last transfer, unless it was rejected
+    last: Option<(u64, Hash)>,
+    // when an address or IP last got tokens
+    by_address: HashMap<Address, Instant>,
+    by_ip: HashMap<IpAddr, Instant>,
+}
+
+impl Faucet {
+    pub fn new(handle: ServerHandle, opts: FaucetOpts) -> Self {
+        Self {
+            handle,
+            opts,
+            state: Mutex::new(FaucetState::default()),
+        }
+    }
+
+    // Sends the amount to address for a client at ip, returns the hash of the transfer
+    pub async fn fund(&self, address: Address, ip: IpAddr) -> Result<Hash, FaucetError> {
+        // held until the transfer is submitted, so concurrent requests get their own nonces
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let cooldown = self.opts.cooldown;
+        state.by_address.retain(|_, at| now - *at < cooldown);
+        state.by_ip.retain(|_, at| now - *at < cooldown);
+
+        let last = [state.by_address.get(&address), state.by_ip.get(&ip)]
+            .into_iter()
+            .flatten()
+            .max();
+        if let Some(last) = last {
+            return Err(FaucetError::RateLimited(cooldown - (now - *last)));
+        }
+
+        let nonce = self
+            .next_nonce(state.last)
+            .await
+            .map_err(FaucetError::Rejected)?;
+        let mut tx = Transaction::new_kind(TxKind::Transfer {
+            to: address,
+            amount: self.opts.amount,
+        });
+        tx.nonce = nonce;
+        tx.fee = self.opts.fee;
+        tx.sign(&self.opts.key);
+        tx.calculate_and_cache_hash(Box::new(TxHasher))
+            .map_err(FaucetError::Rejected)?;
+        let hash = tx.hash();
+        if let Err(err) = self.handle.submit_transaction(tx).await {
+            state.last = None;
+            return Err(FaucetError::Rejected(err));
+        }
+
+        info!(
+            "faucet sent {} to {address} for {ip} in tx {hash}",
+            self.opts.amount
+        );
+        state.last = Some((nonce, hash));
+        state.by_address.insert(address, now);
+        state.by_ip.insert(ip, now);
+        Ok(hash)
+    }
+
+    // The nonce after the last transfer while it's pooled, the account nonce of the chain tip otherwise
+    async fn next_nonce(&self, last: Option<(u64, Hash)>) -> anyhow::Result<u64> {
+        if let Some((nonce, hash)) = last {
+            if self.handle.get_tx_status(hash).await? == TxStatus::Pending {
+                return Ok(nonce + 1);
+            }
+        }
+        self.handle
+            .get_nonce(self.opts.key.public_key().address())
+            .await
+    }
+}
+
+#[derive(Deserialize)]
+struct FaucetRequest {
+    address: String,
+}
+
+// The faucet route, the router has to be served with the ConnectInfo of the clients
+pub(super) fn router(faucet: Faucet) -> Router {
+    Router::new()
+        .route("/faucet", post(handle_post))
+        .with_state(Arc::new(faucet))
+}
+
+async fn handle_post(
+    State(faucet): State<Arc<Faucet>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    body: String,
+) -> (StatusCode, Json<Value>) {
+    let address = match serde_json::from_str::<FaucetRequest>(&body)
+        .map_err(anyhow::Error::from)
+        .and_then(|request| request.address.parse::<Address>())
+    {
+        Ok(address) => address,
+        Err(err) => return (StatusCode::BAD_REQUEST, error_json(err)),
+    };
+
+    match faucet.fund(address, client.ip()).await {
+        Ok(hash) => (StatusCode::OK, Json(json!({ "tx": hash.to_string() }))),
+        Err(err @ FaucetError::RateLimited(_)) => (StatusCode::TOO_MANY_REQUESTS, error_json(err)),
+        Err(err @ FaucetError::Rejected(_)) => (StatusCode::SERVICE_UNAVAILABLE, error_json(err)),
+    }
+}
+
+fn error_json(err: impl fmt::Display) -> Json<Value> {
+    Json(json!({ "error": err.to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        api::{serve_on, ApiOpts},
+        core::GenesisConfig,
+        network::{LocalTransport, Server},
+        test_utils::random_tx,
+    };
+
+    async fn start_faucet(opts: &FaucetOpts, balance: u64) -> Result<ServerHandle> {
+        let genesis = GenesisConfig::new().with_balance(opts.key.public_key().address(), balance);
+        let mut server = Server::builder()
+            .with_transport(Box::new(LocalTransport::new("A".into())))
+            .with_genesis(genesis)
+            .build()
+            .await?;
+        let handle = server.handle();
+        tokio::task::spawn(async move { server.start().await });
+        Ok(handle)
+    }
+
+    fn address() -> Address {
+        PrivateKey::generate().public_key().address()
+    }
+
+    #[tokio::test]
+    async fn test_fund() -> Result<()> {
+        let opts = FaucetOpts::new(PrivateKey::generate());
+        let handle = start_faucet(&opts, 3 * opts.amount).await?;
+        let faucet = Faucet::new(handle.clone(), opts.clone());
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        let a = address();
+        let hash = faucet.fund(a, ip).await?;
+        let mempool = handle.get_mempool().await?;
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool[0].hash(), hash);
+        assert!(matches!(
+            mempool[0].kind,
+            TxKind::Transfer { to, amount } if to == a && amount == opts.amount
+        ));
+
+        // the address and the IP have to wait
+        let other_ip: IpAddr = [10, 0, 0, 2].into();
+        assert!(matches!(
+            faucet.fund(a, other_ip).await,
+            Err(FaucetError::RateLimited(_))
+        ));
+        assert!(matches!(
+            faucet.fund(address(), ip).await,
+            Err(FaucetError::RateLimited(_))
+        ));
+
+        // the next transfer has the next nonce
+        faucet.fund(address(), other_ip).await?;
+        let nonces: Vec<u64> = handle
+            .get_mempool()
+            .await?
+            .iter()
+            .map(|tx| tx.nonce)
+            .collect();
+        assert_eq!(nonces.len(), 2);
+        assert!(nonces.contains(&0) && nonces.contains(&1));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nonce_after_dropped_transfer() -> Result<()> {
+        let opts = FaucetOpts {
+            cooldown: Duration::ZERO,
+            ..FaucetOpts::new(PrivateKey::generate())
+        };
+        let handle = start_faucet(&opts, 3 * opts.amount).await?;
+        let faucet = Faucet::new(handle.clone(), opts);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        // the pool is full, the oldest transfer is evicted by the transactions after it
+        let dropped = faucet.fund(address(), ip).await?;
+        for _ in 0..100 {
+            let mut tx = random_tx();
+            tx.sign(&PrivateKey::generate());
+            handle.submit_transaction(tx).await?;
+        }
+        assert!(matches!(
+            handle.get_tx_status(dropped).await?,
+            TxStatus::Dropped { .. }
+        ));
+
+        // the chain never saw the dropped transfer, its nonce is used again
+        let hash = faucet.fund(address(), ip).await?;
+        let mempool = handle.get_mempool().await?;
+        let tx = mempool.iter().find(|tx| tx.hash() == hash).unwrap();
+        assert_eq!(tx.nonce, 0);
+        assert_eq!(handle.get_tx_status(hash).await?, TxStatus::Pending);
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fund_without_balance() -> Result<()> {
+        let opts = FaucetOpts {
+            cooldown: Duration::ZERO,
+            ..FaucetOpts::new(PrivateKey::generate())
+        };
+        let handle = start_faucet(&opts, opts.amount - 1).await?;
+        let faucet = Faucet::new(handle.clone(), opts);
+
+        let result = faucet.fund(address(), [10, 0, 0, 1].into()).await;
+        assert!(matches!(result, Err(FaucetError::Rejected(_))));
+        assert!(handle.get_mempool().await?.is_empty());
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http() -> Result<()> {
+        let faucet = FaucetOpts::new(PrivateKey::generate());
+        let handle = start_faucet(&faucet, faucet.amount).await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let opts = ApiOpts {
+            faucet: Some(faucet),
+            ..ApiOpts::new(addr)
+        };
+        tokio::task::spawn(serve_on(listener, opts, handle.clone()));
+
+        let post = |body: String| async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            let request = format!(
+                "POST /faucet HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            let (head, body) = response.split_once("\r\n\r\n").unwrap_or_default();
+            let status = head.split(' ').nth(1).unwrap_or_default().to_string();
+            Ok::<_, anyhow::Error>((status, serde_json::from_str::<Value>(body)?))
+        };
+
+        let (status, body) = post(format!(r#"{{"address": "{}"}}"#, address())).await?;
+        assert_eq!(status, "200");
+        assert_eq!(
+            body["tx"],
+            handle.get_mempool().await?[0].hash().to_string()
+        );
+
+        // the same client asks again
+        let (status, body) = post(format!(r#"{{"address": "{}"}}"#, address())).await?;
+        assert_eq!(status, "429");
+        assert!(body["error"].is_string());
+
+        let (status, _) = post(r#"{"address": "not an address"}"#.into()).await?;
+        assert_eq!(status, "400");
+
+        handle.shutdown().await;
+        Ok(())
+    }
+}
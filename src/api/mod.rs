@@ -0,0 +1,608 @@
+/*
+The API of a node is JSON-RPC 2.0 over HTTP: a POST to / with a body like
+{"jsonrpc": "2.0", "id": 1, "method": "node_info", "params": {}}. Every call is answered through a ServerHandle, so
+the API never touches the state of the server itself.
+
+Admin methods (see admin.rs) manage the node and need the header "Authorization: Bearer <admin_token>". Without an
+admin_token in the ApiOpts they are disabled. The methods of pool.rs, tx.rs, explorer.rs and state.rs are open to
+everyone.
+
+Test networks can serve a faucet next to the API, see faucet.rs.
+*/
+
+mod admin;
+mod explorer;
+mod faucet;
+mod pool;
+mod state;
+mod tx;
+
+pub use faucet::{Faucet, FaucetError, FaucetOpts, DEFAULT_FAUCET_AMOUNT, DEFAULT_FAUCET_COOLDOWN};
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap},
+    routing::post,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{fmt, net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+
+use crate::{core::Transaction, network::ServerHandle};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// the server failed to carry out a valid request
+pub const SERVER_ERROR: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+// the answer is in blocks a pruned node doesn't keep anymore
+pub const HISTORY_UNAVAILABLE: i64 = -32002;
+
+// Changes the log filter of the running node, e.g. to "debug" or "projectx_rs::network=trace"
+pub type LogLevelFn = Arc<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ApiOpts {
+    pub addr: SocketAddr,
+    pub admin_token: Option<String>,
+    // set by the binary, which owns the logger
+    pub set_log_level: Option<LogLevelFn>,
+    // serves POST /faucet, only for test networks
+    pub faucet: Option<FaucetOpts>,
+}
+
+impl ApiOpts {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            admin_token: None,
+            set_log_level: None,
+            faucet: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    // null for notifications, they are answered anyway
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(SERVER_ERROR, err.to_string())
+    }
+}
+
+pub struct Api {
+    handle: ServerHandle,
+    opts: ApiOpts,
+}
+
+impl Api {
+    pub fn new(handle: ServerHandle, opts: ApiOpts) -> Self {
+        Self { handle, opts }
+    }
+
+    // token is the bearer token the request came with
+    pub async fn call(&self, token: Option<&str>, request: Request) -> Response {
+        let result = if request.jsonrpc != "2.0" {
+            Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))
+        } else if admin::is_admin_method(&request.method) {
+            if self.is_admin(token) {
+                admin::call(self, &request.method, request.params).await
+            } else {
+                Err(RpcError::new(
+                    UNAUTHORIZED,
+                    "admin methods need a valid admin token",
+                ))
+            }
+        } else if pool::is_pool_method(&request.method) {
+            pool::call(self, &request.method, request.params).await
+        } else if tx::is_tx_method(&request.method) {
+            tx::call(self, &request.method, request.params).await
+        } else if explorer::is_explorer_method(&request.method) {
+            explorer::call(self, &request.method, request.params).await
+        } else if state::is_state_method(&request.method) {
+            state::call(self, &request.method, request.params).await
+        } else {
+            Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {}", request.method),
+            ))
+        };
+
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            jsonrpc: "2.0".into(),
+            id: request.id,
+            result,
+            error,
+        }
+    }
+
+    fn is_admin(&self, token: Option<&str>) -> bool {
+        match (&self.opts.admin_token, token) {
+            (Some(admin_token), Some(token)) => constant_time_eq(admin_token, token),
+            _ => false,
+        }
+    }
+}
+
+fn tx_json(tx: &Transaction) -> Value {
+    json!({
+        "hash": tx.hash().to_string(),
+        "from": tx.from.as_ref().map(|from| from.address().to_string()),
+        "nonce": tx.nonce,
+        "fee": tx.fee,
+        "size": tx.data.len(),
+        "first_seen": u64::try_from(tx.first_seen()).unwrap_or(u64::MAX),
+    })
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+// Compares the tokens without returning early, so the time taken doesn't tell how much of a guess was right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub async fn serve(opts: ApiOpts, handle: ServerHandle) -> Result<()> {
+    let listener = TcpListener::bind(opts.addr).await?;
+    serve_on(listener, opts, handle).await
+}
+
+// Serves the API on a bound listener, e.g. one on port 0 in tests
+pub async fn serve_on(listener: TcpListener, opts: ApiOpts, handle: ServerHandle) -> Result<()> {
+    let faucet = opts.faucet.clone();
+    let api = Arc::new(Api::new(handle.clone(), opts));
+    let mut app = Router::new().route("/", post(handle_post)).with_state(api);
+    if let Some(faucet) = faucet {
+        app = app.merge(faucet::router(Faucet::new(handle, faucet)));
+    }
+    // the faucet limits the requests per client IP
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+// The body is parsed here instead of by axum, so a malformed request is answered with a JSON-RPC error
+async fn handle_post(
+    State(api): State<Arc<Api>>,
+    headers: HeaderMap,
+    body: String,
+) -> Json<Response> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let response = match serde_json::from_str::<Request>(&body) {
+        Ok(request) => api.call(token, request).await,
+        Err(err) => Response {
+            jsonrpc: "2.0".into(),
+            id: Value::Null,
+            result: None,
+            error: Some(RpcError::new(PARSE_ERROR, err.to_string())),
+        },
+    };
+    Json(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{
+        core::{GenesisConfig, NodeMode, TxHasher, TxKind},
+        crypto::PrivateKey,
+        network::{BTransport, LocalTransport, Server, UdpOpts, UdpTransport},
+        test_utils::random_tx,
+        types::Hash,
+    };
+
+    const TOKEN: &str = "secret";
+
+    async fn start_api(opts: ApiOpts) -> Result<(Api, ServerHandle)> {
+        start_api_on(Box::new(LocalTransport::new("A".into())), opts).await
+    }
+
+    async fn start_api_on(transport: BTransport, opts: ApiOpts) -> Result<(Api, ServerHandle)> {
+        let mut server = Server::builder().with_transport(transport).build().await?;
+        let handle = server.handle();
+        tokio::task::spawn(async move { server.start().await });
+        Ok((Api::new(handle.clone(), opts), handle))
+    }
+
+    fn admin_opts() -> ApiOpts {
+        ApiOpts {
+            admin_token: Some(TOKEN.into()),
+            ..ApiOpts::new(([127, 0, 0, 1], 0).into())
+        }
+    }
+
+    fn request(method: &str, params: Value) -> Request {
+        Request {
+            jsonrpc: "2.0".into(),
+            id: json!(1),
+            method: method.into(),
+            params,
+        }
+    }
+
+    async fn call(api: &Api, method: &str, params: Value) -> Result<Value, RpcError> {
+        let response = api.call(Some(TOKEN), request(method, params)).await;
+        match response.error {
+            Some(error) => Err(error),
+            None => Ok(response.result.unwrap_or_default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_token() -> Result<()> {
+        let (api, handle) = start_api(admin_opts()).await?;
+
+        for token in [None, Some("wrong"), Some("secre")] {
+            let response = api.call(token, request("node_info", Value::Null)).await;
+            assert_eq!(response.error.map(|e| e.code), Some(UNAUTHORIZED));
+        }
+
+        let info = call(&api, "node_info", Value::Null).await?;
+        assert_eq!(info["id"], "A");
+        assert_eq!(info["height"], 0);
+        assert_eq!(info["dropped_messages"]["tx"], 0);
+        assert_eq!(info["tasks"]["decode loop"]["running"], 1);
+        assert_eq!(info["tasks"]["decode loop"]["failures"], 0);
+
+        // admin methods are disabled without a token
+        let open = Api::new(handle.clone(), ApiOpts::new(([127, 0, 0, 1], 0).into()));
+        let response = open.call(None, request("node_info", Value::Null)).await;
+        assert_eq!(response.error.map(|e| e.code), Some(UNAUTHORIZED));
+
+        assert_eq!(
+            call(&api, "no_such_method", Value::Null)
+                .await
+                .map_err(|e| e.code),
+            Err(METHOD_NOT_FOUND)
+        );
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_methods() -> Result<()> {
+        let level = Arc::new(Mutex::new(String::new()));
+        let set_level = level.clone();
+        // a local transport can't reach the dialed address, its messages to it would pile up
+        let transport = UdpTransport::bind("127.0.0.1:0".parse()?, UdpOpts::default()).await?;
+        let (api, handle) = start_api_on(
+            Box::new(transport),
+            ApiOpts {
+                set_log_level: Some(Arc::new(move |new: &str| {
+                    *set_level.lock().unwrap() = new.into();
+                    Ok(())
+                })),
+                ..admin_opts()
+            },
+        )
+        .await?;
+
+        call(&api, "add_peer", json!({"addr": "127.0.0.1:9"})).await?;
+        let peers = call(&api, "peers", Value::Null).await?;
+        assert_eq!(peers[0]["addr"], "127.0.0.1:9");
+        assert_eq!(peers[0]["direction"], "outbound");
+        assert_eq!(
+            call(&api, "add_peer", json!({"addr": "not an address"}))
+                .await
+                .map_err(|e| e.code),
+            Err(INVALID_PARAMS)
+        );
+
+        call(&api, "remove_peer", json!({"addr": "127.0.0.1:9"})).await?;
+        assert_eq!(call(&api, "peers", Value::Null).await?, json!([]));
+        assert_eq!(
+            call(&api, "remove_peer", json!({"addr": "127.0.0.1:9"}))
+                .await
+                .map_err(|e| e.code),
+            Err(SERVER_ERROR)
+        );
+
+        let tx = random_tx();
+        handle.submit_transaction(tx.clone()).await?;
+        let mempool = call(&api, "mempool_content", Value::Null).await?;
+        assert_eq!(mempool.as_array().map(Vec::len), Some(1));
+        assert_eq!(mempool[0]["size"], tx.data.len());
+
+        call(&api, "set_log_level", json!({"level": "debug"})).await?;
+        assert_eq!(*level.lock().unwrap(), "debug");
+
+        assert_eq!(
+            call(&api, "sync_status", Value::Null).await?,
+            json!({ "status": "synced" })
+        );
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pool_content() -> Result<()> {
+        // the sender has to be able to pay the fees
+        let key = PrivateKey::generate();
+        let mut server = Server::builder()
+            .with_transport(Box::new(LocalTransport::new("A".into())))
+            .with_genesis(GenesisConfig::new().with_balance(key.public_key().address(), 10))
+            .build()
+            .await?;
+        let handle = server.handle();
+        tokio::task::spawn(async move { server.start().await });
+        let api = Api::new(handle.clone(), ApiOpts::new(([127, 0, 0, 1], 0).into()));
+
+        for fee in [1, 2] {
+            let mut tx = random_tx();
+            tx.fee = fee;
+            tx.sign(&key);
+            handle.submit_transaction(tx).await?;
+        }
+
+        // open to everyone, the second transaction replaced the first one
+        let response = api.call(None, request("pool_content", Value::Null)).await;
+        let content = response.result.unwrap_or_default();
+        let txx = &content[key.public_key().address().to_string()];
+        assert_eq!(txx.as_array().map(Vec::len), Some(1));
+        assert_eq!(txx[0]["fee"], 2);
+
+        // no block was added yet, the pending transaction fits into one
+        let response = api
+            .call(None, request("estimate_fee", json!({"target_blocks": 1})))
+            .await;
+        assert_eq!(response.result, Some(json!(0)));
+        let response = api.call(None, request("estimate_fee", Value::Null)).await;
+        assert_eq!(response.error.map(|e| e.code), Some(INVALID_PARAMS));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_raw_transaction() -> Result<()> {
+        let (api, handle) = start_api(ApiOpts::new(([127, 0, 0, 1], 0).into())).await?;
+        let send = |tx: String| {
+            let request = request("send_raw_transaction", json!({ "tx": tx }));
+            api.call(None, request)
+        };
+
+        // built offline, only the hex form reaches the node
+        let mut tx = random_tx();
+        tx.sign(&PrivateKey::generate());
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        let hash = tx.hash();
+        let response = send(tx.to_hex()?).await;
+        assert_eq!(response.result, Some(json!(hash.to_string())));
+        assert_eq!(handle.get_mempool().await?[0].hash(), hash);
+
+        for malformed in ["0x", "0xzz", "0x0102"] {
+            let response = send(malformed.into()).await;
+            assert_eq!(response.error.map(|e| e.code), Some(INVALID_PARAMS));
+        }
+        let response = send(format!("{}00", tx.to_hex()?)).await;
+        assert_eq!(response.error.map(|e| e.code), Some(INVALID_PARAMS));
+
+        // decoded, but the node doesn't take it
+        let response = send(Transaction::new(vec![1]).to_hex()?).await;
+        assert_eq!(response.error.map(|e| e.code), Some(SERVER_ERROR));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tx_status() -> Result<()> {
+        let (api, handle) = start_api(ApiOpts::new(([127, 0, 0, 1], 0).into())).await?;
+        let call_with_hash = |method: &'static str, hash: String| {
+            api.call(None, request(method, json!({ "hash": hash })))
+        };
+
+        let mut tx = random_tx();
+        tx.sign(&PrivateKey::generate());
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        handle.submit_transaction(tx.clone()).await?;
+
+        let response = call_with_hash("tx_status", tx.hash().to_string()).await;
+        assert_eq!(response.result, Some(json!({ "status": "pending" })));
+        // not in a block yet
+        let response = call_with_hash("tx_receipt", tx.hash().to_string()).await;
+        assert_eq!(response.result, Some(Value::Null));
+
+        let response = call_with_hash("tx_status", Hash::random().to_string()).await;
+        assert_eq!(response.result, Some(json!({ "status": "unknown" })));
+        let response = call_with_hash("tx_status", "0x0102".into()).await;
+        assert_eq!(response.error.map(|e| e.code), Some(INVALID_PARAMS));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_state_at_height() -> Result<()> {
+        let key = PrivateKey::generate();
+        let from = key.public_key().address();
+        let to = PrivateKey::generate().public_key().address();
+        let mut server = Server::builder()
+            .with_transport(Box::new(LocalTransport::new("A".into())))
+            .with_genesis(GenesisConfig::new().with_balance(from, 1_000))
+            .build()
+            .await?;
+        {
+            let chain = server.chain();
+            let mut chain = chain.write().await;
+            for h in 1..=2 {
+                let mut tx = Transaction::new_kind(TxKind::Transfer { to, amount: 100 });
+                tx.nonce = u64::from(h) - 1;
+                tx.sign(&key);
+                tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+                let mut block = chain.next_block(h, vec![tx]).await?;
+                block.sign(&key)?;
+                chain.add_block(&mut block).await?;
+            }
+        }
+        let handle = server.handle();
+        tokio::task::spawn(async move { server.start().await });
+        let api = Api::new(handle.clone(), admin_opts());
+
+        let balance = |height: Value| {
+            let params = json!({ "address": to.to_string(), "height": height });
+            api.call(None, request("get_balance", params))
+        };
+        for (height, expected) in [(json!(0), 0), (json!(1), 100), (Value::Null, 200)] {
+            assert_eq!(balance(height).await.result, Some(json!(expected)));
+        }
+        let response = balance(json!(3)).await;
+        assert_eq!(response.error.map(|e| e.code), Some(INVALID_PARAMS));
+
+        let params = json!({ "contract": to.to_string(), "key": "0x0102", "height": 1 });
+        let response = api.call(None, request("get_storage", params)).await;
+        assert_eq!(response.result, Some(Value::Null));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pruned_history() -> Result<()> {
+        let mut server = Server::builder()
+            .with_transport(Box::new(LocalTransport::new("A".into())))
+            .with_mode(NodeMode::Pruned { keep_blocks: 1 })
+            .build()
+            .await?;
+        {
+            let chain = server.chain();
+            let mut chain = chain.write().await;
+            let mut block = chain.next_block(1, vec![]).await?;
+            block.sign(&PrivateKey::generate())?;
+            chain.add_block(&mut block).await?;
+        }
+        let handle = server.handle();
+        tokio::task::spawn(async move { server.start().await });
+        let api = Api::new(handle.clone(), admin_opts());
+
+        // only the current state is kept
+        let address = PrivateKey::generate().public_key().address();
+        let params = json!({ "address": address.to_string(), "height": 0 });
+        let response = api.call(None, request("get_balance", params)).await;
+        assert_eq!(response.error.map(|e| e.code), Some(HISTORY_UNAVAILABLE));
+
+        // an unknown transaction may be in a pruned block
+        for method in ["tx_status", "tx_receipt"] {
+            let response = api
+                .call(
+                    None,
+                    request(method, json!({ "hash": Hash::random().to_string() })),
+                )
+                .await;
+            assert_eq!(response.error.map(|e| e.code), Some(HISTORY_UNAVAILABLE));
+        }
+        let info = call(&api, "node_info", Value::Null).await?;
+        assert_eq!(info["lowest_block"], json!(1));
+        assert_eq!(info["mode"], json!({ "kind": "pruned", "keep_blocks": 1 }));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http() -> Result<()> {
+        let (_, handle) = start_api(admin_opts()).await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::task::spawn(serve_on(listener, admin_opts(), handle.clone()));
+
+        let post = |body: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            let request = format!(
+                "POST / HTTP/1.1\r\nHost: {addr}\r\nAuthorization: Bearer {TOKEN}\r\n\
+                 Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            let (_, body) = response.split_once("\r\n\r\n").unwrap_or_default();
+            Ok::<Response, anyhow::Error>(serde_json::from_str(body)?)
+        };
+
+        let response = post(r#"{"jsonrpc": "2.0", "id": 7, "method": "node_info"}"#).await?;
+        assert_eq!(response.id, json!(7));
+        assert_eq!(
+            response.result.map(|info| info["height"].clone()),
+            Some(json!(0))
+        );
+
+        let response = post("{not json").await?;
+        assert_eq!(response.error.map(|e| e.code), Some(PARSE_ERROR));
+
+        handle.shutdown().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+// Methods showing the transactions waiting in the mem_pool, e.g. for wallets checking a replacement went through
+// or prefilling the fee of a new transaction
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::{parse_params, tx_json, Api, RpcError};
+
+const POOL_METHODS: [&str; 2] = ["pool_content", "estimate_fee"];
+
+#[derive(Deserialize)]
+struct EstimateFeeParams {
+    target_blocks: u32,
+}
+
+pub(super) fn is_pool_method(method: &str) -> bool {
+    POOL_METHODS.contains(&method)
+}
+
+pub(super) async fn call(api: &Api, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "pool_content" => pool_content(api).await,
+        "estimate_fee" => {
+            let EstimateFeeParams { target_blocks } = parse_params(params)?;
+            Ok(api.handle.estimate_fee(target_blocks).await?.into())
+        }
+        _ => unreachable!("{method} is not a pool method"),
+    }
+}
+
+// The pending transactions by sender address, ordered by nonce
+async fn pool_content(api: &Api) -> Result<Value, RpcError> {
+    let content: Map<String, Value> = api
+        .handle
+        .get_pool_content()
+        .await?
+        .into_iter()
+        .map(|(sender, txx)| (sender.to_string(), txx.iter().map(tx_json).collect()))
+        .collect();
+    Ok(Value::Object(content))
+}
@@ -0,0 +1,80 @@
+// Methods reading the state of the chain, at the tip or after an older block, e.g. for audits. Only archival nodes
+// answer for older blocks, see Blockchain::state_at.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+
+use super::{parse_params, Api, RpcError, HISTORY_UNAVAILABLE, INVALID_PARAMS};
+use crate::{core::NodeMode, types::Address};
+
+const STATE_METHODS: [&str; 2] = ["get_balance", "get_storage"];
+
+#[derive(Deserialize)]
+struct BalanceParams {
+    address: String,
+    // the chain tip if missing
+    height: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct StorageParams {
+    contract: String,
+    // hex
+    key: String,
+    height: Option<u32>,
+}
+
+pub(super) fn is_state_method(method: &str) -> bool {
+    STATE_METHODS.contains(&method)
+}
+
+pub(super) async fn call(api: &Api, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "get_balance" => {
+            let BalanceParams { address, height } = parse_params(params)?;
+            let address = parse_address(&address)?;
+            check_height(api, height).await?;
+            Ok(api.handle.get_balance(address, height).await?.into())
+        }
+        "get_storage" => {
+            let StorageParams {
+                contract,
+                key,
+                height,
+            } = parse_params(params)?;
+            let contract = parse_address(&contract)?;
+            let key = hex::decode(key.trim_start_matches("0x"))
+                .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+            check_height(api, height).await?;
+            let value = api.handle.get_storage(contract, key, height).await?;
+            Ok(value.map_or(Value::Null, |value| hex::encode(value).into()))
+        }
+        _ => unreachable!("{method} is not a state method"),
+    }
+}
+
+fn parse_address(address: &str) -> Result<Address, RpcError> {
+    Address::from_str(address).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+// Heights above the tip are invalid, the ones below it need an archival node
+async fn check_height(api: &Api, height: Option<u32>) -> Result<(), RpcError> {
+    let Some(height) = height else {
+        return Ok(());
+    };
+    let info = api.handle.get_node_info().await?;
+    if height > info.height {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("height {height} is above the chain tip {}", info.height),
+        ));
+    }
+    if height < info.height && info.mode != NodeMode::Archival {
+        return Err(RpcError::new(
+            HISTORY_UNAVAILABLE,
+            "the node only keeps the current state, ask an archival node",
+        ));
+    }
+    Ok(())
+}
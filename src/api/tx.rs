@@ -0,0 +1,33 @@
+// Methods for submitting transactions built and signed offline, e.g. by a wallet that doesn't run a node
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{parse_params, Api, RpcError, INVALID_PARAMS};
+use crate::core::Transaction;
+
+const TX_METHODS: [&str; 1] = ["send_raw_transaction"];
+
+#[derive(Deserialize)]
+struct RawTxParams {
+    // the hex form of Transaction::to_hex
+    tx: String,
+}
+
+pub(super) fn is_tx_method(method: &str) -> bool {
+    TX_METHODS.contains(&method)
+}
+
+pub(super) async fn call(api: &Api, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "send_raw_transaction" => {
+            let RawTxParams { tx } = parse_params(params)?;
+            let tx = Transaction::from_hex(&tx)
+                .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+            let hash = tx.hash();
+            api.handle.submit_transaction(tx).await?;
+            Ok(hash.to_string().into())
+        }
+        _ => unreachable!("{method} is not a tx method"),
+    }
+}
@@ -0,0 +1,524 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand, ValueEnum};
+use projectx_rs::{
+    api::{ApiOpts, FaucetOpts, LogLevelFn, DEFAULT_FAUCET_AMOUNT},
+    core::{
+        assemble, disassemble, BincodeEncoder, Blockchain, Call, Checkpoints, ExecutionContext,
+        FileStore, GenesisConfig, Pruning, State, Transaction, VM,
+    },
+    crypto::PrivateKey,
+    lang,
+    network::{
+        self, BTransport, ConnectionManagerOpts, Message, MessageType, NetAddr, RemotePeer, Server,
+        ServerBuilder,
+    },
+};
+use tracing::error;
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter};
+
+#[derive(Parser)]
+#[command(name = "projectx", about = "projectx blockchain node")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        help = "format of the log lines, filtered with RUST_LOG"
+    )]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Run the local demo network (default)")]
+    Run {
+        #[arg(
+            long,
+            help = "file with the checkpoints of the chain, one height and hash per line"
+        )]
+        checkpoints: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "file with the funded accounts of the genesis state, one address and balance per line"
+        )]
+        genesis: Option<PathBuf>,
+        #[arg(
+            long,
+            value_enum,
+            default_value_t,
+            help = "transport between the nodes"
+        )]
+        transport: TransportKind,
+        #[arg(
+            long = "bootnode",
+            help = "address of a node to join the network through"
+        )]
+        bootnodes: Vec<SocketAddr>,
+        #[arg(
+            long = "dns-seed",
+            help = "host:port resolving to the addresses of bootnodes"
+        )]
+        dns_seeds: Vec<String>,
+        #[arg(long, help = "directory the node keeps its chain and address book in")]
+        data_dir: Option<PathBuf>,
+        #[arg(long, help = "serve the JSON-RPC API on this port of localhost")]
+        api_port: Option<u16>,
+        #[arg(
+            long,
+            env = "PROJECTX_ADMIN_TOKEN",
+            help = "bearer token of the admin API methods, they are disabled without one"
+        )]
+        admin_token: Option<String>,
+        #[arg(
+            long,
+            env = "PROJECTX_FAUCET_KEY",
+            requires = "api_port",
+            help = "hex encoded private key of a funded account, serves a faucet next to the API"
+        )]
+        faucet_key: Option<String>,
+        #[arg(long, default_value_t = DEFAULT_FAUCET_AMOUNT, help = "tokens the faucet sends per request")]
+        faucet_amount: u64,
+    },
+    #[command(about = "Assemble a VM assembly file and print the bytecode as hex")]
+    Asm { file: PathBuf },
+    #[command(about = "Disassemble hex encoded VM bytecode")]
+    Disasm { bytecode: String },
+    #[command(about = "Run hex encoded VM bytecode on an empty state and print every step")]
+    Trace {
+        bytecode: String,
+        #[arg(long, help = "hex encoded call data")]
+        input: Option<String>,
+    },
+    #[command(about = "Encode the call data of a contract call, arguments are hex encoded")]
+    Calldata { selector: u8, args: Vec<String> },
+    #[command(about = "Compile a contract source file and print the bytecode as hex")]
+    Compile {
+        file: PathBuf,
+        #[arg(long, help = "print the generated assembly instead of the bytecode")]
+        asm: bool,
+    },
+    #[command(about = "Maintain the chain stored in a data directory")]
+    Db {
+        #[arg(long, help = "data directory of the node")]
+        data_dir: PathBuf,
+        #[arg(long, help = "genesis file the node was started with")]
+        genesis: Option<PathBuf>,
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    #[command(
+        about = "Copy the committed chain into a new data directory, the node may keep running"
+    )]
+    Backup { path: PathBuf },
+    #[command(about = "Drop what a crash left in the chain files, the node has to be stopped")]
+    Compact,
+    #[command(about = "Write blocks of the chain to a block file, the node has to be stopped")]
+    Export {
+        path: PathBuf,
+        #[arg(long, default_value_t = 0, help = "first block to write")]
+        from: u32,
+        #[arg(long, help = "last block to write, the tip by default")]
+        to: Option<u32>,
+    },
+    #[command(about = "Validate and add the blocks of a block file, the node has to be stopped")]
+    Import { path: PathBuf },
+}
+
+// What the nodes of a network have to agree on
+#[derive(Clone, Default)]
+struct ChainSpec {
+    checkpoints: Checkpoints,
+    genesis: GenesisConfig,
+}
+
+// file in the data directory the address book is saved to
+const ADDRESS_BOOK_FILE: &str = "peers.bin";
+// files in the data directory the chain is stored in, see FileStore
+const CHAIN_FILE: &str = "chain";
+// headers of the stored chain kept in memory
+const KEEP_HEADERS: usize = 10_000;
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum LogFormat {
+    #[default]
+    Text,
+    // one JSON object per line with the fields of the event and its spans
+    Json,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum TransportKind {
+    // in process channels
+    #[default]
+    Local,
+    // QUIC on localhost, needs the quic feature
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let set_log_level = init_logging(cli.log_format);
+
+    let command = cli.command.unwrap_or(Command::Run {
+        checkpoints: None,
+        genesis: None,
+        transport: TransportKind::default(),
+        bootnodes: vec![],
+        dns_seeds: vec![],
+        data_dir: None,
+        api_port: None,
+        admin_token: None,
+        faucet_key: None,
+        faucet_amount: DEFAULT_FAUCET_AMOUNT,
+    });
+    match command {
+        Command::Run {
+            checkpoints,
+            genesis,
+            transport,
+            bootnodes,
+            dns_seeds,
+            data_dir,
+            api_port,
+            admin_token,
+            faucet_key,
+            faucet_amount,
+        } => {
+            let spec = ChainSpec {
+                checkpoints: checkpoints
+                    .map(Checkpoints::load)
+                    .transpose()?
+                    .unwrap_or_default(),
+                genesis: load_genesis(genesis)?,
+            };
+            if let Some(dir) = &data_dir {
+                std::fs::create_dir_all(dir)?;
+            }
+            let storage = data_dir.as_ref().map(|dir| Pruning {
+                keep_headers: KEEP_HEADERS,
+                path: dir.join(CHAIN_FILE),
+            });
+            let connection_opts = ConnectionManagerOpts {
+                bootnodes: bootnodes
+                    .into_iter()
+                    .map(|addr| -> BTransport { Box::new(RemotePeer::new(addr.into())) })
+                    .collect(),
+                dns_seeds,
+                address_book_path: data_dir.map(|dir| dir.join(ADDRESS_BOOK_FILE)),
+                ..ConnectionManagerOpts::default()
+            };
+            let faucet = faucet_key
+                .map(|key| -> Result<FaucetOpts> {
+                    Ok(FaucetOpts {
+                        amount: faucet_amount,
+                        ..FaucetOpts::new(PrivateKey::from_bytes(&decode_hex(&key)?)?)
+                    })
+                })
+                .transpose()?;
+            let api = api_port.map(|port| ApiOpts {
+                admin_token,
+                set_log_level: Some(set_log_level),
+                faucet,
+                ..ApiOpts::new(([127, 0, 0, 1], port).into())
+            });
+            run(spec, transport, connection_opts, storage, api).await
+        }
+        Command::Db {
+            data_dir,
+            genesis,
+            command,
+        } => {
+            let chain = data_dir.join(CHAIN_FILE);
+            let genesis = load_genesis(genesis)?;
+            match command {
+                DbCommand::Backup { path } => {
+                    std::fs::create_dir_all(&path)?;
+                    let meta = FileStore::backup(&chain, &path.join(CHAIN_FILE))?;
+                    println!(
+                        "backed up the chain up to height {} ({}) to {}",
+                        meta.tip_height,
+                        meta.tip_hash,
+                        path.display()
+                    );
+                }
+                DbCommand::Compact => {
+                    let freed = FileStore::compact(&chain)?;
+                    println!("freed {freed} bytes");
+                }
+                DbCommand::Export { path, from, to } => {
+                    let bc = open_chain(chain, &genesis).await?;
+                    let to = match to {
+                        Some(to) => to,
+                        None => bc.height().await,
+                    };
+                    let written = bc.export_blocks(&path, from, to).await?;
+                    println!("wrote {written} blocks to {}", path.display());
+                }
+                DbCommand::Import { path } => {
+                    let mut bc = open_chain(chain, &genesis).await?;
+                    let added = bc.import_blocks(&path).await?;
+                    println!("added {added} blocks, the height is {}", bc.height().await);
+                }
+            }
+            Ok(())
+        }
+        Command::Asm { file } => {
+            let src = std::fs::read_to_string(file)?;
+            println!("{}", hex::encode(assemble(&src)?));
+            Ok(())
+        }
+        Command::Compile { file, asm } => {
+            let src = std::fs::read_to_string(file)?;
+            if asm {
+                println!("{}", lang::compile_to_asm(&src)?);
+            } else {
+                println!("{}", hex::encode(lang::compile(&src)?));
+            }
+            Ok(())
+        }
+        Command::Disasm { bytecode } => {
+            let code = decode_hex(&bytecode)?;
+            print!("{}", disassemble(&code));
+            Ok(())
+        }
+        Command::Trace { bytecode, input } => {
+            let code = decode_hex(&bytecode)?;
+            let mut state = State::new();
+            let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+            vm.set_calldata(
+                input
+                    .as_deref()
+                    .map(decode_hex)
+                    .transpose()?
+                    .unwrap_or_default(),
+            );
+            vm.enable_tracing();
+
+            let outcome = vm.run();
+            for step in vm.trace() {
+                println!("{step}");
+            }
+            let outcome = outcome.into_result()?;
+            println!("return data: {}", hex::encode(outcome.return_data));
+            Ok(())
+        }
+        Command::Calldata { selector, args } => {
+            let mut call = Call::new(selector);
+            for arg in args {
+                call = call.arg(decode_hex(&arg)?);
+            }
+            println!("{}", hex::encode(call.encode()?));
+            Ok(())
+        }
+    }
+}
+
+// The filter starts from RUST_LOG, the returned function replaces it while the node runs
+fn init_logging(format: LogFormat) -> LogLevelFn {
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let (text, json) = match format {
+        LogFormat::Text => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json())),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .init();
+
+    Arc::new(move |level: &str| Ok(filter_handle.reload(EnvFilter::try_new(level)?)?))
+}
+
+// The chain stored in a data directory, as the node continues it
+async fn open_chain(path: PathBuf, genesis: &GenesisConfig) -> Result<Blockchain> {
+    let pruning = Pruning {
+        keep_headers: KEEP_HEADERS,
+        path,
+    };
+    let mut bc = Blockchain::with_pruning("DB".into(), genesis.block(), &pruning).await?;
+    bc.apply_genesis(genesis).await?;
+    bc.recover().await?;
+    Ok(bc)
+}
+
+// A chain without a genesis file starts without funded accounts
+fn load_genesis(path: Option<PathBuf>) -> Result<GenesisConfig> {
+    Ok(path
+        .map(GenesisConfig::load)
+        .transpose()?
+        .unwrap_or_default())
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    Ok(hex::decode(s.trim().trim_start_matches("0x"))?)
+}
+
+async fn run(
+    spec: ChainSpec,
+    kind: TransportKind,
+    connection_opts: ConnectionManagerOpts,
+    storage: Option<Pruning>,
+    api: Option<ApiOpts>,
+) -> Result<()> {
+    let transports = transports(kind)?;
+
+    let tr_local = transports[0].clone();
+    let tr_late = transports[1].clone();
+
+    late_server_task(tr_local.clone(), tr_late.clone(), spec.clone());
+
+    let private_key = PrivateKey::generate();
+    let mut builder = server_builder("LOCAL_SERVER".into(), tr_local, spec)
+        .with_validator_key(private_key)
+        .with_connection_opts(connection_opts);
+    if let Some(storage) = storage {
+        builder = builder.with_storage(storage);
+    }
+    if let Some(api) = api {
+        builder = builder.with_api(api);
+    }
+    let mut local_server = builder.build().await?;
+
+    let quit = local_server.quit_sender();
+    tokio::task::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = quit.send(()).await;
+        }
+    });
+    local_server.start().await?;
+
+    Ok(())
+}
+
+fn new_local_transport(name: &str) -> BTransport {
+    Box::new(network::LocalTransport::new(name.into()))
+}
+
+#[cfg(feature = "quic")]
+fn new_quic_transport() -> Result<BTransport> {
+    Ok(Box::new(network::QuicTransport::bind(
+        "127.0.0.1:0".parse()?,
+    )?))
+}
+
+fn transports(kind: TransportKind) -> Result<Vec<BTransport>> {
+    Ok(match kind {
+        TransportKind::Local => vec![
+            new_local_transport("LOCAL"),
+            new_local_transport("LATE_REMOTE"),
+        ],
+        #[cfg(feature = "quic")]
+        TransportKind::Quic => vec![new_quic_transport()?, new_quic_transport()?],
+    })
+}
+
+async fn late_node(tr_local: BTransport, tr_late: BTransport, spec: ChainSpec) -> Result<()> {
+    tokio::time::sleep(tokio::time::Duration::from_secs(7)).await;
+    tr_late.connect(tr_local.clone()).await?;
+    tr_local.connect(tr_late.clone()).await?;
+
+    let mut late_server = server_builder("LATE_SERVER".into(), tr_late.clone(), spec)
+        .build()
+        .await?;
+
+    late_server.start().await?;
+
+    Ok(())
+}
+
+fn late_server_task(tr_local: BTransport, tr_late: BTransport, spec: ChainSpec) {
+    tokio::task::spawn(async move {
+        if let Err(err) = late_node(tr_late, tr_local, spec).await {
+            error!("{}", err)
+        }
+    });
+}
+
+fn send_initial_transaction() {
+    // let mut tr_local_clone = transports_mut[0].clone();
+    // let tr_remote_a = transports_mut[1].clone();
+
+    //Send a transaction from remote_a to local
+    // tokio::task::spawn(async move {
+    //     loop {
+    //         if let Err(err) = send_transaction(tr_remote_a.clone(), tr_local_clone.addr()).await {
+    //             println!("Error: {err}");
+    //         }
+
+    //         tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+    //     }
+    // });
+}
+
+// Connect all nodes to each other
+// async fn bootstrap_nodes(transports: &Vec<BTransport>) -> Result<()> {
+//     for tr_mut in transports {
+//         for tr in transports {
+//             let tr = tr.read().await;
+
+//             if tr.addr() != tr_mut.read().await.addr() {
+//                 if let Err(err) = tr_mut.write().await.connect(tr.clone()) {
+//                     error!("could not connect to remote error: {:?}", err);
+//                 }
+//                 info!(
+//                     "we {} connected to remote node: {}",
+//                     tr_mut.read().await.addr(),
+//                     tr.addr()
+//                 );
+//             }
+//         }
+//     }
+
+//     Ok(())
+// }
+
+async fn init_remote_servers(trs: Vec<BTransport>) -> Result<()> {
+    for (i, tr) in trs.into_iter().enumerate() {
+        tokio::task::spawn(async move {
+            let id = format!("REMOTE_{i}");
+            let mut s = server_builder(id, tr, ChainSpec::default())
+                .build()
+                .await
+                .unwrap();
+            s.start().await.unwrap();
+        });
+    }
+    Ok(())
+}
+
+fn server_builder(id: String, tr: BTransport, spec: ChainSpec) -> ServerBuilder {
+    Server::builder()
+        .with_id(id)
+        .with_transport(tr)
+        .with_checkpoints(spec.checkpoints)
+        .with_genesis(spec.genesis)
+}
+
+async fn send_transaction(tr: BTransport, to: NetAddr) -> Result<()> {
+    let priv_key = PrivateKey::generate();
+    let contract = contract()?;
+    let mut tx = Transaction::new(contract);
+    tx.sign(&priv_key);
+    let mut buf: Vec<u8> = Vec::new();
+    tx.encode(&mut BincodeEncoder::new(&mut buf))?;
+
+    let msg = Message::new(MessageType::Tx, buf);
+
+    tr.send_message(&to, msg.bytes()?).await?;
+    Ok(())
+}
+
+fn contract() -> Result<Vec<u8>> {
+    assemble("push 2; push 3; add; store FOO; get FOO; return")
+}
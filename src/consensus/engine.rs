@@ -0,0 +1,651 @@
+/*
+The ConsensusEngine decides one block per height among the members of a ValidatorSet, in rounds of three steps:
+
+1. Propose: the proposer of the round broadcasts a block.
+2. Prevote: every validator prevotes for the proposal, or for nil if it didn't arrive in time.
+3. Precommit: after a quorum (2/3) of prevotes for the block a validator precommits it, otherwise it
+   precommits nil.
+
+A block with a quorum of precommits is committed and final, there is no fork choice. It carries the header
+signatures of the precommits, so every node can check it with BlockValidator::with_validator_set. If a round
+doesn't commit a block the next round starts with the next proposer.
+
+A validator that precommitted a block is locked on it: it only prevotes for that block until a quorum prevotes
+for something else in a later round, and proposes it again when it's the proposer. This keeps two rounds from
+committing different blocks at the same height.
+
+The engine doesn't do any IO, the server feeds it messages and timeouts and carries out the returned actions.
+*/
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, error};
+
+use super::{ConsensusMessage, Proposal, Vote, VoteKind};
+use crate::{
+    core::{Block, BlockHasher, BlockSignature, Hasher, ValidatorSet},
+    crypto::{PrivateKey, PublicKey},
+    types::Hash,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+    // the block of the height is committed, waiting for the next height
+    Commit,
+}
+
+// Fires when a step of a round took too long. In the Commit step it starts the next height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    pub height: u32,
+    pub round: u32,
+    pub step: Step,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub propose: Duration,
+    pub prevote: Duration,
+    pub precommit: Duration,
+    // time between committing a block and starting the next height
+    pub commit: Duration,
+    // added to the other timeouts in every round, so a slow network eventually decides
+    pub round_increase: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            propose: Duration::from_secs(3),
+            prevote: Duration::from_secs(1),
+            precommit: Duration::from_secs(1),
+            commit: Duration::from_secs(1),
+            round_increase: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsensusOpts {
+    // the validators of the genesis block, later ones join by staking
+    pub validators: ValidatorSet,
+    // number of blocks the validator set stays the same
+    pub epoch_length: u32,
+    pub timeouts: Timeouts,
+}
+
+#[derive(Debug)]
+pub enum Action {
+    // We propose in this round. The block is built on top of the chain, signed and passed to propose().
+    BuildProposal { height: u32, round: u32 },
+    Broadcast(ConsensusMessage),
+    // call on_timeout after the duration
+    ScheduleTimeout(Timeout, Duration),
+    // the block got a quorum of precommits and carries their signatures
+    Commit(Block),
+}
+
+pub struct ConsensusEngine {
+    validators: ValidatorSet,
+    key: PrivateKey,
+    timeouts: Timeouts,
+    height: u32,
+    round: u32,
+    step: Step,
+    // proposed blocks of the current height by round
+    proposals: HashMap<u32, Block>,
+    // votes of the current height, one per validator, round and kind
+    votes: HashMap<(u32, VoteKind), Vec<Vote>>,
+    // the round we precommitted a block in and the block
+    locked: Option<(u32, Block)>,
+}
+
+impl ConsensusEngine {
+    pub fn new(validators: ValidatorSet, key: PrivateKey, timeouts: Timeouts) -> Result<Self> {
+        if !validators.contains(&key.public_key()) {
+            return Err(anyhow!(
+                "{} is not a member of the validator set",
+                key.public_key().address()
+            ));
+        }
+
+        Ok(Self {
+            validators,
+            key,
+            timeouts,
+            height: 0,
+            round: 0,
+            step: Step::Commit,
+            proposals: HashMap::new(),
+            votes: HashMap::new(),
+            locked: None,
+        })
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    pub fn proposer(&self, height: u32, round: u32) -> PublicKey {
+        let validators = self.validators.validators();
+        validators[(height as usize + round as usize) % validators.len()]
+    }
+
+    // The validator set changes with the stakes, called after a block was committed
+    pub fn set_validators(&mut self, validators: ValidatorSet) {
+        self.validators = validators;
+    }
+
+    pub fn start_height(&mut self, height: u32) -> Vec<Action> {
+        self.height = height;
+        self.proposals.clear();
+        self.votes.clear();
+        self.locked = None;
+        self.start_round(0)
+    }
+
+    fn start_round(&mut self, round: u32) -> Vec<Action> {
+        debug!(
+            "consensus: starting round {} of height {}",
+            round, self.height
+        );
+        self.round = round;
+        self.step = Step::Propose;
+
+        let mut actions = vec![self.schedule(Step::Propose)];
+        if self.proposer(self.height, round) == self.key.public_key() {
+            match self.locked.clone() {
+                Some((_, mut block)) => {
+                    // signed again, the proposer of the round has to sign the proposal
+                    if let Err(err) = block.sign(&self.key) {
+                        error!("consensus: could not sign proposal: {err}");
+                        return actions;
+                    }
+                    actions.extend(self.propose(block));
+                }
+                None => actions.push(Action::BuildProposal {
+                    height: self.height,
+                    round,
+                }),
+            }
+        } else if self.proposals.contains_key(&round) {
+            // the proposal arrived before we started the round
+            actions.extend(self.prevote());
+        }
+        actions.extend(self.check_votes());
+        actions
+    }
+
+    // Proposes block in the current round, it has to extend the chain and be signed by us
+    pub fn propose(&mut self, block: Block) -> Vec<Action> {
+        if self.step != Step::Propose || block.header.height != self.height {
+            return vec![];
+        }
+
+        let proposal = Proposal {
+            height: self.height,
+            round: self.round,
+            block,
+        };
+        let mut actions = vec![Action::Broadcast(ConsensusMessage::Proposal(
+            proposal.clone(),
+        ))];
+        actions.extend(self.on_proposal(proposal));
+        actions
+    }
+
+    // The server has checked that the block is valid and extends the chain
+    pub fn on_proposal(&mut self, proposal: Proposal) -> Vec<Action> {
+        if proposal.height != self.height
+            || proposal.block.header.height != self.height
+            || self.step == Step::Commit
+        {
+            return vec![];
+        }
+        if proposal.block.validator() != Some(&self.proposer(proposal.height, proposal.round)) {
+            debug!(
+                "consensus: ignoring proposal for round {} not signed by its proposer",
+                proposal.round
+            );
+            return vec![];
+        }
+        // only the first proposal of a round counts
+        if self.proposals.contains_key(&proposal.round) {
+            return vec![];
+        }
+        self.proposals.insert(proposal.round, proposal.block);
+
+        let mut actions = vec![];
+        if proposal.round == self.round && self.step == Step::Propose {
+            actions.extend(self.prevote());
+        }
+        actions.extend(self.check_votes());
+        actions
+    }
+
+    pub fn on_vote(&mut self, vote: Vote) -> Vec<Action> {
+        if vote.height != self.height || self.step == Step::Commit {
+            return vec![];
+        }
+        if !self.validators.contains(&vote.validator) {
+            debug!(
+                "consensus: ignoring vote of {}, not a validator",
+                vote.validator.address()
+            );
+            return vec![];
+        }
+        if let Err(err) = vote.verify() {
+            debug!("consensus: ignoring vote: {err}");
+            return vec![];
+        }
+
+        let votes = self.votes.entry((vote.round, vote.kind)).or_default();
+        if votes.iter().any(|v| v.validator == vote.validator) {
+            return vec![];
+        }
+        votes.push(vote);
+
+        self.check_votes()
+    }
+
+    pub fn on_timeout(&mut self, timeout: Timeout) -> Vec<Action> {
+        if timeout.height != self.height || timeout.round != self.round || timeout.step != self.step
+        {
+            return vec![];
+        }
+
+        match timeout.step {
+            Step::Propose => self.vote(VoteKind::Prevote, None),
+            Step::Prevote => self.vote(VoteKind::Precommit, None),
+            Step::Precommit => self.start_round(self.round + 1),
+            Step::Commit => self.start_height(self.height + 1),
+        }
+    }
+
+    fn prevote(&mut self) -> Vec<Action> {
+        let block = match (&self.locked, self.proposals.get(&self.round)) {
+            (Some((_, locked)), Some(block)) if block_hash(locked) != block_hash(block) => None,
+            (_, block) => block.cloned(),
+        };
+        self.vote(VoteKind::Prevote, block.as_ref())
+    }
+
+    // Casts our vote in the current round and moves on to the step after it
+    fn vote(&mut self, kind: VoteKind, block: Option<&Block>) -> Vec<Action> {
+        let vote = match Vote::new(kind, self.height, self.round, block, &self.key) {
+            Ok(vote) => vote,
+            Err(err) => {
+                error!("consensus: could not sign vote: {err}");
+                return vec![];
+            }
+        };
+
+        self.step = match kind {
+            VoteKind::Prevote => Step::Prevote,
+            VoteKind::Precommit => Step::Precommit,
+        };
+        let mut actions = vec![
+            self.schedule(self.step),
+            Action::Broadcast(ConsensusMessage::Vote(vote.clone())),
+        ];
+        self.votes.entry((vote.round, kind)).or_default().push(vote);
+
+        actions.extend(self.check_votes());
+        actions
+    }
+
+    fn check_votes(&mut self) -> Vec<Action> {
+        if self.step == Step::Commit {
+            return vec![];
+        }
+        if let Some(actions) = self.try_commit() {
+            return actions;
+        }
+
+        // more than 1/3 of the validators are in a later round, at least one honest one moved on
+        if let Some(round) = self.later_round() {
+            return self.start_round(round);
+        }
+
+        let prevotes = self.quorum_for(self.round, VoteKind::Prevote);
+        if let (Some((locked_round, locked)), Some(hash)) = (&self.locked, prevotes) {
+            if *locked_round < self.round && hash != Some(block_hash(locked)) {
+                debug!("consensus: releasing the lock of round {locked_round}");
+                self.locked = None;
+            }
+        }
+
+        match (self.step, prevotes) {
+            (Step::Prevote, Some(Some(hash))) => {
+                // without the block we can't precommit it, the prevote timeout precommits nil
+                if let Some(block) = self.proposal_with_hash(hash) {
+                    self.locked = Some((self.round, block.clone()));
+                    return self.vote(VoteKind::Precommit, Some(&block));
+                }
+            }
+            (Step::Prevote, Some(None)) => return self.vote(VoteKind::Precommit, None),
+            _ => {}
+        }
+
+        if self.step == Step::Precommit
+            && self.quorum_for(self.round, VoteKind::Precommit) == Some(None)
+        {
+            return self.start_round(self.round + 1);
+        }
+        vec![]
+    }
+
+    // Commits a block that has a quorum of precommits in any round
+    fn try_commit(&mut self) -> Option<Vec<Action>> {
+        let rounds: Vec<u32> = self
+            .votes
+            .keys()
+            .filter(|(_, kind)| *kind == VoteKind::Precommit)
+            .map(|(round, _)| *round)
+            .collect();
+
+        for round in rounds {
+            let Some(Some(hash)) = self.quorum_for(round, VoteKind::Precommit) else {
+                continue;
+            };
+            let Some(mut block) = self.proposal_with_hash(hash) else {
+                continue;
+            };
+
+            for vote in &self.votes[&(round, VoteKind::Precommit)] {
+                let Some(signature) = vote
+                    .header_signature
+                    .filter(|_| vote.block_hash == Some(hash))
+                else {
+                    continue;
+                };
+                let sig = BlockSignature {
+                    validator: vote.validator,
+                    signature,
+                };
+                if let Err(err) = block.add_signature(sig) {
+                    debug!("consensus: dropping precommit signature: {err}");
+                }
+            }
+
+            debug!(
+                "consensus: committing block {} at height {} in round {}",
+                hash, self.height, round
+            );
+            self.step = Step::Commit;
+            return Some(vec![Action::Commit(block), self.schedule(Step::Commit)]);
+        }
+        None
+    }
+
+    // The value a quorum voted for in round, None is nil
+    fn quorum_for(&self, round: u32, kind: VoteKind) -> Option<Option<Hash>> {
+        let mut counts: HashMap<Option<Hash>, usize> = HashMap::new();
+        for vote in self.votes.get(&(round, kind))? {
+            *counts.entry(vote.block_hash).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .find(|(_, count)| *count >= self.validators.quorum())
+            .map(|(hash, _)| hash)
+    }
+
+    fn later_round(&self) -> Option<u32> {
+        let mut voters: HashMap<u32, Vec<&PublicKey>> = HashMap::new();
+        for ((round, _), votes) in &self.votes {
+            if *round <= self.round {
+                continue;
+            }
+            let round_voters = voters.entry(*round).or_default();
+            for vote in votes {
+                if !round_voters.contains(&&vote.validator) {
+                    round_voters.push(&vote.validator);
+                }
+            }
+        }
+
+        voters
+            .into_iter()
+            .filter(|(_, voters)| voters.len() * 3 > self.validators.len())
+            .map(|(round, _)| round)
+            .min()
+    }
+
+    fn proposal_with_hash(&self, hash: Hash) -> Option<Block> {
+        self.proposals
+            .values()
+            .find(|block| block_hash(block) == hash)
+            .cloned()
+    }
+
+    fn schedule(&self, step: Step) -> Action {
+        let increase = self.timeouts.round_increase * self.round;
+        let after = match step {
+            Step::Propose => self.timeouts.propose + increase,
+            Step::Prevote => self.timeouts.prevote + increase,
+            Step::Precommit => self.timeouts.precommit + increase,
+            Step::Commit => self.timeouts.commit,
+        };
+
+        Action::ScheduleTimeout(
+            Timeout {
+                height: self.height,
+                round: self.round,
+                step,
+            },
+            after,
+        )
+    }
+}
+
+fn block_hash(block: &Block) -> Hash {
+    BlockHasher
+        .hash(&block.header)
+        .unwrap_or_else(|err| panic!("hashing a header failed: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Header;
+    use std::collections::VecDeque;
+
+    // Validators exchanging messages in memory, the ones without an engine are offline
+    struct Network {
+        keys: Vec<PrivateKey>,
+        engines: Vec<Option<ConsensusEngine>>,
+        prev: Header,
+        queue: VecDeque<(usize, Action)>,
+        timeouts: Vec<(usize, Timeout)>,
+        commits: Vec<(usize, Block)>,
+    }
+
+    impl Network {
+        fn new(n: usize, offline: &[usize]) -> Result<Self> {
+            let keys: Vec<PrivateKey> = (0..n).map(|_| PrivateKey::generate()).collect();
+            let set = ValidatorSet::new(keys.iter().map(|key| key.public_key()).collect());
+
+            let mut engines = vec![];
+            for (i, key) in keys.iter().enumerate() {
+                engines.push(match offline.contains(&i) {
+                    true => None,
+                    false => Some(ConsensusEngine::new(
+                        set.clone(),
+                        key.clone(),
+                        Timeouts::default(),
+                    )?),
+                });
+            }
+
+            Ok(Self {
+                keys,
+                engines,
+                prev: Block::genesis().header,
+                queue: VecDeque::new(),
+                timeouts: vec![],
+                commits: vec![],
+            })
+        }
+
+        fn push(&mut self, from: usize, actions: Vec<Action>) {
+            self.queue
+                .extend(actions.into_iter().map(|action| (from, action)));
+        }
+
+        fn start(&mut self, height: u32) {
+            for i in 0..self.engines.len() {
+                if let Some(engine) = &mut self.engines[i] {
+                    let actions = engine.start_height(height);
+                    self.push(i, actions);
+                }
+            }
+            self.run();
+        }
+
+        fn run(&mut self) {
+            while let Some((from, action)) = self.queue.pop_front() {
+                match action {
+                    Action::BuildProposal { .. } => {
+                        let mut b = Block::from_prev_header(self.prev, vec![]).unwrap();
+                        b.sign(&self.keys[from]).unwrap();
+                        let actions = self.engines[from].as_mut().unwrap().propose(b);
+                        self.push(from, actions);
+                    }
+                    Action::Broadcast(msg) => {
+                        for i in (0..self.engines.len()).filter(|i| *i != from) {
+                            let Some(engine) = &mut self.engines[i] else {
+                                continue;
+                            };
+                            let actions = match msg.clone() {
+                                ConsensusMessage::Proposal(p) => engine.on_proposal(p),
+                                ConsensusMessage::Vote(v) => engine.on_vote(v),
+                            };
+                            self.push(i, actions);
+                        }
+                    }
+                    Action::ScheduleTimeout(timeout, _) => self.timeouts.push((from, timeout)),
+                    Action::Commit(b) => self.commits.push((from, b)),
+                }
+            }
+        }
+
+        // fires the pending timeouts of step
+        fn fire(&mut self, step: Step) {
+            let timeouts = std::mem::take(&mut self.timeouts);
+            for (i, timeout) in timeouts {
+                if timeout.step != step {
+                    self.timeouts.push((i, timeout));
+                    continue;
+                }
+                let actions = self.engines[i].as_mut().unwrap().on_timeout(timeout);
+                self.push(i, actions);
+            }
+            self.run();
+        }
+
+        fn online(&self) -> usize {
+            self.engines.iter().flatten().count()
+        }
+
+        fn check_commits(&self) -> Result<Block> {
+            assert_eq!(self.commits.len(), self.online());
+            let mut block = self.commits[0].1.clone();
+            for (_, b) in &self.commits {
+                assert_eq!(b.header, block.header);
+            }
+
+            let set = ValidatorSet::new(self.keys.iter().map(|key| key.public_key()).collect());
+            set.check_quorum(&block.signers())?;
+            block.verify()?;
+            Ok(block)
+        }
+    }
+
+    #[test]
+    fn test_commit() -> Result<()> {
+        let mut net = Network::new(4, &[])?;
+        net.start(1);
+
+        let block = net.check_commits()?;
+        assert_eq!(block.header.height, 1);
+        // the proposer of round 0
+        assert_eq!(block.validator(), Some(&net.keys[1].public_key()));
+
+        // the next height starts after the commit timeout
+        net.fire(Step::Commit);
+        assert!(net
+            .engines
+            .iter()
+            .flatten()
+            .all(|engine| engine.height() == 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_offline_proposer() -> Result<()> {
+        // the proposer of round 0 is offline, the round commits nothing
+        let mut net = Network::new(4, &[1])?;
+        net.start(1);
+        assert!(net.commits.is_empty());
+
+        // everyone prevotes and precommits nil and moves on to the next proposer
+        net.fire(Step::Propose);
+        assert!(net
+            .engines
+            .iter()
+            .flatten()
+            .all(|engine| engine.round() == 1));
+
+        let block = net.check_commits()?;
+        assert_eq!(block.validator(), Some(&net.keys[2].public_key()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_quorum() -> Result<()> {
+        // two of four validators can't commit anything
+        let mut net = Network::new(4, &[2, 3])?;
+        net.start(1);
+        for _ in 0..3 {
+            net.fire(Step::Propose);
+            net.fire(Step::Prevote);
+            net.fire(Step::Precommit);
+        }
+        assert!(net.commits.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_foreign_votes() -> Result<()> {
+        let mut net = Network::new(4, &[])?;
+        let engine = net.engines[0].as_mut().unwrap();
+        engine.start_height(1);
+
+        let outsider = PrivateKey::generate();
+        let vote = Vote::new(VoteKind::Prevote, 1, 0, None, &outsider)?;
+        assert!(engine.on_vote(vote).is_empty());
+
+        // a vote with a signature of another height
+        let mut vote = Vote::new(VoteKind::Prevote, 2, 0, None, &net.keys[1])?;
+        vote.height = 1;
+        assert!(engine.on_vote(vote).is_empty());
+        assert!(engine.votes.is_empty());
+
+        Ok(())
+    }
+}
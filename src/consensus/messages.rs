@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{Block, BlockHasher, Hasher},
+    crypto::{PrivateKey, PublicKey, Signature},
+    types::Hash,
+};
+
+// The block the proposer of a round wants to commit, signed by the proposer like any other block
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Proposal {
+    pub height: u32,
+    pub round: u32,
+    pub block: Block,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub height: u32,
+    pub round: u32,
+    // None is a vote for nil, no block in this round
+    pub block_hash: Option<Hash>,
+    pub validator: PublicKey,
+    pub signature: Signature,
+    // A precommit for a block also signs its header. The signature is added to the committed block,
+    // so nodes that didn't take part in the round can check the block had a quorum.
+    pub header_signature: Option<Signature>,
+}
+
+impl Vote {
+    pub fn new(
+        kind: VoteKind,
+        height: u32,
+        round: u32,
+        block: Option<&Block>,
+        key: &PrivateKey,
+    ) -> Result<Self> {
+        let block_hash = block.map(|b| BlockHasher.hash(&b.header)).transpose()?;
+        let header_signature = match (kind, block) {
+            (VoteKind::Precommit, Some(b)) => Some(key.sign(&b.header.bytes()?)),
+            _ => None,
+        };
+
+        Ok(Self {
+            kind,
+            height,
+            round,
+            block_hash,
+            validator: key.public_key(),
+            signature: key.sign(&signing_bytes(kind, height, round, block_hash)?),
+            header_signature,
+        })
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let bytes = signing_bytes(self.kind, self.height, self.round, self.block_hash)?;
+        if !self.signature.verify(&bytes, &self.validator) {
+            return Err(anyhow!(
+                "vote has an invalid signature from {}",
+                self.validator.address()
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn signing_bytes(
+    kind: VoteKind,
+    height: u32,
+    round: u32,
+    block_hash: Option<Hash>,
+) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(kind, height, round, block_hash))?)
+}
+
+#[derive(Clone, Debug)]
+pub enum ConsensusMessage {
+    Proposal(Proposal),
+    Vote(Vote),
+}
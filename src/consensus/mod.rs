@@ -0,0 +1,5 @@
+mod engine;
+mod messages;
+
+pub use engine::*;
+pub use messages::*;
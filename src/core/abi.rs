@@ -0,0 +1,104 @@
+/*
+ABI convention for contract calls.
+The call data of a transaction (Transaction::input) starts with a selector byte that tells the contract which
+function to run, followed by the arguments. Every argument is length prefixed:
+
+    | selector: u8 | len: u8 | arg 0 | len: u8 | arg 1 | ...
+
+Arguments are at most 255 bytes long, so a contract can read them with CallDataLoad using offsets pushed with
+a single push. The selector is at offset 0, the first argument starts at offset 2.
+*/
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub selector: u8,
+    pub args: Vec<Vec<u8>>,
+}
+
+impl Call {
+    pub fn new(selector: u8) -> Self {
+        Self {
+            selector,
+            args: vec![],
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<Vec<u8>>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut out = vec![self.selector];
+        for arg in &self.args {
+            let len = u8::try_from(arg.len())
+                .map_err(|_| anyhow!("call argument of {} bytes is too long", arg.len()))?;
+            out.push(len);
+            out.extend_from_slice(arg);
+        }
+        Ok(out)
+    }
+
+    pub fn decode(calldata: &[u8]) -> Result<Self> {
+        let (selector, mut rest) = calldata
+            .split_first()
+            .ok_or_else(|| anyhow!("call data has no selector"))?;
+
+        let mut args = vec![];
+        while let Some((len, tail)) = rest.split_first() {
+            let len = *len as usize;
+            if tail.len() < len {
+                return Err(anyhow!("call argument {} is truncated", args.len()));
+            }
+            args.push(tail[..len].to_vec());
+            rest = &tail[len..];
+        }
+
+        Ok(Self {
+            selector: *selector,
+            args,
+        })
+    }
+
+    // Offset of the bytes of an argument in the encoded call data
+    pub fn arg_offset(&self, index: usize) -> Option<usize> {
+        if index >= self.args.len() {
+            return None;
+        }
+        Some(
+            1 + self.args[..index]
+                .iter()
+                .map(|a| 1 + a.len())
+                .sum::<usize>()
+                + 1,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() -> Result<()> {
+        let call = Call::new(0x01)
+            .arg(vec![7])
+            .arg(b"FOO".to_vec())
+            .arg(vec![]);
+        let encoded = call.encode()?;
+
+        assert_eq!(encoded, vec![0x01, 1, 7, 3, b'F', b'O', b'O', 0]);
+        assert_eq!(Call::decode(&encoded)?, call);
+        assert_eq!(call.arg_offset(0), Some(2));
+        assert_eq!(call.arg_offset(1), Some(4));
+        assert_eq!(call.arg_offset(3), None);
+
+        assert!(Call::decode(&[]).is_err());
+        assert!(Call::decode(&[0x01, 3, 1]).is_err());
+        assert!(Call::new(0).arg(vec![0; 256]).encode().is_err());
+
+        Ok(())
+    }
+}
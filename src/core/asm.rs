@@ -0,0 +1,390 @@
+/*
+A small assembly language for the VM, so contracts don't have to be written as raw byte vectors.
+
+    push 2; push 3; add; store FOO    # comments run until the end of the line
+
+Statements are separated by ';' or newlines. Supported statements:
+
+    push N        push the int N (0..=255)
+    pushb N       push the byte N (0..=255)
+    add, sub, mul, div, mod, pack, store, get
+    dup, swap, drop, over, return
+    cdload        pop an offset and a length and push that part of the call data
+    cdsize        push the length of the call data
+    jump          pop a target and continue there
+    jumpif        pop a target and a condition, jump if the condition isn't 0
+    pick          pop n and push a copy of the item at depth n
+    eq, lt, gt    compare the two top items (top OP second), push 1 or 0
+    mstore        pop an offset and a value and write the bytes of the value to memory at the offset
+    mload         pop an offset and a length and push that part of the memory
+    msize         push the size of the memory, it grows in words of 32 bytes
+    caller        push the address of the signer of the transaction
+    height        push the height of the block
+    timestamp     push the timestamp of the block in seconds
+    txhash        push the hash of the transaction
+    balance       pop an address and push its balance
+    transfer      pop an address and an amount (not 0) and send the amount from the contract account to the address
+    NAME:         define a label
+    push @NAME    push the address of a label
+    store KEY     store the value on top of the stack under KEY
+    get KEY       push the value stored under KEY
+
+Numbers are decimal or hex (0x..). KEY is a string of up to 255 bytes, it is pushed byte by byte and packed.
+
+The VM reads the operand of a push from the byte before the opcode and skips bytes that are not opcodes.
+An operand that happens to be an opcode would therefore be executed, so the assembler builds such values
+from safe operands and adds instead (which leaves an int on the stack, even for pushb).
+*/
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use super::Instruction;
+
+// A statement after parsing, label addresses are only known once the code is laid out
+enum Item {
+    Code(Vec<u8>),
+    Label(String),
+    PushLabel(String),
+}
+
+pub fn assemble(src: &str) -> Result<Vec<u8>> {
+    let mut items = vec![];
+
+    for (line_no, line) in src.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+
+        for stmt in line.split(';') {
+            let stmt = stmt.trim();
+            if stmt.is_empty() {
+                continue;
+            }
+            parse_statement(stmt, &mut items)
+                .map_err(|err| anyhow!("line {}: {}: {}", line_no + 1, stmt, err))?;
+        }
+    }
+
+    layout(&items)
+}
+
+// The length of a push depends on its operand, so moving a label can move the labels after it.
+// Start with all labels at 0 and lay out the code until the addresses don't change anymore. A push
+// of a label never gets shorter than in the pass before, it's padded with bytes the VM skips
+// instead, so the addresses only grow and can't flip back and forth. The padding goes after the
+// push, a label before it has to point at the operand to be a valid jump target.
+fn layout(items: &[Item]) -> Result<Vec<u8>> {
+    let mut labels: HashMap<&str, u8> = HashMap::new();
+    for item in items {
+        if let Item::Label(name) = item {
+            if labels.insert(name, 0).is_some() {
+                return Err(anyhow!("label {name} is defined twice"));
+            }
+        }
+    }
+    let mut push_lens = vec![0; items.len()];
+
+    for _ in 0..MAX_LAYOUT_PASSES {
+        let mut code = vec![];
+        let mut addresses = HashMap::new();
+
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Item::Code(bytes) => code.extend_from_slice(bytes),
+                Item::Label(name) => {
+                    let addr = u8::try_from(code.len())
+                        .map_err(|_| anyhow!("label {name} is out of reach of push"))?;
+                    addresses.insert(name.as_str(), addr);
+                }
+                Item::PushLabel(name) => {
+                    let addr = labels
+                        .get(name.as_str())
+                        .ok_or_else(|| anyhow!("label {name} is not defined"))?;
+                    let mut bytes = vec![];
+                    push(&mut bytes, *addr, Instruction::PushInt);
+                    push_lens[i] = push_lens[i].max(bytes.len());
+                    let padding = push_lens[i] - bytes.len();
+                    code.extend(bytes);
+                    code.resize(code.len() + padding, PADDING);
+                }
+            }
+        }
+
+        if addresses == labels {
+            return Ok(code);
+        }
+        labels = addresses;
+    }
+
+    Err(anyhow!("label addresses don't settle"))
+}
+
+const MAX_LAYOUT_PASSES: usize = 64;
+
+// not an opcode and not read as an operand, the push after it reads its own operand. It's no jump
+// target either.
+const PADDING: u8 = 0;
+
+fn parse_statement(stmt: &str, items: &mut Vec<Item>) -> Result<()> {
+    // a label can be followed by an instruction: "end: push 1"
+    let stmt = match stmt.split_once(':') {
+        Some((label, rest)) if !label.contains(char::is_whitespace) => {
+            if label.is_empty() {
+                return Err(anyhow!("invalid label"));
+            }
+            items.push(Item::Label(label.to_string()));
+            rest.trim()
+        }
+        _ => stmt,
+    };
+    if stmt.is_empty() {
+        return Ok(());
+    }
+
+    let mut parts = stmt.split_whitespace();
+    let op = parts.next().unwrap_or_default().to_lowercase();
+    let arg = parts.next();
+
+    if parts.next().is_some() {
+        return Err(anyhow!("too many operands"));
+    }
+
+    let mut code = vec![];
+    match (op.as_str(), arg) {
+        ("push", Some(label)) if label.starts_with('@') => {
+            items.push(Item::PushLabel(label[1..].to_string()));
+            return Ok(());
+        }
+        ("push", Some(n)) => push(&mut code, parse_number(n)?, Instruction::PushInt),
+        ("pushb", Some(n)) => push(&mut code, parse_number(n)?, Instruction::PushByte),
+        ("store", Some(key)) => {
+            push_key(&mut code, key)?;
+            code.push(Instruction::Store as u8);
+        }
+        ("get", Some(key)) => {
+            push_key(&mut code, key)?;
+            code.push(Instruction::Get as u8);
+        }
+        (op, None) => code.push(parse_instruction(op)? as u8),
+        (op, Some(_)) => {
+            parse_instruction(op)?;
+            return Err(anyhow!("{op} takes no operand"));
+        }
+    }
+
+    items.push(Item::Code(code));
+    Ok(())
+}
+
+fn parse_instruction(op: &str) -> Result<Instruction> {
+    use Instruction::*;
+
+    let instr = match op {
+        "add" => Add,
+        "sub" => Sub,
+        "mul" => Mul,
+        "div" => Div,
+        "mod" => Mod,
+        "pack" => Pack,
+        "store" => Store,
+        "get" => Get,
+        "dup" => Dup,
+        "swap" => Swap,
+        "drop" => Drop,
+        "over" => Over,
+        "return" => Return,
+        "cdload" => CallDataLoad,
+        "cdsize" => CallDataSize,
+        "jump" => Jump,
+        "jumpif" => JumpIf,
+        "pick" => Pick,
+        "eq" => Eq,
+        "lt" => Lt,
+        "gt" => Gt,
+        "mload" => MLoad,
+        "mstore" => MStore,
+        "msize" => MSize,
+        "caller" => Caller,
+        "height" => BlockHeight,
+        "timestamp" => Timestamp,
+        "txhash" => TxHash,
+        "balance" => Balance,
+        "transfer" => Transfer,
+        "push" | "pushb" => return Err(anyhow!("{op} needs an operand")),
+        _ => return Err(anyhow!("unknown instruction {op}")),
+    };
+    Ok(instr)
+}
+
+fn parse_number(s: &str) -> Result<u8> {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse::<u8>(),
+    };
+    n.map_err(|_| anyhow!("{s} is not a number between 0 and 255"))
+}
+
+fn is_opcode(b: u8) -> bool {
+    Instruction::from_byte(b).is_some()
+}
+
+fn push(code: &mut Vec<u8>, n: u8, instr: Instruction) {
+    if !is_opcode(n) {
+        code.extend([n, instr as u8]);
+        return;
+    }
+
+    // Split n into a safe operand a and the rest, preferring a split where the rest is safe
+    // too. Otherwise the rest gets split again, the first opcode (0x0a) is small enough that
+    // this always terminates.
+    let a = (1..n)
+        .find(|a| !is_opcode(*a) && !is_opcode(n - a))
+        .or_else(|| (1..n).rev().find(|a| !is_opcode(*a)))
+        .expect("operands below the first opcode are safe");
+    code.extend([a, Instruction::PushInt as u8]);
+    push(code, n - a, Instruction::PushInt);
+    code.push(Instruction::Add as u8);
+}
+
+fn push_key(code: &mut Vec<u8>, key: &str) -> Result<()> {
+    let bytes = key.as_bytes();
+    if bytes.is_empty() || bytes.len() > u8::MAX as usize {
+        return Err(anyhow!("key must be between 1 and 255 bytes long"));
+    }
+
+    // pack pops the bytes in order, so they are pushed in reverse
+    for b in bytes.iter().rev() {
+        push(code, *b, Instruction::PushByte);
+    }
+    push(code, bytes.len() as u8, Instruction::PushInt);
+    code.push(Instruction::Pack as u8);
+
+    Ok(())
+}
+
+// Pretty prints bytecode, one instruction per line with its offset.
+// Bytes that are not opcodes are operands (or ignored by the VM) and are not listed.
+pub fn disassemble(code: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (ip, b) in code.iter().enumerate() {
+        let Some(instr) = Instruction::from_byte(*b) else {
+            continue;
+        };
+
+        let line = match instr {
+            Instruction::PushInt | Instruction::PushByte => {
+                let operand = code[ip.saturating_sub(1)];
+                format!("{:04x}  {:<6} {}\n", ip, instr, operand)
+            }
+            _ => format!("{:04x}  {}\n", ip, instr),
+        };
+        out.push_str(&line);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{ExecutionContext, StackItem, State, VM},
+        types::Address,
+    };
+
+    fn run(code: Vec<u8>, state: &mut State) -> Result<StackItem> {
+        let mut vm = VM::new(code, state, ExecutionContext::default());
+        vm.run().into_result()?;
+        Ok(vm.stack.pop())
+    }
+
+    #[test]
+    fn test_assemble() -> Result<()> {
+        assert_eq!(
+            assemble("push 2; push 3; sub")?,
+            vec![0x02, 0x0a, 0x03, 0x0a, 0x0e]
+        );
+        assert_eq!(
+            assemble("pushb 0x4f\npushb 0x4f # comment\npushb 0x46; push 3; pack")?,
+            vec![0x4f, 0x0c, 0x4f, 0x0c, 0x46, 0x0c, 0x03, 0x0a, 0x0d]
+        );
+
+        assert!(assemble("push").is_err());
+        assert!(assemble("push 256").is_err());
+        assert!(assemble("add 1").is_err());
+        assert_eq!(
+            assemble("dup; swap; drop; over")?,
+            vec![0x10, 0x11, 0x12, 0x13]
+        );
+        assert!(assemble("jmp").is_err());
+        assert!(assemble("push @nowhere").is_err());
+        assert!(assemble("a:; a:").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_and_get() -> Result<()> {
+        let mut state = State::new();
+        let result = run(
+            assemble("push 2; push 3; add; store FOO; get FOO")?,
+            &mut state,
+        )?;
+
+        assert_eq!(state.get(&Address::default(), b"FOO")?, vec![5]);
+        assert_eq!(5, TryInto::<u8>::try_into(result)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_operands_colliding_with_opcodes() -> Result<()> {
+        let mut state = State::new();
+        for n in 0..=255_u8 {
+            let result = run(assemble(&format!("push {n}"))?, &mut state)?;
+            assert_eq!(n, TryInto::<u8>::try_into(result)?);
+
+            let result = run(assemble(&format!("pushb {n}"))?, &mut state)?;
+            assert_eq!(n, TryInto::<u8>::try_into(result)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_disassemble() -> Result<()> {
+        let code = assemble("push 2; pushb 3; mul; push 10")?;
+
+        assert_eq!(
+            disassemble(&code),
+            "0001  push   2\n0003  pushb  3\n0004  mul\n0006  push   1\n0008  push   9\n0009  add\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels() -> Result<()> {
+        // a forward and a backward reference
+        let code = assemble("push @end; jump; start: push 1; end: push @start")?;
+        assert_eq!(code, vec![0x05, 0x0a, 0x18, 0x01, 0x0a, 0x03, 0x0a]);
+
+        // labels whose address collides with an opcode need a longer push, which moves the
+        // labels after them
+        let mut src = "push @end; jump;".to_string();
+        src.push_str(&"push 1;".repeat(5));
+        src.push_str("end: push 2");
+        let code = assemble(&src)?;
+
+        let mut state = State::new();
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
+        assert_eq!(vm.stack.items(), vec![StackItem::Int(2)]);
+
+        Ok(())
+    }
+}
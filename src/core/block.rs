@@ -1,7 +1,7 @@
-use std::time::Instant;
+use std::{collections::HashSet, iter, time::Instant};
 
 use crate::{
-    crypto::{PrivateKey, PublicKey, Signature},
+    crypto::{verify_batch, PrivateKey, PublicKey, Signature, SignedMessage},
     types::Hash,
 };
 use anyhow::{anyhow, Result};
@@ -12,32 +12,71 @@ use super::{
     encoding::{Decoder, Encoder},
     hasher::Hasher,
     transaction::Transaction,
-    BincodeEncoder, BlockHasher, TxHasher,
+    BincodeEncoder, BlockHasher, Canonical, GenesisConfig, HeaderVersion, SigCache, TxHasher,
 };
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Header {
-    version: u32,
-    data_hash: Hash,
+    pub version: u32,
+    pub data_hash: Hash,
     pub prev_block_hash: Option<Hash>,
-    timestamp: u128,
+    pub timestamp: u128,
     pub height: u32,
+    // the validator set of the epoch signs the block, see Blockchain::epoch_of
+    pub epoch: u32,
+    // the State::root_hash of the accounts after the block, so far only the genesis block commits to it and the
+    // other blocks leave it zero
+    pub state_root: Hash,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Block {
     pub header: Header,
     pub transactions: Vec<Transaction>,
+    // the proposer of the block
     validator: Option<PublicKey>,
     signature: Option<Signature>,
+    // signatures of the other members of the validator set, see ValidatorSet
+    committee_signatures: Vec<BlockSignature>,
     // Cached version of the header hash
     #[serde(skip)]
     hash: Hash,
+    // the header the block had when it passed pre_validate
+    #[serde(skip)]
+    pre_validated: Option<Box<Header>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct BlockSignature {
+    pub validator: PublicKey,
+    pub signature: Signature,
 }
 
 impl Header {
+    // The bytes the header is hashed and signed over, see canonical.rs
     pub fn bytes(&self) -> Result<Vec<u8>> {
-        Ok(bincode::serialize(&self)?)
+        Ok(self.canonical_bytes())
+    }
+
+    // Fails for a version this node doesn't know
+    pub fn version(&self) -> Result<HeaderVersion> {
+        HeaderVersion::try_from(self.version)
+    }
+
+    pub fn data_hash(&self) -> Hash {
+        self.data_hash
+    }
+
+    pub fn prev_block_hash(&self) -> Option<Hash> {
+        self.prev_block_hash
+    }
+
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
     }
 }
 
@@ -48,7 +87,9 @@ impl Block {
             transactions: txx,
             validator: None,
             signature: None,
+            committee_signatures: vec![],
             hash: Hash::default(),
+            pre_validated: None,
         }
     }
 
@@ -61,18 +102,24 @@ impl Block {
             prev_block_hash: Some(BlockHasher {}.hash(&ph)?),
             timestamp: Instant::now().elapsed().as_nanos(),
             height: ph.height + 1,
+            // the caller moves the block to the next epoch at an epoch boundary
+            epoch: ph.epoch,
+            state_root: Hash::default(),
         };
 
         Ok(Self::new(header, txx))
     }
 
-    pub fn hash(&mut self, hasher: Box<dyn Hasher<Header>>) -> Hash {
+    // Hashes the header on the first call and caches the hash, a header that can't be encoded is an error
+    pub fn hash(&mut self, hasher: Box<dyn Hasher<Header>>) -> Result<Hash> {
         if self.hash.is_zero() {
-            self.hash = hasher
-                .hash(&self.header)
-                .map_err(|err| panic!("block.rs hashing failed: {err}"))
-                .unwrap();
+            self.hash = hasher.hash(&self.header)?;
         }
+        Ok(self.hash)
+    }
+
+    // the hash calculated by the last call to hash(), zero if it was never called
+    pub fn cached_hash(&self) -> Hash {
         self.hash
     }
 
@@ -85,7 +132,105 @@ impl Block {
         Ok(())
     }
 
+    // Adds the signature of another validator, it signs the same header as the proposer
+    pub fn add_committee_signature(&mut self, private_key: &PrivateKey) -> Result<()> {
+        let signature = private_key.sign(&self.header.bytes()?);
+        self.add_signature(BlockSignature {
+            validator: private_key.public_key(),
+            signature,
+        })
+    }
+
+    // Adds a signature another validator made of the header, a second one of the same validator is ignored
+    pub fn add_signature(&mut self, sig: BlockSignature) -> Result<()> {
+        if self.signers().contains(&sig.validator) {
+            return Ok(());
+        }
+        if !sig.signature.verify(&self.header.bytes()?, &sig.validator) {
+            return Err(anyhow!(
+                "invalid block signature from {}",
+                sig.validator.address()
+            ));
+        }
+
+        self.committee_signatures.push(sig);
+        Ok(())
+    }
+
+    // Sets the proposer without checking its signature, e.g. of a block translated from another wire format
+    pub fn set_proposer(&mut self, sig: BlockSignature) {
+        self.validator = Some(sig.validator);
+        self.signature = Some(sig.signature);
+    }
+
+    pub fn validator(&self) -> Option<&PublicKey> {
+        self.validator.as_ref()
+    }
+
+    // The proposer followed by the committee
+    pub fn signers(&self) -> Vec<PublicKey> {
+        self.validator
+            .iter()
+            .chain(self.committee_signatures.iter().map(|sig| &sig.validator))
+            .copied()
+            .collect()
+    }
+
+    // The signature of the proposer followed by the ones of the committee
+    pub fn signatures(&self) -> Vec<BlockSignature> {
+        let proposer = self
+            .validator
+            .zip(self.signature)
+            .map(|(validator, signature)| BlockSignature {
+                validator,
+                signature,
+            });
+        proposer
+            .into_iter()
+            .chain(self.committee_signatures.iter().copied())
+            .collect()
+    }
+
     pub fn verify(&mut self) -> Result<()> {
+        self.verify_signatures()?;
+        self.unique_tx_hashes()?;
+        let txx: Vec<&Transaction> = self.transactions.iter().collect();
+        Transaction::verify_batch(&txx)?;
+        self.verify_data_hash()
+    }
+
+    // The hashes of the transactions in block order, a transaction included twice would be applied twice and
+    // fails the block. Cached hashes aren't calculated again.
+    pub fn unique_tx_hashes(&self) -> Result<Vec<Hash>> {
+        let mut seen = HashSet::new();
+        let mut hashes = Vec::with_capacity(self.transactions.len());
+        for tx in &self.transactions {
+            let hash = match tx.has_cached_hash() {
+                true => tx.hash(),
+                false => TxHasher.hash(tx)?,
+            };
+            if !seen.insert(hash) {
+                return Err(anyhow!("block contains transaction {hash} twice"));
+            }
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    // Like verify, the transactions verified before by the cache aren't verified again and a block that passed
+    // pre_validate isn't verified again at all, unless its header changed since
+    pub fn verify_cached(&mut self, cache: &SigCache) -> Result<()> {
+        if self.pre_validated.as_deref() == Some(&self.header) {
+            return Ok(());
+        }
+        self.verify_signatures()?;
+        let txx: Vec<&Transaction> = self.transactions.iter().collect();
+        cache.verify_batch(&txx)?;
+        self.verify_data_hash()
+    }
+
+    // The signatures of the proposer and the committee
+    fn verify_signatures(&self) -> Result<()> {
         let sig = self
             .signature
             .as_ref()
@@ -95,14 +240,68 @@ impl Block {
             .as_ref()
             .ok_or_else(|| anyhow!("block has no validator (public_key)"))?;
 
-        if !sig.verify(&self.header.bytes()?, pub_key) {
+        let signers = self.signers();
+        for (i, sig) in self.committee_signatures.iter().enumerate() {
+            // the proposer is the first signer
+            if signers[..=i].contains(&sig.validator) {
+                return Err(anyhow!(
+                    "block is signed twice by {}",
+                    sig.validator.address()
+                ));
+            }
+        }
+
+        let header = self.header.bytes()?;
+        let batch: Vec<SignedMessage> = iter::once((header.as_slice(), sig, pub_key))
+            .chain(
+                self.committee_signatures
+                    .iter()
+                    .map(|sig| (header.as_slice(), &sig.signature, &sig.validator)),
+            )
+            .collect();
+        if verify_batch(&batch) {
+            return Ok(());
+        }
+
+        // the batch doesn't tell which signature is invalid
+        if !sig.verify(&header, pub_key) {
             return Err(anyhow!("block has invalid signature"));
         }
+        let invalid = self
+            .committee_signatures
+            .iter()
+            .find(|sig| !sig.signature.verify(&header, &sig.validator))
+            .map(|sig| sig.validator.address());
+        Err(anyhow!(
+            "block has invalid signature from {}",
+            invalid.unwrap_or_default()
+        ))
+    }
 
-        for tx in &self.transactions {
-            tx.verify()?;
+    // The checks that don't need the chain: the size, the signatures and the data hash. They run before the block
+    // is handed to the chain, which then only checks where the block fits, see BlockVerifier.
+    pub fn pre_validate(&mut self, cache: &SigCache, max_size: usize) -> Result<()> {
+        let size = bincode::serialized_size(self)?;
+        if size > max_size as u64 {
+            return Err(anyhow!(
+                "block has {size} bytes, at most {max_size} are allowed"
+            ));
         }
 
+        for tx in &mut self.transactions {
+            if !tx.has_cached_hash() {
+                tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            }
+        }
+        self.hash(Box::new(BlockHasher))?;
+
+        self.verify_cached(cache)?;
+        self.pre_validated = Some(Box::new(self.header));
+        Ok(())
+    }
+
+    // Checks that the transactions are the ones the header commits to, without checking any signature
+    pub fn verify_data_hash(&self) -> Result<()> {
         let data_hash = calculate_data_hash(&self.transactions)?;
 
         if data_hash != self.header.data_hash {
@@ -124,70 +323,103 @@ impl Block {
         dec.decode(self)
     }
 
+    // The genesis block of a chain without a GenesisConfig
     pub fn genesis() -> Block {
-        let header = Header {
-            version: 1,
-            data_hash: Hash::default(),
-            prev_block_hash: None,
-            timestamp: 0,
-            height: 0,
-        };
-
-        Block::new(header, vec![])
+        GenesisConfig::new().block()
     }
+}
 
-    pub fn random(height: u32, prev_block_hash: Hash) -> Result<Block> {
-        let private_key = PrivateKey::generate();
-        let mut tx = Transaction::new(vec![0x03, 0x0a, 0x02, 0x0a, 0x0e]);
-        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
-
-        let header = Header {
-            version: 1,
-            data_hash: Hash::random(),
-            prev_block_hash: Some(prev_block_hash),
-            timestamp: std::time::Instant::now().elapsed().as_nanos(),
-            height,
-        };
-
-        let mut b = Block::new(header, vec![]);
-        let data_hash = calculate_data_hash(&b.transactions)?;
-        b.header.data_hash = data_hash;
-        b.sign(&private_key)?;
-
-        Ok(b)
+pub fn calculate_data_hash(txx: &[Transaction]) -> Result<Hash> {
+    let mut hasher = DataHasher::new();
+    for tx in txx {
+        hasher.add(tx)?;
     }
+    Ok(hasher.finish())
 }
 
-pub fn calculate_data_hash(txx: &Vec<Transaction>) -> Result<Hash> {
-    let mut buf: Vec<u8> = vec![];
+// The data hash of a block, fed one transaction at a time. Each transaction is encoded straight into the
+// digest, the result is the calculate_data_hash of the transactions added so far.
+#[derive(Clone, Default)]
+pub struct DataHasher {
+    digest: Sha256,
+}
 
-    for tx in txx {
-        tx.encode(&mut BincodeEncoder::new(&mut buf))?;
+impl DataHasher {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    let hash = Sha256::digest(buf.as_slice());
+    pub fn add(&mut self, tx: &Transaction) -> Result<()> {
+        tx.encode(&mut BincodeEncoder::new(&mut self.digest))
+    }
 
-    Ok(Hash::from_bytes(&hash))
+    pub fn finish(self) -> Hash {
+        Hash::from_digest(self.digest.finalize().into())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::hasher::BlockHasher;
+    use crate::{core::hasher::BlockHasher, test_utils::*};
     use anyhow::Result;
+    use proptest::prelude::*;
 
     #[test]
     fn test_hash_block() -> Result<()> {
-        let mut block = Block::random(0, Hash::default())?;
-        let hash = block.hash(Box::new(BlockHasher));
+        let mut block = random_block(0, Hash::default())?;
+        let hash = block.hash(Box::new(BlockHasher))?;
         println!("hash: {hash}");
         Ok(())
     }
 
+    #[test]
+    fn test_streaming_hashers() -> Result<()> {
+        let block = random_block(1, Hash::random())?;
+        let digest = |bytes: &[u8]| Hash::from_digest(Sha256::digest(bytes).into());
+        assert_eq!(
+            BlockHasher.hash(&block.header)?,
+            digest(&block.header.bytes()?)
+        );
+
+        let mut buf = vec![];
+        let mut hasher = DataHasher::new();
+        for tx in &block.transactions {
+            assert_eq!(TxHasher.hash(tx)?, digest(&tx.signing_bytes()));
+            tx.encode(&mut BincodeEncoder::new(&mut buf))?;
+            hasher.add(tx)?;
+        }
+        assert_eq!(hasher.finish(), digest(&buf));
+        assert_eq!(
+            block.header.data_hash,
+            calculate_data_hash(&block.transactions)?
+        );
+        Ok(())
+    }
+
+    struct FailingHasher;
+
+    impl Hasher<Header> for FailingHasher {
+        fn update(&self, _: &Header, _: &mut Sha256) -> Result<()> {
+            Err(anyhow!("header can't be encoded"))
+        }
+    }
+
+    #[test]
+    fn test_hash_block_error() -> Result<()> {
+        let mut block = random_block(0, Hash::default())?;
+        assert!(block.hash(Box::new(FailingHasher)).is_err());
+        assert!(block.cached_hash().is_zero());
+
+        let hash = block.hash(Box::new(BlockHasher))?;
+        assert_eq!(block.cached_hash(), hash);
+        Ok(())
+    }
+
     #[test]
     fn test_sign_block() -> Result<()> {
         let private_key = PrivateKey::generate();
-        let mut b = Block::random(0, Hash::default())?;
+        let mut b = random_block(0, Hash::default())?;
         b.sign(&private_key)?;
         assert!(b.signature.is_some());
 
@@ -196,7 +428,7 @@ mod tests {
     #[test]
     fn test_verify_block() -> Result<()> {
         let private_key = PrivateKey::generate();
-        let mut b = Block::random(0, Hash::default())?;
+        let mut b = random_block(0, Hash::default())?;
         b.sign(&private_key)?;
         b.verify()?;
 
@@ -212,4 +444,79 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_committee_signatures() -> Result<()> {
+        let proposer = PrivateKey::generate();
+        let member = PrivateKey::generate();
+        let mut b = random_block(0, Hash::default())?;
+        b.sign(&proposer)?;
+        b.add_committee_signature(&member)?;
+        // signing again doesn't add another signature
+        b.add_committee_signature(&member)?;
+        b.add_committee_signature(&proposer)?;
+
+        assert_eq!(
+            b.signers(),
+            vec![proposer.public_key(), member.public_key()]
+        );
+        b.verify()?;
+
+        // a committee signature of another header is invalid
+        let mut other = b.clone();
+        other.header.height = 1;
+        other.sign(&proposer)?;
+        assert!(other.verify().is_err());
+
+        // duplicates are rejected
+        b.committee_signatures.push(b.committee_signatures[0]);
+        assert!(b.verify().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pre_validate() -> Result<()> {
+        let cache = SigCache::default();
+        let mut b = next_block(Header::default(), vec![random_tx(), random_tx()])?;
+        let size = encoded(&b)?.len();
+
+        assert!(b.clone().pre_validate(&cache, size - 1).is_err());
+        b.pre_validate(&cache, size)?;
+        assert!(b.transactions.iter().all(|tx| tx.has_cached_hash()));
+        b.verify_cached(&cache)?;
+
+        // a changed header is verified again
+        b.header.height = 2;
+        assert!(b.verify_cached(&cache).is_err());
+
+        let mut unsigned = Block::new(b.header, b.transactions.clone());
+        assert!(unsigned.pre_validate(&cache, size).is_err());
+
+        Ok(())
+    }
+
+    proptest! {
+        // signing is slow in debug builds
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn prop_header_round_trip(header in arb_header()) {
+            prop_assert_eq!(round_trip(&header, Header::default()).unwrap(), header);
+        }
+
+        #[test]
+        fn prop_block_round_trip(b in arb_block()) {
+            let mut decoded = round_trip(&b, Block::new(Header::default(), vec![])).unwrap();
+            prop_assert_eq!(decoded.header, b.header);
+            prop_assert_eq!(encoded(&decoded).unwrap(), encoded(&b).unwrap());
+            prop_assert!(decoded.verify().is_ok());
+        }
+
+        #[test]
+        fn prop_verify(mut valid in arb_block(), mut invalid in arb_invalid_block()) {
+            prop_assert!(valid.verify().is_ok());
+            prop_assert!(invalid.verify().is_err());
+        }
+    }
 }
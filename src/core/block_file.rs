@@ -0,0 +1,121 @@
+/*
+A block file holds a range of blocks in chain order, every block is the u32 big endian length of its bincode
+encoding followed by the encoding. Blockchain::export_blocks writes one and Blockchain::import_blocks validates and
+adds the blocks of one, so a chain can be moved between nodes or seeded offline.
+*/
+
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{anyhow, Result};
+
+use super::Block;
+
+// a longer record is rejected before it's read
+pub const MAX_BLOCK_RECORD_SIZE: u32 = 16 * 1024 * 1024;
+
+pub struct BlockFileWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> BlockFileWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    pub fn write(&mut self, block: &Block) -> Result<()> {
+        let record = bincode::serialize(block)?;
+        let len = u32::try_from(record.len())
+            .ok()
+            .filter(|len| *len <= MAX_BLOCK_RECORD_SIZE)
+            .ok_or_else(|| {
+                anyhow!(
+                    "block {} has {} bytes, a block file allows {}",
+                    block.header.height,
+                    record.len(),
+                    MAX_BLOCK_RECORD_SIZE
+                )
+            })?;
+        self.inner.write_all(&len.to_be_bytes())?;
+        self.inner.write_all(&record)?;
+        Ok(())
+    }
+
+    // Flushes the blocks and returns the writer
+    pub fn finish(mut self) -> Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+// Reads the blocks one at a time, a file that ends within a record fails with the last item
+pub struct BlockFileReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> BlockFileReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    fn read_block(&mut self) -> Result<Option<Block>> {
+        let mut len = [0; 4];
+        match self.inner.read(&mut len[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => self.inner.read_exact(&mut len[1..])?,
+            Err(err) if err.kind() == ErrorKind::Interrupted => return self.read_block(),
+            Err(err) => return Err(err.into()),
+        }
+
+        let len = u32::from_be_bytes(len);
+        if len > MAX_BLOCK_RECORD_SIZE {
+            return Err(anyhow!(
+                "block record has {len} bytes, a block file allows {MAX_BLOCK_RECORD_SIZE}"
+            ));
+        }
+        let mut record = vec![0; len as usize];
+        self.inner.read_exact(&mut record)?;
+        Ok(Some(bincode::deserialize(&record)?))
+    }
+}
+
+impl<R: Read> Iterator for BlockFileReader<R> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_block().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::*, types::Hash};
+
+    #[test]
+    fn test_block_file() -> Result<()> {
+        let first = random_block(0, Hash::default())?;
+        let second = next_block(first.header, vec![random_tx()])?;
+
+        let mut writer = BlockFileWriter::new(vec![]);
+        writer.write(&first)?;
+        writer.write(&second)?;
+        let file = writer.finish()?;
+
+        let blocks = BlockFileReader::new(file.as_slice()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].header, first.header);
+        assert_eq!(blocks[1].header, second.header);
+        assert_eq!(blocks[1].transactions.len(), 1);
+
+        // a torn record
+        let mut reader = BlockFileReader::new(&file[..file.len() - 1]);
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+
+        let too_long = (MAX_BLOCK_RECORD_SIZE + 1).to_be_bytes();
+        assert!(BlockFileReader::new(&too_long[..]).next().unwrap().is_err());
+        assert!(BlockFileReader::new(&[][..]).next().is_none());
+
+        Ok(())
+    }
+}
@@ -1,38 +1,154 @@
-use crate::types::Hash;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::types::{Address, Hash};
 
 use super::{
     block::{Block, Header},
+    contract_address,
     hasher::{BlockHasher, Hasher},
-    storage::{MemoryStore, Storage},
+    storage::{ChainMeta, FileStore, MemoryStore, Storage},
     validator::{BlockValidator, Validator},
-    State, VM,
+    BClock, BlockFileReader, BlockFileWriter, Checkpoints, ExecutionContext, GenesisConfig,
+    HeaderUpgrades, HeaderVersion, IncludedTxs, Receipt, RejectCode, SigCache, State, StateStore,
+    SystemClock, Transaction, TxHasher, TxKind, TxRejection, TxStatus, ValidatorSet, VmOutcome,
+    MIN_VALIDATOR_STAKE, VM,
 };
+use crate::crypto::PublicKey;
 use anyhow::{anyhow, Result};
-use log::info;
 use tokio::sync::RwLock;
+use tracing::{debug, field, info, info_span, warn, Instrument};
 
 // maybe use a lifetime to only store a reference to the header?
 // headers: Vec<&'a Header>,
 pub struct Blockchain {
     store: Box<dyn Storage>,
-    headers: RwLock<Vec<Header>>,
+    // the newest headers with their hashes, all of them if pruning is disabled
+    headers: RwLock<VecDeque<(Hash, Header)>>,
+    // the height of every block of the chain by hash, pruned ones included
+    heights: HashMap<Hash, u32>,
+    // number of headers that were dropped from memory, the height of the first one in headers
+    pruned: u32,
+    // maximum number of headers kept in memory
+    keep_headers: Option<usize>,
     validator: Option<Box<dyn Validator>>,
+    checkpoints: Checkpoints,
     pub server_id: String,
-    // TODO: make this an interface
     contract_state: State,
+    // (height, index in block, block timestamp) of every transaction on the chain
+    tx_index: HashMap<Hash, (u32, u32, u128)>,
+    // hashes of the transactions sent by an address or transferring to it, in chain order
+    address_index: HashMap<Address, Vec<Hash>>,
+    receipts: HashMap<Hash, Receipt>,
+    // every transaction on the chain, pruned ones included, shared with the TxPool
+    included: IncludedTxs,
+    mode: NodeMode,
+    // the state after every snapshot_interval-th block by height, only on archival nodes, see state_at
+    snapshots: BTreeMap<u32, State>,
+    snapshot_interval: u32,
+    // the lowest height whose block and receipts are kept, 0 unless the node is pruned
+    history_start: u32,
+    // number of blocks of an epoch, the validator set only changes between epochs
+    epoch_length: u32,
+    // timestamps of the blocks built by next_block
+    clock: BClock,
+    // shared with the TxPool, the transactions of the mem_pool aren't verified again in their block
+    sig_cache: SigCache,
+    header_upgrades: HeaderUpgrades,
+}
+
+// number of transactions returned per page by txs_for_address
+pub const ADDRESS_TX_PAGE_SIZE: usize = 50;
+
+pub const DEFAULT_EPOCH_LENGTH: u32 = 100;
+
+// number of blocks between two state snapshots of an archival node, state_at replays at most this many blocks
+pub const DEFAULT_SNAPSHOT_INTERVAL: u32 = 100;
+
+// Keeps the newest keep_headers headers in memory, the older ones are read from a file at path. The chain in the
+// file is continued when the node restarts, see recover.
+#[derive(Debug, Clone)]
+pub struct Pruning {
+    pub keep_headers: usize,
+    pub path: PathBuf,
+}
+
+// Archival nodes keep every block and receipt. Pruned nodes keep the newest keep_blocks of them, the older ones
+// can't be served to syncing peers or looked up. Both keep every header and the current state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NodeMode {
+    #[default]
+    Archival,
+    Pruned {
+        keep_blocks: u32,
+    },
 }
 
 impl Blockchain {
-    pub async fn new(server_id: String, mut genesis: Block) -> Result<Self> {
+    pub async fn new(server_id: String, genesis: Block) -> Result<Self> {
+        Self::with_store(server_id, genesis, Box::new(MemoryStore::new())).await
+    }
+
+    pub async fn with_store(
+        server_id: String,
+        mut genesis: Block,
+        store: Box<dyn Storage>,
+    ) -> Result<Self> {
         let mut bc = Blockchain {
-            store: Box::new(MemoryStore::new()),
+            store,
             validator: Some(Box::new(BlockValidator::new())),
-            headers: RwLock::new(vec![]),
+            checkpoints: Checkpoints::new(),
+            headers: RwLock::new(VecDeque::new()),
+            heights: HashMap::new(),
+            pruned: 0,
+            keep_headers: None,
             server_id,
             contract_state: State::new(),
+            tx_index: HashMap::new(),
+            address_index: HashMap::new(),
+            receipts: HashMap::new(),
+            included: IncludedTxs::new(),
+            mode: NodeMode::Archival,
+            snapshots: BTreeMap::new(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            history_start: 0,
+            epoch_length: DEFAULT_EPOCH_LENGTH,
+            clock: SystemClock::shared(),
+            sig_cache: SigCache::default(),
+            header_upgrades: HeaderUpgrades::new(),
         };
 
-        bc.add_block_without_validation(&mut genesis).await?;
+        // a reopened store has the genesis block already, the blocks above it are replayed by recover
+        match bc.store.get_header(0)? {
+            Some(header) if header == genesis.header => {
+                bc.index_block(&genesis)?;
+                let hash = genesis.hash(Box::new(BlockHasher))?;
+                bc.push_header(hash, genesis.header).await;
+            }
+            Some(header) => {
+                return Err(anyhow!(
+                    "the store belongs to another chain, its genesis block is {}",
+                    BlockHasher.hash(&header)?
+                ))
+            }
+            None => bc.add_block_without_validation(&mut genesis).await?,
+        }
+        Ok(bc)
+    }
+
+    pub async fn with_pruning(
+        server_id: String,
+        genesis: Block,
+        pruning: &Pruning,
+    ) -> Result<Self> {
+        let store = FileStore::open(&pruning.path)?;
+        let mut bc = Self::with_store(server_id, genesis, Box::new(store)).await?;
+        bc.set_pruning(pruning.keep_headers).await;
         Ok(bc)
     }
 
@@ -40,114 +156,783 @@ impl Blockchain {
         self.validator = Some(v);
     }
 
+    pub fn set_checkpoints(&mut self, checkpoints: Checkpoints) {
+        self.checkpoints = checkpoints;
+    }
+
+    // Keeps the contract data in store, the data committed so far is moved into it. Set it before the chain is
+    // recovered, the data of the replayed blocks goes into store then.
+    pub fn set_state_store(&mut self, store: Box<dyn StateStore>) -> Result<()> {
+        self.contract_state.set_store(store)
+    }
+
+    pub fn set_sig_cache(&mut self, sig_cache: SigCache) {
+        self.sig_cache = sig_cache;
+    }
+
+    pub fn sig_cache(&self) -> &SigCache {
+        &self.sig_cache
+    }
+
+    pub fn set_header_upgrades(&mut self, header_upgrades: HeaderUpgrades) {
+        self.header_upgrades = header_upgrades;
+    }
+
+    // The header version a block at height has to have
+    pub fn header_version_at(&self, height: u32) -> HeaderVersion {
+        self.header_upgrades.version_at(height)
+    }
+
+    pub fn checkpoints(&self) -> &Checkpoints {
+        &self.checkpoints
+    }
+
+    pub fn set_epoch_length(&mut self, epoch_length: u32) {
+        self.epoch_length = epoch_length.max(1);
+    }
+
+    pub fn epoch_of(&self, height: u32) -> u32 {
+        height / self.epoch_length
+    }
+
+    pub fn set_clock(&mut self, clock: BClock) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> BClock {
+        self.clock.clone()
+    }
+
+    // Builds an unsigned block with the given height on top of the block before it
+    pub async fn next_block(&self, height: u32, txx: Vec<Transaction>) -> Result<Block> {
+        let prev_header = self.get_header(height - 1).await?;
+        let mut block = Block::from_prev_header(prev_header, txx)?;
+        block.header.timestamp = self.clock.unix_nanos();
+        block.header.epoch = self.epoch_of(height);
+        block.header.version = self.header_version_at(height).as_u32();
+        Ok(block)
+    }
+
+    // Drops all but the newest keep_headers headers from memory, they're still in the store.
+    // The newest header is always kept, the next block is validated against it.
+    pub async fn set_pruning(&mut self, keep_headers: usize) {
+        self.keep_headers = Some(keep_headers.max(1));
+        self.prune().await;
+    }
+
+    async fn prune(&mut self) {
+        let Some(keep) = self.keep_headers else {
+            return;
+        };
+        let mut headers = self.headers.write().await;
+        while headers.len() > keep {
+            headers.pop_front();
+            self.pruned += 1;
+        }
+    }
+
+    // A pruned node drops the blocks below the newest keep_blocks right away, the newest block is always kept
+    pub async fn set_mode(&mut self, mode: NodeMode) -> Result<()> {
+        self.mode = match mode {
+            NodeMode::Pruned { keep_blocks } => NodeMode::Pruned {
+                keep_blocks: keep_blocks.max(1),
+            },
+            NodeMode::Archival => NodeMode::Archival,
+        };
+        if self.mode != NodeMode::Archival {
+            self.snapshots.clear();
+        }
+        self.prune_history().await
+    }
+
+    // Snapshots are taken from the next block on, a shorter interval makes state_at faster and uses more memory
+    pub fn set_snapshot_interval(&mut self, snapshot_interval: u32) {
+        self.snapshot_interval = snapshot_interval.max(1);
+    }
+
+    pub fn mode(&self) -> NodeMode {
+        self.mode
+    }
+
+    // The lowest height whose block and receipts are kept, peers can sync from it
+    pub fn lowest_block(&self) -> u32 {
+        self.history_start
+    }
+
+    // Drops the blocks a pruned node doesn't keep and their transactions from the indexes
+    async fn prune_history(&mut self) -> Result<()> {
+        let NodeMode::Pruned { keep_blocks } = self.mode else {
+            return Ok(());
+        };
+        let lowest = (self.height().await + 1).saturating_sub(keep_blocks);
+        if lowest <= self.history_start {
+            return Ok(());
+        }
+        for height in self.history_start..lowest {
+            if let Some(b) = self.store.get_block(height)? {
+                self.forget_block(&b)?;
+            }
+        }
+        self.history_start = lowest;
+        self.store.prune_blocks(lowest)
+    }
+
     pub async fn has_block(&self, height: u32) -> bool {
         height <= self.height().await
     }
 
     pub async fn add_block(&mut self, b: &mut Block) -> Result<()> {
+        let hash = b.hash(Box::new(BlockHasher))?;
+        let span = info_span!(
+            "block",
+            id = %self.server_id,
+            height = b.header.height,
+            hash = %hash
+        );
+        self.apply_block(b).instrument(span).await
+    }
+
+    async fn apply_block(&mut self, b: &mut Block) -> Result<()> {
         self.validator
             .as_ref()
             .ok_or_else(|| anyhow!("blockchain has no validator"))?
             .validate_block(self, b)
             .await?;
 
-        // run vm code
-        for tx in &b.transactions {
-            info!(
-                "ID={} Running VM code hash={} len={}",
-                self.server_id,
-                tx.data.len(),
-                tx.hash()
-            );
-            let mut vm = VM::new(tx.data.clone(), &mut self.contract_state);
-            vm.run()?;
+        self.execute_block(b)?;
+        self.add_block_without_validation(b).await?;
+        Ok(())
+    }
+
+    // Replays the blocks of a reopened store on top of the genesis block. The blocks up to the state height of
+    // the ChainMeta were validated before and are only executed again. The ones above it were being added when
+    // the node stopped, they're validated and added again, or dropped with everything above them if they fail.
+    // Call it once the chain is configured, validation and execution depend on the validator, epochs and
+    // checkpoints. Returns the height of the recovered chain.
+    pub async fn recover(&mut self) -> Result<u32> {
+        let meta = self.store.get_meta()?.unwrap_or_default();
+        let tip = self.store.get_header(meta.tip_height)?;
+        let trusted = match tip {
+            Some(header) if BlockHasher.hash(&header)? == meta.tip_hash => meta.state_height,
+            // the tip was replaced by a block that wasn't committed, none of the blocks are trusted
+            _ => 0,
+        };
 
-            let result = vm.stack.pop();
-            info!("VM RESULT: {:?}", result);
-            info!("VM STATE: {:?}", self.contract_state);
+        let mut height = self.height().await;
+        while height < trusted {
+            let mut b = self
+                .store
+                .get_block(height + 1)?
+                .ok_or_else(|| anyhow!("committed block {} is missing", height + 1))?;
+            self.execute_block(&b)?;
+            self.index_block(&b)?;
+            let hash = b.hash(Box::new(BlockHasher))?;
+            self.push_header(hash, b.header).await;
+            height += 1;
         }
 
-        self.add_block_without_validation(b).await?;
+        // adding a block drops the stored blocks above it, so they're read first
+        let mut uncommitted = vec![];
+        while let Some(b) = self
+            .store
+            .get_block(height + 1 + uncommitted.len() as u32)?
+        {
+            uncommitted.push(b);
+        }
+        for mut b in uncommitted {
+            if let Err(err) = self.add_block(&mut b).await {
+                warn!(
+                    "ID={} dropping the uncommitted blocks from height {}: {}",
+                    self.server_id, b.header.height, err
+                );
+                self.store.truncate(height)?;
+                break;
+            }
+            height += 1;
+        }
+
+        self.prune_history().await?;
+        info!(
+            "ID={} recovered the chain up to height {}",
+            self.server_id, height
+        );
+        Ok(height)
+    }
+
+    // Applies the transactions of a valid block to the state. Archival nodes keep a snapshot of the state every
+    // snapshot_interval blocks, taken before the block above it changes it.
+    fn execute_block(&mut self, b: &Block) -> Result<()> {
+        let parent = b.header.height.saturating_sub(1);
+        if self.mode == NodeMode::Archival && parent.is_multiple_of(self.snapshot_interval) {
+            self.snapshots
+                .insert(parent, self.contract_state.snapshot());
+        }
+
+        let receipts = execute_on(
+            &mut self.contract_state,
+            b,
+            self.epoch_length,
+            &self.server_id,
+        )?;
+        self.receipts.extend(
+            receipts
+                .into_iter()
+                .map(|receipt| (receipt.tx_hash, receipt)),
+        );
         Ok(())
     }
 
+    pub fn is_slashed(&self, validator: &PublicKey) -> bool {
+        self.contract_state.is_slashed(validator)
+    }
+
+    // The validators that sign the blocks of the current epoch, see State::validator_set
+    pub fn validator_set(&self) -> ValidatorSet {
+        self.contract_state.validator_set()
+    }
+
+    // Credits the balances of the genesis state, before any block above the genesis block is added. Fails if the
+    // genesis block commits to another state.
+    pub async fn apply_genesis(&mut self, config: &GenesisConfig) -> Result<()> {
+        let genesis = self.get_header(0).await?;
+        let root = config.state_root();
+        if genesis.state_root != root {
+            return Err(anyhow!(
+                "the genesis block commits to the state root {}, the genesis config gives {}",
+                genesis.state_root,
+                root
+            ));
+        }
+        for (address, balance) in &config.alloc {
+            self.contract_state.credit(*address, *balance);
+        }
+        Ok(())
+    }
+
+    // Gives the genesis validators their stake, they decide the first blocks
+    pub fn add_genesis_validators(&mut self, validators: &ValidatorSet) {
+        for validator in validators.validators() {
+            self.contract_state
+                .credit(validator.address(), MIN_VALIDATOR_STAKE);
+            self.contract_state
+                .stake(validator, MIN_VALIDATOR_STAKE)
+                .expect("the genesis validator has the balance it stakes");
+        }
+        self.contract_state.start_epoch();
+    }
+
+    pub fn state(&self) -> &State {
+        &self.contract_state
+    }
+
+    // The state after the block at height. Archival nodes replay the blocks above the snapshot below it, pruned
+    // nodes only have the current state.
+    pub async fn state_at(&self, height: u32) -> Result<State> {
+        let tip = self.height().await;
+        if height > tip {
+            return Err(anyhow!("given height {height} too high"));
+        }
+        if height == tip {
+            return Ok(self.contract_state.snapshot());
+        }
+        if self.mode != NodeMode::Archival {
+            return Err(anyhow!(
+                "the state at height {height} isn't kept, only archival nodes keep historical states"
+            ));
+        }
+
+        let (&from, snapshot) =
+            self.snapshots.range(..=height).next_back().ok_or_else(|| {
+                anyhow!("there is no snapshot of the state below height {height}")
+            })?;
+        let mut state = snapshot.snapshot();
+        for h in from + 1..=height {
+            let b = self.get_block(h).await?;
+            execute_on(&mut state, &b, self.epoch_length, &self.server_id)?;
+        }
+        Ok(state)
+    }
+
     async fn add_block_without_validation(&mut self, b: &mut Block) -> Result<()> {
         info!(
             "ID={} Adding block {} with height {} to and transaction len {} to blockchain",
             self.server_id,
-            b.hash(Box::new(BlockHasher)),
+            b.hash(Box::new(BlockHasher))?,
             b.header.height,
             b.transactions.len(),
         );
-        self.headers.write().await.push(b.header);
+        self.index_block(b)?;
+        self.store.put_header(&b.header)?;
+        self.store.put_block(b)?;
+        // the block is committed once the meta points at it
+        let height = b.header.height;
+        let hash = b.hash(Box::new(BlockHasher))?;
+        self.store.put_meta(&ChainMeta {
+            tip_height: height,
+            tip_hash: hash,
+            state_height: height,
+        })?;
+        self.push_header(hash, b.header).await;
+        self.prune_history().await
+    }
+
+    async fn push_header(&mut self, hash: Hash, header: Header) {
+        self.heights.insert(hash, header.height);
+        self.headers.write().await.push_back((hash, header));
+        self.prune().await;
+    }
+
+    fn index_block(&mut self, b: &Block) -> Result<()> {
+        for (index, tx) in b.transactions.iter().enumerate() {
+            let hash = tx_hash(tx)?;
+            self.included.insert([hash]);
+            self.tx_index
+                .insert(hash, (b.header.height, index as u32, b.header.timestamp));
+            // mined, the signature isn't checked again
+            self.sig_cache.remove(&[hash]);
+
+            for address in tx_addresses(tx) {
+                self.address_index.entry(address).or_default().push(hash);
+            }
+        }
         Ok(())
     }
 
+    // Removes the transactions of a block from the indexes, used when the block is
+    // unwound in a reorg
+    pub fn unindex_block(&mut self, b: &Block) -> Result<()> {
+        let hashes = b
+            .transactions
+            .iter()
+            .map(tx_hash)
+            .collect::<Result<Vec<_>>>()?;
+        self.included.remove(&hashes);
+        self.forget_block(b)
+    }
+
+    // Removes the transactions of a block from the lookups, they stay included
+    fn forget_block(&mut self, b: &Block) -> Result<()> {
+        for tx in b.transactions.iter().rev() {
+            let hash = tx_hash(tx)?;
+            self.tx_index.remove(&hash);
+            self.receipts.remove(&hash);
+
+            for address in tx_addresses(tx) {
+                if let Some(hashes) = self.address_index.get_mut(&address) {
+                    if let Some(pos) = hashes.iter().rposition(|h| *h == hash) {
+                        hashes.remove(pos);
+                    }
+                    if hashes.is_empty() {
+                        self.address_index.remove(&address);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Hashes of the transactions sent by addr or transferring to it, oldest first,
+    // ADDRESS_TX_PAGE_SIZE per page
+    pub fn txs_for_address(&self, addr: &Address, page: usize) -> Vec<Hash> {
+        self.address_index
+            .get(addr)
+            .map(|hashes| {
+                hashes
+                    .iter()
+                    .skip(page.saturating_mul(ADDRESS_TX_PAGE_SIZE))
+                    .take(ADDRESS_TX_PAGE_SIZE)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Whether the transaction is in a block of the chain, pruned blocks included
+    pub fn is_included(&self, hash: &Hash) -> bool {
+        self.included.contains(hash)
+    }
+
+    // Shared with the TxPool, which rejects included transactions
+    pub fn included_txs(&self) -> IncludedTxs {
+        self.included.clone()
+    }
+
+    pub fn receipt(&self, hash: &Hash) -> Option<&Receipt> {
+        self.receipts.get(hash)
+    }
+
+    // Checks a transaction with a valid signature against the state of the chain tip before it enters the
+    // mem_pool. A transaction passing may still fail in a block, e.g. if a pending one spends the same balance.
+    pub fn check_transaction(&self, tx: &Transaction) -> Result<(), TxRejection> {
+        let hash = tx_hash(tx)
+            .map_err(|err| TxRejection::new(RejectCode::InvalidSignature, err.to_string()))?;
+        if self.included.contains(&hash) {
+            let message = match self.tx_status(&hash) {
+                TxStatus::InBlock { height, .. } => {
+                    format!("tx {hash} is in the block with height {height}")
+                }
+                _ => format!("tx {hash} is in a pruned block"),
+            };
+            return Err(TxRejection::new(RejectCode::AlreadyIncluded, message));
+        }
+        self.contract_state.check_transaction(tx, &hash)
+    }
+
+    pub fn tx_status(&self, hash: &Hash) -> TxStatus {
+        match self.tx_index.get(hash) {
+            Some(&(height, index, timestamp)) => TxStatus::InBlock {
+                height,
+                index,
+                timestamp,
+            },
+            None => TxStatus::Unknown,
+        }
+    }
+
     pub async fn get_header(&self, height: u32) -> Result<Header> {
         if height > self.height().await {
             return Err(anyhow!("given height {height} too high"));
         }
-        Ok(*self
-            .headers
-            .read()
-            .await
-            .get(height as usize)
-            .ok_or_else(|| anyhow!("Block Header with height {height} not found"))?)
+        let header = match height.checked_sub(self.pruned) {
+            Some(index) => self
+                .headers
+                .read()
+                .await
+                .get(index as usize)
+                .map(|(_, header)| *header),
+            // pruned headers are only in the store
+            None => self.store.get_header(height)?,
+        };
+        header.ok_or_else(|| anyhow!("Block Header with height {height} not found"))
+    }
+
+    // The hash of the block with the height, only pruned headers are hashed again
+    pub async fn get_hash(&self, height: u32) -> Result<Hash> {
+        if height > self.height().await {
+            return Err(anyhow!("given height {height} too high"));
+        }
+        match height.checked_sub(self.pruned) {
+            Some(index) => self
+                .headers
+                .read()
+                .await
+                .get(index as usize)
+                .map(|(hash, _)| *hash)
+                .ok_or_else(|| anyhow!("Block Header with height {height} not found")),
+            None => BlockHasher.hash(&self.get_header(height).await?),
+        }
+    }
+
+    // The height of the block with the hash, None if it isn't part of the chain
+    pub fn height_of(&self, hash: &Hash) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    pub async fn get_header_by_hash(&self, hash: &Hash) -> Result<Option<Header>> {
+        match self.height_of(hash) {
+            Some(height) => Ok(Some(self.get_header(height).await?)),
+            None => Ok(None),
+        }
+    }
+
+    // Blocks are only kept in the store, peers that are syncing ask for them
+    pub async fn get_block(&self, height: u32) -> Result<Block> {
+        if height > self.height().await {
+            return Err(anyhow!("given height {height} too high"));
+        }
+        if height < self.history_start {
+            return Err(anyhow!(
+                "block {height} was pruned, the node keeps the blocks from {}",
+                self.history_start
+            ));
+        }
+        self.store
+            .get_block(height)?
+            .ok_or_else(|| anyhow!("Block with height {height} not found"))
+    }
+
+    // Writes the blocks from..=to to a block file at path, see block_file.rs. Returns the number of blocks written.
+    pub async fn export_blocks(&self, path: &Path, from: u32, to: u32) -> Result<u32> {
+        let height = self.height().await;
+        if from > to || to > height {
+            return Err(anyhow!(
+                "can't export the blocks {from}..={to}, the chain has the height {height}"
+            ));
+        }
+
+        let mut writer = BlockFileWriter::new(BufWriter::new(File::create(path)?));
+        for height in from..=to {
+            writer.write(&self.get_block(height).await?)?;
+        }
+        writer.finish()?;
+        Ok(to - from + 1)
+    }
+
+    // Validates and adds the blocks of the block file at path. Blocks the chain has already are skipped, a
+    // different block at one of their heights fails the import. Returns the number of blocks added.
+    pub async fn import_blocks(&mut self, path: &Path) -> Result<u32> {
+        let reader = BlockFileReader::new(BufReader::new(File::open(path)?));
+        let mut added = 0;
+        for block in reader {
+            let mut block = block?;
+            let height = block.header.height;
+            if height <= self.height().await {
+                if self.get_header(height).await? != block.header {
+                    return Err(anyhow!(
+                        "block {height} of {} conflicts with the block of the chain",
+                        path.display()
+                    ));
+                }
+                continue;
+            }
+
+            self.add_block(&mut block)
+                .await
+                .map_err(|err| anyhow!("block {height} of {}: {err}", path.display()))?;
+            added += 1;
+        }
+        Ok(added)
     }
 
     pub async fn get_prev_block_hash(&self, height: u32) -> Result<Hash> {
-        let header = self.get_header(height - 1).await?;
-        BlockHasher {}.hash(&header)
+        let height = height
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("the genesis block has no previous block"))?;
+        self.get_hash(height).await
     }
 
     pub async fn len(&self) -> usize {
-        self.headers.read().await.len()
+        self.pruned as usize + self.headers.read().await.len()
+    }
+
+    // never true once the genesis block was added
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
     }
 
     pub async fn height(&self) -> u32 {
-        self.headers.read().await.len() as u32 - 1
+        self.len().await as u32 - 1
+    }
+
+    // The average time between the newest window blocks, None while the chain has fewer than two blocks above
+    // the genesis block, whose timestamp is fixed
+    pub async fn block_time_average(&self, window: u32) -> Result<Option<Duration>> {
+        let tip = self.height().await;
+        let from = tip.saturating_sub(window).max(1);
+        if tip <= from {
+            return Ok(None);
+        }
+
+        let first = self.get_header(from).await?.timestamp;
+        let last = self.get_header(tip).await?.timestamp;
+        let average = last.saturating_sub(first) / u128::from(tip - from);
+        Ok(Some(Duration::from_nanos(
+            u64::try_from(average).unwrap_or(u64::MAX),
+        )))
+    }
+}
+
+// Applies the transactions of a valid block to state and returns their receipts
+fn execute_on(
+    state: &mut State,
+    b: &Block,
+    epoch_length: u32,
+    server_id: &str,
+) -> Result<Vec<Receipt>> {
+    let mut receipts = vec![];
+    let proposer = b.validator().map(|v| v.address());
+    // run vm code, every transaction executes against an overlay of the contract state
+    // that is only committed if it succeeds
+    for tx in &b.transactions {
+        let hash = tx_hash(tx)?;
+        // failed transactions use up their nonce and pay their fee as well, one that can't pay
+        // its fee fails without running
+        if let Some(from) = &tx.from {
+            state.bump_nonce(from.address(), tx.nonce);
+            if let Err(err) = state.charge_fee(&from.address(), tx.fee, proposer) {
+                let outcome = VmOutcome {
+                    error: Some(err.to_string()),
+                    ..VmOutcome::default()
+                };
+                receipts.push(Receipt::new(hash, &b.header, outcome));
+                continue;
+            }
+        }
+        if !matches!(tx.kind, TxKind::Call) {
+            // a transaction that can't be applied fails like a failed VM run
+            let outcome = VmOutcome {
+                error: apply_native(state, tx, b.header.height, server_id)
+                    .err()
+                    .map(|e| e.to_string()),
+                ..VmOutcome::default()
+            };
+            receipts.push(Receipt::new(hash, &b.header, outcome));
+            continue;
+        }
+
+        info!(
+            "ID={} Running VM code hash={} len={}",
+            server_id,
+            hash,
+            tx.data.len()
+        );
+
+        // gas is recorded once the run finished
+        let span = info_span!("tx", hash = %hash, gas = field::Empty).entered();
+        state.begin();
+        let context = ExecutionContext {
+            contract: contract_address(&tx.data),
+            caller: tx.from.map(|from| from.address()).unwrap_or_default(),
+            block_height: b.header.height,
+            timestamp: b.header.timestamp,
+            tx_hash: hash,
+        };
+        let mut vm = VM::new(tx.data.clone(), state, context);
+        vm.set_calldata(tx.input.clone());
+        let mut outcome = vm.run();
+        span.record("gas", outcome.gas_used);
+        // the tokens move after the code ran, a failed transfer fails the transaction
+        if outcome.is_success() {
+            if let Err(err) = state.apply_transfers(&outcome.transfers) {
+                outcome.error = Some(err.to_string());
+                outcome.transfers.clear();
+            }
+        }
+
+        match &outcome.error {
+            None => {
+                debug!(
+                    "ID={} tx {} returned {:?}",
+                    server_id, hash, outcome.return_data
+                );
+                state.commit()?;
+            }
+            Some(err) => {
+                debug!("ID={} tx {} failed: {}", server_id, hash, err);
+                state.discard();
+            }
+        }
+        let receipt = Receipt::new(hash, &b.header, outcome);
+        info!("VM STATE: {:?}", state);
+        receipts.push(receipt);
     }
+
+    // the last block of an epoch decides the validator set of the next one
+    let height = b.header.height;
+    if (height + 1) / epoch_length != height / epoch_length {
+        state.start_epoch();
+        info!(
+            "ID={} Epoch {} starts with {} validators",
+            server_id,
+            (height + 1) / epoch_length,
+            state.validator_set().len()
+        );
+    }
+    Ok(receipts)
+}
+
+// Applies a transaction that changes the native state instead of running code
+fn apply_native(state: &mut State, tx: &Transaction, height: u32, server_id: &str) -> Result<()> {
+    let from = tx
+        .from
+        .as_ref()
+        .ok_or_else(|| anyhow!("transaction has no sender"))?;
+
+    match &tx.kind {
+        TxKind::Call => Err(anyhow!("call transactions run in the VM")),
+        TxKind::Stake { amount } => state.stake(from, *amount),
+        TxKind::Transfer { to, amount } => state.transfer(&from.address(), *to, *amount),
+        TxKind::Unstake => state.unstake(from),
+        TxKind::Evidence(evidence) => {
+            let validator = evidence.validator();
+            if !state.slash(validator) {
+                return Err(anyhow!(
+                    "validator {} is already slashed",
+                    validator.address()
+                ));
+            }
+            info!(
+                "ID={} Slashing validator {} at height {}",
+                server_id,
+                validator.address(),
+                height
+            );
+            Ok(())
+        }
+    }
+}
+
+// The addresses a transaction is listed under in the address index: its sender and the recipient of a transfer
+fn tx_addresses(tx: &Transaction) -> Vec<Address> {
+    let mut addresses: Vec<Address> = tx.from.iter().map(|from| from.address()).collect();
+    if let TxKind::Transfer { to, .. } = &tx.kind {
+        if !addresses.contains(to) {
+            addresses.push(*to);
+        }
+    }
+    addresses
+}
+
+fn tx_hash(tx: &Transaction) -> Result<Hash> {
+    if tx.has_cached_hash() {
+        return Ok(tx.hash());
+    }
+    TxHasher.hash(tx)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        core::{assemble, SledStateStore, Transaction, ValueTransfer},
+        crypto::PrivateKey,
+        test_utils::*,
+    };
     use anyhow::Result;
 
-    async fn blockchain() -> Result<Blockchain> {
-        Blockchain::new("".into(), Block::random(0, Hash::default())?).await
-    }
-
     #[tokio::test]
     async fn test_blockchain() -> Result<()> {
-        assert_eq!(blockchain().await?.height().await, 0);
+        assert_eq!(chain(0).await?.height().await, 0);
 
         Ok(())
     }
 
     #[tokio::test]
     async fn test_has_block() -> Result<()> {
-        assert!(blockchain().await?.has_block(0).await);
+        assert!(chain(0).await?.has_block(0).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_genesis() -> Result<()> {
+        let faucet = PrivateKey::generate().public_key().address();
+        let genesis = GenesisConfig::new().with_balance(faucet, 1_000_000);
+
+        let mut bc = Blockchain::new("".into(), genesis.block()).await?;
+        bc.apply_genesis(&genesis).await?;
+        assert_eq!(bc.state().balance(&faucet), 1_000_000);
+        assert_eq!(bc.state().root_hash(), bc.get_header(0).await?.state_root);
+
+        // another alloc doesn't match the state root of the genesis block
+        let mut bc = Blockchain::new("".into(), genesis.block()).await?;
+        let other = GenesisConfig::new().with_balance(faucet, 1);
+        assert!(bc.apply_genesis(&other).await.is_err());
+        assert_eq!(bc.state().balance(&faucet), 0);
+
         Ok(())
     }
 
     // this is quite slow, optimize this
     #[tokio::test]
     async fn test_add_block() -> Result<()> {
-        let mut bc = blockchain().await?;
-
         let len_blocks = 10;
-        for i in 0..len_blocks {
-            let prev_block_hash = bc.get_prev_block_hash(i + 1).await?;
-            let mut b = Block::random(i + 1, prev_block_hash)?;
-            bc.add_block(&mut b).await?;
-        }
+        let mut bc = chain(len_blocks).await?;
 
         assert_eq!(bc.height().await, len_blocks);
         assert_eq!(bc.len().await as u32, len_blocks + 1);
 
         assert!(bc
-            .add_block(&mut Block::random(89, Hash::random())?)
+            .add_block(&mut random_block(89, Hash::random())?)
             .await
             .is_err());
 
@@ -157,27 +942,722 @@ mod tests {
     // this is quite slow
     #[tokio::test]
     async fn test_get_header() -> Result<()> {
-        let mut bc = blockchain().await?;
+        let mut bc = chain(0).await?;
 
-        let len_blocks = 10;
+        for (i, b) in extend_chain(&mut bc, 10).await?.iter().enumerate() {
+            let header = bc.get_header(i as u32 + 1).await?;
+            assert_eq!(header, b.header);
+            assert_eq!(bc.get_block(i as u32 + 1).await?.header, b.header);
+        }
+        assert!(bc.get_block(11).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pruned_headers() -> Result<()> {
+        let mut bc = chain(0).await?;
+        bc.set_pruning(3).await;
+        let genesis = bc.get_header(0).await?;
+
+        let blocks = extend_chain(&mut bc, 10).await?;
+        assert_eq!(bc.headers.read().await.len(), 3);
+        assert_eq!(bc.height().await, 10);
+
+        // older headers fall through to the store
+        assert_eq!(bc.get_header(0).await?, genesis);
+        for b in &blocks {
+            assert_eq!(bc.get_header(b.header.height).await?, b.header);
+        }
+        assert!(bc.get_header(11).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_by_hash() -> Result<()> {
+        let mut bc = chain(0).await?;
+        bc.set_pruning(3).await;
+        let blocks = extend_chain(&mut bc, 5).await?;
+
+        // pruned or not, the hashes are the ones of the headers
+        for height in 0..=5 {
+            let header = bc.get_header(height).await?;
+            let hash = bc.get_hash(height).await?;
+            assert_eq!(hash, BlockHasher.hash(&header)?);
+            assert_eq!(bc.height_of(&hash), Some(height));
+            assert_eq!(bc.get_header_by_hash(&hash).await?, Some(header));
+        }
+        let last = &blocks[4].header;
+        assert_eq!(
+            bc.get_prev_block_hash(5).await?,
+            last.prev_block_hash.unwrap()
+        );
+        assert!(bc.get_prev_block_hash(0).await.is_err());
+        assert!(bc.get_hash(6).await.is_err());
 
-        for i in 0..len_blocks {
-            let prev_block_hash = bc.get_prev_block_hash(i + 1).await?;
-            let mut b = Block::random(i + 1, prev_block_hash)?;
+        assert_eq!(bc.height_of(&Hash::random()), None);
+        assert_eq!(bc.get_header_by_hash(&Hash::random()).await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pruned_headers_on_disk() -> Result<()> {
+        let pruning = Pruning {
+            keep_headers: 1,
+            path: std::env::temp_dir().join(format!("projectx-chain-{}", Hash::random())),
+        };
+        let genesis = random_block(0, Hash::default())?;
+        let mut bc = Blockchain::with_pruning("".into(), genesis.clone(), &pruning).await?;
+
+        let result = async {
+            let blocks = extend_chain(&mut bc, 5).await?;
+            assert_eq!(bc.headers.read().await.len(), 1);
+
+            assert_eq!(bc.get_header(0).await?, genesis.header);
+            for b in &blocks {
+                assert_eq!(bc.get_header(b.header.height).await?, b.header);
+            }
+            Ok(())
+        }
+        .await;
+
+        std::fs::remove_file(FileStore::blocks_path(&pruning.path))?;
+        std::fs::remove_file(FileStore::meta_path(&pruning.path))?;
+        std::fs::remove_file(&pruning.path)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn test_recover() -> Result<()> {
+        let pruning = Pruning {
+            keep_headers: 2,
+            path: std::env::temp_dir().join(format!("projectx-chain-{}", Hash::random())),
+        };
+        let genesis = random_block(0, Hash::default())?;
+
+        let result = async {
+            let mut bc = Blockchain::with_pruning("".into(), genesis.clone(), &pruning).await?;
+            let tx = signed_tx(vec![0x03, 0x0a, 0x02, 0x0a, 0x0e])?;
+            let mut b = next_block(bc.get_header(0).await?, vec![tx.clone()])?;
             bc.add_block(&mut b).await?;
-            let header = bc.get_header(i + 1).await?;
-            assert_eq!(header, b.header);
+            let blocks = extend_chain(&mut bc, 2).await?;
+            drop(bc);
+
+            // the node stopped after storing two more blocks but before committing them,
+            // the second one is invalid
+            let store = FileStore::open(&pruning.path)?;
+            let next = next_block(blocks[1].header, vec![])?;
+            let invalid = random_block(5, Hash::random())?;
+            for b in [&next, &invalid] {
+                store.put_header(&b.header)?;
+                store.put_block(b)?;
+            }
+            drop(store);
+
+            let mut bc = Blockchain::with_pruning("".into(), genesis.clone(), &pruning).await?;
+            assert_eq!(bc.recover().await?, 4);
+            assert_eq!(bc.get_header(4).await?, next.header);
+            assert!(bc.store.get_block(5)?.is_none());
+            assert_eq!(bc.store.get_meta()?.map(|meta| meta.state_height), Some(4));
+            // the replayed blocks are indexed again
+            assert_eq!(
+                bc.tx_status(&tx.hash()),
+                TxStatus::InBlock {
+                    height: 1,
+                    index: 0,
+                    timestamp: b.header.timestamp,
+                }
+            );
+            drop(bc);
+
+            let mut bc = Blockchain::with_pruning("".into(), genesis.clone(), &pruning).await?;
+            assert_eq!(bc.recover().await?, 4);
+            drop(bc);
+
+            // the files of another chain aren't touched
+            let other = random_block(0, Hash::default())?;
+            assert!(Blockchain::with_pruning("".into(), other, &pruning)
+                .await
+                .is_err());
+            Ok(())
+        }
+        .await;
+
+        for path in [
+            FileStore::blocks_path(&pruning.path),
+            FileStore::meta_path(&pruning.path),
+            pruning.path.clone(),
+        ] {
+            std::fs::remove_file(path)?;
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn test_recover_into_state_store() -> Result<()> {
+        let pruning = Pruning {
+            keep_headers: 2,
+            path: std::env::temp_dir().join(format!("projectx-chain-{}", Hash::random())),
+        };
+        let state_path = FileStore::state_path(&pruning.path);
+        let genesis = random_block(0, Hash::default())?;
+
+        let result = async {
+            let mut bc = Blockchain::with_pruning("".into(), genesis.clone(), &pruning).await?;
+            bc.set_state_store(Box::new(SledStateStore::open(&state_path)?))?;
+            let code = assemble("push 1; store FOO")?;
+            let contract = contract_address(&code);
+            let mut b = next_block(bc.get_header(0).await?, vec![signed_tx(code)?])?;
+            bc.add_block(&mut b).await?;
+            let root = bc.state().root_hash();
+            assert_eq!(bc.state().iterate_prefix(&contract, b"").len(), 1);
+            drop(bc);
+
+            // the data of the last run is dropped and written again by the replayed blocks
+            let mut bc = Blockchain::with_pruning("".into(), genesis.clone(), &pruning).await?;
+            bc.set_state_store(Box::new(SledStateStore::open(&state_path)?))?;
+            assert!(bc.state().iterate_prefix(&contract, b"").is_empty());
+            assert_eq!(bc.recover().await?, 1);
+            assert_eq!(bc.state().root_hash(), root);
+            Ok(())
+        }
+        .await;
+
+        for path in [
+            FileStore::blocks_path(&pruning.path),
+            FileStore::meta_path(&pruning.path),
+            pruning.path.clone(),
+        ] {
+            std::fs::remove_file(path)?;
+        }
+        std::fs::remove_dir_all(state_path)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_blocks() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("projectx-export-{}", Hash::random()));
+        let result = async {
+            let bc = chain(4).await?;
+            let genesis = bc.get_block(0).await?;
+            assert_eq!(bc.export_blocks(&path, 0, 4).await?, 5);
+            assert!(bc.export_blocks(&path, 3, 5).await.is_err());
+
+            let mut other = Blockchain::new("".into(), genesis.clone()).await?;
+            assert_eq!(other.import_blocks(&path).await?, 4);
+            assert_eq!(other.get_header(4).await?, bc.get_header(4).await?);
+            // known blocks are skipped
+            assert_eq!(other.import_blocks(&path).await?, 0);
+
+            // a chain that forked from it
+            let mut forked = Blockchain::new("".into(), genesis).await?;
+            extend_chain(&mut forked, 1).await?;
+            assert!(forked.import_blocks(&path).await.is_err());
+
+            // the blocks go through validation, a gap fails the import
+            bc.export_blocks(&path, 3, 4).await?;
+            let mut behind = Blockchain::new("".into(), bc.get_block(0).await?).await?;
+            assert!(behind.import_blocks(&path).await.is_err());
+            assert_eq!(behind.height().await, 0);
+            Ok(())
+        }
+        .await;
+
+        std::fs::remove_file(&path)?;
+        result
+    }
+
+    #[tokio::test]
+    async fn test_tx_status() -> Result<()> {
+        let mut bc = chain(0).await?;
+
+        let tx = signed_tx(vec![0x03, 0x0a, 0x02, 0x0a, 0x0e])?;
+        let mut b = next_block(bc.get_header(0).await?, vec![tx.clone()])?;
+        bc.add_block(&mut b).await?;
+
+        assert_eq!(
+            bc.tx_status(&tx.hash()),
+            TxStatus::InBlock {
+                height: 1,
+                index: 0,
+                timestamp: b.header.timestamp,
+            }
+        );
+        assert_eq!(bc.tx_status(&Hash::random()), TxStatus::Unknown);
+        let receipt = bc.receipt(&tx.hash()).unwrap();
+        assert_eq!(
+            (receipt.block_height, receipt.timestamp),
+            (1, b.header.timestamp)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_mode() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let tx = signed_tx(vec![0x03, 0x0a, 0x02, 0x0a, 0x0e])?;
+        let mut b = next_block(bc.get_header(0).await?, vec![tx.clone()])?;
+        bc.add_block(&mut b).await?;
+        extend_chain(&mut bc, 3).await?;
+        assert_eq!(bc.lowest_block(), 0);
+
+        bc.set_mode(NodeMode::Pruned { keep_blocks: 2 }).await?;
+        assert_eq!(bc.lowest_block(), 3);
+        assert!(bc.get_block(2).await.is_err());
+        assert!(bc.get_block(3).await.is_ok());
+        // the headers are kept, the receipts of the pruned blocks aren't
+        assert_eq!(bc.get_header(1).await?, b.header);
+        assert_eq!(bc.tx_status(&tx.hash()), TxStatus::Unknown);
+        assert!(bc.receipt(&tx.hash()).is_none());
+        // it can't be mined again
+        assert!(bc.is_included(&tx.hash()));
+        assert_eq!(
+            bc.check_transaction(&tx).map_err(|r| r.code),
+            Err(RejectCode::AlreadyIncluded)
+        );
+
+        extend_chain(&mut bc, 2).await?;
+        assert_eq!(bc.lowest_block(), 5);
+        assert!(bc.get_block(4).await.is_err());
+        assert!(bc.get_block(6).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_time_average() -> Result<()> {
+        let mut bc = chain(0).await?;
+        assert_eq!(bc.block_time_average(10).await?, None);
+
+        // blocks 2 and 4 seconds apart
+        for secs in [100, 102, 106] {
+            let mut b = Block::from_prev_header(bc.get_header(bc.height().await).await?, vec![])?;
+            b.header.timestamp = Duration::from_secs(secs).as_nanos();
+            b.sign(&PrivateKey::generate())?;
+            bc.add_block(&mut b).await?;
+        }
+        assert_eq!(
+            bc.block_time_average(10).await?,
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            bc.block_time_average(1).await?,
+            Some(Duration::from_secs(4))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_txs_for_address() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let sender = PrivateKey::generate();
+
+        let mut txx = vec![];
+        for i in 0..ADDRESS_TX_PAGE_SIZE + 2 {
+            let mut tx = Transaction::new(assemble(&format!("push {i}"))?);
+            tx.nonce = i as u64;
+            tx.sign(&sender);
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            txx.push(tx);
         }
+        let hashes: Vec<Hash> = txx.iter().map(|tx| tx.hash()).collect();
+
+        let mut b = next_block(bc.get_header(0).await?, txx)?;
+        bc.add_block(&mut b).await?;
+
+        let address = sender.public_key().address();
+        assert_eq!(
+            bc.txs_for_address(&address, 0),
+            hashes[..ADDRESS_TX_PAGE_SIZE]
+        );
+        assert_eq!(
+            bc.txs_for_address(&address, 1),
+            hashes[ADDRESS_TX_PAGE_SIZE..]
+        );
+        assert!(bc.txs_for_address(&address, 2).is_empty());
+        assert!(bc.txs_for_address(&address, usize::MAX).is_empty());
+        assert!(bc.txs_for_address(&Address::default(), 0).is_empty());
+
+        bc.unindex_block(&b)?;
+        assert!(bc.txs_for_address(&address, 0).is_empty());
+        assert_eq!(bc.tx_status(&hashes[0]), TxStatus::Unknown);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_tx_is_isolated() -> Result<()> {
+        let mut bc = chain(0).await?;
+
+        // the second transaction stores FOO and fails afterwards
+        let txx = vec![
+            signed_tx(assemble("push 1; store FOO; push 7; return")?)?,
+            signed_tx(assemble(
+                "push 2; store FOO; push 3; store BAR; push 0; push 1; div",
+            )?)?,
+        ];
+
+        let mut b = next_block(bc.get_header(0).await?, txx.clone())?;
+        bc.add_block(&mut b).await?;
+
+        // every contract has its own FOO
+        let (first, second) = (
+            contract_address(&txx[0].data),
+            contract_address(&txx[1].data),
+        );
+        assert_eq!(bc.contract_state.get(&first, b"FOO")?, vec![1]);
+        assert!(bc.contract_state.get(&second, b"FOO").is_err());
+        assert!(bc.contract_state.get(&second, b"BAR").is_err());
+
+        let receipt = bc.receipt(&txx[0].hash()).unwrap();
+        assert!(receipt.success);
+        assert_eq!(receipt.return_data, vec![7]);
+        let receipt = bc.receipt(&txx[1].hash()).unwrap();
+        assert!(!receipt.success);
+        assert!(receipt.error.is_some());
+        assert!(receipt.gas_used > 0);
+
+        // failed transactions are still part of the block
+        assert!(matches!(
+            bc.tx_status(&txx[1].hash()),
+            TxStatus::InBlock { .. }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_evidence_slashes_validator() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let offender = PrivateKey::generate();
+        assert!(!bc.is_slashed(&offender.public_key()));
+
+        let mut txx = vec![];
+        for _ in 0..2 {
+            let mut tx = Transaction::new_evidence(double_sign_evidence(&offender)?);
+            tx.sign(&PrivateKey::generate());
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            txx.push(tx);
+        }
+        let mut b = next_block(bc.get_header(0).await?, txx.clone())?;
+        bc.add_block(&mut b).await?;
+
+        assert!(bc.is_slashed(&offender.public_key()));
+        assert!(bc.receipt(&txx[0].hash()).unwrap().success);
+        // a validator is only slashed once
+        assert!(!bc.receipt(&txx[1].hash()).unwrap().success);
+
+        Ok(())
+    }
+
+    // signs tx with key and adds it in the next block
+    async fn add_signed_tx(bc: &mut Blockchain, key: &PrivateKey, kind: TxKind) -> Result<Hash> {
+        let mut tx = Transaction::new_kind(kind);
+        // the same transaction can't be included twice
+        tx.nonce = bc.state().nonce(&key.public_key().address());
+        tx.sign(key);
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        let hash = tx.hash();
+
+        let height = bc.height().await;
+        let mut b = next_block(bc.get_header(height).await?, vec![tx])?;
+        b.header.epoch = bc.epoch_of(height + 1);
+        b.sign(key)?;
+        bc.add_block(&mut b).await?;
+        Ok(hash)
+    }
+
+    #[tokio::test]
+    async fn test_staking_transactions() -> Result<()> {
+        let mut bc = chain(0).await?;
+        bc.set_epoch_length(3);
+        let validator = PrivateKey::generate();
+        let address = validator.public_key().address();
+        bc.contract_state.credit(address, 2 * MIN_VALIDATOR_STAKE);
+
+        let amount = MIN_VALIDATOR_STAKE;
+        let hash = add_signed_tx(&mut bc, &validator, TxKind::Stake { amount }).await?;
+        assert!(bc.receipt(&hash).unwrap().success);
+        assert!(bc.validator_set().is_empty());
+
+        // the balance doesn't cover the stake
+        let amount = 3 * MIN_VALIDATOR_STAKE;
+        let hash = add_signed_tx(&mut bc, &validator, TxKind::Stake { amount }).await?;
+        assert!(!bc.receipt(&hash).unwrap().success);
+        // the validator joins with the epoch starting at height 3
+        assert_eq!(bc.validator_set().validators(), &[validator.public_key()]);
+
+        let hash = add_signed_tx(&mut bc, &validator, TxKind::Unstake).await?;
+        assert!(bc.receipt(&hash).unwrap().success);
+        assert_eq!(bc.validator_set().validators(), &[validator.public_key()]);
+        assert_eq!(bc.state().balance(&address), MIN_VALIDATOR_STAKE);
+
+        // and leaves with the one starting at height 6
+        extend_chain(&mut bc, 1).await?;
+        assert_eq!(bc.validator_set().validators(), &[validator.public_key()]);
+        extend_chain(&mut bc, 1).await?;
+        assert!(bc.validator_set().is_empty());
+        assert_eq!(bc.state().balance(&address), 2 * MIN_VALIDATOR_STAKE);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execution_context() -> Result<()> {
+        let mut bc = chain(0).await?;
+
+        let txx = vec![
+            signed_tx(assemble("caller; return")?)?,
+            signed_tx(assemble("txhash; return")?)?,
+            signed_tx(assemble("height; return")?)?,
+            signed_tx(assemble("caller; balance; return")?)?,
+        ];
+        let caller = txx[3].from.unwrap().address();
+        bc.contract_state.credit(caller, 42);
+
+        let mut b = next_block(bc.get_header(0).await?, txx.clone())?;
+        bc.add_block(&mut b).await?;
+
+        let return_data = |i: usize| bc.receipt(&txx[i].hash()).unwrap().return_data.clone();
+        assert_eq!(
+            return_data(0),
+            txx[0].from.unwrap().address().into_bytes().to_vec()
+        );
+        assert_eq!(return_data(1), txx[1].hash().into_bytes().to_vec());
+        assert_eq!(return_data(2), vec![1]);
+        assert_eq!(return_data(3), vec![42]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_contract_transfers() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let key = PrivateKey::generate();
+        bc.contract_state.credit(key.public_key().address(), 100);
+
+        // pays 40 to every caller
+        let code = assemble("push 40; caller; transfer")?;
+        let contract = contract_address(&code);
+        let fund = TxKind::Transfer {
+            to: contract,
+            amount: 100,
+        };
+        add_signed_tx(&mut bc, &key, fund).await?;
+
+        // the same code with different call data, so the transactions have different hashes
+        let mut txx = vec![];
+        for input in [vec![1], vec![2]] {
+            let mut tx = Transaction::new(code.clone());
+            tx.input = input;
+            tx.sign(&PrivateKey::generate());
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            txx.push(tx);
+        }
+        let mut b = next_block(bc.get_header(1).await?, txx.clone())?;
+        bc.add_block(&mut b).await?;
+
+        let caller = txx[0].from.unwrap().address();
+        assert_eq!(bc.state().balance(&caller), 40);
+        assert_eq!(bc.state().balance(&contract), 20);
+        let receipt = bc.receipt(&txx[0].hash()).unwrap();
+        assert_eq!(
+            receipt.transfers,
+            vec![ValueTransfer {
+                from: contract,
+                to: caller,
+                amount: 40
+            }]
+        );
+
+        // the third caller isn't covered anymore
+        let tx = signed_tx(code)?;
+        let mut b = next_block(bc.get_header(2).await?, vec![tx.clone()])?;
+        bc.add_block(&mut b).await?;
+        let receipt = bc.receipt(&tx.hash()).unwrap();
+        assert!(!receipt.success);
+        assert!(receipt.transfers.is_empty());
+        assert_eq!(bc.state().balance(&contract), 20);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_transactions() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let key = PrivateKey::generate();
+        let from = key.public_key().address();
+        let to = PrivateKey::generate().public_key().address();
+        bc.contract_state.credit(from, 1_000);
+
+        let hash = add_signed_tx(&mut bc, &key, TxKind::Transfer { to, amount: 600 }).await?;
+        assert!(bc.receipt(&hash).unwrap().success);
+        assert_eq!(bc.state().balance(&from), 400);
+        assert_eq!(bc.state().balance(&to), 600);
+
+        // the balance doesn't cover the transfer
+        let failed = add_signed_tx(&mut bc, &key, TxKind::Transfer { to, amount: 600 }).await?;
+        assert!(!bc.receipt(&failed).unwrap().success);
+        assert_eq!(bc.state().balance(&to), 600);
+
+        // both transfers are listed for the sender and the recipient
+        assert_eq!(bc.txs_for_address(&from, 0), [hash, failed]);
+        assert_eq!(bc.txs_for_address(&to, 0), [hash, failed]);
+        let height = bc.height().await;
+        let last = bc.get_block(height).await?;
+        bc.unindex_block(&last)?;
+        assert_eq!(bc.txs_for_address(&to, 0), [hash]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_fees() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let key = PrivateKey::generate();
+        let from = key.public_key().address();
+        let proposer = PrivateKey::generate();
+        let to = PrivateKey::generate().public_key().address();
+        bc.contract_state.credit(from, 100);
+
+        let transfer = |nonce: u64, fee: u64| {
+            let mut tx = Transaction::new_kind(TxKind::Transfer { to, amount: 50 });
+            tx.nonce = nonce;
+            tx.fee = fee;
+            tx.sign(&key);
+            tx
+        };
+        let paid = transfer(0, 10);
+        // the fee is charged even though the transfer fails, the nonce is used
+        let failed = transfer(1, 10);
+        // the balance doesn't cover the fee anymore
+        let unpaid = transfer(2, 100);
+        let txx = vec![paid, failed, unpaid];
+        let mut b = next_block(bc.get_header(0).await?, txx.clone())?;
+        b.sign(&proposer)?;
+        bc.add_block(&mut b).await?;
+
+        let receipt = |tx: &Transaction| bc.receipt(&tx_hash(tx).unwrap()).unwrap().clone();
+        assert!(receipt(&txx[0]).success);
+        assert!(!receipt(&txx[1]).success);
+        assert!(!receipt(&txx[2]).success);
+        assert_eq!(bc.state().balance(&from), 30);
+        assert_eq!(bc.state().balance(&to), 50);
+        assert_eq!(bc.state().balance(&proposer.public_key().address()), 20);
+        assert_eq!(bc.state().nonce(&from), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_state_at() -> Result<()> {
+        let mut bc = chain(0).await?;
+        bc.set_snapshot_interval(2);
+        let key = PrivateKey::generate();
+        let from = key.public_key().address();
+        let to = PrivateKey::generate().public_key().address();
+        bc.contract_state.credit(from, 1_000);
+
+        for _ in 0..5 {
+            add_signed_tx(&mut bc, &key, TxKind::Transfer { to, amount: 100 }).await?;
+        }
+        assert_eq!(bc.snapshots.keys().copied().collect::<Vec<_>>(), [0, 2, 4]);
+        for height in 0..=5 {
+            let state = bc.state_at(height).await?;
+            assert_eq!(state.balance(&to), 100 * u64::from(height));
+            assert_eq!(state.balance(&from), 1_000 - 100 * u64::from(height));
+        }
+        assert!(bc.state_at(6).await.is_err());
+
+        // pruned nodes only have the current state
+        bc.set_mode(NodeMode::Pruned { keep_blocks: 10 }).await?;
+        assert!(bc.state_at(4).await.is_err());
+        assert_eq!(bc.state_at(5).await?.balance(&to), 500);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_transaction() -> Result<()> {
+        let mut bc = chain(0).await?;
+        let key = PrivateKey::generate();
+        let address = key.public_key().address();
+        bc.contract_state.credit(address, MIN_VALIDATOR_STAKE);
+
+        let check = |bc: &Blockchain, kind: TxKind| {
+            let mut tx = Transaction::new_kind(kind);
+            tx.nonce = bc.state().nonce(&address);
+            tx.sign(&key);
+            bc.check_transaction(&tx)
+                .map_err(|rejection| rejection.code)
+        };
+        let amount = MIN_VALIDATOR_STAKE;
+        assert_eq!(check(&bc, TxKind::Stake { amount }), Ok(()));
+        assert_eq!(
+            check(&bc, TxKind::Stake { amount: amount + 1 }),
+            Err(RejectCode::InsufficientBalance)
+        );
+        assert_eq!(check(&bc, TxKind::Unstake), Err(RejectCode::NotStaked));
+        let to = PrivateKey::generate().public_key().address();
+        assert_eq!(check(&bc, TxKind::Transfer { to, amount }), Ok(()));
+        // the balance covers the amount but not the fee on top of it
+        let mut tx = Transaction::new_kind(TxKind::Transfer { to, amount });
+        tx.fee = 1;
+        tx.sign(&key);
+        assert_eq!(
+            bc.check_transaction(&tx).map_err(|r| r.code),
+            Err(RejectCode::InsufficientBalance)
+        );
+        assert_eq!(
+            check(
+                &bc,
+                TxKind::Transfer {
+                    to,
+                    amount: amount + 1
+                }
+            ),
+            Err(RejectCode::InsufficientBalance)
+        );
+
+        let hash = add_signed_tx(&mut bc, &key, TxKind::Stake { amount }).await?;
+        assert_eq!(check(&bc, TxKind::Unstake), Ok(()));
+        // the nonce of the included transaction is used
+        let mut reused = Transaction::new_kind(TxKind::Unstake);
+        reused.sign(&key);
+        assert_eq!(
+            bc.check_transaction(&reused).map_err(|r| r.code),
+            Err(RejectCode::NonceTooLow)
+        );
+        let included = bc.get_block(1).await?.transactions[0].clone();
+        assert_eq!(tx_hash(&included)?, hash);
+        assert_eq!(
+            bc.check_transaction(&included).map_err(|r| r.code),
+            Err(RejectCode::AlreadyIncluded)
+        );
+
+        bc.contract_state.slash(&key.public_key());
+        assert_eq!(
+            check(&bc, TxKind::Stake { amount: 0 }),
+            Err(RejectCode::Slashed)
+        );
+        let mut evidence = Transaction::new_evidence(double_sign_evidence(&key)?);
+        evidence.sign(&PrivateKey::generate());
+        assert_eq!(
+            bc.check_transaction(&evidence).map_err(|r| r.code),
+            Err(RejectCode::Slashed)
+        );
+
         Ok(())
     }
 
     #[tokio::test]
     async fn test_add_block_too_high() -> Result<()> {
-        let mut bc = blockchain().await?;
-        bc.add_block(&mut Block::random(1, bc.get_prev_block_hash(1).await?)?)
-            .await?;
+        let mut bc = chain(1).await?;
         assert!(bc
-            .add_block(&mut Block::random(3, Hash::random())?)
+            .add_block(&mut random_block(3, Hash::random())?)
             .await
             .is_err());
 
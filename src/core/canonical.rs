@@ -0,0 +1,253 @@
+/*
+The byte layout headers and transactions are hashed and signed over. It's written out field by field instead of
+being whatever bincode produces, so a new bincode version or configuration can't change the hash of a block or
+invalidate a signature. Messages on the wire are still encoded with bincode, see encoding.rs.
+
+Layout version 1, the byte for byte output of bincode 1 with its default configuration, so the hashes of the
+chains from before the layout was written down stay the same:
+
+    integers:       little endian, full width
+    Hash, Address:  the 32 / 20 bytes
+    PublicKey:      compressed SEC1 point, 33 bytes
+    Signature:      r || s, 64 bytes
+    Option:         0, or 1 followed by the value
+    enum:           the index of the variant as u32, followed by its fields
+    struct, tuple:  the fields in order of declaration
+
+A change of the layout changes the hashes, it has to come with a new HeaderVersion and a new layout version, the
+blocks below the upgrade keep the old one. The golden vectors of the tests pin version 1.
+*/
+
+use std::io::{self, Write};
+
+use super::{Evidence, Header, TxKind};
+use crate::{
+    crypto::{PublicKey, Signature},
+    types::{Address, Hash},
+};
+
+pub const LAYOUT_VERSION: u32 = 1;
+
+pub trait Canonical {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()>;
+
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write_canonical(&mut bytes)
+            .expect("writing to a Vec doesn't fail");
+        bytes
+    }
+}
+
+macro_rules! canonical_int {
+    ($($t:ty),*) => {
+        $(impl Canonical for $t {
+            fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+        })*
+    };
+}
+
+canonical_int!(u32, u64, u128);
+
+impl Canonical for Hash {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.into_bytes())
+    }
+}
+
+impl Canonical for Address {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.into_bytes())
+    }
+}
+
+impl Canonical for PublicKey {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.to_bytes())
+    }
+}
+
+impl Canonical for Signature {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.to_bytes())
+    }
+}
+
+impl<T: Canonical> Canonical for Option<T> {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self {
+            None => w.write_all(&[0]),
+            Some(t) => {
+                w.write_all(&[1])?;
+                t.write_canonical(w)
+            }
+        }
+    }
+}
+
+impl<A: Canonical, B: Canonical> Canonical for (A, B) {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.0.write_canonical(w)?;
+        self.1.write_canonical(w)
+    }
+}
+
+impl Canonical for Header {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.version.write_canonical(w)?;
+        self.data_hash.write_canonical(w)?;
+        self.prev_block_hash.write_canonical(w)?;
+        self.timestamp.write_canonical(w)?;
+        self.height.write_canonical(w)?;
+        self.epoch.write_canonical(w)?;
+        self.state_root.write_canonical(w)
+    }
+}
+
+impl Canonical for TxKind {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self {
+            TxKind::Call => 0u32.write_canonical(w),
+            TxKind::Stake { amount } => {
+                1u32.write_canonical(w)?;
+                amount.write_canonical(w)
+            }
+            TxKind::Unstake => 2u32.write_canonical(w),
+            TxKind::Evidence(evidence) => {
+                3u32.write_canonical(w)?;
+                evidence.write_canonical(w)
+            }
+            TxKind::Transfer { to, amount } => {
+                4u32.write_canonical(w)?;
+                to.write_canonical(w)?;
+                amount.write_canonical(w)
+            }
+        }
+    }
+}
+
+impl Canonical for Evidence {
+    fn write_canonical(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Evidence::DoubleSign {
+                validator,
+                first,
+                second,
+            } => {
+                0u32.write_canonical(w)?;
+                validator.write_canonical(w)?;
+                first.write_canonical(w)?;
+                second.write_canonical(w)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{
+        core::{hasher::Hasher, BlockHasher, Transaction, TxHasher},
+        crypto::PrivateKey,
+        test_utils::*,
+    };
+
+    fn golden_header() -> Header {
+        Header {
+            version: 1,
+            data_hash: Hash::from_digest([0x11; 32]),
+            prev_block_hash: Some(Hash::from_digest([0x22; 32])),
+            timestamp: 1_700_000_000_000_000_000,
+            height: 42,
+            epoch: 3,
+            state_root: Hash::from_digest([0x33; 32]),
+        }
+    }
+
+    #[test]
+    fn test_golden_header() -> Result<()> {
+        assert_eq!(LAYOUT_VERSION, 1);
+        let header = golden_header();
+        let bytes = header.canonical_bytes();
+        assert_eq!(
+            hex::encode(&bytes[..36]),
+            format!("01000000{}", "11".repeat(32))
+        );
+        assert_eq!(
+            hex::encode(&bytes[36..]),
+            format!(
+                "01{}00002a36fe9c971700000000000000002a00000003000000{}",
+                "22".repeat(32),
+                "33".repeat(32)
+            )
+        );
+        assert_eq!(
+            BlockHasher.hash(&header)?.to_string(),
+            "4489ad595ddef84c4f76a5f52811a1ba063ad465fd3d6504deb31a3d7ac1ecb2"
+        );
+
+        let genesis = Header {
+            prev_block_hash: None,
+            ..Header::default()
+        };
+        assert_eq!(hex::encode(genesis.canonical_bytes()), "00".repeat(93));
+        Ok(())
+    }
+
+    #[test]
+    fn test_golden_transactions() -> Result<()> {
+        let mut call = Transaction::new(vec![0x01, 0x02]);
+        call.input = vec![0xaa];
+        call.nonce = 7;
+        call.fee = 5;
+        assert_eq!(
+            hex::encode(call.signing_bytes()),
+            "0102aa0000000100000000000000070000000000000005"
+        );
+
+        let mut transfer = Transaction::new_kind(TxKind::Transfer {
+            to: Address::try_from_bytes(&[0x44; 20])?,
+            amount: 1000,
+        });
+        transfer.nonce = 1;
+        assert_eq!(
+            hex::encode(transfer.signing_bytes()),
+            format!(
+                "6b696e6404000000{}e8030000000000000000000000000001{}",
+                "44".repeat(20),
+                "00".repeat(8)
+            )
+        );
+        assert_eq!(
+            TxHasher.hash(&transfer)?.to_string(),
+            "405da8cf41672965ad4266bec20ff0f79d3138e94d777afa335f16be29d45d94"
+        );
+
+        let stake = Transaction::new_kind(TxKind::Stake { amount: 1 });
+        assert_eq!(
+            hex::encode(stake.signing_bytes()),
+            format!("6b696e64010000000100000000000000{}", "00".repeat(16))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_evidence_matches_bincode() -> Result<()> {
+        let kind = TxKind::Evidence(Box::new(double_sign_evidence(&PrivateKey::generate())?));
+        assert_eq!(kind.canonical_bytes(), bincode::serialize(&kind)?);
+        Ok(())
+    }
+
+    proptest! {
+        // layout version 1 is what bincode wrote before the layout was pinned
+        #[test]
+        fn prop_header_matches_bincode(header in arb_header()) {
+            prop_assert_eq!(header.canonical_bytes(), bincode::serialize(&header).unwrap());
+        }
+    }
+}
@@ -0,0 +1,115 @@
+/*
+Checkpoints pin the hash of the block at a height. Blocks up to the latest checkpoint are final: a block that
+conflicts with a checkpoint is rejected and the chain is never reorganized below it.
+
+The checkpoint file has one checkpoint per line, the height followed by the hex encoded block hash:
+
+    # comments and empty lines are ignored
+    1000 3f1a...c2
+    2000 9b07...e4
+*/
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use crate::types::Hash;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Checkpoints(BTreeMap<u32, Hash>);
+
+impl Checkpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut checkpoints = Self::new();
+
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (height, hash) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("line {}: expected a height and a hash", n + 1))?;
+            let height = height
+                .parse()
+                .map_err(|err| anyhow!("line {}: invalid height: {err}", n + 1))?;
+            let hash = parse_hash(hash.trim()).map_err(|err| anyhow!("line {}: {err}", n + 1))?;
+
+            if checkpoints.0.insert(height, hash).is_some() {
+                return Err(anyhow!("line {}: duplicate checkpoint {height}", n + 1));
+            }
+        }
+
+        Ok(checkpoints)
+    }
+
+    pub fn add(&mut self, height: u32, hash: Hash) {
+        self.0.insert(height, hash);
+    }
+
+    pub fn get(&self, height: u32) -> Option<Hash> {
+        self.0.get(&height).copied()
+    }
+
+    // Height of the latest checkpoint, blocks up to it are final
+    pub fn final_height(&self) -> Option<u32> {
+        self.0.keys().next_back().copied()
+    }
+
+    pub fn is_final(&self, height: u32) -> bool {
+        self.final_height().is_some_and(|last| height <= last)
+    }
+}
+
+fn parse_hash(s: &str) -> Result<Hash> {
+    let bytes = hex::decode(s.trim_start_matches("0x"))
+        .map_err(|err| anyhow!("invalid hash {s}: {err}"))?;
+    Hash::try_from_bytes(&bytes).map_err(|err| anyhow!("invalid hash {s}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let a = Hash::random();
+        let b = Hash::random();
+        let checkpoints = Checkpoints::parse(&format!(
+            "# checkpoints\n\n10 {a}\n  20 0x{b}  # the latest\n"
+        ))?;
+
+        assert_eq!(checkpoints.get(10), Some(a));
+        assert_eq!(checkpoints.get(20), Some(b));
+        assert_eq!(checkpoints.get(15), None);
+        assert_eq!(checkpoints.final_height(), Some(20));
+        assert!(checkpoints.is_final(20));
+        assert!(!checkpoints.is_final(21));
+        assert!(!Checkpoints::new().is_final(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let hash = Hash::random();
+        for src in [
+            "10".to_string(),
+            format!("ten {hash}"),
+            "10 abcd".to_string(),
+            "10 not-hex".to_string(),
+            format!("10 {hash}\n10 {hash}"),
+        ] {
+            assert!(Checkpoints::parse(&src).is_err(), "{src}");
+        }
+    }
+}
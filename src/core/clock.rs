@@ -0,0 +1,141 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use tokio::{sync::Notify, time};
+
+// Source of time of the server, the chain and the mem_pool. Tests use a ManualClock to control it.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    // monotonic time, for deadlines and intervals
+    fn now(&self) -> Instant;
+    // wall clock time in nanoseconds since the unix epoch, for timestamps
+    fn unix_nanos(&self) -> u128;
+    async fn sleep_until(&self, deadline: Instant);
+
+    fn unix_secs(&self) -> u64 {
+        (self.unix_nanos() / 1_000_000_000) as u64
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+}
+
+pub type BClock = Arc<dyn Clock>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> BClock {
+        Arc::new(Self)
+    }
+}
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_nanos(&self) -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        time::sleep_until(deadline.into()).await
+    }
+}
+
+// A clock that only moves when advanced. Sleepers wake up once the clock passed their deadline, so timers
+// fire in a test without waiting for them. Its wall clock starts at the unix epoch.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Notify,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            advanced: Notify::new(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+        self.advanced.notify_waiters();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn unix_nanos(&self) -> u128 {
+        self.elapsed().as_nanos()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        loop {
+            // registered before checking the time, an advance in between isn't missed
+            let advanced = self.advanced.notified();
+            if self.now() >= deadline {
+                return;
+            }
+            advanced.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_clock() -> Result<()> {
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+
+        let sleeper = {
+            let clock = clock.clone();
+            tokio::task::spawn(
+                async move { clock.sleep_until(start + Duration::from_secs(10)).await },
+            )
+        };
+
+        clock.advance(Duration::from_secs(5));
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!sleeper.is_finished());
+
+        clock.advance(Duration::from_secs(5));
+        time::timeout(Duration::from_secs(1), sleeper).await??;
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+        assert_eq!(clock.unix_nanos(), Duration::from_secs(10).as_nanos());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+/*
+Evidence proves that a validator misbehaved. A validator that signs two different headers at the same height
+(double signing) can get conflicting blocks accepted by different parts of the network.
+
+Any node that sees both headers submits the evidence in a transaction. Once the transaction is in a block the
+validator is slashed: it loses its registration, its signatures no longer count towards a quorum and its blocks
+are rejected.
+*/
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{Block, BlockHasher, BlockSignature, Hasher, Header};
+use crate::{
+    crypto::{PublicKey, Signature},
+    types::Address,
+};
+
+// number of heights below the newest block whose signatures the detector remembers
+pub const DOUBLE_SIGN_WINDOW: u32 = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Evidence {
+    // two different headers with the same height, both signed by validator
+    DoubleSign {
+        validator: PublicKey,
+        first: (Header, Signature),
+        second: (Header, Signature),
+    },
+}
+
+impl Evidence {
+    pub fn validator(&self) -> &PublicKey {
+        match self {
+            Evidence::DoubleSign { validator, .. } => validator,
+        }
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        match self {
+            Evidence::DoubleSign {
+                validator,
+                first,
+                second,
+            } => {
+                if first.0.height != second.0.height {
+                    return Err(anyhow!(
+                        "double sign evidence has headers with heights {} and {}",
+                        first.0.height,
+                        second.0.height
+                    ));
+                }
+                if BlockHasher.hash(&first.0)? == BlockHasher.hash(&second.0)? {
+                    return Err(anyhow!("double sign evidence has the same header twice"));
+                }
+                for (header, signature) in [first, second] {
+                    if !signature.verify(&header.bytes()?, validator) {
+                        return Err(anyhow!(
+                            "double sign evidence has an invalid signature from {}",
+                            validator.address()
+                        ));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Remembers the header every validator signed at a height and reports a second, different one
+#[derive(Default)]
+pub struct DoubleSignDetector {
+    signed: HashMap<(u32, Address), (Header, Signature)>,
+    // height of the newest block that was observed
+    height: u32,
+}
+
+impl DoubleSignDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Records the signatures of block, returns evidence for every signer that signed another header at the
+    // same height. Invalid signatures are ignored.
+    pub fn observe(&mut self, b: &Block) -> Result<Vec<Evidence>> {
+        let height = b.header.height;
+        if height + DOUBLE_SIGN_WINDOW < self.height {
+            return Ok(vec![]);
+        }
+        if height > self.height {
+            self.height = height;
+            let min_height = height.saturating_sub(DOUBLE_SIGN_WINDOW);
+            self.signed.retain(|(height, _), _| *height >= min_height);
+        }
+
+        let bytes = b.header.bytes()?;
+        let hash = BlockHasher.hash(&b.header)?;
+        let mut evidence = vec![];
+
+        for BlockSignature {
+            validator,
+            signature,
+        } in b.signatures()
+        {
+            if !signature.verify(&bytes, &validator) {
+                continue;
+            }
+            let (header, first) = *self
+                .signed
+                .entry((height, validator.address()))
+                .or_insert((b.header, signature));
+            if BlockHasher.hash(&header)? != hash {
+                evidence.push(Evidence::DoubleSign {
+                    validator,
+                    first: (header, first),
+                    second: (b.header, signature),
+                });
+            }
+        }
+
+        Ok(evidence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::PrivateKey, test_utils::*, types::Hash};
+
+    // two different blocks following the same header, both signed by key
+    fn conflicting_blocks(key: &PrivateKey) -> Result<(Block, Block)> {
+        let head = random_block(0, Hash::default())?.header;
+        let mut a = next_block(head, vec![])?;
+        let mut b = next_block(head, vec![random_tx()])?;
+        a.sign(key)?;
+        b.sign(key)?;
+        Ok((a, b))
+    }
+
+    fn double_sign(key: &PrivateKey, a: &Header, b: &Header) -> Result<Evidence> {
+        Ok(Evidence::DoubleSign {
+            validator: key.public_key(),
+            first: (*a, key.sign(&a.bytes()?)),
+            second: (*b, key.sign(&b.bytes()?)),
+        })
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let key = PrivateKey::generate();
+        let (a, b) = conflicting_blocks(&key)?;
+
+        double_sign(&key, &a.header, &b.header)?.verify()?;
+        assert!(double_sign(&key, &a.header, &a.header)?.verify().is_err());
+
+        let mut higher = b.header;
+        higher.height += 1;
+        assert!(double_sign(&key, &a.header, &higher)?.verify().is_err());
+
+        // signed by someone else
+        let Evidence::DoubleSign { first, second, .. } = double_sign(&key, &a.header, &b.header)?;
+        let forged = Evidence::DoubleSign {
+            validator: PrivateKey::generate().public_key(),
+            first,
+            second,
+        };
+        assert!(forged.verify().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detector() -> Result<()> {
+        let key = PrivateKey::generate();
+        let other = PrivateKey::generate();
+        let (mut a, mut b) = conflicting_blocks(&key)?;
+        a.add_committee_signature(&other)?;
+
+        let mut detector = DoubleSignDetector::new();
+        assert!(detector.observe(&a)?.is_empty());
+        // the same block again is no evidence
+        assert!(detector.observe(&a)?.is_empty());
+
+        b.add_committee_signature(&other)?;
+        let evidence = detector.observe(&b)?;
+        assert_eq!(evidence.len(), 2);
+        for e in &evidence {
+            e.verify()?;
+        }
+        assert_eq!(evidence[0].validator(), &key.public_key());
+        assert_eq!(evidence[1].validator(), &other.public_key());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+/*
+GenesisConfig describes the state a chain starts with, so test networks can begin with funded accounts for faucets
+and demos. The genesis block commits to that state with its state_root, a node whose alloc gives another root
+refuses to start the chain, see Blockchain::apply_genesis.
+
+The genesis file has one account per line, the hex encoded address followed by its balance:
+
+    # comments and empty lines are ignored
+    3f1a...c2 1000000
+    9b07...e4 5000
+*/
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Result};
+
+use super::{Block, Header, HeaderVersion, State};
+use crate::types::{Address, Hash};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GenesisConfig {
+    // the balances of the accounts in the genesis state
+    pub alloc: BTreeMap<Address, u64>,
+}
+
+impl GenesisConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut config = Self::new();
+
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (address, balance) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("line {}: expected an address and a balance", n + 1))?;
+            let address: Address = address
+                .parse()
+                .map_err(|err| anyhow!("line {}: {err}", n + 1))?;
+            let balance = balance
+                .trim()
+                .parse()
+                .map_err(|err| anyhow!("line {}: invalid balance: {err}", n + 1))?;
+
+            if config.alloc.insert(address, balance).is_some() {
+                return Err(anyhow!("line {}: duplicate account {address}", n + 1));
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn with_balance(mut self, address: Address, balance: u64) -> Self {
+        self.alloc.insert(address, balance);
+        self
+    }
+
+    // The state before the first block
+    pub fn state(&self) -> State {
+        let mut state = State::new();
+        for (address, balance) in &self.alloc {
+            state.credit(*address, *balance);
+        }
+        state
+    }
+
+    pub fn state_root(&self) -> Hash {
+        self.state().root_hash()
+    }
+
+    pub fn block(&self) -> Block {
+        let header = Header {
+            version: HeaderVersion::FIRST.as_u32(),
+            data_hash: Hash::default(),
+            prev_block_hash: None,
+            timestamp: 0,
+            height: 0,
+            epoch: 0,
+            state_root: self.state_root(),
+        };
+
+        Block::new(header, vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let a = PrivateKey::generate().public_key().address();
+        let b = PrivateKey::generate().public_key().address();
+        let config = GenesisConfig::parse(&format!(
+            "# devnet\n\n{a} 1000\n  0x{b}   5  # the faucet\n"
+        ))?;
+        assert_eq!(
+            config,
+            GenesisConfig::new()
+                .with_balance(a, 1000)
+                .with_balance(b, 5)
+        );
+
+        for src in [
+            format!("{a}"),
+            format!("{a} -1"),
+            "abcd 10".to_string(),
+            format!("{a} 1\n{a} 2"),
+        ] {
+            assert!(GenesisConfig::parse(&src).is_err(), "{src}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_genesis_block() -> Result<()> {
+        let a = PrivateKey::generate().public_key().address();
+        let config = GenesisConfig::new().with_balance(a, 1000);
+        assert_eq!(config.state().balance(&a), 1000);
+
+        let block = config.block();
+        assert_eq!(block.header.state_root, config.state_root());
+        assert_ne!(block.header.state_root, GenesisConfig::new().state_root());
+        assert_eq!(Block::genesis().header, GenesisConfig::new().block().header);
+
+        Ok(())
+    }
+}
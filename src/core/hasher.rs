@@ -1,6 +1,4 @@
-use std::io::Read;
-
-use super::{block::Header, Transaction};
+use super::{block::Header, Canonical, Transaction};
 use crate::types::Hash;
 use anyhow::Result;
 use sha2::{Digest, Sha256};
@@ -9,25 +7,30 @@ pub trait Hasher<T>
 where
     T: Sized,
 {
-    fn hash(&self, t: &T) -> Result<Hash>;
+    // Writes the bytes t is hashed over into the digest, several values can be streamed into one digest
+    // without buffering their bytes
+    fn update(&self, t: &T, digest: &mut Sha256) -> Result<()>;
+
+    fn hash(&self, t: &T) -> Result<Hash> {
+        let mut digest = Sha256::new();
+        self.update(t, &mut digest)?;
+        Ok(Hash::from_digest(digest.finalize().into()))
+    }
 }
 
 pub struct BlockHasher;
 
 impl Hasher<Header> for BlockHasher {
-    fn hash(&self, header: &Header) -> Result<Hash> {
-        let bytes = header.bytes()?;
-        let hash = Hash::from_bytes(Sha256::digest(bytes).as_slice());
-        Ok(hash)
+    fn update(&self, header: &Header, digest: &mut Sha256) -> Result<()> {
+        header.write_canonical(digest)?;
+        Ok(())
     }
 }
 
 pub struct TxHasher;
 
 impl Hasher<Transaction> for TxHasher {
-    fn hash(&self, tx: &Transaction) -> Result<Hash> {
-        let bytes = tx.data.clone();
-        let hash = Hash::from_bytes(Sha256::digest(bytes).as_slice());
-        Ok(hash)
+    fn update(&self, tx: &Transaction, digest: &mut Sha256) -> Result<()> {
+        tx.write_signing_bytes(digest)
     }
 }
@@ -0,0 +1,93 @@
+/*
+The version of a header says which fields it has. A new field, e.g. a state_root or the gas_used of a block, comes
+with a new version that activates at a height of the HeaderUpgrades: the blocks below it keep their version and
+stay valid, the blocks from that height on have to use the new one. The BlockValidator rejects unknown versions
+and blocks that don't have the version of their height.
+*/
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HeaderVersion {
+    V1,
+}
+
+impl HeaderVersion {
+    // the version of the genesis block
+    pub const FIRST: Self = Self::V1;
+
+    pub fn as_u32(self) -> u32 {
+        match self {
+            Self::V1 => 1,
+        }
+    }
+}
+
+impl TryFrom<u32> for HeaderVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(version: u32) -> Result<Self> {
+        match version {
+            1 => Ok(Self::V1),
+            _ => Err(anyhow!("unknown header version {version}")),
+        }
+    }
+}
+
+impl Display for HeaderVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.as_u32())
+    }
+}
+
+// The heights newer header versions activate at
+#[derive(Debug, Clone, Default)]
+pub struct HeaderUpgrades(BTreeMap<u32, HeaderVersion>);
+
+impl HeaderUpgrades {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Blocks from height on have the version, a version can't be older than the one before it
+    pub fn activate(&mut self, height: u32, version: HeaderVersion) -> Result<()> {
+        let before = self.version_at(height.saturating_sub(1));
+        let after = self.0.range(height + 1..).next().map(|(_, v)| *v);
+        if version < before || after.is_some_and(|after| version > after) {
+            return Err(anyhow!(
+                "header version {version} at height {height} would go back to an older version"
+            ));
+        }
+        self.0.insert(height, version);
+        Ok(())
+    }
+
+    pub fn version_at(&self, height: u32) -> HeaderVersion {
+        self.0
+            .range(..=height)
+            .next_back()
+            .map(|(_, version)| *version)
+            .unwrap_or(HeaderVersion::FIRST)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_version() -> Result<()> {
+        assert_eq!(HeaderVersion::try_from(1)?, HeaderVersion::V1);
+        assert!(HeaderVersion::try_from(0).is_err());
+        assert!(HeaderVersion::try_from(2).is_err());
+
+        let mut upgrades = HeaderUpgrades::new();
+        assert_eq!(upgrades.version_at(0), HeaderVersion::V1);
+        upgrades.activate(100, HeaderVersion::V1)?;
+        assert_eq!(upgrades.version_at(u32::MAX), HeaderVersion::V1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+/*
+IncludedTxs holds the hash of every transaction on the chain, so a transaction mined at one height can't be mined
+again at a later one. Unlike the lookups of the Blockchain it keeps the transactions of the blocks a pruned node
+dropped. A node rebuilds it when it restarts, Blockchain::recover replays every stored block.
+
+The Blockchain adds the transactions of an added block and removes those of a block unwound in a reorg. The TxPool
+shares it and rejects an included transaction before it takes up room in the pool.
+*/
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::types::Hash;
+
+#[derive(Debug, Clone, Default)]
+pub struct IncludedTxs {
+    hashes: Arc<Mutex<HashSet<Hash>>>,
+}
+
+impl IncludedTxs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.lock().unwrap().contains(hash)
+    }
+
+    pub fn insert(&self, hashes: impl IntoIterator<Item = Hash>) {
+        self.hashes.lock().unwrap().extend(hashes);
+    }
+
+    pub fn remove(&self, hashes: &[Hash]) {
+        let mut included = self.hashes.lock().unwrap();
+        for hash in hashes {
+            included.remove(hash);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
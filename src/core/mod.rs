@@ -1,17 +1,47 @@
+mod abi;
+mod asm;
 mod block;
+mod block_file;
 mod blockchain;
+mod canonical;
+mod checkpoint;
+mod clock;
 mod encoding;
+mod evidence;
+mod genesis;
 mod hasher;
+mod header_version;
+mod included_txs;
+mod receipt;
+mod sig_cache;
 mod state;
+mod state_store;
 mod storage;
 mod transaction;
 mod validator;
+mod validator_set;
 mod vm;
 
+pub use abi::Call;
+pub use asm::{assemble, disassemble};
 pub use block::*;
+pub use block_file::*;
 pub use blockchain::*;
+pub use canonical::{Canonical, LAYOUT_VERSION};
+pub use checkpoint::Checkpoints;
+pub use clock::*;
 pub use encoding::*;
+pub use evidence::{DoubleSignDetector, Evidence};
+pub use genesis::GenesisConfig;
 pub use hasher::*;
-pub use state::State;
-pub use transaction::Transaction;
+pub use header_version::{HeaderUpgrades, HeaderVersion};
+pub use included_txs::IncludedTxs;
+pub use receipt::Receipt;
+pub use sig_cache::{SigCache, DEFAULT_SIG_CACHE_SIZE};
+pub use state::{State, MIN_VALIDATOR_STAKE};
+pub use state_store::{MemoryStateStore, SledStateStore, StateStore};
+pub use storage::{ChainMeta, FileStore};
+pub use transaction::{RejectCode, Transaction, TxKind, TxRejection, TxStatus, MAX_RAW_TX_SIZE};
+pub use validator::BlockValidator;
+pub use validator_set::ValidatorSet;
 pub use vm::*;
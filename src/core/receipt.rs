@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Header, ValueTransfer, VmOutcome};
+use crate::types::Hash;
+
+// Result of executing a transaction of a block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub tx_hash: Hash,
+    // the block the transaction is in and its timestamp, the time the transaction confirmed
+    pub block_height: u32,
+    pub timestamp: u128,
+    pub success: bool,
+    // why the execution failed, its writes have been discarded
+    pub error: Option<String>,
+    // the data passed to the Return instruction
+    pub return_data: Vec<u8>,
+    pub gas_used: u64,
+    // the tokens the contract sent, see the Transfer instruction
+    pub transfers: Vec<ValueTransfer>,
+}
+
+impl Receipt {
+    pub fn new(tx_hash: Hash, header: &Header, outcome: VmOutcome) -> Self {
+        Self {
+            tx_hash,
+            block_height: header.height,
+            timestamp: header.timestamp,
+            success: outcome.is_success(),
+            error: outcome.error,
+            return_data: outcome.return_data,
+            gas_used: outcome.gas_used,
+            transfers: outcome.transfers,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+/*
+SigCache remembers the transactions whose signature was verified, so a transaction is verified once when it enters
+the mem_pool and not again when the block including it is validated. The entries are keyed by the transaction hash,
+which doesn't cover the signature, so the signer and the signature are compared before a cached result is used.
+
+The TxPool removes the entry of a transaction leaving the pool, the Blockchain those of the transactions of an added
+block. The oldest entries are dropped once the cache holds more than capacity.
+*/
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use super::{Hasher, Transaction, TxHasher};
+use crate::{
+    crypto::{PublicKey, Signature},
+    types::Hash,
+};
+
+pub const DEFAULT_SIG_CACHE_SIZE: usize = 10_000;
+
+#[derive(Clone)]
+pub struct SigCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    verified: HashMap<Hash, (PublicKey, Signature)>,
+    // insertion order, a hash that was removed and added again is in it twice
+    order: VecDeque<Hash>,
+    capacity: usize,
+}
+
+impl Default for SigCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIG_CACHE_SIZE)
+    }
+}
+
+impl SigCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                verified: HashMap::new(),
+                order: VecDeque::new(),
+                capacity,
+            })),
+        }
+    }
+
+    // Verifies the transaction unless it was verified before, a valid transaction is remembered
+    pub fn verify(&self, tx: &Transaction) -> Result<()> {
+        self.verify_batch(&[tx])
+    }
+
+    // Like verify, the transactions that weren't verified before are verified in one batch
+    pub fn verify_batch(&self, txx: &[&Transaction]) -> Result<()> {
+        let mut unverified = vec![];
+        let mut entries = vec![];
+        {
+            let inner = self.lock();
+            for tx in txx {
+                let (Some(from), Some(signature)) = (tx.from, tx.signature) else {
+                    // fails with the reason
+                    return tx.verify();
+                };
+                let hash = if tx.has_cached_hash() {
+                    tx.hash()
+                } else {
+                    TxHasher.hash(tx)?
+                };
+                if inner.verified.get(&hash) != Some(&(from, signature)) {
+                    unverified.push(*tx);
+                    entries.push((hash, (from, signature)));
+                }
+            }
+        }
+
+        Transaction::verify_batch(&unverified)?;
+
+        let mut inner = self.lock();
+        for (hash, entry) in entries {
+            inner.verified.insert(hash, entry);
+            inner.order.push_back(hash);
+        }
+        while inner.order.len() > inner.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.verified.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, hashes: &[Hash]) {
+        let mut inner = self.lock();
+        for hash in hashes {
+            inner.verified.remove(hash);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().verified.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("sig cache lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::PrivateKey, test_utils::random_tx};
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let cache = SigCache::new(10);
+        let mut tx = random_tx();
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+
+        cache.verify(&tx)?;
+        cache.verify(&tx)?;
+        assert_eq!(cache.len(), 1);
+
+        // the same hash with another signer isn't covered by the cached result
+        let mut forged = tx.clone();
+        forged.from = Some(PrivateKey::generate().public_key());
+        assert!(cache.verify(&forged).is_err());
+
+        let mut invalid = random_tx();
+        invalid.data.push(1);
+        assert!(cache.verify(&invalid).is_err());
+        assert!(cache.verify(&Transaction::new(vec![1])).is_err());
+        assert_eq!(cache.len(), 1);
+
+        cache.remove(&[tx.hash()]);
+        assert!(cache.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capacity() -> Result<()> {
+        let cache = SigCache::new(2);
+        for _ in 0..3 {
+            cache.verify(&random_tx())?;
+        }
+        assert_eq!(cache.len(), 2);
+
+        Ok(())
+    }
+}
@@ -1,31 +1,645 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use anyhow::anyhow;
 use anyhow::Result;
+use sha2::{Digest, Sha256};
 
+use super::{
+    MemoryStateStore, RejectCode, StateStore, Transaction, TxKind, TxRejection, ValidatorSet,
+    ValueTransfer,
+};
+use crate::{
+    crypto::PublicKey,
+    types::{Address, Hash},
+};
+
+// stake an address needs to be a validator
+pub const MIN_VALIDATOR_STAKE: u64 = 1_000;
+
+// Writes to the contract data can be collected (begin), and are then either applied to the state (commit)
+// or thrown away (discard). Transactions execute this way, so a transaction that fails halfway leaves no
+// partial writes behind. Writes outside of begin are committed right away.
+// All maps are ordered, iterating the state gives the same order on every node.
 #[derive(Debug)]
 pub struct State {
-    data: HashMap<Vec<u8>, Vec<u8>>,
+    // the contract data, namespaced by the account of the contract, see StateStore
+    data: Box<dyn StateStore>,
+    // set between begin and commit or discard
+    in_transaction: bool,
+    // The native tokens aren't part of the contract data, code can't change them. Locked tokens
+    // are in stakes, ordered by address so every node derives the same validator set.
+    balances: BTreeMap<Address, u64>,
+    // the nonce the next transaction of an account has, see bump_nonce
+    nonces: BTreeMap<Address, u64>,
+    stakes: BTreeMap<Address, Stake>,
+    // validators that lost their stake because of evidence, they can't stake again
+    slashed: BTreeSet<Address>,
+    // The validator set is fixed for an epoch. Stakes count from the next epoch on, unstaked
+    // tokens stay locked until it starts.
+    validators: ValidatorSet,
+    unstaking: BTreeSet<Address>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Stake {
+    validator: PublicKey,
+    amount: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStateStore::new()))
+    }
+
+    pub fn with_store(data: Box<dyn StateStore>) -> Self {
         Self {
-            data: HashMap::new(),
+            data,
+            in_transaction: false,
+            balances: BTreeMap::new(),
+            nonces: BTreeMap::new(),
+            stakes: BTreeMap::new(),
+            slashed: BTreeSet::new(),
+            validators: ValidatorSet::default(),
+            unstaking: BTreeSet::new(),
         }
     }
-    pub fn put(&mut self, k: Vec<u8>, v: Vec<u8>) {
-        self.data.insert(k, v);
+
+    // A copy of the committed state, its contract data is kept in memory whatever the store of this one is
+    pub fn snapshot(&self) -> State {
+        let mut data = MemoryStateStore::new();
+        for (owner, key, value) in self.data.committed() {
+            data.put(&owner, &key, &value);
+        }
+        data.commit()
+            .expect("a memory store commits without failing");
+        Self {
+            data: Box::new(data),
+            in_transaction: false,
+            balances: self.balances.clone(),
+            nonces: self.nonces.clone(),
+            stakes: self.stakes.clone(),
+            slashed: self.slashed.clone(),
+            validators: self.validators.clone(),
+            unstaking: self.unstaking.clone(),
+        }
     }
 
-    pub fn delete(&mut self, k: &Vec<u8>) {
-        self.data.remove(k);
+    // Replaces the store of the contract data and moves the committed data into it, pending writes of the old one
+    // are lost
+    pub fn set_store(&mut self, mut data: Box<dyn StateStore>) -> Result<()> {
+        for (owner, key, value) in self.data.committed() {
+            data.put(&owner, &key, &value);
+        }
+        data.commit()?;
+        self.data = data;
+        self.in_transaction = false;
+        Ok(())
+    }
+
+    pub fn put(&mut self, owner: &Address, k: &[u8], v: &[u8]) -> Result<()> {
+        self.data.put(owner, k, v);
+        self.commit_outside_transaction()
     }
 
-    pub fn get(&self, k: &Vec<u8>) -> Result<Vec<u8>> {
+    pub fn delete(&mut self, owner: &Address, k: &[u8]) -> Result<()> {
+        self.data.delete(owner, k);
+        self.commit_outside_transaction()
+    }
+
+    pub fn get(&self, owner: &Address, k: &[u8]) -> Result<Vec<u8>> {
         self.data
-            .get(k)
-            .ok_or_else(|| anyhow!("given key {k:?} not found"))
-            .cloned()
+            .get(owner, k)
+            .ok_or_else(|| anyhow!("given key {k:?} of {owner} not found"))
+    }
+
+    fn commit_outside_transaction(&mut self) -> Result<()> {
+        match self.in_transaction {
+            true => Ok(()),
+            false => self.data.commit(),
+        }
+    }
+
+    // Starts collecting writes, uncommitted writes of a previous transaction are discarded
+    pub fn begin(&mut self) {
+        self.data.revert();
+        self.in_transaction = true;
+    }
+
+    // Applies the collected writes to the state
+    pub fn commit(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        self.data.commit()
+    }
+
+    pub fn discard(&mut self) {
+        self.data.revert();
+        self.in_transaction = false;
+    }
+
+    // The contract data of owner whose keys start with prefix, in the order of the keys
+    pub fn iterate_prefix(&self, owner: &Address, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.data.iterate_prefix(owner, prefix)
+    }
+
+    // Commits to the accounts: the balances, the nonces, the stakes, the slashed validators and the committed
+    // contract data.
+    // Nodes with the same accounts get the same root, no matter in which order the changes were made.
+    pub fn root_hash(&self) -> Hash {
+        let balances: Vec<_> = self
+            .balances
+            .iter()
+            .filter(|(_, balance)| **balance > 0)
+            .collect();
+        let stakes: Vec<_> = self
+            .stakes
+            .iter()
+            .map(|(address, stake)| (address, stake.amount))
+            .collect();
+
+        let bytes = bincode::serialize(&(
+            balances,
+            &self.nonces,
+            stakes,
+            &self.slashed,
+            &self.unstaking,
+            self.data.committed(),
+        ))
+        .expect("state is serializable");
+        Hash::from_digest(Sha256::digest(bytes).into())
+    }
+
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.balances.get(address).copied().unwrap_or_default()
+    }
+
+    pub fn credit(&mut self, address: Address, amount: u64) {
+        *self.balances.entry(address).or_default() += amount;
+    }
+
+    pub fn nonce(&self, address: &Address) -> u64 {
+        self.nonces.get(address).copied().unwrap_or_default()
+    }
+
+    // Records a transaction of the account with the nonce, the next one needs a higher nonce
+    pub fn bump_nonce(&mut self, address: Address, nonce: u64) {
+        let next = self.nonces.entry(address).or_default();
+        *next = (*next).max(nonce.saturating_add(1));
+    }
+
+    // Takes the fee of a transaction from its sender and gives it to the proposer of the block, without a proposer
+    // the fee is burnt
+    pub fn charge_fee(
+        &mut self,
+        from: &Address,
+        fee: u64,
+        proposer: Option<Address>,
+    ) -> Result<()> {
+        let balance = self.balance(from);
+        if balance < fee {
+            return Err(anyhow!(
+                "{from} can't pay the fee {fee}, its balance is {balance}"
+            ));
+        }
+        if fee == 0 {
+            return Ok(());
+        }
+
+        self.balances.insert(*from, balance - fee);
+        if let Some(proposer) = proposer {
+            self.credit(proposer, fee);
+        }
+        Ok(())
+    }
+
+    // Moves amount of the balance of from to the one of to
+    pub fn transfer(&mut self, from: &Address, to: Address, amount: u64) -> Result<()> {
+        let balance = self.balance(from);
+        if balance < amount {
+            return Err(anyhow!(
+                "{from} can't transfer {amount}, its balance is {balance}"
+            ));
+        }
+
+        self.balances.insert(*from, balance - amount);
+        self.credit(to, amount);
+        Ok(())
+    }
+
+    // Applies the transfers of a contract run, all of them or none if a balance doesn't cover them
+    pub fn apply_transfers(&mut self, transfers: &[ValueTransfer]) -> Result<()> {
+        let mut spent: HashMap<Address, u64> = HashMap::new();
+        for t in transfers {
+            let total = spent.entry(t.from).or_default();
+            *total = total.saturating_add(t.amount);
+        }
+        for (from, amount) in &spent {
+            let balance = self.balance(from);
+            if balance < *amount {
+                return Err(anyhow!(
+                    "{from} can't transfer {amount}, its balance is {balance}"
+                ));
+            }
+        }
+
+        for t in transfers {
+            self.transfer(&t.from, t.to, t.amount)?;
+        }
+        Ok(())
+    }
+
+    pub fn stake_of(&self, address: &Address) -> u64 {
+        self.stakes
+            .get(address)
+            .map(|s| s.amount)
+            .unwrap_or_default()
+    }
+
+    // Moves amount of the validator's balance into its stake
+    pub fn stake(&mut self, validator: &PublicKey, amount: u64) -> Result<()> {
+        let address = validator.address();
+        if self.is_slashed(validator) {
+            return Err(anyhow!("{address} was slashed and can't stake"));
+        }
+        if self.unstaking.contains(&address) {
+            return Err(anyhow!("{address} is unstaking until the next epoch"));
+        }
+        let balance = self.balance(&address);
+        if balance < amount {
+            return Err(anyhow!(
+                "{address} can't stake {amount}, its balance is {balance}"
+            ));
+        }
+
+        self.balances.insert(address, balance - amount);
+        self.stakes
+            .entry(address)
+            .or_insert(Stake {
+                validator: *validator,
+                amount: 0,
+            })
+            .amount += amount;
+        Ok(())
+    }
+
+    // Queues the whole stake of the validator to move back to its balance when the next epoch starts
+    pub fn unstake(&mut self, validator: &PublicKey) -> Result<()> {
+        let address = validator.address();
+        if !self.stakes.contains_key(&address) {
+            return Err(anyhow!("{address} has no stake"));
+        }
+        if !self.unstaking.insert(address) {
+            return Err(anyhow!("{address} is already unstaking"));
+        }
+        Ok(())
+    }
+
+    // Burns the stake of the validator, returns false if it was already slashed. Unlike the other
+    // changes this takes effect right away, a slashed validator leaves the set of the current epoch.
+    pub fn slash(&mut self, validator: &PublicKey) -> bool {
+        let address = validator.address();
+        self.stakes.remove(&address);
+        self.unstaking.remove(&address);
+        self.slashed.insert(address)
+    }
+
+    // Applies the queued changes, the validators with at least MIN_VALIDATOR_STAKE form the set of
+    // the new epoch
+    pub fn start_epoch(&mut self) {
+        for address in std::mem::take(&mut self.unstaking) {
+            if let Some(stake) = self.stakes.remove(&address) {
+                self.credit(address, stake.amount);
+            }
+        }
+
+        self.validators = ValidatorSet::new(
+            self.stakes
+                .values()
+                .filter(|s| s.amount >= MIN_VALIDATOR_STAKE)
+                .map(|s| s.validator)
+                .collect(),
+        );
+    }
+
+    pub fn is_slashed(&self, validator: &PublicKey) -> bool {
+        self.slashed.contains(&validator.address())
+    }
+
+    // Checks the nonce of a transaction and if its sender can afford it, see Blockchain::check_transaction.
+    // The TxPool rechecks the transactions of dropped blocks with it after a reorg.
+    pub fn check_transaction(&self, tx: &Transaction, hash: &Hash) -> Result<(), TxRejection> {
+        let Some(from) = &tx.from else {
+            return Ok(());
+        };
+
+        let address = from.address();
+        if tx.nonce < self.nonce(&address) {
+            return Err(TxRejection::new(
+                RejectCode::NonceTooLow,
+                format!(
+                    "tx {hash} has the nonce {}, the next one of {address} is {}",
+                    tx.nonce,
+                    self.nonce(&address)
+                ),
+            ));
+        }
+        let fee = tx.fee;
+        if self.balance(&address) < fee {
+            return Err(TxRejection::new(
+                RejectCode::InsufficientBalance,
+                format!(
+                    "{address} can't pay the fee {fee}, its balance is {}",
+                    self.balance(&address)
+                ),
+            ));
+        }
+        match &tx.kind {
+            TxKind::Call => Ok(()),
+            TxKind::Stake { .. } if self.is_slashed(from) => Err(TxRejection::new(
+                RejectCode::Slashed,
+                format!("{address} was slashed and can't stake"),
+            )),
+            TxKind::Stake { amount } if self.balance(&address) < amount.saturating_add(fee) => {
+                Err(TxRejection::new(
+                    RejectCode::InsufficientBalance,
+                    format!(
+                        "{address} can't stake {amount} and pay the fee {fee}, its balance is {}",
+                        self.balance(&address)
+                    ),
+                ))
+            }
+            TxKind::Stake { .. } => Ok(()),
+            TxKind::Unstake if self.stake_of(&address) == 0 => Err(TxRejection::new(
+                RejectCode::NotStaked,
+                format!("{address} has no stake"),
+            )),
+            TxKind::Unstake => Ok(()),
+            TxKind::Evidence(evidence) if self.is_slashed(evidence.validator()) => {
+                Err(TxRejection::new(
+                    RejectCode::Slashed,
+                    format!(
+                        "validator {} is already slashed",
+                        evidence.validator().address()
+                    ),
+                ))
+            }
+            TxKind::Evidence(_) => Ok(()),
+            TxKind::Transfer { amount, .. } if self.balance(&address) < amount.saturating_add(fee) => {
+                Err(TxRejection::new(
+                    RejectCode::InsufficientBalance,
+                    format!(
+                        "{address} can't transfer {amount} and pay the fee {fee}, its balance is {}",
+                        self.balance(&address)
+                    ),
+                ))
+            }
+            TxKind::Transfer { .. } => Ok(()),
+        }
+    }
+
+    // The validator set of the current epoch without the validators slashed since it started,
+    // ordered by address
+    pub fn validator_set(&self) -> ValidatorSet {
+        ValidatorSet::new(
+            self.validators
+                .validators()
+                .iter()
+                .filter(|v| !self.is_slashed(v))
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    #[test]
+    fn test_commit() -> Result<()> {
+        let o = Address::default();
+        let mut state = State::new();
+        state.put(&o, b"a", &[1])?;
+        state.put(&o, b"b", &[2])?;
+
+        state.begin();
+        state.put(&o, b"a", &[3])?;
+        state.delete(&o, b"b")?;
+        assert_eq!(state.get(&o, b"a")?, vec![3]);
+        assert!(state.get(&o, b"b").is_err());
+
+        state.commit()?;
+        assert_eq!(state.get(&o, b"a")?, vec![3]);
+        assert!(state.get(&o, b"b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard() -> Result<()> {
+        let o = Address::default();
+        let mut state = State::new();
+        state.put(&o, b"a", &[1])?;
+
+        state.begin();
+        state.put(&o, b"a", &[2])?;
+        state.put(&o, b"c", &[3])?;
+        state.discard();
+
+        assert_eq!(state.get(&o, b"a")?, vec![1]);
+        assert!(state.get(&o, b"c").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_staking() -> Result<()> {
+        let mut state = State::new();
+        let a = PrivateKey::generate().public_key();
+        let b = PrivateKey::generate().public_key();
+        state.credit(a.address(), 3_000);
+        state.credit(b.address(), 500);
+
+        assert!(state.stake(&a, 4_000).is_err());
+        state.stake(&a, 600)?;
+        state.start_epoch();
+        assert!(state.validator_set().is_empty());
+        state.stake(&a, 600)?;
+        state.stake(&b, 500)?;
+        assert_eq!(state.balance(&a.address()), 1_800);
+        assert_eq!(state.stake_of(&a.address()), 1_200);
+        // stakes count from the next epoch on
+        assert!(state.validator_set().is_empty());
+        state.start_epoch();
+        assert_eq!(state.validator_set().validators(), &[a]);
+
+        state.unstake(&a)?;
+        assert!(state.unstake(&a).is_err());
+        assert!(state.stake(&a, 100).is_err());
+        assert_eq!(state.validator_set().validators(), &[a]);
+        assert_eq!(state.balance(&a.address()), 1_800);
+
+        state.start_epoch();
+        assert_eq!(state.balance(&a.address()), 3_000);
+        assert!(state.validator_set().is_empty());
+        assert!(state.unstake(&a).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_hash() -> Result<()> {
+        let a = PrivateKey::generate().public_key();
+        let b = PrivateKey::generate().public_key();
+        let o = Address::default();
+        let mut state = State::new();
+        state.credit(a.address(), 3_000);
+        state.credit(b.address(), 500);
+        state.put(&o, b"a", &[1])?;
+
+        // the order of the changes doesn't matter, an empty balance is no balance
+        let mut other = State::new();
+        other.put(&o, b"a", &[1])?;
+        other.credit(b.address(), 500);
+        other.credit(PrivateKey::generate().public_key().address(), 0);
+        other.credit(a.address(), 3_000);
+        assert_eq!(state.root_hash(), other.root_hash());
+
+        // uncommitted writes aren't part of the root
+        other.begin();
+        other.put(&o, b"b", &[2])?;
+        assert_eq!(state.root_hash(), other.root_hash());
+        other.commit()?;
+        assert_ne!(state.root_hash(), other.root_hash());
+
+        // the same data of another contract is another state
+        let mut other = State::new();
+        other.put(&Address::from_digest([1; 32]), b"a", &[1])?;
+        other.credit(a.address(), 3_000);
+        other.credit(b.address(), 500);
+        assert_ne!(state.root_hash(), other.root_hash());
+
+        state.stake(&a, 1_000)?;
+        assert_ne!(state.root_hash(), State::new().root_hash());
+        let root = state.root_hash();
+        state.slash(&a);
+        assert_ne!(state.root_hash(), root);
+
+        Ok(())
+    }
+
+    // A write of contract data or a credit of the balance of an address
+    #[derive(Debug, Clone)]
+    enum Write {
+        Put(Vec<u8>, Vec<u8>),
+        Credit(u8, u64),
+    }
+
+    fn arb_writes() -> impl Strategy<Value = Vec<Write>> {
+        // distinct keys, the last write of a key would depend on the order
+        let puts = proptest::collection::btree_map(
+            proptest::collection::vec(any::<u8>(), 1..8),
+            proptest::collection::vec(any::<u8>(), 0..8),
+            0..16,
+        );
+        let credits = proptest::collection::vec((any::<u8>(), 0..1_000_u64), 0..16);
+        (puts, credits).prop_map(|(puts, credits)| {
+            let puts = puts.into_iter().map(|(k, v)| Write::Put(k, v));
+            let credits = credits
+                .into_iter()
+                .map(|(a, amount)| Write::Credit(a, amount));
+            puts.chain(credits).collect()
+        })
+    }
+
+    fn apply(writes: &[Write]) -> State {
+        let mut state = State::new();
+        for write in writes {
+            match write {
+                Write::Put(k, v) => state.put(&Address::default(), k, v).unwrap(),
+                Write::Credit(a, amount) => state.credit(Address::from_digest([*a; 32]), *amount),
+            }
+        }
+        state
+    }
+
+    proptest! {
+        #[test]
+        fn prop_root_hash_ignores_order(
+            (writes, shuffled) in arb_writes()
+                .prop_flat_map(|writes| (Just(writes.clone()), Just(writes).prop_shuffle()))
+        ) {
+            let state = apply(&writes);
+            let other = apply(&shuffled);
+            prop_assert_eq!(state.root_hash(), other.root_hash());
+            prop_assert_eq!(
+                state.iterate_prefix(&Address::default(), b""),
+                other.iterate_prefix(&Address::default(), b"")
+            );
+        }
+    }
+
+    #[test]
+    fn test_nonce() {
+        let a = PrivateKey::generate().public_key().address();
+        let mut state = State::new();
+        assert_eq!(state.nonce(&a), 0);
+
+        state.bump_nonce(a, 0);
+        assert_eq!(state.nonce(&a), 1);
+        state.bump_nonce(a, 4);
+        assert_eq!(state.nonce(&a), 5);
+        // an older nonce doesn't go back
+        state.bump_nonce(a, 2);
+        assert_eq!(state.nonce(&a), 5);
+        assert_ne!(state.root_hash(), State::new().root_hash());
+    }
+
+    #[test]
+    fn test_transfer() -> Result<()> {
+        let a = PrivateKey::generate().public_key().address();
+        let b = PrivateKey::generate().public_key().address();
+        let mut state = State::new();
+        state.credit(a, 1_000);
+
+        state.transfer(&a, b, 400)?;
+        assert_eq!(state.balance(&a), 600);
+        assert_eq!(state.balance(&b), 400);
+        assert!(state.transfer(&a, b, 601).is_err());
+        assert_eq!(state.balance(&a), 600);
+
+        // to itself
+        state.transfer(&a, a, 600)?;
+        assert_eq!(state.balance(&a), 600);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slash() -> Result<()> {
+        let mut state = State::new();
+        let a = PrivateKey::generate().public_key();
+        state.credit(a.address(), 3_000);
+        state.stake(&a, 2_000)?;
+        state.start_epoch();
+        assert_eq!(state.validator_set().validators(), &[a]);
+
+        assert!(state.slash(&a));
+        assert!(!state.slash(&a));
+        assert!(state.is_slashed(&a));
+        assert!(state.validator_set().is_empty());
+        assert_eq!(state.balance(&a.address()), 1_000);
+        // slashed validators can't come back
+        assert!(state.stake(&a, 1_000).is_err());
+
+        Ok(())
     }
 }
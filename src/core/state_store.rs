@@ -0,0 +1,321 @@
+/*
+A StateStore holds the contract data of the State. Every key belongs to an owner, the account of the contract that
+wrote it (see contract_address), so a contract can only read and change its own data. Entries are ordered by owner
+and key, iterating a store gives the same order on every node.
+
+Writes are pending until commit and dropped by revert, reads see them right away. Only a commit may fail, a store
+that can't be read anymore stops the node.
+
+MemoryStateStore keeps the entries in memory. SledStateStore keeps the committed entries in a sled database, so
+the contract data of a long chain doesn't have to fit into memory. It doesn't outlive the node, the state is
+rebuilt from the blocks on every start.
+*/
+
+use std::{collections::BTreeMap, fmt::Debug, path::Path};
+
+use anyhow::Result;
+
+use crate::types::Address;
+
+pub trait StateStore: Send + Sync + Debug {
+    fn get(&self, owner: &Address, key: &[u8]) -> Option<Vec<u8>>;
+    fn put(&mut self, owner: &Address, key: &[u8], value: &[u8]);
+    fn delete(&mut self, owner: &Address, key: &[u8]);
+    // The entries of owner whose keys start with prefix in the order of the keys, pending writes included
+    fn iterate_prefix(&self, owner: &Address, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)>;
+    // The committed entries of all owners, ordered by owner and key
+    fn committed(&self) -> Vec<(Address, Vec<u8>, Vec<u8>)>;
+    fn commit(&mut self) -> Result<()>;
+    fn revert(&mut self);
+}
+
+type Key = (Address, Vec<u8>);
+
+// A pending write, None deletes the key
+type Change = (Address, Vec<u8>, Option<Vec<u8>>);
+
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    entries: BTreeMap<Key, Vec<u8>>,
+    // None marks a deleted key
+    pending: BTreeMap<Key, Option<Vec<u8>>>,
+}
+
+impl MemoryStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn apply(&mut self, changes: impl IntoIterator<Item = Change>) {
+        for (owner, key, value) in changes {
+            match value {
+                Some(value) => self.entries.insert((owner, key), value),
+                None => self.entries.remove(&(owner, key)),
+            };
+        }
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn get(&self, owner: &Address, key: &[u8]) -> Option<Vec<u8>> {
+        let key = (*owner, key.to_vec());
+        match self.pending.get(&key) {
+            Some(value) => value.clone(),
+            None => self.entries.get(&key).cloned(),
+        }
+    }
+
+    fn put(&mut self, owner: &Address, key: &[u8], value: &[u8]) {
+        self.pending
+            .insert((*owner, key.to_vec()), Some(value.to_vec()));
+    }
+
+    fn delete(&mut self, owner: &Address, key: &[u8]) {
+        self.pending.insert((*owner, key.to_vec()), None);
+    }
+
+    fn iterate_prefix(&self, owner: &Address, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let start = (*owner, prefix.to_vec());
+        let in_range = |(o, key): &&Key| o == owner && key.starts_with(prefix);
+
+        let mut entries: BTreeMap<Vec<u8>, Vec<u8>> = self
+            .entries
+            .range(&start..)
+            .take_while(|(key, _)| in_range(key))
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect();
+        for ((_, key), value) in self
+            .pending
+            .range(&start..)
+            .take_while(|(key, _)| in_range(key))
+        {
+            match value {
+                Some(value) => entries.insert(key.clone(), value.clone()),
+                None => entries.remove(key),
+            };
+        }
+        entries.into_iter().collect()
+    }
+
+    fn committed(&self) -> Vec<(Address, Vec<u8>, Vec<u8>)> {
+        self.entries
+            .iter()
+            .map(|((owner, key), value)| (*owner, key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.apply(
+            pending
+                .into_iter()
+                .map(|((owner, key), value)| (owner, key, value)),
+        );
+        Ok(())
+    }
+
+    fn revert(&mut self) {
+        self.pending.clear();
+    }
+}
+
+// sled keeps the hot part of the data in a page cache of this size, the rest is read from disk
+const SLED_CACHE_CAPACITY: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct SledStateStore {
+    // the committed entries, keyed by the bytes of the owner followed by the key
+    db: sled::Db,
+    // None marks a deleted key
+    pending: BTreeMap<Key, Option<Vec<u8>>>,
+}
+
+impl SledStateStore {
+    // Opens the database at path, it's created if it's missing. The state is rebuilt from the blocks when a
+    // chain is recovered, so the entries of a previous run are dropped.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let db = sled::Config::new()
+            .path(path)
+            .cache_capacity(SLED_CACHE_CAPACITY)
+            .open()?;
+        db.clear()?;
+        Ok(Self {
+            db,
+            pending: BTreeMap::new(),
+        })
+    }
+}
+
+fn sled_key(owner: &Address, key: &[u8]) -> Vec<u8> {
+    let mut bytes = owner.into_bytes().to_vec();
+    bytes.extend_from_slice(key);
+    bytes
+}
+
+// A read error means the node can't know its state anymore, it can't go on executing blocks
+fn sled_read<T>(result: sled::Result<T>) -> T {
+    result.expect("the state store is readable")
+}
+
+impl StateStore for SledStateStore {
+    fn get(&self, owner: &Address, key: &[u8]) -> Option<Vec<u8>> {
+        match self.pending.get(&(*owner, key.to_vec())) {
+            Some(value) => value.clone(),
+            None => sled_read(self.db.get(sled_key(owner, key))).map(|value| value.to_vec()),
+        }
+    }
+
+    fn put(&mut self, owner: &Address, key: &[u8], value: &[u8]) {
+        self.pending
+            .insert((*owner, key.to_vec()), Some(value.to_vec()));
+    }
+
+    fn delete(&mut self, owner: &Address, key: &[u8]) {
+        self.pending.insert((*owner, key.to_vec()), None);
+    }
+
+    fn iterate_prefix(&self, owner: &Address, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let owner_len = owner.into_bytes().len();
+        let mut entries: BTreeMap<Vec<u8>, Vec<u8>> = self
+            .db
+            .scan_prefix(sled_key(owner, prefix))
+            .map(|entry| {
+                let (key, value) = sled_read(entry);
+                (key[owner_len..].to_vec(), value.to_vec())
+            })
+            .collect();
+        let start = (*owner, prefix.to_vec());
+        for ((_, key), value) in self
+            .pending
+            .range(&start..)
+            .take_while(|((o, key), _)| o == owner && key.starts_with(prefix))
+        {
+            match value {
+                Some(value) => entries.insert(key.clone(), value.clone()),
+                None => entries.remove(key),
+            };
+        }
+        entries.into_iter().collect()
+    }
+
+    fn committed(&self) -> Vec<(Address, Vec<u8>, Vec<u8>)> {
+        let owner_len = Address::default().into_bytes().len();
+        self.db
+            .iter()
+            .map(|entry| {
+                let (key, value) = sled_read(entry);
+                let owner = Address::try_from_bytes(&key[..owner_len])
+                    .expect("the keys start with an address");
+                (owner, key[owner_len..].to_vec(), value.to_vec())
+            })
+            .collect()
+    }
+
+    // The changes are applied as one batch, a failed write leaves them pending. They aren't flushed right away,
+    // the database is dropped when it's opened again anyway.
+    fn commit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        for ((owner, key), value) in &self.pending {
+            match value {
+                Some(value) => batch.insert(sled_key(owner, key), value.as_slice()),
+                None => batch.remove(sled_key(owner, key)),
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn revert(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::types::Hash;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("projectx-state-{}", Hash::random()))
+    }
+
+    fn owners() -> (Address, Address) {
+        (Address::from_digest([1; 32]), Address::from_digest([2; 32]))
+    }
+
+    #[test]
+    fn test_namespaces_and_prefixes() -> Result<()> {
+        let (a, b) = owners();
+        let mut store = MemoryStateStore::new();
+        store.put(&a, b"x1", &[1]);
+        store.put(&a, b"x2", &[2]);
+        store.put(&a, b"y", &[3]);
+        store.put(&b, b"x1", &[4]);
+        store.commit()?;
+
+        assert_eq!(store.get(&a, b"x1"), Some(vec![1]));
+        assert_eq!(store.get(&b, b"x1"), Some(vec![4]));
+        assert_eq!(store.get(&b, b"y"), None);
+
+        // pending writes are part of the iteration, ordered by key
+        store.delete(&a, b"x1");
+        store.put(&a, b"x0", &[5]);
+        assert_eq!(
+            store.iterate_prefix(&a, b"x"),
+            vec![(b"x0".to_vec(), vec![5]), (b"x2".to_vec(), vec![2])]
+        );
+        assert_eq!(store.iterate_prefix(&b, b"").len(), 1);
+
+        store.revert();
+        assert_eq!(store.get(&a, b"x1"), Some(vec![1]));
+        assert_eq!(store.committed().len(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sled_store() -> Result<()> {
+        let path = temp_path();
+        let (a, b) = owners();
+
+        let result = (|| {
+            let mut store = SledStateStore::open(&path)?;
+            let mut memory = MemoryStateStore::new();
+            for store in [&mut store as &mut dyn StateStore, &mut memory] {
+                store.put(&a, b"x1", &[1]);
+                store.put(&a, b"x2", &[2]);
+                store.put(&b, b"x1", &[3]);
+                store.commit()?;
+                store.delete(&a, b"x1");
+                store.put(&a, b"x0", &[4]);
+                store.put(&b, b"pending", &[5]);
+            }
+            assert_eq!(store.get(&a, b"x1"), None);
+            assert_eq!(store.get(&b, b"x1"), Some(vec![3]));
+            assert_eq!(
+                store.iterate_prefix(&a, b"x"),
+                memory.iterate_prefix(&a, b"x")
+            );
+
+            store.revert();
+            memory.revert();
+            assert_eq!(store.get(&a, b"x1"), Some(vec![1]));
+            assert_eq!(store.committed(), memory.committed());
+            drop(store);
+
+            // the state of the previous run is rebuilt from the blocks
+            let store = SledStateStore::open(&path)?;
+            assert!(store.committed().is_empty());
+            Ok(())
+        })();
+
+        std::fs::remove_dir_all(&path)?;
+        result
+    }
+}
@@ -1,24 +1,673 @@
-use anyhow::Result;
+/*
+Storage keeps every header of the chain, so the Blockchain only needs the newest ones in memory, and every block, so
+the node can serve them to peers that are syncing.
+
+FileStore writes the headers into one file. Every header is padded to the size of the largest possible
+header, so the header with height h starts at h * record_size and reading it doesn't need an index.
+Blocks differ in size, they are appended to a second file and the offset of every block is kept in memory.
+
+The ChainMeta is the commit point of a block. It's written after the header and the block, so a node that crashed
+in between finds them above the tip of the meta when it restarts, see Blockchain::recover. FileStore::open drops a
+header or block that was only partially written, and writes the meta into a temporary file that replaces the old
+meta once the data files were synced.
+
+A pruned node drops the blocks below the ones it keeps with prune_blocks. The headers stay, and so do the blocks
+of a FileStore, Blockchain::recover rebuilds the state by replaying every block.
+
+FileStore::backup copies the committed chain of a store while its node keeps running, FileStore::compact drops what
+a crash left behind in the store of a stopped node.
+*/
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::{Block, Header};
+use super::{BlockHasher, Hasher};
+use crate::types::Hash;
+
+// a backup is copied again if a block was replaced while copying, see FileStore::backup
+pub const BACKUP_ATTEMPTS: usize = 5;
 
 pub trait Storage: Send + Sync {
-    fn put(&self) -> Result<()>;
-    fn get(&self) -> Result<()>;
+    fn put_header(&self, header: &Header) -> Result<()>;
+    // None if no header with this height was stored
+    fn get_header(&self, height: u32) -> Result<Option<Header>>;
+    fn put_block(&self, block: &Block) -> Result<()>;
+    // None if no block with this height was stored
+    fn get_block(&self, height: u32) -> Result<Option<Block>>;
+    // Drops the headers and blocks above height
+    fn truncate(&self, height: u32) -> Result<()>;
+    // Drops the blocks below height, their headers are kept. A store may keep blocks it still needs.
+    fn prune_blocks(&self, height: u32) -> Result<()>;
+    fn put_meta(&self, meta: &ChainMeta) -> Result<()>;
+    // None until the first block was committed
+    fn get_meta(&self) -> Result<Option<ChainMeta>>;
 }
 
-pub struct MemoryStore;
+// What the chain committed last: the newest block that was stored completely and the newest block whose
+// transactions were applied to the state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainMeta {
+    pub tip_height: u32,
+    pub tip_hash: Hash,
+    pub state_height: u32,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    headers: Mutex<Vec<Header>>,
+    // None for a pruned block
+    blocks: Mutex<Vec<Option<Block>>>,
+    meta: Mutex<Option<ChainMeta>>,
+}
 
 impl MemoryStore {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
     }
 }
 
 impl Storage for MemoryStore {
-    fn put(&self) -> Result<()> {
+    fn put_header(&self, header: &Header) -> Result<()> {
+        let mut headers = self.headers.lock().unwrap();
+        put_at(&mut headers, header.height, *header)
+    }
+
+    fn get_header(&self, height: u32) -> Result<Option<Header>> {
+        Ok(self.headers.lock().unwrap().get(height as usize).copied())
+    }
+
+    fn put_block(&self, block: &Block) -> Result<()> {
+        let mut blocks = self.blocks.lock().unwrap();
+        put_at(&mut blocks, block.header.height, Some(block.clone()))
+    }
+
+    fn get_block(&self, height: u32) -> Result<Option<Block>> {
+        Ok(self
+            .blocks
+            .lock()
+            .unwrap()
+            .get(height as usize)
+            .cloned()
+            .flatten())
+    }
+
+    fn truncate(&self, height: u32) -> Result<()> {
+        let len = height as usize + 1;
+        self.headers.lock().unwrap().truncate(len);
+        self.blocks.lock().unwrap().truncate(len);
+        Ok(())
+    }
+
+    fn prune_blocks(&self, height: u32) -> Result<()> {
+        for block in self.blocks.lock().unwrap().iter_mut().take(height as usize) {
+            *block = None;
+        }
+        Ok(())
+    }
+
+    fn put_meta(&self, meta: &ChainMeta) -> Result<()> {
+        *self.meta.lock().unwrap() = Some(*meta);
+        Ok(())
+    }
+
+    fn get_meta(&self) -> Result<Option<ChainMeta>> {
+        Ok(*self.meta.lock().unwrap())
+    }
+}
+
+// Headers and blocks are stored in order, an entry replaces the one with the same height and everything above it
+fn put_at<T>(entries: &mut Vec<T>, height: u32, entry: T) -> Result<()> {
+    let height = height as usize;
+    if height > entries.len() {
+        return Err(anyhow!(
+            "can't store height {height}, the store has {} entries",
+            entries.len()
+        ));
+    }
+    entries.truncate(height);
+    entries.push(entry);
+    Ok(())
+}
+
+pub struct FileStore {
+    file: Mutex<File>,
+    record_size: u64,
+    blocks: Mutex<BlockFile>,
+    meta_path: PathBuf,
+}
+
+struct BlockFile {
+    file: File,
+    // offset of the block with height h at index h, the file ends at end
+    offsets: Vec<u64>,
+    end: u64,
+}
+
+impl FileStore {
+    // Creates the file at path and the block file next to it, existing files are truncated
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let meta_path = Self::meta_path(path);
+        if meta_path.exists() {
+            fs::remove_file(&meta_path)?;
+        }
+        Self::new(
+            open_file(path, true)?,
+            BlockFile {
+                file: open_file(&Self::blocks_path(path), true)?,
+                offsets: vec![],
+                end: 0,
+            },
+            meta_path,
+        )
+    }
+
+    // Opens the files at path, missing ones are created. A block or header that was only partially written when
+    // the node stopped is dropped, as is a header without its block.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let blocks = BlockFile::open(open_file(&Self::blocks_path(path), false)?)?;
+        let store = Self::new(open_file(path, false)?, blocks, Self::meta_path(path))?;
+
+        let file = store.file.lock().unwrap();
+        let headers = file.metadata()?.len() / store.record_size;
+        let len = headers.min(store.blocks.lock().unwrap().offsets.len() as u64);
+        file.set_len(len * store.record_size)?;
+        drop(file);
+        Ok(store)
+    }
+
+    fn new(file: File, blocks: BlockFile, meta_path: PathBuf) -> Result<Self> {
+        // a header with the hash of the previous block is the largest one
+        let largest = Header {
+            prev_block_hash: Some(Hash::default()),
+            ..Header::default()
+        };
+
+        Ok(Self {
+            file: Mutex::new(file),
+            record_size: bincode::serialized_size(&largest)?,
+            blocks: Mutex::new(blocks),
+            meta_path,
+        })
+    }
+
+    // The blocks of the store at path are written to path.blocks
+    pub fn blocks_path(path: &Path) -> PathBuf {
+        with_extension(path, ".blocks")
+    }
+
+    // The ChainMeta of the store at path is written to path.meta
+    pub fn meta_path(path: &Path) -> PathBuf {
+        with_extension(path, ".meta")
+    }
+
+    // The contract data of the chain at path is kept in the database at path.state, see SledStateStore
+    pub fn state_path(path: &Path) -> PathBuf {
+        with_extension(path, ".state")
+    }
+
+    // Copies the committed chain of the store at path to a new store at dest, the node using the store may keep
+    // adding blocks meanwhile. The copy ends at the tip the meta had before copying, it's copied again if a block
+    // up to that tip was replaced in between.
+    pub fn backup(path: &Path, dest: &Path) -> Result<ChainMeta> {
+        for _ in 0..BACKUP_ATTEMPTS {
+            let meta = read_meta(&Self::meta_path(path))?
+                .ok_or_else(|| anyhow!("{} holds no committed chain", path.display()))?;
+            // a header is only kept with its block, so the blocks are copied first
+            fs::copy(Self::blocks_path(path), Self::blocks_path(dest))?;
+            fs::copy(path, dest)?;
+
+            let store = Self::open(dest)?;
+            store.truncate(meta.tip_height)?;
+            if store.is_chain(&meta)? {
+                store.put_meta(&meta)?;
+                return Ok(meta);
+            }
+        }
+        Err(anyhow!(
+            "the chain at {} kept changing while it was copied",
+            path.display()
+        ))
+    }
+
+    // Drops the torn records and uncommitted blocks a crash left in the store at path, the node using it has to
+    // be stopped. Returns the number of bytes freed.
+    pub fn compact(path: &Path) -> Result<u64> {
+        if !path.exists() {
+            return Err(anyhow!("there is no store at {}", path.display()));
+        }
+        let before = store_size(path)?;
+
+        let store = Self::open(path)?;
+        match store.get_meta()? {
+            Some(meta) => store.truncate(meta.tip_height)?,
+            None => store.truncate(0)?,
+        }
+        drop(store);
+        let tmp = with_extension(&Self::meta_path(path), ".tmp");
+        if tmp.exists() {
+            fs::remove_file(tmp)?;
+        }
+
+        Ok(before.saturating_sub(store_size(path)?))
+    }
+
+    // Checks that the store holds a linked chain up to the tip of meta
+    fn is_chain(&self, meta: &ChainMeta) -> Result<bool> {
+        let mut prev_hash = None;
+        for height in 0..=meta.tip_height {
+            let (Some(header), Some(block)) = (self.get_header(height)?, self.get_block(height)?)
+            else {
+                return Ok(false);
+            };
+            if block.header != header || (height > 0 && header.prev_block_hash != prev_hash) {
+                return Ok(false);
+            }
+            prev_hash = Some(BlockHasher.hash(&header)?);
+        }
+        Ok(prev_hash == Some(meta.tip_hash))
+    }
+}
+
+// The bytes of the files of the store at path
+fn store_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for path in [
+        path.to_path_buf(),
+        FileStore::blocks_path(path),
+        FileStore::meta_path(path),
+        with_extension(&FileStore::meta_path(path), ".tmp"),
+    ] {
+        if path.exists() {
+            size += fs::metadata(path)?.len();
+        }
+    }
+    Ok(size)
+}
+
+fn read_meta(path: &Path) -> Result<Option<ChainMeta>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl BlockFile {
+    // Finds the offsets of the blocks in file, the bytes after the last complete block are dropped
+    fn open(file: File) -> Result<Self> {
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut offsets = vec![];
+        let mut end = 0;
+        while end < len {
+            // the limit keeps a torn length prefix from allocating more than the file holds
+            let options = bincode::options()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(len - end);
+            if options.deserialize_from::<_, Block>(&mut reader).is_err() {
+                break;
+            }
+            offsets.push(end);
+            end = reader.stream_position()?;
+        }
+        drop(reader);
+
+        file.set_len(end)?;
+        Ok(Self { file, offsets, end })
+    }
+}
+
+fn open_file(path: &Path, truncate: bool) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(truncate)
+        .open(path)?)
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(extension);
+    path.into()
+}
+
+impl Storage for FileStore {
+    fn put_header(&self, header: &Header) -> Result<()> {
+        let mut record = bincode::serialize(header)?;
+        record.resize(self.record_size as usize, 0);
+
+        let mut file = self.file.lock().unwrap();
+        let offset = header.height as u64 * self.record_size;
+        if offset > file.metadata()?.len() {
+            return Err(anyhow!(
+                "can't store header {}, the previous header is missing",
+                header.height
+            ));
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&record)?;
+        Ok(())
+    }
+
+    fn get_header(&self, height: u32) -> Result<Option<Header>> {
+        let mut record = vec![0; self.record_size as usize];
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(height as u64 * self.record_size))?;
+        match file.read_exact(&mut record) {
+            Ok(()) => Ok(Some(bincode::deserialize(&record)?)),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put_block(&self, block: &Block) -> Result<()> {
+        let record = bincode::serialize(block)?;
+
+        let mut blocks = self.blocks.lock().unwrap();
+        let height = block.header.height as usize;
+        if height > blocks.offsets.len() {
+            return Err(anyhow!(
+                "can't store block {height}, the previous block is missing"
+            ));
+        }
+        // a replaced block drops the blocks above it as well
+        let offset = blocks.offsets.get(height).copied().unwrap_or(blocks.end);
+        blocks.offsets.truncate(height);
+
+        let end = offset + record.len() as u64;
+        blocks.file.seek(SeekFrom::Start(offset))?;
+        blocks.file.write_all(&record)?;
+        blocks.file.set_len(end)?;
+        blocks.offsets.push(offset);
+        blocks.end = end;
         Ok(())
     }
 
-    fn get(&self) -> Result<()> {
+    fn get_block(&self, height: u32) -> Result<Option<Block>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let height = height as usize;
+        let Some(&offset) = blocks.offsets.get(height) else {
+            return Ok(None);
+        };
+        let end = blocks
+            .offsets
+            .get(height + 1)
+            .copied()
+            .unwrap_or(blocks.end);
+
+        let mut record = vec![0; (end - offset) as usize];
+        blocks.file.seek(SeekFrom::Start(offset))?;
+        blocks.file.read_exact(&mut record)?;
+        Ok(Some(bincode::deserialize(&record)?))
+    }
+
+    fn truncate(&self, height: u32) -> Result<()> {
+        let len = height as usize + 1;
+        let file = self.file.lock().unwrap();
+        if file.metadata()?.len() > len as u64 * self.record_size {
+            file.set_len(len as u64 * self.record_size)?;
+        }
+
+        let mut blocks = self.blocks.lock().unwrap();
+        if let Some(&end) = blocks.offsets.get(len) {
+            blocks.file.set_len(end)?;
+            blocks.offsets.truncate(len);
+            blocks.end = end;
+        }
         Ok(())
     }
+
+    // the blocks are replayed when the node restarts, see Blockchain::recover
+    fn prune_blocks(&self, _height: u32) -> Result<()> {
+        Ok(())
+    }
+
+    // The headers and blocks are synced first, a meta on disk never points at data that isn't
+    fn put_meta(&self, meta: &ChainMeta) -> Result<()> {
+        self.file.lock().unwrap().sync_data()?;
+        self.blocks.lock().unwrap().file.sync_data()?;
+
+        let tmp = with_extension(&self.meta_path, ".tmp");
+        let mut file = open_file(&tmp, true)?;
+        file.write_all(&bincode::serialize(meta)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.meta_path)?;
+        Ok(())
+    }
+
+    fn get_meta(&self) -> Result<Option<ChainMeta>> {
+        read_meta(&self.meta_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn blocks(n: u32) -> Result<Vec<Block>> {
+        let mut blocks = vec![random_block(0, Hash::default())?];
+        for i in 1..n {
+            let txx = (0..i).map(|_| random_tx()).collect();
+            blocks.push(next_block(blocks[i as usize - 1].header, txx)?);
+        }
+        Ok(blocks)
+    }
+
+    fn check_store(store: &dyn Storage) -> Result<()> {
+        let blocks = blocks(5)?;
+        let headers: Vec<Header> = blocks.iter().map(|b| b.header).collect();
+        for header in &headers {
+            store.put_header(header)?;
+        }
+
+        for header in &headers {
+            assert_eq!(store.get_header(header.height)?, Some(*header));
+        }
+        assert_eq!(store.get_header(5)?, None);
+
+        // a gap is rejected
+        let mut header = headers[4];
+        header.height = 7;
+        assert!(store.put_header(&header).is_err());
+
+        for block in &blocks {
+            store.put_block(block)?;
+        }
+        for block in &blocks {
+            let stored = store.get_block(block.header.height)?.unwrap();
+            assert_eq!(stored.header, block.header);
+            assert_eq!(stored.transactions.len(), block.transactions.len());
+        }
+        assert!(store.get_block(5)?.is_none());
+
+        // replacing a block drops the ones above it
+        store.put_block(&blocks[1])?;
+        assert_eq!(store.get_block(1)?.unwrap().header, blocks[1].header);
+        assert!(store.get_block(2)?.is_none());
+        let mut block = blocks[4].clone();
+        block.header.height = 7;
+        assert!(store.put_block(&block).is_err());
+
+        assert_eq!(store.get_meta()?, None);
+        let meta = ChainMeta {
+            tip_height: 1,
+            tip_hash: Hash::random(),
+            state_height: 1,
+        };
+        store.put_meta(&meta)?;
+        assert_eq!(store.get_meta()?, Some(meta));
+
+        store.truncate(0)?;
+        assert!(store.get_header(1)?.is_none());
+        assert!(store.get_block(1)?.is_none());
+        assert_eq!(store.get_block(0)?.unwrap().header, blocks[0].header);
+
+        Ok(())
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("projectx-headers-{}", Hash::random()))
+    }
+
+    fn remove_files(path: &Path) -> Result<()> {
+        for path in [
+            FileStore::blocks_path(path),
+            FileStore::meta_path(path),
+            path.to_path_buf(),
+        ] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_store() -> Result<()> {
+        check_store(&MemoryStore::new())?;
+
+        let store = MemoryStore::new();
+        let blocks = blocks(4)?;
+        for block in &blocks {
+            store.put_header(&block.header)?;
+            store.put_block(block)?;
+        }
+        store.prune_blocks(2)?;
+        assert!(store.get_block(1)?.is_none());
+        assert_eq!(store.get_header(1)?, Some(blocks[1].header));
+        assert_eq!(store.get_block(2)?.unwrap().header, blocks[2].header);
+        // blocks above the pruned ones are still added
+        store.put_block(&next_block(blocks[3].header, vec![])?)?;
+        assert!(store.get_block(4)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store() -> Result<()> {
+        let path = temp_path();
+        let result = check_store(&FileStore::create(&path)?);
+        remove_files(&path)?;
+        result
+    }
+
+    #[test]
+    fn test_reopen_file_store() -> Result<()> {
+        let path = temp_path();
+        let result = (|| {
+            let blocks = blocks(3)?;
+            let store = FileStore::create(&path)?;
+            for block in &blocks {
+                store.put_header(&block.header)?;
+                store.put_block(block)?;
+            }
+            let meta = ChainMeta {
+                tip_height: 2,
+                tip_hash: Hash::random(),
+                state_height: 2,
+            };
+            store.put_meta(&meta)?;
+            drop(store);
+
+            // a crash while the next header and block were written
+            let block = encoded(&next_block(blocks[2].header, vec![random_tx()])?)?;
+            let mut file = OpenOptions::new()
+                .append(true)
+                .open(FileStore::blocks_path(&path))?;
+            file.write_all(&block[..block.len() - 1])?;
+            let mut file = OpenOptions::new().append(true).open(&path)?;
+            file.write_all(&[1, 2, 3])?;
+
+            let store = FileStore::open(&path)?;
+            assert_eq!(store.get_meta()?, Some(meta));
+            for block in &blocks {
+                let height = block.header.height;
+                assert_eq!(store.get_header(height)?, Some(block.header));
+                assert_eq!(store.get_block(height)?.unwrap().header, block.header);
+            }
+            assert!(store.get_block(3)?.is_none());
+
+            // the next block goes where the torn one was
+            let next = next_block(blocks[2].header, vec![])?;
+            store.put_header(&next.header)?;
+            store.put_block(&next)?;
+            drop(store);
+            let store = FileStore::open(&path)?;
+            assert_eq!(store.get_block(3)?.unwrap().header, next.header);
+
+            // a header whose block wasn't written is dropped
+            let mut header = next.header;
+            header.height = 4;
+            store.put_header(&header)?;
+            drop(store);
+            assert!(FileStore::open(&path)?.get_header(4)?.is_none());
+            Ok(())
+        })();
+        remove_files(&path)?;
+        result
+    }
+
+    #[test]
+    fn test_backup_and_compact() -> Result<()> {
+        let (path, dest) = (temp_path(), temp_path());
+        let result = (|| {
+            let blocks = blocks(4)?;
+            let store = FileStore::create(&path)?;
+            assert!(FileStore::backup(&path, &dest).is_err());
+            for block in &blocks {
+                store.put_header(&block.header)?;
+                store.put_block(block)?;
+            }
+            // the last block isn't committed
+            let meta = ChainMeta {
+                tip_height: 2,
+                tip_hash: BlockHasher.hash(&blocks[2].header)?,
+                state_height: 2,
+            };
+            store.put_meta(&meta)?;
+
+            assert_eq!(FileStore::backup(&path, &dest)?, meta);
+            let backup = FileStore::open(&dest)?;
+            assert_eq!(backup.get_meta()?, Some(meta));
+            assert_eq!(backup.get_block(2)?.unwrap().header, blocks[2].header);
+            assert!(backup.get_block(3)?.is_none());
+
+            // a block up to the tip that was replaced breaks the chain of the meta
+            store.put_block(&blocks[1])?;
+            let mut replaced = blocks[2].clone();
+            replaced.header.timestamp += 1;
+            store.put_header(&replaced.header)?;
+            store.put_block(&replaced)?;
+            assert!(FileStore::backup(&path, &dest).is_err());
+            store.put_header(&blocks[2].header)?;
+            store.put_block(&blocks[2])?;
+            store.put_header(&blocks[3].header)?;
+            store.put_block(&blocks[3])?;
+            drop(store);
+
+            assert!(FileStore::compact(&path)? > 0);
+            let store = FileStore::open(&path)?;
+            assert!(store.get_block(3)?.is_none());
+            assert_eq!(store.get_block(2)?.unwrap().header, blocks[2].header);
+            assert_eq!(FileStore::compact(&path)?, 0);
+            Ok(())
+        })();
+        remove_files(&path)?;
+        remove_files(&dest)?;
+        result
+    }
 }
@@ -1,21 +1,36 @@
+use std::{fmt, io::Write};
+
 use anyhow::{anyhow, Result};
-use log::debug;
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use bincode::Options;
 
 use crate::{
-    crypto::{PrivateKey, PublicKey, Signature},
-    types::Hash,
+    crypto::{verify_batch, PrivateKey, PublicKey, Signature},
+    types::{Address, Hash},
 };
 
 use super::{
     encoding::{Decoder, Encoder},
-    hasher::Hasher,
+    hasher::{Hasher, TxHasher},
+    Canonical, Evidence,
 };
 
+// size of the largest raw transaction a node decodes, see Transaction::from_raw
+pub const MAX_RAW_TX_SIZE: usize = 128 * 1024;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
+    // the code executed by the VM
     pub data: Vec<u8>,
+    // call data of a contract call, see abi.rs
+    pub input: Vec<u8>,
+    pub kind: TxKind,
+    // counts the transactions of the signer, a pending transaction is replaced by one with the same nonce
+    // and a higher fee, see TxPool::replace
+    pub nonce: u64,
+    pub fee: u64,
 
     pub from: Option<PublicKey>,
     pub signature: Option<Signature>,
@@ -25,19 +40,171 @@ pub struct Transaction {
     // first_seen is the time when the transaction was first seen locally
     #[serde(skip)]
     first_seen: u128,
+    // the order in which the TxPool took the transaction in, see TxPool::add
+    #[serde(skip)]
+    sequence: u64,
+}
+
+// Only Call transactions run code, the others change the native state of the signer
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub enum TxKind {
+    #[default]
+    Call,
+    // locks amount of the signer's balance, see State::stake
+    Stake {
+        amount: u64,
+    },
+    // unlocks the stake of the signer
+    Unstake,
+    // evidence of a misbehaving validator, slashes it
+    Evidence(Box<Evidence>),
+    // moves amount of the signer's balance to another account
+    Transfer {
+        to: Address,
+        amount: u64,
+    },
+}
+
+// What a node knows about a transaction, e.g. for wallets polling their submissions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxStatus {
+    Unknown,
+    // in the mem_pool, waiting to be included in a block
+    Pending,
+    // index is the position of the transaction in the block, timestamp the time of the block
+    InBlock {
+        height: u32,
+        index: u32,
+        timestamp: u128,
+    },
+    // removed from the mem_pool without being included in a block
+    Dropped {
+        reason: String,
+    },
+}
+
+// Why a transaction wasn't admitted to the mem_pool. The code tells the submitter what to fix, it's stable while the
+// message may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectCode {
+    InvalidSignature = 1,
+    // the transaction is in a block of the chain already
+    AlreadyIncluded = 2,
+    // a replacement has to pay more than the pending transaction with its nonce
+    FeeTooLow = 3,
+    InsufficientBalance = 4,
+    NotStaked = 5,
+    Slashed = 6,
+    // the sender has as many transactions pending as it may, see SenderLimits
+    SenderLimit = 7,
+    // the sender has a transaction with the nonce in a block already
+    NonceTooLow = 8,
+    // a raw transaction that can't be decoded, see Transaction::from_raw
+    Malformed = 9,
+}
+
+impl RejectCode {
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+}
+
+// The error of a rejected transaction, anyhow errors carrying it can be downcast to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxRejection {
+    pub code: RejectCode,
+    pub message: String,
 }
 
+impl TxRejection {
+    pub fn new(code: RejectCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for TxRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code.as_u16())
+    }
+}
+
+impl std::error::Error for TxRejection {}
+
 impl Transaction {
     pub fn new(data: Vec<u8>) -> Self {
         Self {
             data,
+            input: vec![],
+            kind: TxKind::Call,
+            nonce: 0,
+            fee: 0,
             from: None,
             signature: None,
             hash: None,
             first_seen: 0,
+            sequence: 0,
         }
     }
 
+    // A transaction that runs the given code with input as call data
+    pub fn new_call(data: Vec<u8>, input: Vec<u8>) -> Self {
+        Self {
+            input,
+            ..Self::new(data)
+        }
+    }
+
+    // A transaction of the given kind without code
+    pub fn new_kind(kind: TxKind) -> Self {
+        Self {
+            kind,
+            ..Self::new(vec![])
+        }
+    }
+
+    // A transaction submitting evidence, see evidence.rs
+    pub fn new_evidence(evidence: Evidence) -> Self {
+        Self::new_kind(TxKind::Evidence(Box::new(evidence)))
+    }
+
+    pub fn evidence(&self) -> Option<&Evidence> {
+        match &self.kind {
+            TxKind::Evidence(evidence) => Some(evidence),
+            _ => None,
+        }
+    }
+
+    // The bytes covered by the signature and the hash. Transactions without input only cover
+    // their code, the input is length prefixed so bytes can't be moved between code and input.
+    // The other kinds cover their tagged kind. All of them end with the nonce and the fee.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write_signing_bytes(&mut bytes)
+            .expect("writing to a Vec doesn't fail");
+        bytes
+    }
+
+    // Writes the signing bytes piece by piece, the payload isn't copied into a buffer first
+    pub fn write_signing_bytes(&self, w: &mut impl Write) -> Result<()> {
+        if !matches!(self.kind, TxKind::Call) {
+            w.write_all(b"kind")?;
+            self.kind.write_canonical(w)?;
+        } else {
+            w.write_all(&self.data)?;
+            if !self.input.is_empty() {
+                w.write_all(&self.input)?;
+                w.write_all(&(self.input.len() as u32).to_be_bytes())?;
+            }
+        }
+
+        w.write_all(&self.nonce.to_be_bytes())?;
+        w.write_all(&self.fee.to_be_bytes())?;
+        Ok(())
+    }
+
     pub fn set_first_seen(&mut self, first_seen: u128) {
         self.first_seen = first_seen;
     }
@@ -46,6 +213,14 @@ impl Transaction {
         self.first_seen
     }
 
+    pub fn set_sequence(&mut self, sequence: u64) {
+        self.sequence = sequence;
+    }
+
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
     pub fn calculate_and_cache_hash(&mut self, hasher: Box<dyn Hasher<Transaction>>) -> Result<()> {
         if self.hash.is_none() {
             self.hash = Some(hasher.hash(self)?);
@@ -63,7 +238,7 @@ impl Transaction {
     }
 
     pub fn sign(&mut self, private_key: &PrivateKey) {
-        let data = self.data.clone();
+        let data = self.signing_bytes();
         self.from = Some(private_key.public_key());
         self.signature = Some(private_key.sign(&data));
     }
@@ -79,34 +254,95 @@ impl Transaction {
             .as_ref()
             .ok_or_else(|| anyhow!("from has no signature"))?;
 
-        if !sig.verify(&self.data, pub_key) {
+        if !sig.verify(&self.signing_bytes(), pub_key) {
             return Err(anyhow!("transaction has invalid signature"));
         }
 
+        if let Some(evidence) = self.evidence() {
+            evidence.verify()?;
+        }
+
         Ok(())
     }
 
-    pub fn encode(&self, enc: &mut dyn Encoder<Transaction>) -> Result<()> {
-        enc.encode(self)
+    // Verifies the transactions like verify, their signatures in one batch
+    pub fn verify_batch(txx: &[&Transaction]) -> Result<()> {
+        let signing_bytes: Vec<Vec<u8>> = txx.iter().map(|tx| tx.signing_bytes()).collect();
+        let mut batch = Vec::with_capacity(txx.len());
+        for (tx, bytes) in txx.iter().zip(&signing_bytes) {
+            match (&tx.signature, &tx.from) {
+                (Some(signature), Some(from)) => batch.push((bytes.as_slice(), signature, from)),
+                // fails with the reason
+                _ => tx.verify()?,
+            }
+            if let Some(evidence) = tx.evidence() {
+                evidence.verify()?;
+            }
+        }
+
+        if verify_batch(&batch) {
+            return Ok(());
+        }
+        // the batch doesn't tell which transaction is invalid
+        for tx in txx {
+            tx.verify()?;
+        }
+        Ok(())
     }
 
-    pub fn decode(&mut self, dec: &mut dyn Decoder<Transaction>) -> Result<()> {
-        dec.decode(self)
+    // The transaction as it's sent between nodes. Clients can build and sign transactions offline and submit
+    // the raw bytes to any node, see from_raw.
+    pub fn to_raw(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
     }
 
-    pub fn random_with_signature() -> Transaction {
-        let private_key = PrivateKey::generate();
+    // Decodes a raw transaction and caches its hash. Fails with a Malformed TxRejection if raw has more than
+    // MAX_RAW_TX_SIZE bytes, isn't a transaction or has bytes left over. The signature isn't checked.
+    pub fn from_raw(raw: &[u8]) -> Result<Self> {
+        let malformed = |message: String| TxRejection::new(RejectCode::Malformed, message);
+        if raw.len() > MAX_RAW_TX_SIZE {
+            return Err(malformed(format!(
+                "the raw transaction has {} bytes, at most {MAX_RAW_TX_SIZE} are allowed",
+                raw.len()
+            ))
+            .into());
+        }
+        let mut tx: Transaction = bincode::options()
+            .with_fixint_encoding()
+            .with_limit(MAX_RAW_TX_SIZE as u64)
+            .deserialize(raw)
+            .map_err(|err| malformed(format!("invalid raw transaction: {err}")))?;
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        Ok(tx)
+    }
 
-        let mut tx = Transaction {
-            data: thread_rng().gen::<[u8; 32]>().to_vec(),
-            from: None,
-            signature: None,
-            hash: None,
-            first_seen: std::time::Instant::now().elapsed().as_nanos(),
-        };
+    // The raw transaction as 0x followed by its hex digits
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(format!("0x{}", hex::encode(self.to_raw()?)))
+    }
 
-        tx.sign(&private_key);
-        tx
+    // Decodes the hex form of to_hex, with or without 0x
+    pub fn from_hex(s: &str) -> Result<Self> {
+        let digits = s.trim().trim_start_matches("0x");
+        if digits.len() > 2 * MAX_RAW_TX_SIZE {
+            return Err(TxRejection::new(
+                RejectCode::Malformed,
+                format!("the raw transaction has more than {MAX_RAW_TX_SIZE} bytes"),
+            )
+            .into());
+        }
+        let raw = hex::decode(digits).map_err(|err| {
+            TxRejection::new(RejectCode::Malformed, format!("invalid hex: {err}"))
+        })?;
+        Self::from_raw(&raw)
+    }
+
+    pub fn encode(&self, enc: &mut dyn Encoder<Transaction>) -> Result<()> {
+        enc.encode(self)
+    }
+
+    pub fn decode(&mut self, dec: &mut dyn Decoder<Transaction>) -> Result<()> {
+        dec.decode(self)
     }
 }
 
@@ -114,10 +350,14 @@ impl Transaction {
 mod tests {
     use std::io::Cursor;
 
-    use crate::core::{BincodeDecoder, BincodeEncoder};
+    use crate::{
+        core::{BincodeDecoder, BincodeEncoder},
+        test_utils::*,
+    };
 
     use super::*;
     use anyhow::Result;
+    use proptest::prelude::*;
 
     #[test]
     fn test_sign_transaction() {
@@ -142,9 +382,122 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_signature_covers_input() -> Result<()> {
+        let mut tx = Transaction::new_call(vec![1, 2, 3], vec![4, 5]);
+        tx.sign(&PrivateKey::generate());
+        tx.verify()?;
+
+        let mut moved = tx.clone();
+        moved.data = vec![1, 2, 3, 4];
+        moved.input = vec![5];
+        assert!(moved.verify().is_err());
+
+        tx.input = vec![4, 6];
+        assert!(tx.verify().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_transaction() -> Result<()> {
+        let mut tx = Transaction::new_call(vec![1, 2, 3], vec![4, 5]);
+        tx.nonce = 7;
+        tx.sign(&PrivateKey::generate());
+
+        let decoded = Transaction::from_hex(&tx.to_hex()?)?;
+        decoded.verify()?;
+        assert_eq!(decoded.to_raw()?, tx.to_raw()?);
+        assert!(decoded.has_cached_hash());
+        assert!(tx.to_hex()?.starts_with("0x"));
+        assert_eq!(
+            Transaction::from_hex(&tx.to_hex()?[2..])?.hash(),
+            decoded.hash()
+        );
+
+        let rejected = |result: Result<Transaction>| {
+            result
+                .unwrap_err()
+                .downcast_ref::<TxRejection>()
+                .map(|rejection| rejection.code)
+        };
+        let mut raw = tx.to_raw()?;
+        assert_eq!(
+            rejected(Transaction::from_raw(&raw[..raw.len() - 1])),
+            Some(RejectCode::Malformed)
+        );
+        raw.push(0);
+        assert_eq!(
+            rejected(Transaction::from_raw(&raw)),
+            Some(RejectCode::Malformed)
+        );
+        assert_eq!(
+            rejected(Transaction::from_raw(&vec![0; MAX_RAW_TX_SIZE + 1])),
+            Some(RejectCode::Malformed)
+        );
+        assert_eq!(
+            rejected(Transaction::from_hex("0x0g")),
+            Some(RejectCode::Malformed)
+        );
+
+        // a length prefix can't make the decoder allocate more than the limit
+        let big = Transaction::new(vec![0; MAX_RAW_TX_SIZE]);
+        assert_eq!(
+            rejected(Transaction::from_raw(&big.to_raw()?[..1024])),
+            Some(RejectCode::Malformed)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signature_covers_nonce_and_fee() -> Result<()> {
+        let mut tx = Transaction::new(vec![1, 2, 3]);
+        tx.nonce = 4;
+        tx.fee = 10;
+        tx.sign(&PrivateKey::generate());
+        tx.verify()?;
+
+        let mut higher_fee = tx.clone();
+        higher_fee.fee = 11;
+        assert!(higher_fee.verify().is_err());
+
+        tx.nonce = 5;
+        assert!(tx.verify().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evidence_transaction() -> Result<()> {
+        let offender = PrivateKey::generate();
+        let mut tx = Transaction::new_evidence(double_sign_evidence(&offender)?);
+        tx.sign(&PrivateKey::generate());
+        tx.verify()?;
+
+        // the signature covers the evidence
+        let mut other = tx.clone();
+        other.kind = TxKind::Evidence(Box::new(double_sign_evidence(&offender)?));
+        assert!(other.verify().is_err());
+
+        // evidence that doesn't prove anything is rejected
+        let Some(Evidence::DoubleSign { first, .. }) = tx.evidence().cloned() else {
+            unreachable!()
+        };
+        let mut tx = Transaction::new_evidence(Evidence::DoubleSign {
+            validator: offender.public_key(),
+            first,
+            second: first,
+        });
+        tx.sign(&PrivateKey::generate());
+        assert!(tx.verify().is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_encode_decode() -> Result<()> {
-        let tx = Transaction::random_with_signature();
+        let tx = random_tx();
 
         let mut buf: Vec<u8> = vec![];
         let mut enc = BincodeEncoder::new(&mut buf);
@@ -158,4 +511,21 @@ mod tests {
 
         Ok(())
     }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn prop_tx_round_trip(tx in arb_tx()) {
+            let decoded = round_trip(&tx, Transaction::new(vec![])).unwrap();
+            prop_assert_eq!(&decoded.data, &tx.data);
+            prop_assert_eq!(&decoded.input, &tx.input);
+            prop_assert!(decoded.verify().is_ok());
+        }
+
+        #[test]
+        fn prop_invalid_tx(tx in arb_invalid_tx()) {
+            prop_assert!(tx.verify().is_err());
+        }
+    }
 }
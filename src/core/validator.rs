@@ -1,22 +1,60 @@
-use crate::core::block::Block;
+use std::collections::HashMap;
+
+use crate::{core::block::Block, crypto::PublicKey, types::Address};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 
-use super::{
-    blockchain::Blockchain,
-    hasher::{BlockHasher, Hasher},
-};
+use super::{blockchain::Blockchain, hasher::BlockHasher, ValidatorSet};
 
 #[async_trait]
 pub trait Validator: Send + Sync {
     async fn validate_block(&self, bc: &Blockchain, block: &mut Block) -> Result<()>;
+
+    // Checks that the blocks above fork_height may be replaced by the blocks of another branch
+    fn validate_reorg(&self, bc: &Blockchain, fork_height: u32) -> Result<()> {
+        match bc.checkpoints().final_height() {
+            Some(height) if fork_height < height => Err(anyhow!(
+                "can't reorganize from height {fork_height}, blocks up to {height} are final"
+            )),
+            _ => Ok(()),
+        }
+    }
 }
 
-pub struct BlockValidator {}
+pub struct BlockValidator {
+    // without a committee a block signed by any single validator is accepted
+    committee: Option<Committee>,
+}
+
+enum Committee {
+    Fixed(ValidatorSet),
+    // the validators that staked in the state of the chain
+    Staked,
+}
+
+impl Default for BlockValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl BlockValidator {
     pub fn new() -> Self {
-        BlockValidator {}
+        BlockValidator { committee: None }
+    }
+
+    // Requires the signatures of at least 2/3 of the set on every block
+    pub fn with_validator_set(validator_set: ValidatorSet) -> Self {
+        BlockValidator {
+            committee: Some(Committee::Fixed(validator_set)),
+        }
+    }
+
+    // Requires the signatures of at least 2/3 of the staked validators, see Blockchain::validator_set
+    pub fn with_staked_validators() -> Self {
+        BlockValidator {
+            committee: Some(Committee::Staked),
+        }
     }
 }
 
@@ -40,12 +78,11 @@ impl Validator for BlockValidator {
                 "our_height: {}, Block with height {} and hash {} too high!",
                 bc_height,
                 block_height,
-                b.hash(Box::new(BlockHasher))
+                b.hash(Box::new(BlockHasher))?
             ));
         }
 
-        let header = bc.get_header(b.header.height - 1).await?;
-        let hash = BlockHasher {}.hash(&header)?;
+        let hash = bc.get_prev_block_hash(block_height).await?;
 
         match b.header.prev_block_hash {
             Some(prev_block_hash) => {
@@ -61,7 +98,320 @@ impl Validator for BlockValidator {
             }
         };
 
-        b.verify()?;
+        let epoch = bc.epoch_of(block_height);
+        if b.header.epoch != epoch {
+            return Err(anyhow!(
+                "block with height {block_height} is in epoch {}, expected {epoch}",
+                b.header.epoch
+            ));
+        }
+
+        let version = b.header.version()?;
+        let expected = bc.header_version_at(block_height);
+        if version != expected {
+            return Err(anyhow!(
+                "block with height {block_height} has header version {version}, expected {expected}"
+            ));
+        }
+
+        // a transaction is applied once, it can't be in the block twice or again after an earlier block
+        for hash in b.unique_tx_hashes()? {
+            if bc.is_included(&hash) {
+                return Err(anyhow!("transaction {hash} is already on the chain"));
+            }
+        }
+
+        // the transactions of a sender use the nonces the chain expects from it in order, without gaps
+        let mut next_nonces: HashMap<Address, u64> = HashMap::new();
+        for tx in &b.transactions {
+            let Some(from) = &tx.from else {
+                continue;
+            };
+            let sender = from.address();
+            let next = next_nonces
+                .entry(sender)
+                .or_insert_with(|| bc.state().nonce(&sender));
+            if tx.nonce != *next {
+                return Err(anyhow!(
+                    "transaction of {sender} has the nonce {}, expected {next}",
+                    tx.nonce
+                ));
+            }
+            *next += 1;
+        }
+
+        let checkpoints = bc.checkpoints();
+        if let Some(hash) = checkpoints.get(block_height) {
+            let block_hash = b.hash(Box::new(BlockHasher))?;
+            if block_hash != hash {
+                return Err(anyhow!(
+                    "block {block_hash} conflicts with the checkpoint {hash} at height {block_height}"
+                ));
+            }
+        }
+
+        // Blocks up to a checkpoint are vouched for by the hashes linking them to it, skipping
+        // their signatures speeds up the sync. A forged block stops the sync at the checkpoint.
+        if checkpoints.is_final(block_height) {
+            b.verify_data_hash()?;
+        } else {
+            b.verify_cached(bc.sig_cache())?;
+            if let Some(proposer) = b.validator().filter(|v| bc.is_slashed(v)) {
+                return Err(anyhow!(
+                    "block proposed by the slashed validator {}",
+                    proposer.address()
+                ));
+            }
+            let active = match &self.committee {
+                None => None,
+                // slashed validators are no longer part of the set
+                Some(Committee::Fixed(set)) => Some(ValidatorSet::new(
+                    set.validators()
+                        .iter()
+                        .filter(|v| !bc.is_slashed(v))
+                        .copied()
+                        .collect(),
+                )),
+                Some(Committee::Staked) => Some(bc.validator_set()),
+            };
+            if let Some(active) = active {
+                let signers: Vec<PublicKey> = b
+                    .signers()
+                    .into_iter()
+                    .filter(|v| !bc.is_slashed(v))
+                    .collect();
+                active.check_quorum(&signers)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Checkpoints, Hasher, Transaction},
+        crypto::PrivateKey,
+        test_utils::*,
+        types::Hash,
+    };
+    use proptest::prelude::*;
+
+    // a chain of height 1 and the block following its head
+    fn chain_and_next_block(txx: Vec<Transaction>) -> Result<(Blockchain, Block)> {
+        let bc = block_on(chain(1))?;
+        let head = block_on(bc.get_header(1))?;
+        Ok((bc, next_block(head, txx)?))
+    }
+
+    fn validate(bc: &Blockchain, b: &mut Block) -> Result<()> {
+        block_on(BlockValidator::new().validate_block(bc, b))
+    }
+
+    proptest! {
+        // signing is slow in debug builds
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn prop_accepts_next_block(txx in proptest::collection::vec(arb_tx(), 0..4)) {
+            let (bc, mut b) = chain_and_next_block(txx).unwrap();
+            prop_assert!(validate(&bc, &mut b).is_ok());
+        }
+
+        #[test]
+        fn prop_rejects_invalid_transactions(tx in arb_invalid_tx()) {
+            let (bc, mut b) = chain_and_next_block(vec![tx]).unwrap();
+            prop_assert!(validate(&bc, &mut b).is_err());
+        }
+
+        #[test]
+        fn prop_rejects_blocks_not_extending_the_head(mut b in arb_block()) {
+            let bc = block_on(chain(1)).unwrap();
+            prop_assert!(validate(&bc, &mut b).is_err());
+        }
+    }
+
+    #[test]
+    fn test_checkpoints() -> Result<()> {
+        let (mut bc, mut b) = chain_and_next_block(vec![])?;
+        let hash = BlockHasher.hash(&b.header)?;
+
+        bc.set_checkpoints(Checkpoints::parse(&format!("2 {}", Hash::random()))?);
+        assert!(validate(&bc, &mut b.clone()).is_err());
+
+        // up to the checkpoint the signature isn't checked, the data hash is
+        let mut unsigned = Block::new(b.header, vec![]);
+        bc.set_checkpoints(Checkpoints::parse(&format!("2 {hash}"))?);
+        assert!(validate(&bc, &mut unsigned).is_ok());
+        unsigned.header.data_hash = Hash::random();
+        assert!(validate(&bc, &mut unsigned).is_err());
+
+        bc.set_checkpoints(Checkpoints::parse(&format!("3 {}", Hash::random()))?);
+        assert!(validate(&bc, &mut Block::new(b.header, vec![])).is_ok());
+        assert!(validate(&bc, &mut b).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_version() -> Result<()> {
+        let (bc, b) = chain_and_next_block(vec![])?;
+        assert_eq!(b.header.version()?, bc.header_version_at(2));
+
+        let key = PrivateKey::generate();
+        let mut unknown = b.clone();
+        unknown.header.version = 2;
+        unknown.sign(&key)?;
+        let err = validate(&bc, &mut unknown).unwrap_err();
+        assert!(err.to_string().contains("unknown header version"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_transactions() -> Result<()> {
+        let tx = signed_tx(vec![0x01])?;
+        let (mut bc, mut b) = chain_and_next_block(vec![tx.clone(), tx.clone()])?;
+        let err = validate(&bc, &mut b).unwrap_err();
+        assert!(err.to_string().contains("twice"));
+
+        // mined once, it can't be mined again
+        let mut b = next_block(block_on(bc.get_header(1))?, vec![tx.clone()])?;
+        block_on(bc.add_block(&mut b))?;
+        let mut replay = next_block(b.header, vec![tx])?;
+        let err = validate(&bc, &mut replay).unwrap_err();
+        assert!(err.to_string().contains("already on the chain"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonce_order() -> Result<()> {
+        let key = PrivateKey::generate();
+        let txx = |nonces: &[u64]| -> Vec<Transaction> {
+            nonces
+                .iter()
+                .map(|&nonce| {
+                    let mut tx = Transaction::new(vec![0x01]);
+                    tx.nonce = nonce;
+                    tx.sign(&key);
+                    tx
+                })
+                .collect()
+        };
+
+        let (bc, mut b) = chain_and_next_block(txx(&[0, 1]))?;
+        assert!(validate(&bc, &mut b).is_ok());
+        for nonces in [[1, 0], [0, 2], [1, 2]] {
+            let (bc, mut b) = chain_and_next_block(txx(&nonces))?;
+            let err = validate(&bc, &mut b).unwrap_err();
+            assert!(err.to_string().contains("expected"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validator_set_quorum() -> Result<()> {
+        let (bc, mut b) = chain_and_next_block(vec![])?;
+        let keys: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::generate()).collect();
+        let validator = BlockValidator::with_validator_set(ValidatorSet::new(
+            keys.iter().map(|key| key.public_key()).collect(),
+        ));
+
+        // next_block is signed by a key outside of the set
+        assert!(block_on(validator.validate_block(&bc, &mut b.clone())).is_err());
+
+        b.sign(&keys[0])?;
+        assert!(block_on(validator.validate_block(&bc, &mut b.clone())).is_err());
+        b.add_committee_signature(&keys[1])?;
+        block_on(validator.validate_block(&bc, &mut b))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slashed_validator() -> Result<()> {
+        let (mut bc, _) = chain_and_next_block(vec![])?;
+        let keys: Vec<PrivateKey> = (0..4).map(|_| PrivateKey::generate()).collect();
+        let validator = BlockValidator::with_validator_set(ValidatorSet::new(
+            keys.iter().map(|key| key.public_key()).collect(),
+        ));
+
+        // slash keys[3] with evidence in the next block
+        let mut tx = Transaction::new_evidence(double_sign_evidence(&keys[3])?);
+        tx.sign(&keys[0]);
+        let mut slashing = next_block(block_on(bc.get_header(1))?, vec![tx])?;
+        slashing.sign(&keys[0])?;
+        for key in &keys[1..3] {
+            slashing.add_committee_signature(key)?;
+        }
+        block_on(bc.add_block(&mut slashing))?;
+        assert!(bc.is_slashed(&keys[3].public_key()));
+
+        // the slashed validator's signature doesn't count, 2 of the 3 remaining ones are a quorum
+        let head = block_on(bc.get_header(2))?;
+        let mut b = next_block(head, vec![])?;
+        b.sign(&keys[0])?;
+        b.add_committee_signature(&keys[3])?;
+        assert!(block_on(validator.validate_block(&bc, &mut b.clone())).is_err());
+        b.add_committee_signature(&keys[1])?;
+        block_on(validator.validate_block(&bc, &mut b))?;
+
+        // its blocks are rejected
+        let mut b = next_block(head, vec![])?;
+        b.sign(&keys[3])?;
+        assert!(block_on(BlockValidator::new().validate_block(&bc, &mut b)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_staked_validators() -> Result<()> {
+        let (mut bc, mut b) = chain_and_next_block(vec![])?;
+        let keys: Vec<PrivateKey> = (0..2).map(|_| PrivateKey::generate()).collect();
+        bc.add_genesis_validators(&ValidatorSet::new(vec![keys[0].public_key()]));
+        let validator = BlockValidator::with_staked_validators();
+
+        assert!(block_on(validator.validate_block(&bc, &mut b.clone())).is_err());
+        b.sign(&keys[0])?;
+        block_on(validator.validate_block(&bc, &mut b.clone()))?;
+
+        // a second validator raises the quorum to 2
+        bc.add_genesis_validators(&ValidatorSet::new(vec![keys[1].public_key()]));
+        assert!(block_on(validator.validate_block(&bc, &mut b.clone())).is_err());
+        b.add_committee_signature(&keys[1])?;
+        block_on(validator.validate_block(&bc, &mut b))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch() -> Result<()> {
+        let (mut bc, mut b) = chain_and_next_block(vec![])?;
+        bc.set_epoch_length(2);
+
+        // height 2 starts the second epoch
+        assert!(validate(&bc, &mut b.clone()).is_err());
+        let key = PrivateKey::generate();
+        b.header.epoch = 1;
+        b.sign(&key)?;
+        validate(&bc, &mut b)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reorg_across_checkpoint() -> Result<()> {
+        let (mut bc, _) = chain_and_next_block(vec![])?;
+        let validator = BlockValidator::new();
+        assert!(validator.validate_reorg(&bc, 0).is_ok());
+
+        bc.set_checkpoints(Checkpoints::parse(&format!("1 {}", Hash::random()))?);
+        assert!(validator.validate_reorg(&bc, 0).is_err());
+        assert!(validator.validate_reorg(&bc, 1).is_ok());
 
         Ok(())
     }
@@ -0,0 +1,109 @@
+/*
+The validator set is the committee that signs blocks. A block is only valid with signatures from at least
+2/3 of its members, so no single validator can extend the chain on its own.
+*/
+
+use anyhow::{anyhow, Result};
+
+use crate::crypto::PublicKey;
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidatorSet {
+    validators: Vec<PublicKey>,
+}
+
+impl ValidatorSet {
+    pub fn new(validators: Vec<PublicKey>) -> Self {
+        let mut set = Self::default();
+        for validator in validators {
+            if !set.contains(&validator) {
+                set.validators.push(validator);
+            }
+        }
+        set
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.validators.contains(key)
+    }
+
+    pub fn validators(&self) -> &[PublicKey] {
+        &self.validators
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    // Number of signatures a block needs, at least 2/3 of the validators
+    pub fn quorum(&self) -> usize {
+        (2 * self.len()).div_ceil(3)
+    }
+
+    // Checks that signers are members of the set and that enough of them signed
+    pub fn check_quorum(&self, signers: &[PublicKey]) -> Result<()> {
+        let mut counted: Vec<&PublicKey> = vec![];
+        for signer in signers {
+            if !self.contains(signer) {
+                return Err(anyhow!(
+                    "block signed by {}, which is not a validator",
+                    signer.address()
+                ));
+            }
+            if !counted.contains(&signer) {
+                counted.push(signer);
+            }
+        }
+
+        if self.is_empty() || counted.len() < self.quorum() {
+            return Err(anyhow!(
+                "block has {} of the {} validator signatures it needs",
+                counted.len(),
+                self.quorum()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    fn keys(n: usize) -> Vec<PublicKey> {
+        (0..n)
+            .map(|_| PrivateKey::generate().public_key())
+            .collect()
+    }
+
+    #[test]
+    fn test_quorum() {
+        for (n, quorum) in [(1, 1), (2, 2), (3, 2), (4, 3), (6, 4), (7, 5)] {
+            assert_eq!(ValidatorSet::new(keys(n)).quorum(), quorum);
+        }
+    }
+
+    #[test]
+    fn test_check_quorum() {
+        let validators = keys(4);
+        let set = ValidatorSet::new(validators.clone());
+
+        assert!(set.check_quorum(&validators[..3]).is_ok());
+        assert!(set.check_quorum(&validators[..2]).is_err());
+        // a validator signing twice counts once
+        assert!(set
+            .check_quorum(&[validators[0], validators[1], validators[1]])
+            .is_err());
+        // outsiders are rejected
+        let mut signers = validators.clone();
+        signers.push(keys(1)[0]);
+        assert!(set.check_quorum(&signers).is_err());
+
+        assert!(ValidatorSet::default().check_quorum(&[]).is_err());
+    }
+}
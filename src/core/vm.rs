@@ -1,11 +1,13 @@
 //TODO: optimize this vm!
 
-use std::ops::{Add, Sub};
+use std::fmt::Display;
 
 use anyhow::{anyhow, Result};
-use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use super::State;
+use crate::types::{Address, Hash};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
@@ -15,15 +17,102 @@ pub enum Instruction {
     Pack = 0x0d,
     Sub = 0x0e,
     Store = 0x0f,
+    Dup = 0x10,
+    Swap = 0x11,
+    Drop = 0x12,
+    Over = 0x13,
+    Mod = 0x14,
+    Return = 0x15,
+    CallDataLoad = 0x16,
+    CallDataSize = 0x17,
+    Jump = 0x18,
+    JumpIf = 0x19,
+    Pick = 0x1a,
+    Eq = 0x1b,
+    Lt = 0x1c,
+    Gt = 0x1d,
+    MLoad = 0x1e,
+    MStore = 0x1f,
+    MSize = 0x20,
+    Caller = 0x21,
+    BlockHeight = 0x22,
+    Timestamp = 0x23,
+    TxHash = 0x24,
+    Balance = 0x25,
+    Transfer = 0x26,
     Get = 0xae,
     Mul = 0xea,
     Div = 0xfd,
 }
 
-impl TryFrom<u8> for Instruction {
-    type Error = anyhow::Error;
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        use Instruction::*;
 
-    fn try_from(value: u8) -> anyhow::Result<Self> {
+        match self {
+            PushInt => "push",
+            Add => "add",
+            PushByte => "pushb",
+            Pack => "pack",
+            Sub => "sub",
+            Store => "store",
+            Dup => "dup",
+            Swap => "swap",
+            Drop => "drop",
+            Over => "over",
+            Mod => "mod",
+            Return => "return",
+            CallDataLoad => "cdload",
+            CallDataSize => "cdsize",
+            Jump => "jump",
+            JumpIf => "jumpif",
+            Pick => "pick",
+            Eq => "eq",
+            Lt => "lt",
+            Gt => "gt",
+            MLoad => "mload",
+            MStore => "mstore",
+            MSize => "msize",
+            Caller => "caller",
+            BlockHeight => "height",
+            Timestamp => "timestamp",
+            TxHash => "txhash",
+            Balance => "balance",
+            Transfer => "transfer",
+            Get => "get",
+            Mul => "mul",
+            Div => "div",
+        }
+    }
+
+    // gas charged for executing the instruction, storage access is the most expensive. Memory accesses
+    // additionally pay MEMORY_WORD_GAS for every word the memory grows by.
+    pub fn gas_cost(&self) -> u64 {
+        use Instruction::*;
+
+        match self {
+            Transfer => 25,
+            Store => 20,
+            Get | Balance => 10,
+            MLoad | MStore => 3,
+            Pack => 2,
+            PushInt | PushByte | Add | Sub | Mul | Div | Mod | Dup | Swap | Drop | Over
+            | Return | CallDataLoad | CallDataSize | Jump | JumpIf | Pick | Eq | Lt | Gt
+            | MSize | Caller | BlockHeight | Timestamp | TxHash => 1,
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.mnemonic())
+    }
+}
+
+impl Instruction {
+    // Decodes an opcode, None for bytes that are no instructions. The VM skips those on every step,
+    // so this must not allocate an error.
+    pub fn from_byte(value: u8) -> Option<Self> {
         use Instruction::*;
         let v = match value {
             0x0a => PushInt,
@@ -32,29 +121,100 @@ impl TryFrom<u8> for Instruction {
             0x0d => Pack,
             0x0e => Sub,
             0x0f => Store,
+            0x10 => Dup,
+            0x11 => Swap,
+            0x12 => Drop,
+            0x13 => Over,
+            0x14 => Mod,
+            0x15 => Return,
+            0x16 => CallDataLoad,
+            0x17 => CallDataSize,
+            0x18 => Jump,
+            0x19 => JumpIf,
+            0x1a => Pick,
+            0x1b => Eq,
+            0x1c => Lt,
+            0x1d => Gt,
+            0x1e => MLoad,
+            0x1f => MStore,
+            0x20 => MSize,
+            0x21 => Caller,
+            0x22 => BlockHeight,
+            0x23 => Timestamp,
+            0x24 => TxHash,
+            0x25 => Balance,
+            0x26 => Transfer,
             0xae => Get,
             0xea => Mul,
             0xfd => Div,
-            _ => return Err(anyhow!("not a valid instruction")),
+            _ => return None,
         };
-        Ok(v)
+        Some(v)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl TryFrom<u8> for Instruction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> anyhow::Result<Self> {
+        Self::from_byte(value).ok_or_else(|| anyhow!("not a valid instruction"))
+    }
+}
+
+// default limit for the length of a byte array on the stack
+pub const DEFAULT_MAX_BYTES_LEN: usize = 1024;
+
+// default amount of gas a program may use, this bounds programs that jump backwards
+pub const DEFAULT_GAS_LIMIT: u64 = 1_000_000;
+
+// gas charged for every byte that is skipped because it's no opcode (operands of pushes included),
+// otherwise a loop that jumps back over a large junk region would cost a few gas per round but do
+// work that grows with the size of the junk
+pub const SKIP_GAS: u64 = 1;
+
+// the memory of an execution grows in words of this many bytes
+pub const MEMORY_WORD_SIZE: usize = 32;
+
+// gas charged for every word the memory grows by, this bounds the memory a program can use
+pub const MEMORY_WORD_GAS: u64 = 3;
+
+// What a program can learn about the transaction and the block it runs in, see the host instructions
+// Caller, BlockHeight, Timestamp and TxHash
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutionContext {
+    // the account of the contract, Transfer sends its tokens, see contract_address
+    pub contract: Address,
+    // the signer of the transaction, the zero address for unsigned ones
+    pub caller: Address,
+    pub block_height: u32,
+    // of the block header, in nanoseconds since the unix epoch
+    pub timestamp: u128,
+    pub tx_hash: Hash,
+}
+
+// The account of a contract is derived from its code, anyone can send tokens to it and only the code can
+// send them on
+pub fn contract_address(code: &[u8]) -> Address {
+    Address::from_digest(Sha256::digest(code).into())
+}
+
+// Tokens a contract sent with the Transfer instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueTransfer {
+    pub from: Address,
+    pub to: Address,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StackItem {
     Byte(u8),
-    Bytes4([u8; 4]),
-    Bytes8([u8; 8]),
-    Bytes16([u8; 16]),
-    Bytes32([u8; 32]),
-    Bytes64([u8; 64]),
-    //Bytes(Vec<u8>),
+    Bytes(Vec<u8>),
     Int(i32),
 }
 
 impl StackItem {
-    pub fn to_string(self) -> Result<String> {
+    pub fn to_string(&self) -> Result<String> {
         let bytes = self.to_bytes();
         let mut new_bytes = vec![];
         for b in bytes {
@@ -67,15 +227,11 @@ impl StackItem {
         Ok(s)
     }
 
-    pub fn to_bytes(self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            StackItem::Bytes4(b) => b.to_vec(),
-            StackItem::Bytes8(b) => b.to_vec(),
-            StackItem::Bytes16(b) => b.to_vec(),
-            StackItem::Bytes32(b) => b.to_vec(),
-            StackItem::Bytes64(b) => b.to_vec(),
-            StackItem::Int(b) => vec![b as u8],
-            StackItem::Byte(b) => vec![b],
+            StackItem::Bytes(b) => b.clone(),
+            StackItem::Int(b) => vec![*b as u8],
+            StackItem::Byte(b) => vec![*b],
         }
     }
 
@@ -100,22 +256,49 @@ impl StackItem {
         }
     }
 
-    pub fn add(self, rhs: Self) -> Result<Self> {
+    // Arithmetic is checked, overflows and zero divisors are errors that fail the transaction
+    fn checked(self, rhs: Self, op: &str, f: fn(i32, i32) -> Option<i32>) -> Result<Self> {
         let (a, b) = self.a_b_as_int(rhs)?;
-        Ok(StackItem::Int(a + b))
+        f(a, b)
+            .map(StackItem::Int)
+            .ok_or_else(|| anyhow!("{} of {} and {} overflows or divides by zero", op, a, b))
     }
 
-    pub fn sub(self, rhs: Self) -> Result<Self> {
-        let (a, b) = self.a_b_as_int(rhs)?;
-        Ok(StackItem::Int(a - b))
+    pub fn checked_add(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "add", i32::checked_add)
     }
-    pub fn mul(self, rhs: Self) -> Result<Self> {
-        let (a, b) = self.a_b_as_int(rhs)?;
-        Ok(StackItem::Int(a * b))
+
+    pub fn checked_sub(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "sub", i32::checked_sub)
     }
-    pub fn div(self, rhs: Self) -> Result<Self> {
-        let (a, b) = self.a_b_as_int(rhs)?;
-        Ok(StackItem::Int(a / b))
+    pub fn checked_mul(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "mul", i32::checked_mul)
+    }
+    pub fn checked_div(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "div", i32::checked_div)
+    }
+    pub fn checked_rem(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "mod", i32::checked_rem)
+    }
+
+    // Comparisons push 1 if they hold and 0 otherwise
+    pub fn eq_int(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "eq", |a, b| Some((a == b) as i32))
+    }
+    pub fn lt(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "lt", |a, b| Some((a < b) as i32))
+    }
+    pub fn gt(self, rhs: Self) -> Result<Self> {
+        self.checked(rhs, "gt", |a, b| Some((a > b) as i32))
+    }
+
+    // Any int or byte other than 0 is true
+    pub fn is_true(&self) -> Result<bool> {
+        match self {
+            StackItem::Byte(b) => Ok(*b != 0),
+            StackItem::Int(b) => Ok(*b != 0),
+            StackItem::Bytes(_) => Err(anyhow!("{:?} is not a condition", self)),
+        }
     }
 }
 
@@ -125,7 +308,7 @@ impl TryInto<usize> for StackItem {
     fn try_into(self) -> std::result::Result<usize, Self::Error> {
         match self {
             Self::Byte(b) => Ok(b as usize),
-            Self::Int(b) => Ok(b as usize),
+            Self::Int(b) => usize::try_from(b).map_err(|_| anyhow!("can't convert {} to usize", b)),
             _ => Err(anyhow!("can't convert {:?} to usize", self)),
         }
     }
@@ -151,98 +334,335 @@ impl Default for StackItem {
     }
 }
 
+// A stack holding at most N items, pushing onto a full stack drops the bottom item
 #[derive(Debug)]
 pub struct Stack<const N: usize> {
-    data: [StackItem; N],
-    sp: usize,
+    // the top of the stack is the last item
+    data: Vec<StackItem>,
+}
+
+impl<const N: usize> Default for Stack<N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<const N: usize> Stack<N> {
     pub fn new() -> Self {
         Self {
-            data: [StackItem::default(); N],
-            sp: 0,
+            data: Vec::with_capacity(N),
         }
     }
 
+    // Popping an empty stack returns the default item, use require() to check for underflows
     pub fn pop(&mut self) -> StackItem {
-        let val = self.data[0];
+        self.data.pop().unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
 
-        // TODO: optimize this?
-        for i in 1..N {
-            self.data[i - 1] = self.data[i];
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Errors if the stack holds less than n items
+    pub fn require(&self, n: usize) -> Result<()> {
+        if self.len() < n {
+            return Err(anyhow!(
+                "stack underflow: need {} items, have {}",
+                n,
+                self.len()
+            ));
         }
+        Ok(())
+    }
 
-        self.sp = self.sp.saturating_sub(1);
+    // the item at the given depth, 0 is the top of the stack
+    pub fn peek(&self, depth: usize) -> Result<StackItem> {
+        self.require(depth.saturating_add(1))?;
+        Ok(self.data[self.len() - 1 - depth].clone())
+    }
 
-        val
+    pub fn swap(&mut self) -> Result<()> {
+        self.require(2)?;
+        let len = self.len();
+        self.data.swap(len - 1, len - 2);
+        Ok(())
+    }
+
+    // the items on the stack, top first
+    pub fn items(&self) -> Vec<StackItem> {
+        self.data.iter().rev().cloned().collect()
     }
 
     pub fn push(&mut self, item: StackItem) {
-        let mut new_data = [StackItem::default(); N];
+        if self.data.len() == N {
+            self.data.remove(0);
+        }
+        self.data.push(item);
+    }
+}
+
+// State of the VM after executing an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub ip: usize,
+    pub instruction: Instruction,
+    // the stack after the instruction, top first
+    pub stack: Vec<StackItem>,
+    pub gas_used: u64,
+}
 
-        // The code below is equivalent to the line below it, but it's slower
-        // for i in 0..N - 1 {
-        //     new_data[i + 1] = self.data[i];
-        // }
-        new_data[1..((N - 1) + 1)].copy_from_slice(&self.data[..(N - 1)]);
+impl Display for TraceStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04x}  {:<6} gas={:<5} stack={:?}",
+            self.ip, self.instruction, self.gas_used, self.stack
+        )
+    }
+}
+
+// Result of running a program. The stack is not part of the outcome, a program passes its result
+// with the Return instruction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VmOutcome {
+    pub return_data: Vec<u8>,
+    pub gas_used: u64,
+    // set if the execution failed
+    pub error: Option<String>,
+    // the tokens to move once the program succeeded, empty if it failed
+    pub transfers: Vec<ValueTransfer>,
+}
 
-        new_data[0] = item;
-        self.data = new_data;
+impl VmOutcome {
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
 
-        self.sp += 1;
+    pub fn into_result(self) -> Result<Self> {
+        match &self.error {
+            Some(err) => Err(anyhow!("{}", err)),
+            None => Ok(self),
+        }
     }
 }
 
 pub struct VM<'a> {
     data: Vec<u8>,
+    // input of a contract call, read with CallDataLoad
+    calldata: Vec<u8>,
     ip: usize, // instruction pointer
     pub stack: Stack<128>,
     contract_state: &'a mut State,
+    context: ExecutionContext,
+    // scratch memory of the execution, starts empty and is zero filled when it grows
+    memory: Vec<u8>,
+    // maximum length of a byte array on the stack
+    max_bytes_len: usize,
+    gas_limit: u64,
+    gas_used: u64,
+    return_data: Vec<u8>,
+    // the Transfer instructions executed so far, the tokens only move after the run
+    transfers: Vec<ValueTransfer>,
+    // the sum of the amounts of transfers
+    sent: u64,
+    // set by the Return instruction
+    halted: bool,
+    // set by the jump instructions, the ip of the next step
+    jump_target: Option<usize>,
+    // the offsets a jump may target, see jump_dests
+    jump_dests: Vec<bool>,
+    // only recorded if tracing is enabled
+    trace: Option<Vec<TraceStep>>,
 }
 
 impl<'a> VM<'a> {
-    pub fn new(data: Vec<u8>, contract_state: &'a mut State) -> VM<'a> {
+    pub fn new(data: Vec<u8>, contract_state: &'a mut State, context: ExecutionContext) -> VM<'a> {
         Self {
-            data,
+            calldata: vec![],
             ip: 0,
             stack: Stack::new(),
             contract_state,
+            context,
+            memory: vec![],
+            max_bytes_len: DEFAULT_MAX_BYTES_LEN,
+            gas_limit: DEFAULT_GAS_LIMIT,
+            gas_used: 0,
+            return_data: vec![],
+            transfers: vec![],
+            sent: 0,
+            halted: false,
+            jump_target: None,
+            jump_dests: jump_dests(&data),
+            trace: None,
+            data,
         }
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        loop {
-            if let Ok(instr) = Instruction::try_from(self.data[self.ip]) {
-                self.exec(&instr)?;
+    pub fn set_calldata(&mut self, calldata: Vec<u8>) {
+        self.calldata = calldata;
+    }
+
+    pub fn set_max_bytes_len(&mut self, max_bytes_len: usize) {
+        self.max_bytes_len = max_bytes_len;
+    }
+
+    pub fn set_gas_limit(&mut self, gas_limit: u64) {
+        self.gas_limit = gas_limit;
+    }
+
+    // Records a TraceStep for every executed instruction
+    pub fn enable_tracing(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    pub fn trace(&self) -> &[TraceStep] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // Runs the program until it returns, fails or reaches the end of the code
+    pub fn run(&mut self) -> VmOutcome {
+        let error = loop {
+            match self.step() {
+                Ok(true) => continue,
+                Ok(false) => break None,
+                Err(err) => break Some(err.to_string()),
             }
+        };
 
-            self.ip += 1;
+        let transfers = match error {
+            None => self.transfers.clone(),
+            Some(_) => vec![],
+        };
+        VmOutcome {
+            return_data: self.return_data.clone(),
+            gas_used: self.gas_used,
+            error,
+            transfers,
+        }
+    }
 
-            if self.ip > self.data.len() - 1 {
-                break;
+    // Executes the byte at the instruction pointer (bytes that are no instructions are skipped for
+    // SKIP_GAS) and advances to the next one. Returns false once the program returned or the end of the
+    // code is reached.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.halted {
+            return Ok(false);
+        }
+        let Some(b) = self.data.get(self.ip) else {
+            return Ok(false);
+        };
+
+        let Some(instr) = Instruction::from_byte(*b) else {
+            let gas_used = self.gas_used.saturating_add(SKIP_GAS);
+            if gas_used > self.gas_limit {
+                return Err(anyhow!(
+                    "skipping byte at ip {} ran out of gas: limit is {}",
+                    self.ip,
+                    self.gas_limit
+                ));
             }
+            self.gas_used = gas_used;
+            self.ip += 1;
+            return Ok(self.ip < self.data.len());
+        };
+
+        let gas_cost = self
+            .memory_gas(&instr)
+            .map_err(|err| anyhow!("{} at ip {} failed: {}", instr, self.ip, err))?
+            .saturating_add(instr.gas_cost());
+        let gas_used = self.gas_used.saturating_add(gas_cost);
+        if gas_used > self.gas_limit {
+            return Err(anyhow!(
+                "{} at ip {} ran out of gas: limit is {}",
+                instr,
+                self.ip,
+                self.gas_limit
+            ));
         }
 
-        Ok(())
+        self.exec(&instr)
+            .map_err(|err| anyhow!("{} at ip {} failed: {}", instr, self.ip, err))?;
+        self.gas_used = gas_used;
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(TraceStep {
+                ip: self.ip,
+                instruction: instr,
+                stack: self.stack.items(),
+                gas_used: self.gas_used,
+            });
+        }
+
+        self.ip = self.jump_target.take().unwrap_or(self.ip + 1);
+
+        Ok(!self.halted && self.ip < self.data.len())
     }
 
-    fn get_bytes<const N: usize>(&mut self, n: usize) -> Result<[u8; N]> {
-        let mut b = [0_u8; N];
-        for i in 0..n {
-            b[i] = self.stack.pop().try_into()?;
+    // The gas for growing the memory to the end of the range the instruction accesses, read from the
+    // operands before they're popped
+    fn memory_gas(&self, instr: &Instruction) -> Result<u64> {
+        let (offset, len): (usize, usize) = match instr {
+            Instruction::MLoad => (
+                self.stack.peek(0)?.try_into()?,
+                self.stack.peek(1)?.try_into()?,
+            ),
+            Instruction::MStore => (
+                self.stack.peek(0)?.try_into()?,
+                self.stack.peek(1)?.to_bytes().len(),
+            ),
+            _ => return Ok(0),
+        };
+        let end = offset.saturating_add(len);
+        if end <= self.memory.len() {
+            return Ok(0);
         }
-        Ok(b)
+        let words = end.div_ceil(MEMORY_WORD_SIZE) - self.memory.len() / MEMORY_WORD_SIZE;
+        Ok((words as u64).saturating_mul(MEMORY_WORD_GAS))
     }
 
-    fn get_bytes_from_bytes<const N: usize>(bytes: Vec<u8>, n: usize) -> Result<[u8; N]> {
-        let mut b = [0_u8; N];
-        for i in 0..n {
-            if let Some(by) = bytes.get(i) {
-                b[i] = *by;
-            }
+    // Grows the memory to a whole number of words holding at least end bytes, the gas was charged
+    // by memory_gas
+    fn expand_memory(&mut self, end: usize) {
+        if end > self.memory.len() {
+            let words = end.div_ceil(MEMORY_WORD_SIZE);
+            self.memory.resize(words * MEMORY_WORD_SIZE, 0);
+        }
+    }
+
+    fn check_jump_target(&self, target: usize) -> Result<usize> {
+        if !self.jump_dests.get(target).copied().unwrap_or_default() {
+            return Err(anyhow!(
+                "can't jump to {}, it's no instruction boundary",
+                target
+            ));
         }
-        Ok(b)
+        Ok(target)
+    }
+
+    fn check_bytes_len(&self, n: usize) -> Result<()> {
+        if n > self.max_bytes_len {
+            return Err(anyhow!(
+                "can't put more than {} bytes into byte array on vm stack",
+                self.max_bytes_len
+            ));
+        }
+        Ok(())
     }
 
     pub fn exec(&mut self, instr: &Instruction) -> Result<()> {
@@ -251,53 +671,37 @@ impl<'a> VM<'a> {
         match instr {
             Get => {
                 let key = self.stack.pop();
-                let value = self.contract_state.get(&key.to_bytes())?;
-                let n = value.len();
-                let item = if n == 1 {
-                    StackItem::Byte(Self::get_bytes_from_bytes::<1>(value, n)?[0])
-                } else if n <= 4 {
-                    StackItem::Bytes4(Self::get_bytes_from_bytes(value, n)?)
-                } else if n <= 8 {
-                    StackItem::Bytes8(Self::get_bytes_from_bytes(value, n)?)
-                } else if n <= 16 {
-                    StackItem::Bytes16(Self::get_bytes_from_bytes(value, n)?)
-                } else if n <= 32 {
-                    StackItem::Bytes32(Self::get_bytes_from_bytes(value, n)?)
-                } else if n <= 64 {
-                    StackItem::Bytes64(Self::get_bytes_from_bytes(value, n)?)
-                } else {
-                    return Err(anyhow!(
-                        "can't put more than 64 bytes into byte array on vm stack"
-                    ));
+                let value = self
+                    .contract_state
+                    .get(&self.context.contract, &key.to_bytes())?;
+                self.check_bytes_len(value.len())?;
+
+                let item = match value.as_slice() {
+                    [b] => StackItem::Byte(*b),
+                    _ => StackItem::Bytes(value),
                 };
-
                 self.stack.push(item);
             }
             Store => {
                 let key = self.stack.pop();
                 let value = self.stack.pop();
 
-                self.contract_state.put(key.to_bytes(), value.to_bytes());
+                self.contract_state.put(
+                    &self.context.contract,
+                    &key.to_bytes(),
+                    &value.to_bytes(),
+                )?;
             }
 
             Pack => {
                 let n: usize = self.stack.pop().try_into()?;
-                let item = if n <= 4 {
-                    StackItem::Bytes4(self.get_bytes(n)?)
-                } else if n <= 8 {
-                    StackItem::Bytes8(self.get_bytes(n)?)
-                } else if n <= 16 {
-                    StackItem::Bytes16(self.get_bytes(n)?)
-                } else if n <= 32 {
-                    StackItem::Bytes32(self.get_bytes(n)?)
-                } else if n <= 64 {
-                    StackItem::Bytes64(self.get_bytes(n)?)
-                } else {
-                    return Err(anyhow!(
-                        "can't put more than 64 bytes into byte array on vm stack"
-                    ));
-                };
-                self.stack.push(item)
+                self.check_bytes_len(n)?;
+
+                let mut bytes = Vec::with_capacity(n);
+                for _ in 0..n {
+                    bytes.push(self.stack.pop().try_into()?);
+                }
+                self.stack.push(StackItem::Bytes(bytes))
             }
 
             // TODO: change vm data insturction array to accept int
@@ -309,28 +713,192 @@ impl<'a> VM<'a> {
                 let i = self.ip.saturating_sub(1);
                 self.stack.push(StackItem::Byte(self.data[i]));
             }
+            Dup => {
+                let a = self.stack.peek(0)?;
+                self.stack.push(a);
+            }
+            Swap => self.stack.swap()?,
+            Drop => {
+                self.stack.require(1)?;
+                self.stack.pop();
+            }
+            Over => {
+                let b = self.stack.peek(1)?;
+                self.stack.push(b);
+            }
             Add => {
                 let a = self.stack.pop();
                 let b = self.stack.pop();
-                let c = a.add(b)?;
+                let c = a.checked_add(b)?;
                 self.stack.push(c)
             }
             Sub => {
                 let a = self.stack.pop();
                 let b = self.stack.pop();
-                let c = a.sub(b)?;
+                let c = a.checked_sub(b)?;
                 self.stack.push(c)
             }
             Mul => {
                 let a = self.stack.pop();
                 let b = self.stack.pop();
-                let c = a.mul(b)?;
+                let c = a.checked_mul(b)?;
                 self.stack.push(c)
             }
             Div => {
                 let a = self.stack.pop();
                 let b = self.stack.pop();
-                let c = a.div(b)?;
+                let c = a.checked_div(b)?;
+                self.stack.push(c)
+            }
+            // pops the offset and the number of bytes to load
+            CallDataLoad => {
+                self.stack.require(2)?;
+                let offset: usize = self.stack.pop().try_into()?;
+                let n: usize = self.stack.pop().try_into()?;
+                self.check_bytes_len(n)?;
+
+                let bytes = offset
+                    .checked_add(n)
+                    .and_then(|end| self.calldata.get(offset..end))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "can't load {} bytes at offset {} from {} bytes of call data",
+                            n,
+                            offset,
+                            self.calldata.len()
+                        )
+                    })?;
+
+                let item = match bytes {
+                    [b] => StackItem::Byte(*b),
+                    _ => StackItem::Bytes(bytes.to_vec()),
+                };
+                self.stack.push(item);
+            }
+            CallDataSize => {
+                let len = i32::try_from(self.calldata.len())?;
+                self.stack.push(StackItem::Int(len));
+            }
+            // pops the offset and the number of bytes to load, memory that was never written reads as zeros
+            MLoad => {
+                self.stack.require(2)?;
+                let offset: usize = self.stack.pop().try_into()?;
+                let n: usize = self.stack.pop().try_into()?;
+                self.check_bytes_len(n)?;
+                self.expand_memory(offset + n);
+
+                let item = match &self.memory[offset..offset + n] {
+                    [b] => StackItem::Byte(*b),
+                    bytes => StackItem::Bytes(bytes.to_vec()),
+                };
+                self.stack.push(item);
+            }
+            // pops the offset and the value, whose bytes are written to the memory at the offset
+            MStore => {
+                self.stack.require(2)?;
+                let offset: usize = self.stack.pop().try_into()?;
+                let bytes = self.stack.pop().to_bytes();
+                self.expand_memory(offset + bytes.len());
+                self.memory[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            }
+            // pushes the size of the memory in bytes, always a multiple of the word size
+            MSize => {
+                let len = i32::try_from(self.memory.len())?;
+                self.stack.push(StackItem::Int(len));
+            }
+            // pushes the 20 bytes of the signer's address
+            Caller => {
+                let caller = self.context.caller.into_bytes().to_vec();
+                self.stack.push(StackItem::Bytes(caller));
+            }
+            BlockHeight => {
+                let height = i32::try_from(self.context.block_height)?;
+                self.stack.push(StackItem::Int(height));
+            }
+            // pushes the timestamp of the block in seconds, ints on the stack are too small for nanoseconds
+            Timestamp => {
+                let secs = i32::try_from(self.context.timestamp / 1_000_000_000)?;
+                self.stack.push(StackItem::Int(secs));
+            }
+            TxHash => {
+                let hash = self.context.tx_hash.into_bytes().to_vec();
+                self.stack.push(StackItem::Bytes(hash));
+            }
+            // pops an address and pushes its balance, it fails if the balance doesn't fit into an int
+            Balance => {
+                self.stack.require(1)?;
+                let address = Address::try_from_bytes(&self.stack.pop().to_bytes())?;
+                let balance = i32::try_from(self.contract_state.balance(&address))?;
+                self.stack.push(StackItem::Int(balance));
+            }
+            // pops an address and an amount and sends the amount from the contract account to the address.
+            // The balance has to cover all transfers of the run, the tokens move once the program succeeded,
+            // so the code never sees a half finished transfer.
+            Transfer => {
+                self.stack.require(2)?;
+                let to = Address::try_from_bytes(&self.stack.pop().to_bytes())?;
+                let amount: usize = self.stack.pop().try_into()?;
+                let amount = amount as u64;
+                if amount == 0 {
+                    return Err(anyhow!("can't transfer nothing"));
+                }
+
+                let from = self.context.contract;
+                let sent = self.sent;
+                let balance = self.contract_state.balance(&from);
+                if sent.saturating_add(amount) > balance {
+                    return Err(anyhow!(
+                        "contract {from} can't send {amount}, its balance is {balance} and it sent {sent} already"
+                    ));
+                }
+                self.sent = sent + amount;
+                self.transfers.push(ValueTransfer { from, to, amount });
+            }
+            // pops the target
+            Jump => {
+                self.stack.require(1)?;
+                let target = self.stack.pop().try_into()?;
+                self.jump_target = Some(self.check_jump_target(target)?);
+            }
+            // pops the target and the condition
+            JumpIf => {
+                self.stack.require(2)?;
+                let target: usize = self.stack.pop().try_into()?;
+                if self.stack.pop().is_true()? {
+                    self.jump_target = Some(self.check_jump_target(target)?);
+                }
+            }
+            // pops n and pushes a copy of the item at depth n
+            Pick => {
+                self.stack.require(1)?;
+                let n: usize = self.stack.pop().try_into()?;
+                let item = self.stack.peek(n)?;
+                self.stack.push(item);
+            }
+            Eq => {
+                let a = self.stack.pop();
+                let b = self.stack.pop();
+                self.stack.push(a.eq_int(b)?)
+            }
+            Lt => {
+                let a = self.stack.pop();
+                let b = self.stack.pop();
+                self.stack.push(a.lt(b)?)
+            }
+            Gt => {
+                let a = self.stack.pop();
+                let b = self.stack.pop();
+                self.stack.push(a.gt(b)?)
+            }
+            Return => {
+                self.stack.require(1)?;
+                self.return_data = self.stack.pop().to_bytes();
+                self.halted = true;
+            }
+            Mod => {
+                let a = self.stack.pop();
+                let b = self.stack.pop();
+                let c = a.checked_rem(b)?;
                 self.stack.push(c)
             }
         }
@@ -339,15 +907,36 @@ impl<'a> VM<'a> {
     }
 }
 
+// The offsets a program may jump to, computed once when it's loaded: opcodes, the operands of pushes
+// (the assembler puts labels there) and the end of the code. Jumping into junk or padding is rejected.
+fn jump_dests(code: &[u8]) -> Vec<bool> {
+    let mut dests: Vec<bool> = code
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let next = code.get(i + 1).copied().and_then(Instruction::from_byte);
+            Instruction::from_byte(*b).is_some()
+                || matches!(next, Some(Instruction::PushInt | Instruction::PushByte))
+        })
+        .collect();
+    dests.push(true);
+    dests
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::{assemble, Call};
 
     #[test]
     fn test_vm() -> Result<()> {
         let mut state = State::new();
-        let mut vm = VM::new(vec![0x02, 0x0a, 0x03, 0x0a, 0x0e], &mut state);
-        vm.run()?;
+        let mut vm = VM::new(
+            vec![0x02, 0x0a, 0x03, 0x0a, 0x0e],
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.run().into_result()?;
 
         assert_eq!(StackItem::Int(1), vm.stack.pop());
 
@@ -359,8 +948,8 @@ mod tests {
         let data = vec![0x4f, 0x0c, 0x4f, 0x0c, 0x46, 0x0c, 0x03, 0x0a, 0x0d];
 
         let mut state = State::new();
-        let mut vm = VM::new(data, &mut state);
-        vm.run()?;
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
 
         let result = vm.stack.pop();
         assert_eq!("FOO", result.to_string()?);
@@ -375,10 +964,10 @@ mod tests {
         ];
 
         let mut state = State::new();
-        let mut vm = VM::new(data, &mut state);
-        vm.run()?;
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
 
-        assert_eq!(state.get(&vec![70, 79, 79, 0])?, vec![5]);
+        assert_eq!(state.get(&Address::default(), b"FOO")?, vec![5]);
 
         Ok(())
     }
@@ -391,10 +980,10 @@ mod tests {
         ];
 
         let mut state = State::new();
-        let mut vm = VM::new(data, &mut state);
-        vm.run()?;
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
 
-        assert_eq!(state.get(&vec![70, 79, 79, 0])?, vec![5]);
+        assert_eq!(state.get(&Address::default(), b"FOO")?, vec![5]);
 
         Ok(())
     }
@@ -408,14 +997,128 @@ mod tests {
         data.extend(push_foo);
 
         let mut state = State::new();
-        let mut vm = VM::new(data, &mut state);
-        vm.run()?;
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
 
         let val = vm.stack.pop();
 
-        assert_eq!(5_u8, val.try_into()?);
+        assert_eq!(5, TryInto::<u8>::try_into(val)?);
+
+        //assert_eq!(state.get(&Address::default(), b"FOO")?, vec![5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_step_and_trace() -> Result<()> {
+        let mut state = State::new();
+        let mut vm = VM::new(
+            vec![0x02, 0x0a, 0x03, 0x0a, 0x0e],
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.enable_tracing();
+
+        assert!(vm.step()?);
+        assert!(vm.trace().is_empty());
+        assert!(vm.step()?);
+        assert_eq!(vm.ip(), 2);
+        assert_eq!(vm.stack.items(), vec![StackItem::Int(2)]);
+
+        vm.run().into_result()?;
+        assert!(!vm.step()?);
+
+        let trace = vm.trace();
+        assert_eq!(trace.len(), 3);
+        assert_eq!(
+            trace[1],
+            TraceStep {
+                ip: 3,
+                instruction: Instruction::PushInt,
+                stack: vec![StackItem::Int(3), StackItem::Int(2)],
+                gas_used: 2 * (SKIP_GAS + 1),
+            }
+        );
+        assert_eq!(trace[2].stack, vec![StackItem::Int(1)]);
+        assert_eq!(
+            trace[2].to_string(),
+            "0004  sub    gas=5     stack=[Int(1)]"
+        );
+        // the operands are skipped for SKIP_GAS each
+        assert_eq!(vm.gas_used(), 2 * SKIP_GAS + 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_empty_program() -> Result<()> {
+        let mut state = State::new();
+        let mut vm = VM::new(vec![], &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
+        assert_eq!(vm.gas_used(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_stack_ops() -> Result<()> {
+        let mut state = State::new();
 
-        //assert_eq!(state.get(&vec![70, 79, 79, 0])?, vec![5]);
+        // 2 3 over -> 2 3 2, swap -> 2 2 3, drop -> 2 2, dup -> 2 2 2
+        let data = vec![0x02, 0x0a, 0x03, 0x0a, 0x13, 0x11, 0x12, 0x10];
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
+        assert_eq!(vm.stack.items(), vec![StackItem::Int(2); 3]);
+
+        // sub subtracts the second item from the top one: 2 3 swap sub -> 2 - 3
+        let mut vm = VM::new(
+            vec![0x02, 0x0a, 0x03, 0x0a, 0x11, 0x0e],
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.run().into_result()?;
+        assert_eq!(vm.stack.items(), vec![StackItem::Int(-1)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_stack_underflow() {
+        let mut state = State::new();
+
+        for data in [
+            vec![0x10],
+            vec![0x12],
+            vec![0x02, 0x0a, 0x11],
+            vec![0x02, 0x0a, 0x13],
+        ] {
+            assert!(!VM::new(data, &mut state, ExecutionContext::default())
+                .run()
+                .is_success());
+        }
+    }
+
+    #[test]
+    fn test_vm_long_bytes() -> Result<()> {
+        let mut state = State::new();
+        let key = "A".repeat(100);
+
+        let code = assemble(&format!("push 7; store {key}; get {key}"))?;
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
+
+        assert_eq!(vm.stack.pop(), StackItem::Byte(7));
+        assert_eq!(state.get(&Address::default(), key.as_bytes())?, vec![7]);
+
+        let code = assemble(&format!("{} push 100; pack", "pushb 0x42;".repeat(100)))?;
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
+        assert_eq!(vm.stack.pop(), StackItem::Bytes(vec![0x42; 100]));
+
+        let code = assemble(&format!("get {key}"))?;
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        vm.set_max_bytes_len(64);
+        assert!(!vm.run().is_success());
 
         Ok(())
     }
@@ -424,24 +1127,367 @@ mod tests {
     fn test_vm_mul() -> Result<()> {
         let data = vec![0x02, 0x0c, 0x03, 0x0c, 0xea];
         let mut state = State::new();
-        let mut vm = VM::new(data, &mut state);
-        vm.run()?;
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
 
         let val = vm.stack.pop();
 
-        assert_eq!(6_u8, val.try_into()?);
+        assert_eq!(6, TryInto::<u8>::try_into(val)?);
         Ok(())
     }
     #[test]
     fn test_vm_div() -> Result<()> {
         let data = vec![0x03, 0x0c, 0x06, 0x0c, 0xfd];
         let mut state = State::new();
-        let mut vm = VM::new(data, &mut state);
-        vm.run()?;
+        let mut vm = VM::new(data, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
 
         let val = vm.stack.pop();
 
-        assert_eq!(2_u8, val.try_into()?);
+        assert_eq!(2, TryInto::<u8>::try_into(val)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_mod() -> Result<()> {
+        let mut state = State::new();
+        let mut vm = VM::new(
+            assemble("push 3; push 7; mod")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.run().into_result()?;
+
+        assert_eq!(vm.stack.pop(), StackItem::Int(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let max = StackItem::Int(i32::MAX);
+        let min = StackItem::Int(i32::MIN);
+        let zero = StackItem::Int(0);
+
+        assert!(max.clone().checked_add(StackItem::Int(1)).is_err());
+        assert!(min.clone().checked_sub(StackItem::Int(1)).is_err());
+        assert!(max.clone().checked_mul(StackItem::Int(2)).is_err());
+        assert!(min.clone().checked_div(StackItem::Int(-1)).is_err());
+        assert!(max.clone().checked_div(zero.clone()).is_err());
+        assert!(max.checked_rem(zero).is_err());
+        assert!(StackItem::Bytes(vec![1])
+            .checked_add(StackItem::Int(1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_vm_div_by_zero() -> Result<()> {
+        let mut state = State::new();
+
+        for code in ["push 0; push 6; div", "push 0; push 6; mod"] {
+            let mut vm = VM::new(assemble(code)?, &mut state, ExecutionContext::default());
+            assert!(!vm.run().is_success());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_return() -> Result<()> {
+        let mut state = State::new();
+
+        // execution stops at return, the store is never reached
+        let code = assemble("push 2; push 3; add; return; push 1; store FOO")?;
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        let outcome = vm.run();
+
+        assert_eq!(
+            outcome,
+            VmOutcome {
+                return_data: vec![5],
+                gas_used: 2 * SKIP_GAS + 4,
+                error: None,
+                transfers: vec![],
+            }
+        );
+        assert!(!vm.step()?);
+        assert!(state.get(&Address::default(), b"FOO").is_err());
+
+        let outcome = VM::new(
+            assemble("push 1; store FOO")?,
+            &mut state,
+            ExecutionContext::default(),
+        )
+        .run();
+        assert!(outcome.is_success());
+        assert!(outcome.return_data.is_empty());
+
+        let outcome = VM::new(assemble("return")?, &mut state, ExecutionContext::default()).run();
+        assert!(outcome.error.is_some());
+        assert_eq!(outcome.gas_used, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_calldata() -> Result<()> {
+        let mut state = State::new();
+        let call = Call::new(0x01).arg(vec![6]).arg(vec![2]);
+        let calldata = call.encode()?;
+
+        // arg 0 / arg 1
+        let code = assemble("push 1; push 4; cdload; push 1; push 2; cdload; div; return")?;
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        vm.set_calldata(calldata.clone());
+        assert_eq!(vm.run().into_result()?.return_data, vec![3]);
+
+        let mut vm = VM::new(
+            assemble("cdsize; return")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.set_calldata(calldata);
+        assert_eq!(vm.run().into_result()?.return_data, vec![5]);
+
+        let mut vm = VM::new(
+            assemble("push 2; push 4; cdload")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.set_calldata(vec![1, 2, 3, 4]);
+        assert!(vm.run().error.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_jumps() -> Result<()> {
+        let mut state = State::new();
+
+        // 1 < 2, so the jump skips the return of 7
+        let code =
+            assemble("push 2; push 1; lt; push @yes; jumpif; push 7; return; yes: push 9; return")?;
+        let outcome = VM::new(code, &mut state, ExecutionContext::default())
+            .run()
+            .into_result()?;
+        assert_eq!(outcome.return_data, vec![9]);
+
+        let code = assemble("push @end; jump; push 1; store FOO; end: push 3; return")?;
+        let outcome = VM::new(code, &mut state, ExecutionContext::default())
+            .run()
+            .into_result()?;
+        assert_eq!(outcome.return_data, vec![3]);
+        assert!(state.get(&Address::default(), b"FOO").is_err());
+
+        // targets must be instruction boundaries: 5 is junk and 10 past the end, 6 is the operand
+        // of the push and 9 the end
+        let code = vec![0x05, 0x0a, 0x18, 0x00, 0x00, 0x00, 0x03, 0x0a, 0x15];
+        let outcome = VM::new(code.clone(), &mut state, ExecutionContext::default()).run();
+        assert!(outcome.error.is_some());
+        for target in [6, 9] {
+            let mut code = code.clone();
+            code[0] = target;
+            let outcome = VM::new(code, &mut state, ExecutionContext::default()).run();
+            assert!(outcome.is_success());
+        }
+        let mut code = code.clone();
+        code[0] = 10;
+        assert!(VM::new(code, &mut state, ExecutionContext::default())
+            .run()
+            .error
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_gas_limit() -> Result<()> {
+        let mut state = State::new();
+
+        let mut vm = VM::new(
+            assemble("start: push @start; jump")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.set_gas_limit(100);
+        let outcome = vm.run();
+        assert!(outcome.error.is_some());
+        assert_eq!(outcome.gas_used, 100);
+
+        // negative depths and targets are rejected instead of wrapping around
+        let mut vm = VM::new(
+            assemble("push 0; push 1; sub; pick")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        assert!(vm.run().error.is_some());
+        let mut vm = VM::new(
+            assemble("push 0; push 1; sub; jump")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        assert!(vm.run().error.is_some());
+
+        assert!(VM::new(vec![], &mut state, ExecutionContext::default())
+            .run()
+            .is_success());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_memory() -> Result<()> {
+        let mut state = State::new();
+
+        // 7 at offset 40 grows the memory to two words, the bytes around it read as zeros
+        let code = assemble("push 7; push 40; mstore; push 3; push 39; mload; msize")?;
+        let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+        vm.run().into_result()?;
+        assert_eq!(
+            vm.stack.items(),
+            vec![StackItem::Int(64), StackItem::Bytes(vec![0, 7, 0])]
+        );
+        assert_eq!(vm.memory().len(), 2 * MEMORY_WORD_SIZE);
+        // push, push, mstore with two words, push, push, mload within the memory, msize and the
+        // skipped operands of the four pushes
+        assert_eq!(
+            vm.gas_used(),
+            2 + 3 + 2 * MEMORY_WORD_GAS + 2 + 3 + 1 + 4 * SKIP_GAS
+        );
+
+        let code = assemble(
+            "pushb 2; pushb 1; push 2; pack; push 0; mstore; push 1; push 1; mload; return",
+        )?;
+        let outcome = VM::new(code, &mut state, ExecutionContext::default())
+            .run()
+            .into_result()?;
+        assert_eq!(outcome.return_data, vec![2]);
+
+        // the expansion is paid before the memory grows
+        let mut vm = VM::new(
+            assemble("push 1; push 100; push 100; mul; mload")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.set_gas_limit(500);
+        assert!(vm.run().error.is_some());
+        assert!(vm.memory().is_empty());
+
+        let mut vm = VM::new(
+            assemble("push 0; push 1; sub; mload")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        assert!(vm.run().error.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_host_instructions() -> Result<()> {
+        let mut state = State::new();
+        let caller = Address::try_from_bytes(&[0x11; 20])?;
+        state.credit(caller, 100);
+        let context = ExecutionContext {
+            caller,
+            block_height: 7,
+            timestamp: 1_700_000_000_123_456_789,
+            tx_hash: Hash::from_digest([0x22; 32]),
+            ..ExecutionContext::default()
+        };
+
+        let code = assemble("caller; txhash; timestamp; height")?;
+        let mut vm = VM::new(code, &mut state, context);
+        vm.run().into_result()?;
+        assert_eq!(
+            vm.stack.items(),
+            vec![
+                StackItem::Int(7),
+                StackItem::Int(1_700_000_000),
+                StackItem::Bytes(vec![0x22; 32]),
+                StackItem::Bytes(vec![0x11; 20]),
+            ]
+        );
+
+        let outcome = VM::new(assemble("caller; balance; return")?, &mut state, context).run();
+        assert_eq!(outcome.into_result()?.return_data, vec![100]);
+
+        // the balance doesn't fit into an int, and an address has 20 bytes
+        state.credit(caller, u32::MAX as u64);
+        let outcome = VM::new(assemble("caller; balance")?, &mut state, context).run();
+        assert!(outcome.error.is_some());
+        let outcome = VM::new(assemble("push 1; balance")?, &mut state, context).run();
+        assert!(outcome.error.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_transfer() -> Result<()> {
+        let mut state = State::new();
+        let code = assemble("push 30; caller; transfer; push 20; caller; transfer")?;
+        let contract = contract_address(&code);
+        let caller = Address::try_from_bytes(&[0x11; 20])?;
+        let context = ExecutionContext {
+            contract,
+            caller,
+            ..ExecutionContext::default()
+        };
+
+        // the transfers are only recorded, the tokens move once the block applies them
+        state.credit(contract, 50);
+        let outcome = VM::new(code.clone(), &mut state, context)
+            .run()
+            .into_result()?;
+        let sent = ValueTransfer {
+            from: contract,
+            to: caller,
+            amount: 0,
+        };
+        assert_eq!(
+            outcome.transfers,
+            vec![
+                ValueTransfer { amount: 30, ..sent },
+                ValueTransfer { amount: 20, ..sent }
+            ]
+        );
+        assert_eq!(state.balance(&contract), 50);
+
+        // the balance has to cover all transfers of the run, a failed run sends nothing
+        let mut state = State::new();
+        state.credit(contract, 49);
+        let outcome = VM::new(code, &mut state, context).run();
+        assert!(outcome.error.is_some());
+        assert!(outcome.transfers.is_empty());
+
+        // a transfer of 0 would be a free receipt
+        let code = assemble("push 0; caller; transfer")?;
+        let outcome = VM::new(code, &mut state, context).run();
+        assert!(outcome.error.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_pick_and_compare() -> Result<()> {
+        let mut state = State::new();
+
+        let mut vm = VM::new(
+            assemble("push 5; push 6; push 1; pick")?,
+            &mut state,
+            ExecutionContext::default(),
+        );
+        vm.run().into_result()?;
+        assert_eq!(vm.stack.items()[0], StackItem::Int(5));
+
+        for (code, result) in [
+            ("push 3; push 3; eq", 1),
+            ("push 3; push 4; gt", 1),
+            ("push 3; push 4; lt", 0),
+        ] {
+            let mut vm = VM::new(assemble(code)?, &mut state, ExecutionContext::default());
+            vm.run().into_result()?;
+            assert_eq!(vm.stack.pop(), StackItem::Int(result));
+        }
+
         Ok(())
     }
 }
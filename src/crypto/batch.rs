@@ -0,0 +1,79 @@
+/*
+verify_batch checks many signatures at once, e.g. those of the transactions of a block. ECDSA has no batch
+verification that is faster than checking the signatures one by one, so the batch is split into chunks that are
+verified in parallel. A scheme supporting true batch verification can plug it in here.
+*/
+
+use std::thread;
+
+use super::{PublicKey, Signature};
+
+// below this many signatures spawning threads costs more than it saves
+const MIN_PARALLEL_BATCH: usize = 16;
+
+// The message, the signature over it and the key that made the signature
+pub type SignedMessage<'a> = (&'a [u8], &'a Signature, &'a PublicKey);
+
+// True if every signature of the batch is valid, it doesn't tell which one isn't
+pub fn verify_batch(items: &[SignedMessage<'_>]) -> bool {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    if items.len() < MIN_PARALLEL_BATCH || threads == 1 {
+        return verify_chunk(items);
+    }
+
+    let chunk_size = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let chunks: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| verify_chunk(chunk)))
+            .collect();
+        // the scope waits for the chunks that aren't joined
+        chunks
+            .into_iter()
+            .all(|chunk| chunk.join().unwrap_or(false))
+    })
+}
+
+fn verify_chunk(items: &[SignedMessage<'_>]) -> bool {
+    items
+        .iter()
+        .all(|(msg, signature, public_key)| signature.verify(msg, public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    fn signed(n: usize) -> Vec<(Vec<u8>, Signature, PublicKey)> {
+        (0..n)
+            .map(|i| {
+                let key = PrivateKey::generate();
+                let msg = i.to_be_bytes().to_vec();
+                let signature = key.sign(&msg);
+                (msg, signature, key.public_key())
+            })
+            .collect()
+    }
+
+    fn items(signed: &[(Vec<u8>, Signature, PublicKey)]) -> Vec<SignedMessage<'_>> {
+        signed
+            .iter()
+            .map(|(msg, signature, key)| (msg.as_slice(), signature, key))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        assert!(verify_batch(&[]));
+
+        // small batches are verified in place, large ones in parallel
+        for n in [3, 100] {
+            let mut signed = signed(n);
+            assert!(verify_batch(&items(&signed)));
+
+            signed[n - 1].0.push(1);
+            assert!(!verify_batch(&items(&signed)));
+        }
+    }
+}
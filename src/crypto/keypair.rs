@@ -1,10 +1,31 @@
-use std::fmt::Display;
+/*
+Public keys and signatures have a fixed size encoding of their own instead of the serde format of p256, so they are
+as small as possible on the wire and don't change with the dependency:
 
-use p256::ecdsa::{
-    signature::{Signer, Verifier},
-    SigningKey, VerifyingKey,
+    public key: compressed SEC1 point, 33 bytes
+    signature:  r || s, 64 bytes
+
+Binary formats like bincode write the bytes without a length prefix, human readable ones like JSON a hex string.
+*/
+
+use std::fmt::{self, Display};
+
+use anyhow::anyhow;
+use p256::{
+    ecdsa::{
+        signature::{
+            hazmat::{PrehashSigner, PrehashVerifier},
+            Signer, Verifier,
+        },
+        SigningKey, VerifyingKey,
+    },
+    elliptic_curve::sec1::ToEncodedPoint,
+};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
 };
-use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
 use crate::types::Address;
@@ -20,6 +41,17 @@ impl PrivateKey {
         Self { key }
     }
 
+    // The 32 byte scalar, e.g. of a key kept in a file
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let key = p256::SecretKey::from_be_bytes(bytes)
+            .map_err(|_| anyhow!("private key is not a 32 byte scalar of the curve"))?;
+        Ok(Self { key })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.key.to_be_bytes().into()
+    }
+
     pub fn public_key(&self) -> PublicKey {
         PublicKey {
             key: self.key.public_key(),
@@ -31,19 +63,50 @@ impl PrivateKey {
         let signature: p256::ecdsa::Signature = signing_key.sign(data);
         Signature(signature)
     }
+
+    // Signs a digest as it is instead of hashing the data first, like Go's ecdsa.Sign
+    pub fn sign_prehash(&self, digest: &[u8; 32]) -> anyhow::Result<Signature> {
+        let signing_key = SigningKey::from(&self.key);
+        let signature: p256::ecdsa::Signature = signing_key
+            .sign_prehash(digest)
+            .map_err(|_| anyhow!("digest can't be signed"))?;
+        Ok(Signature(signature))
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub const PUBLIC_KEY_SIZE: usize = 33;
+pub const SIGNATURE_SIZE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PublicKey {
     key: p256::PublicKey,
 }
 
 impl PublicKey {
+    // The compressed SEC1 encoding of the point
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        let point = self.key.to_encoded_point(true);
+        let mut bytes = [0; PUBLIC_KEY_SIZE];
+        bytes.copy_from_slice(point.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() != PUBLIC_KEY_SIZE {
+            return Err(anyhow!(
+                "public key has {} bytes, expected {PUBLIC_KEY_SIZE}",
+                bytes.len()
+            ));
+        }
+        let key = p256::PublicKey::from_sec1_bytes(bytes)
+            .map_err(|_| anyhow!("public key is not a point of the curve"))?;
+        Ok(Self { key })
+    }
+
     pub fn address(&self) -> Address {
         let mut sha = sha2::Sha256::new();
         sha.update(self.key.to_string().as_bytes());
-        let b = sha.finalize();
-        Address::from_bytes(b[b.len() - 20..].as_ref())
+        Address::from_digest(sha.finalize().into())
     }
     pub fn verifying_key(&self) -> VerifyingKey {
         VerifyingKey::from(&self.key)
@@ -59,13 +122,110 @@ impl Display for Signature {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Signature(p256::ecdsa::Signature);
 
 impl Signature {
+    pub fn to_bytes(&self) -> [u8; SIGNATURE_SIZE] {
+        let mut bytes = [0; SIGNATURE_SIZE];
+        bytes.copy_from_slice(&self.0.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() != SIGNATURE_SIZE {
+            return Err(anyhow!(
+                "signature has {} bytes, expected {SIGNATURE_SIZE}",
+                bytes.len()
+            ));
+        }
+        p256::ecdsa::Signature::try_from(bytes)
+            .map(Signature)
+            .map_err(|_| anyhow!("signature is out of range"))
+    }
+
     pub fn verify(&self, data: &[u8], public_key: &PublicKey) -> bool {
         public_key.verifying_key().verify(data, &self.0).is_ok()
     }
+
+    // Verifies a signature made by sign_prehash
+    pub fn verify_prehash(&self, digest: &[u8; 32], public_key: &PublicKey) -> bool {
+        public_key
+            .verifying_key()
+            .verify_prehash(digest, &self.0)
+            .is_ok()
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_fixed(&self.to_bytes(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: [u8; PUBLIC_KEY_SIZE] = deserialize_fixed(deserializer)?;
+        PublicKey::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_fixed(&self.to_bytes(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: [u8; SIGNATURE_SIZE] = deserialize_fixed(deserializer)?;
+        Signature::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+}
+
+// A tuple of bytes has no length prefix, unlike a byte slice
+fn serialize_fixed<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        return serializer.serialize_str(&hex::encode(bytes));
+    }
+    let mut tuple = serializer.serialize_tuple(bytes.len())?;
+    for byte in bytes {
+        tuple.serialize_element(byte)?;
+    }
+    tuple.end()
+}
+
+fn deserialize_fixed<'de, D: Deserializer<'de>, const N: usize>(
+    deserializer: D,
+) -> Result<[u8; N], D::Error> {
+    if deserializer.is_human_readable() {
+        let s = String::deserialize(deserializer)?;
+        let bytes = hex::decode(s).map_err(de::Error::custom)?;
+        return bytes.try_into().map_err(|bytes: Vec<u8>| {
+            de::Error::invalid_length(bytes.len(), &FixedBytesVisitor::<N>)
+        });
+    }
+    deserializer.deserialize_tuple(N, FixedBytesVisitor::<N>)
+}
+
+struct FixedBytesVisitor<const N: usize>;
+
+impl<'de, const N: usize> Visitor<'de> for FixedBytesVisitor<N> {
+    type Value = [u8; N];
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{N} bytes")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = [0; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        Ok(bytes)
+    }
 }
 
 #[cfg(test)]
@@ -83,6 +243,58 @@ mod tests {
         assert!(sig.verify(msg, &public_key));
     }
 
+    #[test]
+    fn test_private_key_bytes() -> anyhow::Result<()> {
+        let private_key = PrivateKey::generate();
+        let decoded = PrivateKey::from_bytes(&private_key.to_bytes())?;
+        assert_eq!(decoded.public_key(), private_key.public_key());
+
+        assert!(PrivateKey::from_bytes(&[1; 31]).is_err());
+        assert!(PrivateKey::from_bytes(&[0; 32]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_prehash() -> anyhow::Result<()> {
+        let private_key = PrivateKey::generate();
+        let public_key = private_key.public_key();
+
+        let digest = [7; 32];
+        let sig = private_key.sign_prehash(&digest)?;
+        assert!(sig.verify_prehash(&digest, &public_key));
+        // the digest isn't hashed again
+        assert!(!sig.verify(&digest, &public_key));
+        assert!(!sig.verify_prehash(&[8; 32], &public_key));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_encoding() -> anyhow::Result<()> {
+        let private_key = PrivateKey::generate();
+        let public_key = private_key.public_key();
+        let sig = private_key.sign(b"hello world");
+
+        let bytes = bincode::serialize(&(public_key, sig))?;
+        assert_eq!(bytes.len(), PUBLIC_KEY_SIZE + SIGNATURE_SIZE);
+        let (decoded_key, decoded_sig): (PublicKey, Signature) = bincode::deserialize(&bytes)?;
+        assert_eq!(decoded_key, public_key);
+        assert_eq!(decoded_sig, sig);
+
+        // a compressed point starts with its parity
+        assert!(matches!(bytes[0], 2 | 3));
+        let mut bytes = bytes;
+        bytes[0] = 4;
+        assert!(bincode::deserialize::<(PublicKey, Signature)>(&bytes).is_err());
+
+        let json = serde_json::to_string(&public_key)?;
+        assert_eq!(json, format!("\"{}\"", hex::encode(public_key.to_bytes())));
+        assert_eq!(serde_json::from_str::<PublicKey>(&json)?, public_key);
+
+        Ok(())
+    }
+
     #[test]
     fn test_keypair_sign_verify_fail() {
         let private_key = PrivateKey::generate();
@@ -1,2 +1,4 @@
+mod batch;
 mod keypair;
+pub use batch::*;
 pub use keypair::*;
@@ -0,0 +1,151 @@
+/*
+Harness functions for fuzzing. Each takes the raw bytes produced by the fuzzer and must return without
+panicking or looping forever for any input. Errors are expected and ignored.
+
+cargo fuzz builds the targets in fuzz/ with --cfg fuzzing, e.g. cargo fuzz run vm_run. The tests below run every
+harness on random and mutated inputs.
+*/
+
+use bytes::Bytes;
+
+use crate::{
+    core::{ExecutionContext, State, VM},
+    network::{default_rpc_decode_fn, RPC},
+};
+
+// Programs that loop are stopped by the gas limit, a low one keeps the fuzzer fast
+const GAS_LIMIT: u64 = 10_000;
+
+// The first byte splits the input into call data and bytecode
+pub fn vm_run(data: &[u8]) {
+    let (calldata, code) = match data.split_first() {
+        Some((n, rest)) => rest.split_at((*n as usize).min(rest.len())),
+        None => (data, data),
+    };
+
+    let mut state = State::new();
+    let mut vm = VM::new(code.to_vec(), &mut state, ExecutionContext::default());
+    vm.set_calldata(calldata.to_vec());
+    vm.set_gas_limit(GAS_LIMIT);
+    let outcome = vm.run();
+
+    assert!(outcome.gas_used <= GAS_LIMIT);
+}
+
+pub fn rpc_decode(data: &[u8]) {
+    let _ = default_rpc_decode_fn(RPC {
+        from: "FUZZ".into(),
+        payload: Bytes::copy_from_slice(data),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{assemble, Instruction},
+        network::{Message, MessageType},
+        test_utils::{encoded, random_block},
+        types::Hash,
+    };
+    use anyhow::Result;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const RUNS: usize = 1000;
+
+    fn random_bytes(rng: &mut StdRng, max_len: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    // flips, inserts and removes a few random bytes
+    fn mutate(rng: &mut StdRng, mut data: Vec<u8>) -> Vec<u8> {
+        for _ in 0..rng.gen_range(1..4) {
+            let i = rng.gen_range(0..=data.len());
+            match rng.gen_range(0..3) {
+                0 if i < data.len() => data[i] = rng.gen(),
+                1 => data.insert(i, rng.gen()),
+                _ if i < data.len() => {
+                    data.remove(i);
+                }
+                _ => {}
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_vm_run() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(2838);
+
+        vm_run(&[]);
+        vm_run(&[0]);
+        // an endless loop stops when it runs out of gas
+        vm_run(&[0, 0, 0x0a, 0x18]);
+
+        let programs = [
+            assemble("push 2; push 3; add; push 1; pick; mul; return")?,
+            assemble("start: push 1; store A; push @start; jump")?,
+            assemble("push 1; cdsize; cdload; dup; pack; get A")?,
+        ];
+        for _ in 0..RUNS {
+            vm_run(&random_bytes(&mut rng, 64));
+
+            let program = programs[rng.gen_range(0..programs.len())].clone();
+            vm_run(&mutate(&mut rng, program));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vm_run_junk() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(2838);
+
+        // a loop that jumps back over a region of bytes that are no opcodes pays for every skipped
+        // byte, so the steps it takes are bounded by the gas limit and not by the size of the junk
+        for len in [1_000, 100_000] {
+            let junk = (0..len)
+                .map(|_| rng.gen())
+                .filter(|b| Instruction::from_byte(*b).is_none());
+            let code: Vec<u8> = junk.chain(assemble("push 0; jump")?).collect();
+
+            let mut data = vec![0];
+            data.extend(&code);
+            vm_run(&data);
+
+            let mut state = State::new();
+            let mut vm = VM::new(code, &mut state, ExecutionContext::default());
+            vm.set_gas_limit(GAS_LIMIT);
+            let mut steps = 0;
+            while let Ok(true) = vm.step() {
+                steps += 1;
+            }
+            assert!(steps <= GAS_LIMIT);
+            assert!(vm.gas_used() <= GAS_LIMIT);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rpc_decode() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(2838);
+
+        let block = encoded(&random_block(1, Hash::random())?)?;
+        let messages = [
+            Message::new(MessageType::Block, block).bytes()?,
+            Message::new(MessageType::GetStatus, vec![]).bytes()?,
+        ];
+
+        rpc_decode(&[]);
+        for _ in 0..RUNS {
+            rpc_decode(&random_bytes(&mut rng, 64));
+
+            let msg = messages[rng.gen_range(0..messages.len())].to_vec();
+            rpc_decode(&mutate(&mut rng, msg));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+/*
+The compiler translates the syntax tree into the assembly language of core::asm, which resolves the labels
+of if statements and encodes the pushes.
+
+Variables live on the VM stack. The compiler tracks how many items are on the stack, so a variable is read by
+picking it from its depth. Variables declared inside a block are dropped at the end of the block, so both
+branches of an if leave the stack as they found it.
+*/
+
+use anyhow::{anyhow, Result};
+
+use super::parser::{BinOp, Expr, Stmt};
+
+pub fn generate(program: &[Stmt]) -> Result<String> {
+    let mut gen = Generator::default();
+    gen.block(program)?;
+    Ok(gen.out.join("\n"))
+}
+
+#[derive(Default)]
+struct Generator {
+    out: Vec<String>,
+    // number of items on the stack
+    depth: usize,
+    // variables of every open block with their position on the stack (0 is the bottom)
+    scopes: Vec<Vec<(String, usize)>>,
+    labels: usize,
+}
+
+impl Generator {
+    fn emit(&mut self, line: impl Into<String>) {
+        self.out.push(line.into());
+    }
+
+    fn push(&mut self, n: u8) {
+        self.emit(format!("push {n}"));
+        self.depth += 1;
+    }
+
+    fn label(&mut self, prefix: &str) -> String {
+        self.labels += 1;
+        format!("{}_{}", prefix, self.labels)
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        self.scopes.push(vec![]);
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+
+        let vars = self.scopes.pop().unwrap_or_default();
+        // the variables of the top level are left on the stack
+        if !self.scopes.is_empty() {
+            for _ in &vars {
+                self.emit("drop");
+                self.depth -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<()> {
+        match stmt {
+            Stmt::Let(name, expr) => {
+                self.expr(expr)?;
+                let slot = self.depth - 1;
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.push((name.clone(), slot));
+                }
+            }
+            Stmt::Store(key, expr) => {
+                self.expr(expr)?;
+                self.emit(format!("store {}", check_key(key)?));
+                self.depth -= 1;
+            }
+            Stmt::If(cond, then, otherwise) => {
+                let then_label = self.label("then");
+                let end_label = self.label("end");
+
+                self.expr(cond)?;
+                self.emit(format!("push @{then_label}"));
+                self.emit("jumpif");
+                self.depth -= 1;
+
+                self.block(otherwise)?;
+                self.emit(format!("push @{end_label}"));
+                self.emit("jump");
+
+                self.emit(format!("{then_label}:"));
+                self.block(then)?;
+                self.emit(format!("{end_label}:"));
+            }
+            Stmt::Return(expr) => {
+                self.expr(expr)?;
+                self.emit("return");
+                self.depth -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<()> {
+        match expr {
+            Expr::Number(n) => self.number(*n),
+            Expr::Var(name) => {
+                let slot = self
+                    .scopes
+                    .iter()
+                    .rev()
+                    .flat_map(|scope| scope.iter().rev())
+                    .find(|(var, _)| var == name)
+                    .map(|(_, slot)| *slot)
+                    .ok_or_else(|| anyhow!("variable {name} is not defined"))?;
+
+                let depth = u8::try_from(self.depth - 1 - slot)
+                    .map_err(|_| anyhow!("variable {name} is too deep in the stack"))?;
+                self.push(depth);
+                self.emit("pick");
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                // the VM applies the operators to the top item and the one below it
+                self.expr(rhs)?;
+                self.expr(lhs)?;
+                let op = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "div",
+                    BinOp::Mod => "mod",
+                    BinOp::Eq => "eq",
+                    BinOp::Lt => "lt",
+                    BinOp::Gt => "gt",
+                };
+                self.emit(op);
+                self.depth -= 1;
+            }
+            Expr::Load(key) => {
+                self.emit(format!("get {}", check_key(key)?));
+                self.depth += 1;
+            }
+            Expr::Input { offset, len } => {
+                self.expr(len)?;
+                self.expr(offset)?;
+                self.emit("cdload");
+                self.depth -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    // push only takes a single byte, larger numbers are built from n / 255 * 255 + n % 255
+    fn number(&mut self, n: i32) {
+        if let Ok(b) = u8::try_from(n) {
+            self.push(b);
+            return;
+        }
+
+        self.number(n / 255);
+        self.push(255);
+        self.emit("mul");
+        self.depth -= 1;
+        self.push((n % 255) as u8);
+        self.emit("add");
+        self.depth -= 1;
+    }
+}
+
+// storage keys end up in the assembly text, so they can't contain its separators
+fn check_key(key: &str) -> Result<&str> {
+    if key.is_empty() || key.len() > u8::MAX as usize {
+        return Err(anyhow!("storage key must be between 1 and 255 bytes long"));
+    }
+    if key.contains(|c: char| c.is_whitespace() || c == ';' || c == '#' || c == ':' || c == '@') {
+        return Err(anyhow!("storage key {key:?} contains invalid characters"));
+    }
+    Ok(key)
+}
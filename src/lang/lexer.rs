@@ -0,0 +1,171 @@
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Let,
+    If,
+    Else,
+    Return,
+    Storage,
+    Input,
+    Ident(String),
+    Number(i32),
+    Str(String),
+    Assign,
+    Eq,
+    Lt,
+    Gt,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    Comma,
+    Semicolon,
+}
+
+// A token and the line it starts on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned {
+    pub token: Token,
+    pub line: usize,
+}
+
+pub fn tokenize(src: &str) -> Result<Vec<Spanned>> {
+    let mut tokens = vec![];
+    let mut chars = src.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '/' if chars.peek() == Some(&'/') => {
+                // comments run until the end of the line
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+                continue;
+            }
+            '=' if chars.peek() == Some(&'=') => {
+                chars.next();
+                Token::Eq
+            }
+            '=' => Token::Assign,
+            '<' => Token::Lt,
+            '>' => Token::Gt,
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '{' => Token::LBrace,
+            '}' => Token::RBrace,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            ';' => Token::Semicolon,
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => {
+                            return Err(anyhow!("line {line}: unterminated string"))
+                        }
+                        Some(c) => s.push(c),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if c.is_ascii_digit() => {
+                let mut s = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                    s.push(c);
+                }
+                let n = s
+                    .parse()
+                    .map_err(|_| anyhow!("line {line}: number {s} is too large"))?;
+                Token::Number(n)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    s.push(c);
+                }
+                match s.as_str() {
+                    "let" => Token::Let,
+                    "if" => Token::If,
+                    "else" => Token::Else,
+                    "return" => Token::Return,
+                    "storage" => Token::Storage,
+                    "input" => Token::Input,
+                    _ => Token::Ident(s),
+                }
+            }
+            c => return Err(anyhow!("line {line}: unexpected character {c:?}")),
+        };
+
+        tokens.push(Spanned { token, line });
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() -> Result<()> {
+        let tokens: Vec<Token> =
+            tokenize("let a = 10; // comment\nif a == 2 { storage[\"FOO\"] = a; }")?
+                .into_iter()
+                .map(|t| t.token)
+                .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Let,
+                Token::Ident("a".into()),
+                Token::Assign,
+                Token::Number(10),
+                Token::Semicolon,
+                Token::If,
+                Token::Ident("a".into()),
+                Token::Eq,
+                Token::Number(2),
+                Token::LBrace,
+                Token::Storage,
+                Token::LBracket,
+                Token::Str("FOO".into()),
+                Token::RBracket,
+                Token::Assign,
+                Token::Ident("a".into()),
+                Token::Semicolon,
+                Token::RBrace,
+            ]
+        );
+
+        assert!(tokenize("let a = \"open").is_err());
+        assert!(tokenize("let a = 99999999999").is_err());
+        assert!(tokenize("a & b").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+/*
+A small language for writing contracts without raw opcodes:
+
+    let amount = input(2, 1);
+    if amount > 100 {
+        return 0;
+    }
+    storage["TOTAL"] = storage["TOTAL"] + amount;
+    return 1;
+
+Values are ints, storage values are single bytes (see VM Store). Numbers are non-negative.
+*/
+
+mod compiler;
+mod lexer;
+mod parser;
+
+use anyhow::Result;
+
+use crate::core::assemble;
+
+// Compiles a program into the assembly language of core::asm
+pub fn compile_to_asm(src: &str) -> Result<String> {
+    let tokens = lexer::tokenize(src)?;
+    let program = parser::parse(&tokens)?;
+    compiler::generate(&program)
+}
+
+// Compiles a program into VM bytecode
+pub fn compile(src: &str) -> Result<Vec<u8>> {
+    assemble(&compile_to_asm(src)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{ExecutionContext, State, VM},
+        types::Address,
+    };
+
+    fn run(src: &str, input: Vec<u8>, state: &mut State) -> Result<Vec<u8>> {
+        let mut vm = VM::new(compile(src)?, state, ExecutionContext::default());
+        vm.set_calldata(input);
+        Ok(vm.run().into_result()?.return_data)
+    }
+
+    #[test]
+    fn test_arithmetic_and_variables() -> Result<()> {
+        let mut state = State::new();
+
+        assert_eq!(run("return 10 - 4 / 2;", vec![], &mut state)?, vec![8]);
+        assert_eq!(
+            run(
+                "let a = 3; let b = a * 4; return (b - a) % 5;",
+                vec![],
+                &mut state
+            )?,
+            vec![4]
+        );
+        // 1000 doesn't fit into a push
+        assert_eq!(
+            run("let big = 1000; return big - 990;", vec![], &mut state)?,
+            vec![10]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_if() -> Result<()> {
+        let src = "
+            let a = input(2, 1);
+            if a > 100 {
+                let twice = a * 2;
+                storage[\"BIG\"] = twice - a;
+            } else {
+                storage[\"SMALL\"] = a;
+            }
+            // the variables of the branches are gone again
+            return a + 1;
+        ";
+
+        let mut state = State::new();
+        assert_eq!(run(src, vec![1, 1, 200], &mut state)?, vec![201]);
+        assert_eq!(state.get(&Address::default(), b"BIG")?, vec![200]);
+        assert!(state.get(&Address::default(), b"SMALL").is_err());
+
+        assert_eq!(run(src, vec![1, 1, 7], &mut state)?, vec![8]);
+        assert_eq!(state.get(&Address::default(), b"SMALL")?, vec![7]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage() -> Result<()> {
+        let src =
+            "storage[\"TOTAL\"] = storage[\"TOTAL\"] + input(0, 1); return storage[\"TOTAL\"];";
+
+        let mut state = State::new();
+        state.put(&Address::default(), b"TOTAL", &[0])?;
+        assert_eq!(run(src, vec![5], &mut state)?, vec![5]);
+        assert_eq!(run(src, vec![6], &mut state)?, vec![11]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(compile("return a;").is_err());
+        assert!(compile("if 1 { let a = 1; } return a;").is_err());
+        assert!(compile("storage[\"A B\"] = 1;").is_err());
+    }
+}
@@ -0,0 +1,267 @@
+/*
+Grammar of the contract language:
+
+    program   := stmt*
+    stmt      := "let" IDENT "=" expr ";"
+               | "storage" "[" STRING "]" "=" expr ";"
+               | "if" expr block ("else" block)?
+               | "return" expr ";"
+    block     := "{" stmt* "}"
+    expr      := sum (("==" | "<" | ">") sum)?
+    sum       := term (("+" | "-") term)*
+    term      := factor (("*" | "/" | "%") factor)*
+    factor    := NUMBER | IDENT | "(" expr ")"
+               | "storage" "[" STRING "]"
+               | "input" "(" expr "," expr ")"
+*/
+
+use anyhow::{anyhow, Result};
+
+use super::lexer::{Spanned, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Lt,
+    Gt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i32),
+    Var(String),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+    // read from contract storage
+    Load(String),
+    // read len bytes of call data at offset
+    Input { offset: Box<Expr>, len: Box<Expr> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stmt {
+    Let(String, Expr),
+    Store(String, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    Return(Expr),
+}
+
+pub fn parse(tokens: &[Spanned]) -> Result<Vec<Stmt>> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let mut program = vec![];
+    while parser.peek().is_some() {
+        program.push(parser.stmt()?);
+    }
+    Ok(program)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Spanned],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    fn next(&mut self) -> Result<&Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| anyhow!("unexpected end of program"))?;
+        self.pos += 1;
+        Ok(&token.token)
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        match self.tokens.get(self.pos.saturating_sub(1)) {
+            Some(t) => anyhow!("line {}: {} (found {:?})", t.line, msg, t.token),
+            None => anyhow!("{msg}"),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        if *self.next()? != expected {
+            return Err(self.error(&format!("expected {expected:?}")));
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String> {
+        match self.next()? {
+            Token::Ident(name) => Ok(name.clone()),
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    // storage["KEY"] after the storage keyword
+    fn storage_key(&mut self) -> Result<String> {
+        self.expect(Token::LBracket)?;
+        let key = match self.next()? {
+            Token::Str(key) => key.clone(),
+            _ => return Err(self.error("expected a storage key")),
+        };
+        self.expect(Token::RBracket)?;
+        Ok(key)
+    }
+
+    fn stmt(&mut self) -> Result<Stmt> {
+        let stmt = match self.next()? {
+            Token::Let => {
+                let name = self.ident()?;
+                self.expect(Token::Assign)?;
+                Stmt::Let(name, self.expr()?)
+            }
+            Token::Storage => {
+                let key = self.storage_key()?;
+                self.expect(Token::Assign)?;
+                Stmt::Store(key, self.expr()?)
+            }
+            Token::If => {
+                let cond = self.expr()?;
+                let then = self.block()?;
+                let otherwise = if self.peek() == Some(&Token::Else) {
+                    self.pos += 1;
+                    self.block()?
+                } else {
+                    vec![]
+                };
+                return Ok(Stmt::If(cond, then, otherwise));
+            }
+            Token::Return => Stmt::Return(self.expr()?),
+            _ => return Err(self.error("expected a statement")),
+        };
+        self.expect(Token::Semicolon)?;
+        Ok(stmt)
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>> {
+        self.expect(Token::LBrace)?;
+        let mut stmts = vec![];
+        while self.peek() != Some(&Token::RBrace) {
+            stmts.push(self.stmt()?);
+        }
+        self.pos += 1;
+        Ok(stmts)
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let lhs = self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Eq) => BinOp::Eq,
+            Some(Token::Lt) => BinOp::Lt,
+            Some(Token::Gt) => BinOp::Gt,
+            _ => return Ok(lhs),
+        };
+        self.pos += 1;
+        Ok(Expr::Binary(Box::new(lhs), op, Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Plus) => BinOp::Add,
+                Some(Token::Minus) => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr> {
+        let mut lhs = self.factor()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Star) => BinOp::Mul,
+                Some(Token::Slash) => BinOp::Div,
+                Some(Token::Percent) => BinOp::Mod,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.factor()?));
+        }
+    }
+
+    fn factor(&mut self) -> Result<Expr> {
+        let expr = match self.next()? {
+            Token::Number(n) => Expr::Number(*n),
+            Token::Ident(name) => Expr::Var(name.clone()),
+            Token::LParen => {
+                let expr = self.expr()?;
+                self.expect(Token::RParen)?;
+                expr
+            }
+            Token::Storage => Expr::Load(self.storage_key()?),
+            Token::Input => {
+                self.expect(Token::LParen)?;
+                let offset = self.expr()?;
+                self.expect(Token::Comma)?;
+                let len = self.expr()?;
+                self.expect(Token::RParen)?;
+                Expr::Input {
+                    offset: Box::new(offset),
+                    len: Box::new(len),
+                }
+            }
+            _ => return Err(self.error("expected an expression")),
+        };
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::lexer::tokenize;
+
+    fn parse_str(src: &str) -> Result<Vec<Stmt>> {
+        parse(&tokenize(src)?)
+    }
+
+    #[test]
+    fn test_precedence() -> Result<()> {
+        let program = parse_str("return 1 + 2 * 3 < 8;")?;
+
+        let mul = Expr::Binary(
+            Box::new(Expr::Number(2)),
+            BinOp::Mul,
+            Box::new(Expr::Number(3)),
+        );
+        let add = Expr::Binary(Box::new(Expr::Number(1)), BinOp::Add, Box::new(mul));
+        assert_eq!(
+            program,
+            vec![Stmt::Return(Expr::Binary(
+                Box::new(add),
+                BinOp::Lt,
+                Box::new(Expr::Number(8))
+            ))]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_statements() -> Result<()> {
+        let program = parse_str(
+            "let a = input(2, 1); if a > 3 { storage[\"BIG\"] = a; } else { return 0; }",
+        )?;
+        assert_eq!(program.len(), 2);
+        assert!(
+            matches!(&program[1], Stmt::If(_, then, otherwise) if then.len() == 1 && otherwise.len() == 1)
+        );
+
+        assert!(parse_str("let = 1;").is_err());
+        assert!(parse_str("let a = 1").is_err());
+        assert!(parse_str("if a { return 1;").is_err());
+        assert!(parse_str("storage[FOO] = 1;").is_err());
+
+        Ok(())
+    }
+}
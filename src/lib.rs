@@ -0,0 +1,38 @@
+/*
+projectx_rs is a modular blockchain node as a library, the projectx binary in src/bin/node.rs is a thin command
+line wrapper around it.
+
+The public surface are the modules below and the prelude, which re-exports what an application embedding a node
+needs most: the chain types, keys and Server::builder with its handles. The simulator and the test helpers are
+only compiled for the tests, the fuzz harnesses also for cargo fuzz.
+
+Subsystems pulling in extra dependencies are behind cargo features: cli (default) for the binary, api for the
+JSON-RPC API (enabled by cli), quic for the QUIC transport and upnp for UPnP port mapping. The library alone needs default-features = false.
+*/
+
+#[cfg(feature = "api")]
+pub mod api;
+pub mod consensus;
+pub mod core;
+pub mod crypto;
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
+pub mod lang;
+pub mod network;
+#[cfg(test)]
+mod simulator;
+#[cfg(test)]
+mod test_utils;
+pub mod types;
+
+pub mod prelude {
+    pub use crate::{
+        core::{Block, Blockchain, Checkpoints, Header, Pruning, Transaction, TxStatus},
+        crypto::{PrivateKey, PublicKey, Signature},
+        network::{
+            BTransport, ConnectionManagerOpts, LocalTransport, NetAddr, RunningServer, Server,
+            ServerBuilder, ServerHandle, ServerOpts, Transport,
+        },
+        types::{Address, Hash},
+    };
+}
@@ -0,0 +1,176 @@
+/*
+BlockProducer decides when a validator creates the next block.
+With the Interval policy a block is created every block_time. With the TxTriggered policy a block is created as soon as
+the pending pool is big enough, or once max_wait elapsed since the last block, which gives lower latency under load
+and fewer empty blocks when the network is idle.
+
+Under either policy a validator whose peers told it they are ahead waits until it synced to within max_lag blocks of
+the highest of them, a block on top of an older chain would fork off the one the peers have.
+*/
+
+use std::time::{Duration, Instant};
+
+use super::SyncStatus;
+
+// by default a validator doesn't produce while it's behind at all
+pub const DEFAULT_MAX_PRODUCTION_LAG: u32 = 0;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlockProductionPolicy {
+    // create a block every block_time
+    #[default]
+    Interval,
+    // create a block once the pending pool holds max_txs transactions or max_bytes bytes of
+    // transaction data, or max_wait elapsed since the last block
+    TxTriggered {
+        max_txs: usize,
+        max_bytes: usize,
+        max_wait: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlockProducer {
+    pub policy: BlockProductionPolicy,
+    pub block_time: Duration,
+    // If false blocks without transactions are skipped, unless the chain has been idle
+    // for longer than max_idle_interval
+    pub produce_empty_blocks: bool,
+    pub max_idle_interval: Option<Duration>,
+    // number of blocks the chain may be behind the best known height for blocks to be produced
+    pub max_lag: u32,
+}
+
+impl BlockProducer {
+    pub fn should_produce(
+        &self,
+        pending_count: usize,
+        pending_bytes: usize,
+        idle: Duration,
+    ) -> bool {
+        let allow_empty = self.produce_empty_blocks
+            || matches!(self.max_idle_interval, Some(max_idle) if idle >= max_idle);
+
+        if pending_count == 0 {
+            return allow_empty;
+        }
+
+        match self.policy {
+            BlockProductionPolicy::Interval => true,
+            BlockProductionPolicy::TxTriggered {
+                max_txs,
+                max_bytes,
+                max_wait,
+            } => pending_count >= max_txs || pending_bytes >= max_bytes || idle >= max_wait,
+        }
+    }
+
+    // Whether the chain is close enough to the best height the peers told us to build on it
+    pub fn in_sync(&self, status: SyncStatus) -> bool {
+        match status {
+            SyncStatus::Synced => true,
+            SyncStatus::Syncing {
+                current, target, ..
+            } => target.saturating_sub(current) <= self.max_lag,
+        }
+    }
+
+    // The time at which a TxTriggered producer has to re-check the pool even if no new
+    // transactions arrive, None if it only has to wake up for new transactions.
+    // Interval producers are driven by a ticker and have no deadline.
+    pub fn next_deadline(&self, pending_count: usize, last_block: Instant) -> Option<Instant> {
+        let max_wait = match self.policy {
+            BlockProductionPolicy::Interval => return None,
+            BlockProductionPolicy::TxTriggered { max_wait, .. } => max_wait,
+        };
+
+        if pending_count > 0 || self.produce_empty_blocks {
+            return Some(last_block + max_wait);
+        }
+        self.max_idle_interval.map(|max_idle| last_block + max_idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn producer(policy: BlockProductionPolicy, produce_empty_blocks: bool) -> BlockProducer {
+        BlockProducer {
+            policy,
+            block_time: Duration::from_secs(5),
+            produce_empty_blocks,
+            max_idle_interval: None,
+            max_lag: DEFAULT_MAX_PRODUCTION_LAG,
+        }
+    }
+
+    fn tx_triggered() -> BlockProductionPolicy {
+        BlockProductionPolicy::TxTriggered {
+            max_txs: 10,
+            max_bytes: 1000,
+            max_wait: Duration::from_secs(2),
+        }
+    }
+
+    #[test]
+    fn test_interval_empty_blocks() {
+        let idle = Duration::from_secs(10);
+
+        let p = producer(BlockProductionPolicy::Interval, true);
+        assert!(p.should_produce(0, 0, idle));
+
+        let mut p = producer(BlockProductionPolicy::Interval, false);
+        assert!(p.should_produce(1, 10, idle));
+        assert!(!p.should_produce(0, 0, idle));
+
+        p.max_idle_interval = Some(Duration::from_secs(30));
+        assert!(!p.should_produce(0, 0, idle));
+        p.max_idle_interval = Some(Duration::from_secs(10));
+        assert!(p.should_produce(0, 0, idle));
+    }
+
+    #[test]
+    fn test_tx_triggered_thresholds() {
+        let p = producer(tx_triggered(), false);
+        let short = Duration::from_millis(100);
+
+        assert!(!p.should_produce(1, 10, short));
+        assert!(p.should_produce(10, 10, short));
+        assert!(p.should_produce(1, 1000, short));
+        assert!(p.should_produce(1, 10, Duration::from_secs(2)));
+        assert!(!p.should_produce(0, 0, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_in_sync() {
+        let syncing = |current, target| SyncStatus::Syncing {
+            current,
+            target,
+            peers: 1,
+        };
+
+        let mut p = producer(BlockProductionPolicy::Interval, true);
+        assert!(p.in_sync(SyncStatus::Synced));
+        assert!(!p.in_sync(syncing(10, 11)));
+
+        p.max_lag = 3;
+        assert!(p.in_sync(syncing(10, 13)));
+        assert!(!p.in_sync(syncing(10, 14)));
+    }
+
+    #[test]
+    fn test_next_deadline() {
+        let now = Instant::now();
+
+        let p = producer(tx_triggered(), false);
+        assert_eq!(p.next_deadline(0, now), None);
+        assert_eq!(p.next_deadline(1, now), Some(now + Duration::from_secs(2)));
+
+        let p = producer(tx_triggered(), true);
+        assert_eq!(p.next_deadline(0, now), Some(now + Duration::from_secs(2)));
+
+        let p = producer(BlockProductionPolicy::Interval, false);
+        assert_eq!(p.next_deadline(1, now), None);
+    }
+}
@@ -0,0 +1,50 @@
+/*
+Blocks used to be verified completely while the server held the lock of the chain, so a large block stalled every
+other message for as long as its signatures were checked. The BlockVerifier runs the checks that don't need the
+chain (size, signatures and data hash, see Block::pre_validate) on the workers of the DecodePool, right after a
+block was decoded. A block failing them is dropped like a message that can't be decoded.
+
+The chain only runs the stateful checks under its lock: the height and the link to the previous block, the epoch,
+the committee and the application of the transactions. verify_cached skips the checks a block passed already.
+*/
+
+use anyhow::Result;
+
+use super::DecodedMessageData;
+use crate::core::{Block, SigCache};
+
+// blocks are sent in one frame, see DEFAULT_MAX_FRAME_SIZE
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct BlockVerifier {
+    // shared with the chain and the mem_pool, a transaction verified by one of them isn't verified again
+    sig_cache: SigCache,
+    max_block_size: usize,
+}
+
+impl BlockVerifier {
+    pub fn new(sig_cache: SigCache, max_block_size: usize) -> Self {
+        Self {
+            sig_cache,
+            max_block_size,
+        }
+    }
+
+    pub fn verify(&self, block: &mut Block) -> Result<()> {
+        block.pre_validate(&self.sig_cache, self.max_block_size)
+    }
+
+    // Verifies the blocks carried by a decoded message, other messages pass
+    pub fn verify_message(&self, data: &mut DecodedMessageData) -> Result<()> {
+        match data {
+            DecodedMessageData::Block(block) => self.verify(block),
+            DecodedMessageData::BlocksMessage(msg) => msg
+                .blocks
+                .iter_mut()
+                .try_for_each(|block| self.verify(block)),
+            DecodedMessageData::Proposal(proposal) => self.verify(&mut proposal.block),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,411 @@
+/*
+The Broadcaster sends blocks, transactions and consensus messages to the peers of the ConnectionManager. A transport
+may know more peers than that, e.g. an inbound peer rejected because of the inbound limit, those don't get
+broadcasts.
+
+Every peer has its own bounded outbound queue drained by a sender task, so a slow peer only delays the messages
+to itself. What happens when the queue of a peer is full is the SlowPeerPolicy: the message is dropped or the peer
+is disconnected. The queue of a peer that disconnected is closed on the next broadcast, which ends its task.
+
+New blocks are pushed in full to the square root of the peers only, the others that advertised
+FEATURE_BLOCK_ANNOUNCEMENTS get a NewBlockHashesMessage and ask for the block if they don't have it.
+*/
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use rand::{seq::SliceRandom, thread_rng};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Mutex, RwLock,
+};
+use tracing::{debug, warn};
+
+use super::{
+    BTransport, ConnectionManager, Handshake, Message, NetAddr, Payload,
+    FEATURE_BLOCK_ANNOUNCEMENTS, PROTOCOL_VERSION,
+};
+use crate::core::BClock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowPeerPolicy {
+    // messages to a peer with a full queue are dropped
+    Drop,
+    // a peer with a full queue is disconnected
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundOpts {
+    // number of messages waiting to be sent to a single peer
+    pub capacity: usize,
+    pub policy: SlowPeerPolicy,
+}
+
+impl Default for OutboundOpts {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: SlowPeerPolicy::Drop,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Broadcaster {
+    transport: BTransport,
+    conn_manager: Arc<Mutex<ConnectionManager>>,
+    // the features and protocol versions of the peers, see broadcast_block and enqueue
+    handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>,
+    opts: OutboundOpts,
+    clock: BClock,
+    queues: Arc<std::sync::Mutex<HashMap<NetAddr, mpsc::Sender<Payload>>>>,
+    // messages dropped because the queue of their peer was full
+    dropped: Arc<AtomicU64>,
+}
+
+impl Broadcaster {
+    pub fn new(
+        transport: BTransport,
+        conn_manager: Arc<Mutex<ConnectionManager>>,
+        opts: OutboundOpts,
+        clock: BClock,
+    ) -> Self {
+        Self {
+            transport,
+            conn_manager,
+            handshakes: Arc::new(RwLock::new(HashMap::new())),
+            opts,
+            clock,
+            queues: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Without handshakes no peer is known to support block announcements, every peer gets the full blocks
+    pub fn with_handshakes(mut self, handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>) -> Self {
+        self.handshakes = handshakes;
+        self
+    }
+
+    pub async fn broadcast(&self, msg: Message) -> Result<()> {
+        self.send(msg, None).await
+    }
+
+    // Sends to every peer but except, e.g. the one a relayed message came from
+    pub async fn broadcast_except(&self, msg: Message, except: &NetAddr) -> Result<()> {
+        self.send(msg, Some(except)).await
+    }
+
+    // Sends the block to the square root of the peers but except and the announcement to the others. Peers that
+    // don't support announcements always get the block and count towards the square root.
+    pub async fn broadcast_block(
+        &self,
+        block: Message,
+        announcement: Message,
+        except: Option<&NetAddr>,
+    ) -> Result<()> {
+        let peers = self.connected().await;
+        let (mut announcing, pushed): (Vec<NetAddr>, Vec<NetAddr>) = {
+            let handshakes = self.handshakes.read().await;
+            peers
+                .iter()
+                .filter(|addr| Some(*addr) != except)
+                .cloned()
+                .partition(|addr| {
+                    handshakes
+                        .get(addr)
+                        .is_some_and(|h| h.features & FEATURE_BLOCK_ANNOUNCEMENTS != 0)
+                })
+        };
+
+        let push = full_push_count(announcing.len() + pushed.len()).saturating_sub(pushed.len());
+        announcing.shuffle(&mut thread_rng());
+        let announced = announcing.split_off(push.min(announcing.len()));
+
+        let targets = pushed
+            .into_iter()
+            .chain(announcing)
+            .map(|addr| (addr, 0))
+            .chain(announced.into_iter().map(|addr| (addr, 1)))
+            .collect();
+        self.enqueue(&peers, &[block, announcement], targets).await
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    async fn connected(&self) -> HashSet<NetAddr> {
+        self.conn_manager
+            .lock()
+            .await
+            .connected()
+            .into_iter()
+            .collect()
+    }
+
+    async fn send(&self, msg: Message, except: Option<&NetAddr>) -> Result<()> {
+        let peers = self.connected().await;
+        let targets = peers
+            .iter()
+            .filter(|addr| Some(*addr) != except)
+            .map(|addr| (addr.clone(), 0))
+            .collect();
+        self.enqueue(&peers, &[msg], targets).await
+    }
+
+    // Queues the message of every target for its peer, encoded in the protocol version negotiated with it. A
+    // target is a peer and the index of its message in msgs. The queues of the peers no longer connected are
+    // closed.
+    async fn enqueue(
+        &self,
+        peers: &HashSet<NetAddr>,
+        msgs: &[Message],
+        targets: Vec<(NetAddr, usize)>,
+    ) -> Result<()> {
+        let mut messages: Vec<(NetAddr, Payload)> = vec![];
+        {
+            let handshakes = self.handshakes.read().await;
+            // every message is encoded once per version
+            let mut encoded: HashMap<(usize, u32), Payload> = HashMap::new();
+            for (addr, i) in targets {
+                let version = handshakes
+                    .get(&addr)
+                    .map_or(PROTOCOL_VERSION, |h| h.version);
+                let payload = match encoded.get(&(i, version)) {
+                    Some(payload) => payload.clone(),
+                    None => match msgs[i].encode_in(version) {
+                        Ok(payload) => encoded.entry((i, version)).or_insert(payload).clone(),
+                        Err(err) => {
+                            debug!("not sending a message to {}: {}", addr, err);
+                            continue;
+                        }
+                    },
+                };
+                messages.push((addr, payload));
+            }
+        }
+
+        // peers whose queue is full
+        let mut slow = vec![];
+        {
+            let mut queues = self.queues.lock().expect("outbound queues lock poisoned");
+            queues.retain(|addr, _| peers.contains(addr));
+
+            for (addr, payload) in messages {
+                let queue = queues
+                    .entry(addr.clone())
+                    .or_insert_with(|| self.spawn_sender(addr.clone()));
+                if let Err(TrySendError::Full(_)) = queue.try_send(payload) {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    slow.push(addr);
+                }
+            }
+
+            if self.opts.policy == SlowPeerPolicy::Disconnect {
+                for addr in &slow {
+                    queues.remove(addr);
+                }
+            }
+        }
+
+        for addr in slow {
+            match self.opts.policy {
+                SlowPeerPolicy::Drop => {
+                    debug!("dropping a message to {}, its outbound queue is full", addr);
+                }
+                SlowPeerPolicy::Disconnect => {
+                    let now = self.clock.now();
+                    let mut cm = self.conn_manager.lock().await;
+                    if let Err(err) = cm.disconnect(&addr, "can't keep up", now).await {
+                        warn!("could not disconnect slow peer {}: {}", addr, err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The task ends when the queue is closed
+    fn spawn_sender(&self, addr: NetAddr) -> mpsc::Sender<Payload> {
+        let (tx, mut rx) = mpsc::channel::<Payload>(self.opts.capacity.max(1));
+        let transport = self.transport.clone();
+
+        tokio::task::spawn(async move {
+            while let Some(payload) = rx.recv().await {
+                if let Err(err) = transport.send_message(&addr, payload).await {
+                    debug!("could not send to peer {}: {}", addr, err);
+                }
+            }
+        });
+        tx
+    }
+}
+
+// Number of peers of peer_count a new block is pushed to in full, at least one
+fn full_push_count(peer_count: usize) -> usize {
+    ((peer_count as f64).sqrt().ceil() as usize).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{
+        core::SystemClock,
+        network::{ConnectionManagerOpts, LocalTransport, MessageType, PeerId, Transport},
+    };
+    use tokio::time;
+
+    async fn connected(
+        tr: &LocalTransport,
+        peers: &[&LocalTransport],
+    ) -> Result<Arc<Mutex<ConnectionManager>>> {
+        let cm = Arc::new(Mutex::new(ConnectionManager::new(
+            Box::new(tr.clone()),
+            ConnectionManagerOpts::default(),
+        )));
+        for peer in peers {
+            tr.connect(Box::new((*peer).clone())).await?;
+            cm.lock()
+                .await
+                .on_message(&peer.addr(), Instant::now())
+                .await;
+        }
+        Ok(cm)
+    }
+
+    fn msg(n: u8) -> Message {
+        Message::new(MessageType::Tx, vec![n])
+    }
+
+    // Reads the data of everything the peer gets until nothing arrives for a while
+    async fn received(peer: &LocalTransport) -> Vec<u8> {
+        let mut data = vec![];
+        while let Ok(Some(rpc)) = time::timeout(Duration::from_millis(50), peer.recv()).await {
+            data.push(Message::from_payload(&rpc.payload).unwrap().data[0]);
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_connected_peers() -> Result<()> {
+        let tr = LocalTransport::new("A".into());
+        let b = LocalTransport::new("B".into());
+        let c = LocalTransport::new("C".into());
+        let cm = connected(&tr, &[&b, &c]).await?;
+        // the transport knows D, but the connection manager never accepted it
+        let d = LocalTransport::new("D".into());
+        tr.connect(Box::new(d.clone())).await?;
+
+        let broadcaster = Broadcaster::new(
+            Box::new(tr),
+            cm,
+            OutboundOpts::default(),
+            SystemClock::shared(),
+        );
+        broadcaster.broadcast(msg(1)).await?;
+        broadcaster.broadcast_except(msg(2), &b.addr()).await?;
+
+        assert_eq!(received(&b).await, vec![1]);
+        assert_eq!(received(&c).await, vec![1, 2]);
+        assert!(received(&d).await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_peer() -> Result<()> {
+        let tr = LocalTransport::new("A".into());
+        let fast = LocalTransport::new("FAST".into());
+        let slow = LocalTransport::new("SLOW".into());
+        let cm = connected(&tr, &[&fast, &slow]).await?;
+        let opts = OutboundOpts {
+            capacity: 4,
+            policy: SlowPeerPolicy::Drop,
+        };
+        let broadcaster = Broadcaster::new(Box::new(tr), cm.clone(), opts, SystemClock::shared());
+
+        // SLOW never reads, its sender task blocks once the channel of the LocalTransport is full
+        let reader = tokio::task::spawn(async move { received(&fast).await.len() });
+        let count = 2000;
+        for _ in 0..count {
+            broadcaster.broadcast(msg(1)).await?;
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(reader.await?, count);
+        assert!(broadcaster.dropped() > 0);
+        assert!(cm.lock().await.is_connected(&slow.addr()));
+
+        // with the disconnect policy the next overflow drops the peer
+        let broadcaster = Broadcaster {
+            opts: OutboundOpts {
+                policy: SlowPeerPolicy::Disconnect,
+                ..opts
+            },
+            ..broadcaster
+        };
+        for _ in 0..opts.capacity + 1 {
+            broadcaster.broadcast(msg(1)).await?;
+        }
+        assert!(!cm.lock().await.is_connected(&slow.addr()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_announcements() -> Result<()> {
+        let tr = LocalTransport::new("A".into());
+        let peers: Vec<LocalTransport> = (0..9)
+            .map(|i| LocalTransport::new(format!("P{i}").as_str().into()))
+            .collect();
+        // an older node, it doesn't know announcements
+        let old = LocalTransport::new("OLD".into());
+        let all: Vec<&LocalTransport> = peers.iter().chain([&old]).collect();
+        let cm = connected(&tr, &all).await?;
+
+        let handshakes = Arc::new(RwLock::new(HashMap::new()));
+        for peer in &peers {
+            let handshake = Handshake {
+                id: PeerId::default(),
+                version: 1,
+                features: FEATURE_BLOCK_ANNOUNCEMENTS,
+                height: 0,
+                lowest_block: 0,
+            };
+            handshakes.write().await.insert(peer.addr(), handshake);
+        }
+        let broadcaster = Broadcaster::new(
+            Box::new(tr),
+            cm,
+            OutboundOpts::default(),
+            SystemClock::shared(),
+        )
+        .with_handshakes(handshakes);
+
+        let except = peers[0].addr();
+        broadcaster
+            .broadcast_block(msg(1), msg(2), Some(&except))
+            .await?;
+
+        assert_eq!(received(&old).await, vec![1]);
+        assert!(received(&peers[0]).await.is_empty());
+        let mut got = vec![];
+        for peer in &peers[1..] {
+            got.extend(received(peer).await);
+        }
+        got.sort();
+        // 3 of the 9 receivers get the block, OLD being one of them
+        assert_eq!(got, vec![1, 1, 2, 2, 2, 2, 2, 2]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+/*
+FrameCodec implements the framing used by stream based transports (e.g. TCP).
+A stream has no message boundaries, a single read can return half a message or several messages at once.
+Every frame is therefore prefixed with its length:
+
+    | length: u32 (big endian) | message type: u16 (big endian) | payload |
+
+The length covers the message type and the payload. Frames larger than max_frame_size are rejected
+before their payload is buffered, so a peer can't make us allocate arbitrary amounts of memory.
+*/
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+const LENGTH_SIZE: usize = 4;
+const TYPE_SIZE: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub msg_type: u16,
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn new(msg_type: u16, payload: impl Into<Bytes>) -> Self {
+        Self {
+            msg_type,
+            payload: payload.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    // maximum size of message type + payload
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        if src.len() < LENGTH_SIZE {
+            return Ok(None);
+        }
+
+        let mut length_bytes = [0_u8; LENGTH_SIZE];
+        length_bytes.copy_from_slice(&src[..LENGTH_SIZE]);
+        let len = u32::from_be_bytes(length_bytes) as usize;
+
+        if len < TYPE_SIZE {
+            return Err(anyhow!("frame without message type"));
+        }
+
+        if len > self.max_frame_size {
+            return Err(anyhow!(
+                "frame of {} bytes exceeds the maximum of {} bytes",
+                len,
+                self.max_frame_size
+            ));
+        }
+
+        if src.len() < LENGTH_SIZE + len {
+            // the rest of the frame has not arrived yet
+            src.reserve(LENGTH_SIZE + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_SIZE);
+        let msg_type = src.get_u16();
+        let payload = src.split_to(len - TYPE_SIZE).freeze();
+
+        Ok(Some(Frame { msg_type, payload }))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<()> {
+        let len = TYPE_SIZE + frame.payload.len();
+
+        if len > self.max_frame_size {
+            return Err(anyhow!(
+                "frame of {} bytes exceeds the maximum of {} bytes",
+                len,
+                self.max_frame_size
+            ));
+        }
+
+        dst.reserve(LENGTH_SIZE + len);
+        dst.put_u32(len as u32);
+        dst.put_u16(frame.msg_type);
+        dst.put_slice(&frame.payload);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(codec: &mut FrameCodec, frames: Vec<Frame>) -> Result<BytesMut> {
+        let mut buf = BytesMut::new();
+        for frame in frames {
+            codec.encode(frame, &mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_encode_decode() -> Result<()> {
+        let mut codec = FrameCodec::default();
+        let frame = Frame::new(0x02, b"hello world".to_vec());
+
+        let mut buf = encode(&mut codec, vec![frame.clone()])?;
+        assert_eq!(buf.len(), LENGTH_SIZE + TYPE_SIZE + 11);
+
+        assert_eq!(codec.decode(&mut buf)?, Some(frame));
+        assert!(buf.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_message_types() -> Result<()> {
+        let mut codec = FrameCodec::default();
+        let frame = Frame::new(0x1234, b"unknown".to_vec());
+        let mut buf = encode(&mut codec, vec![frame.clone()])?;
+        assert_eq!(codec.decode(&mut buf)?, Some(frame));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_partial_reads() -> Result<()> {
+        let mut codec = FrameCodec::default();
+        let frame = Frame::new(0x01, vec![7_u8; 100]);
+        let encoded = encode(&mut codec, vec![frame.clone()])?;
+
+        // feed the frame one byte at a time
+        let mut buf = BytesMut::new();
+        for (i, byte) in encoded.iter().enumerate() {
+            buf.put_u8(*byte);
+            let decoded = codec.decode(&mut buf)?;
+            if i < encoded.len() - 1 {
+                assert_eq!(decoded, None);
+            } else {
+                assert_eq!(decoded, Some(frame.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_coalesced_frames() -> Result<()> {
+        let mut codec = FrameCodec::default();
+        let frames = vec![
+            Frame::new(0x01, b"foo".to_vec()),
+            Frame::new(0x02, vec![]),
+            Frame::new(0x03, b"bar".to_vec()),
+        ];
+        let mut buf = encode(&mut codec, frames.clone())?;
+
+        for frame in frames {
+            assert_eq!(codec.decode(&mut buf)?, Some(frame));
+        }
+        assert_eq!(codec.decode(&mut buf)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_frames() -> Result<()> {
+        let mut codec = FrameCodec::new(16);
+
+        let mut buf = BytesMut::new();
+        assert!(codec
+            .encode(Frame::new(0x01, vec![0_u8; 16]), &mut buf)
+            .is_err());
+        assert!(buf.is_empty());
+
+        // only the length prefix is needed to reject an oversized frame
+        buf.put_u32(1024);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(0);
+        assert!(codec.decode(&mut buf).is_err());
+
+        // a length that doesn't even cover the message type
+        let mut buf = BytesMut::new();
+        buf.put_u32(1);
+        buf.put_u8(0x01);
+        assert!(codec.decode(&mut buf).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1207 @@
+/*
+The ConnectionManager keeps track of the peers a Server is connected to.
+It enforces limits on the number of inbound and outbound peers, re-dials persistent peers with exponential backoff
+after they got disconnected and prunes peers we haven't heard from for longer than the peer timeout.
+A new node finds the network through bootnodes, static ones and those resolved from DNS seeds at startup. They're
+dialed while we have less than min_peers peers.
+Peers we dialed are asked for the addresses they know (peer exchange) right after connecting and every
+pex_interval. Learned addresses go into the address book and are dialed after the bootnodes, the ones that
+connected most often first. The address book is saved to address_book_path, so a restarted node reconnects
+without going through the bootnodes again.
+Every connected peer is pinged periodically, peers that don't answer in time are disconnected and the round trip
+time of answered pings is recorded as the peer's latency.
+Every change to the peer set is reported as a PeerEvent, so the Server can react to it.
+Misbehaving peers, e.g. those sending messages over the size limits (see DecodeLimits), are banned: they're
+disconnected and neither accepted nor dialed for ban_duration.
+*/
+
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::lookup_host, sync::RwLock};
+use tracing::{info, warn};
+
+use super::{
+    message_version, new_channel, BTransport, Channel, GetPeersMessage, Handshake, Message,
+    MessageType, NetAddr, PeerAddr, PeerInfo, PingMessage, PongMessage, RemotePeer,
+};
+use crate::core::{BincodeDecoder, BincodeEncoder, Decoder, Encoder};
+
+// number of addresses sent in and accepted from a PeersMessage
+pub const PEX_SAMPLE_SIZE: usize = 32;
+// addresses nobody heard from for longer are neither shared nor dialed
+pub const MAX_ADDRESS_AGE: Duration = Duration::from_secs(3 * 60 * 60);
+// the oldest addresses are dropped from a full address book
+pub const MAX_ADDRESS_BOOK_SIZE: usize = 1024;
+// a successful dial raises the score of an address and a failed one lowers it, addresses at the minimum are dropped
+pub const MAX_ADDRESS_SCORE: i32 = 10;
+pub const MIN_ADDRESS_SCORE: i32 = -5;
+// how often the Server saves the address book, it's also saved on shutdown
+pub const ADDRESS_BOOK_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // the peer connected to us
+    Inbound,
+    // we dialed the peer
+    Outbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Connected { addr: NetAddr, direction: Direction },
+    Disconnected { addr: NetAddr, reason: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionManagerOpts {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    // Peers that get re-dialed whenever we lose the connection to them
+    pub persistent_peers: Vec<BTransport>,
+    // Peers dialed to join the network, a RemotePeer for every address on a socket transport
+    pub bootnodes: Vec<BTransport>,
+    // host:port names resolved to more bootnodes at startup
+    pub dns_seeds: Vec<String>,
+    // Bootnodes are dialed while we have fewer peers
+    pub min_peers: usize,
+    // The first re-dial happens after dial_backoff, every failed attempt doubles it up to max_dial_backoff
+    pub dial_backoff: Duration,
+    pub max_dial_backoff: Duration,
+    // Peers that didn't send anything for this long are disconnected
+    pub peer_timeout: Duration,
+    pub ping_interval: Duration,
+    // Peers that don't answer a ping within this time are disconnected
+    pub pong_timeout: Duration,
+    // how often the peers we dialed are asked for addresses
+    pub pex_interval: Duration,
+    // file the address book is loaded from at startup and saved to
+    pub address_book_path: Option<PathBuf>,
+    pub tick_interval: Duration,
+    // how long a banned peer is kept out
+    pub ban_duration: Duration,
+}
+
+impl Default for ConnectionManagerOpts {
+    fn default() -> Self {
+        Self {
+            max_inbound: 32,
+            max_outbound: 8,
+            persistent_peers: vec![],
+            bootnodes: vec![],
+            dns_seeds: vec![],
+            min_peers: 4,
+            dial_backoff: Duration::from_secs(1),
+            max_dial_backoff: Duration::from_secs(60),
+            peer_timeout: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(15),
+            pong_timeout: Duration::from_secs(10),
+            pex_interval: Duration::from_secs(60),
+            address_book_path: None,
+            tick_interval: Duration::from_secs(1),
+            ban_duration: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    direction: Direction,
+    last_seen: Instant,
+    last_ping: Option<Instant>,
+    last_get_peers: Option<Instant>,
+    // nonce and send time of the ping we are waiting for an answer to
+    pending_ping: Option<(u64, Instant)>,
+    latency: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KnownAddress {
+    // when somebody last heard from the address
+    last_seen: Instant,
+    score: i32,
+}
+
+// An address book entry as it is saved, last_seen is in seconds since the unix epoch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedAddress {
+    pub addr: NetAddr,
+    pub last_seen: u64,
+    pub score: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Redial {
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+pub struct ConnectionManager {
+    transport: BTransport,
+    opts: ConnectionManagerOpts,
+    connections: HashMap<NetAddr, Connection>,
+    redials: HashMap<NetAddr, Redial>,
+    bootnodes: Vec<BTransport>,
+    // addresses of nodes we learned from peers or dialed before
+    address_book: HashMap<NetAddr, KnownAddress>,
+    // next dial of every bootnode and address we dialed before
+    dials: HashMap<NetAddr, Redial>,
+    // banned peers and when their ban ends
+    banned: HashMap<NetAddr, Instant>,
+    event_channel: Channel<PeerEvent>,
+    // the protocol versions of the peers, pings and peer requests are sent in them
+    handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>,
+}
+
+impl ConnectionManager {
+    pub fn new(transport: BTransport, opts: ConnectionManagerOpts) -> Self {
+        // persistent peers are dialed on the first tick
+        let now = Instant::now();
+        let redials = opts
+            .persistent_peers
+            .iter()
+            .map(|peer| {
+                (
+                    peer.addr(),
+                    Redial {
+                        attempts: 0,
+                        next_attempt: now,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            transport,
+            bootnodes: opts.bootnodes.clone(),
+            opts,
+            connections: HashMap::new(),
+            redials,
+            address_book: HashMap::new(),
+            dials: HashMap::new(),
+            banned: HashMap::new(),
+            event_channel: new_channel(1024),
+            handshakes: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // Without handshakes every message is sent in PROTOCOL_VERSION
+    pub fn with_handshakes(mut self, handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>) -> Self {
+        self.handshakes = handshakes;
+        self
+    }
+
+    // Adds bootnodes found after the start, e.g. from DNS seeds
+    pub fn add_bootnodes(&mut self, peers: Vec<BTransport>) {
+        for peer in peers {
+            if !self.bootnodes.iter().any(|b| b.addr() == peer.addr()) {
+                self.bootnodes.push(peer);
+            }
+        }
+    }
+
+    pub fn opts(&self) -> &ConnectionManagerOpts {
+        &self.opts
+    }
+
+    pub fn events(&self) -> Channel<PeerEvent> {
+        self.event_channel.clone()
+    }
+
+    pub fn is_connected(&self, addr: &NetAddr) -> bool {
+        self.connections.contains_key(addr)
+    }
+
+    pub fn connected(&self) -> Vec<NetAddr> {
+        self.connections.keys().cloned().collect()
+    }
+
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        self.connections
+            .iter()
+            .map(|(addr, conn)| PeerInfo {
+                addr: addr.clone(),
+                direction: conn.direction,
+                last_seen: conn.last_seen,
+                latency: conn.latency,
+                id: None,
+                version: None,
+                features: None,
+            })
+            .collect()
+    }
+
+    pub fn count(&self, direction: Direction) -> usize {
+        self.connections
+            .values()
+            .filter(|conn| conn.direction == direction)
+            .count()
+    }
+
+    // Removes the address from the address book, so it isn't dialed again
+    pub fn forget(&mut self, addr: &NetAddr) {
+        self.address_book.remove(addr);
+    }
+
+    pub fn known_addresses(&self) -> Vec<NetAddr> {
+        self.address_book.keys().cloned().collect()
+    }
+
+    // Adds the addresses of a PeersMessage to the address book. Only socket addresses can be dialed by other
+    // nodes, the names of local transports are ignored.
+    pub fn add_addresses(&mut self, peers: &[PeerAddr], now: Instant) {
+        for peer in peers.iter().take(PEX_SAMPLE_SIZE) {
+            let age = Duration::from_secs(peer.age_secs);
+            if !matches!(peer.addr, NetAddr::Socket(_))
+                || peer.addr == self.transport.addr()
+                || age > MAX_ADDRESS_AGE
+            {
+                continue;
+            }
+            if let Some(last_seen) = now.checked_sub(age) {
+                self.remember(peer.addr.clone(), last_seen);
+            }
+        }
+
+        self.evict_oldest();
+    }
+
+    // Addresses with their score, for the saved address book. Times are converted with unix_secs, the
+    // current wall clock time.
+    pub fn saved_addresses(&self, now: Instant, unix_secs: u64) -> Vec<SavedAddress> {
+        self.address_book
+            .iter()
+            .map(|(addr, known)| SavedAddress {
+                addr: addr.clone(),
+                last_seen: unix_secs
+                    .saturating_sub(now.saturating_duration_since(known.last_seen).as_secs()),
+                score: known.score,
+            })
+            .collect()
+    }
+
+    // Adds saved addresses to the address book, the same checks as for addresses from peers apply
+    pub fn restore_addresses(&mut self, saved: &[SavedAddress], now: Instant, unix_secs: u64) {
+        for entry in saved {
+            let age = Duration::from_secs(unix_secs.saturating_sub(entry.last_seen));
+            if !matches!(entry.addr, NetAddr::Socket(_))
+                || entry.addr == self.transport.addr()
+                || age > MAX_ADDRESS_AGE
+                || entry.score <= MIN_ADDRESS_SCORE
+            {
+                continue;
+            }
+            if let Some(last_seen) = now.checked_sub(age) {
+                self.remember(entry.addr.clone(), last_seen);
+                if let Some(known) = self.address_book.get_mut(&entry.addr) {
+                    known.score = entry.score.min(MAX_ADDRESS_SCORE);
+                }
+            }
+        }
+
+        self.evict_oldest();
+    }
+
+    pub fn save_address_book(&self, path: &Path, now: Instant, unix_secs: u64) -> Result<()> {
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&self.saved_addresses(now, unix_secs))?;
+
+        // written next to the old file and renamed, a crash while writing doesn't lose the address book
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, buf)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // Loads the address book saved at path, returns the number of addresses added. A missing file is an
+    // empty address book.
+    pub fn load_address_book(
+        &mut self,
+        path: &Path,
+        now: Instant,
+        unix_secs: u64,
+    ) -> Result<usize> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let mut saved: Vec<SavedAddress> = vec![];
+        BincodeDecoder::new(&mut &buf[..]).decode(&mut saved)?;
+
+        let before = self.address_book.len();
+        self.restore_addresses(&saved, now, unix_secs);
+        Ok(self.address_book.len().saturating_sub(before))
+    }
+
+    // A random sample of the fresh addresses we know, for the PeersMessage answering to
+    pub fn peer_sample(&self, to: &NetAddr, now: Instant) -> Vec<PeerAddr> {
+        let mut known: HashMap<&NetAddr, Instant> = self
+            .address_book
+            .iter()
+            .map(|(addr, known)| (addr, known.last_seen))
+            .collect();
+        // we know the peers we dialed are reachable, inbound peers may be behind a NAT
+        for (addr, conn) in &self.connections {
+            if conn.direction == Direction::Outbound && matches!(addr, NetAddr::Socket(_)) {
+                known.insert(addr, conn.last_seen);
+            }
+        }
+
+        let fresh: Vec<PeerAddr> = known
+            .into_iter()
+            .filter(|(addr, last_seen)| {
+                *addr != to && now.saturating_duration_since(*last_seen) <= MAX_ADDRESS_AGE
+            })
+            .map(|(addr, last_seen)| PeerAddr {
+                addr: addr.clone(),
+                age_secs: now.saturating_duration_since(last_seen).as_secs(),
+            })
+            .collect();
+
+        fresh
+            .choose_multiple(&mut rand::thread_rng(), PEX_SAMPLE_SIZE)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn dial(&mut self, peer: BTransport, now: Instant) -> Result<()> {
+        let addr = peer.addr();
+        if self.is_connected(&addr) {
+            return Ok(());
+        }
+
+        if self.is_banned(&addr, now) {
+            return Err(anyhow!("can't dial {addr}, the peer is banned"));
+        }
+
+        if self.count(Direction::Outbound) >= self.opts.max_outbound {
+            return Err(anyhow!(
+                "can't dial {addr}, reached the maximum of {} outbound peers",
+                self.opts.max_outbound
+            ));
+        }
+
+        self.transport.connect(peer).await?;
+        self.add_connection(addr, Direction::Outbound, now).await;
+
+        Ok(())
+    }
+
+    // Called for every message we receive, returns false if the message should be dropped
+    // because the sender is banned or would exceed the inbound peer limit.
+    pub async fn on_message(&mut self, from: &NetAddr, now: Instant) -> bool {
+        if self.is_banned(from, now) {
+            return false;
+        }
+
+        if let Some(conn) = self.connections.get_mut(from) {
+            conn.last_seen = now;
+            return true;
+        }
+
+        if self.count(Direction::Inbound) >= self.opts.max_inbound {
+            warn!(
+                "rejecting inbound peer {}, reached the maximum of {} inbound peers",
+                from, self.opts.max_inbound
+            );
+            return false;
+        }
+
+        self.add_connection(from.clone(), Direction::Inbound, now)
+            .await;
+        true
+    }
+
+    // Records the round trip time if the pong answers our outstanding ping
+    pub fn on_pong(
+        &mut self,
+        from: &NetAddr,
+        pong: &PongMessage,
+        now: Instant,
+    ) -> Option<Duration> {
+        let conn = self.connections.get_mut(from)?;
+
+        match conn.pending_ping {
+            Some((nonce, sent)) if nonce == pong.nonce => {
+                let latency = now.duration_since(sent);
+                conn.pending_ping = None;
+                conn.latency = Some(latency);
+                Some(latency)
+            }
+            _ => None,
+        }
+    }
+
+    pub async fn disconnect(&mut self, addr: &NetAddr, reason: &str, now: Instant) -> Result<()> {
+        let conn = match self.connections.remove(addr) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        if conn.direction == Direction::Outbound && matches!(addr, NetAddr::Socket(_)) {
+            self.remember(addr.clone(), conn.last_seen);
+        }
+
+        info!("disconnecting peer {}: {}", addr, reason);
+
+        if self.is_persistent(addr) {
+            self.redials.insert(
+                addr.clone(),
+                Redial {
+                    attempts: 0,
+                    next_attempt: now + self.opts.dial_backoff,
+                },
+            );
+        }
+
+        self.emit(PeerEvent::Disconnected {
+            addr: addr.clone(),
+            reason: reason.to_string(),
+        })
+        .await;
+
+        self.transport.disconnect(addr).await
+    }
+
+    // Disconnects the peer and keeps it out for ban_duration
+    pub async fn ban(&mut self, addr: &NetAddr, reason: &str, now: Instant) -> Result<()> {
+        warn!(
+            "banning peer {} for {:?}: {}",
+            addr, self.opts.ban_duration, reason
+        );
+        self.banned
+            .insert(addr.clone(), now + self.opts.ban_duration);
+        self.disconnect(addr, reason, now).await
+    }
+
+    pub fn is_banned(&self, addr: &NetAddr, now: Instant) -> bool {
+        self.banned.get(addr).is_some_and(|until| now < *until)
+    }
+
+    // Prunes timed out peers and expired bans and re-dials persistent peers whose backoff has elapsed
+    pub async fn tick(&mut self, now: Instant) -> Result<()> {
+        self.banned.retain(|_, until| now < *until);
+
+        let timed_out: Vec<NetAddr> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| now.duration_since(conn.last_seen) > self.opts.peer_timeout)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in timed_out {
+            self.disconnect(&addr, "timed out", now).await?;
+        }
+
+        let unresponsive: Vec<NetAddr> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| match conn.pending_ping {
+                Some((_, sent)) => now.duration_since(sent) > self.opts.pong_timeout,
+                None => false,
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in unresponsive {
+            self.disconnect(&addr, "ping timeout", now).await?;
+        }
+
+        self.ping_peers(now).await;
+        self.request_peers(now).await;
+        self.dial_new_peers(now).await;
+
+        let due: Vec<NetAddr> = self
+            .redials
+            .iter()
+            .filter(|(_, redial)| redial.next_attempt <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in due {
+            let peer = match self.persistent_peer(&addr) {
+                Some(peer) => peer,
+                None => continue,
+            };
+
+            match self.dial(peer, now).await {
+                Ok(()) => {
+                    self.redials.remove(&addr);
+                }
+                Err(err) => {
+                    let attempts = self.redials[&addr].attempts + 1;
+                    let backoff = self.backoff(attempts);
+                    self.redials.insert(
+                        addr.clone(),
+                        Redial {
+                            attempts,
+                            next_attempt: now + backoff,
+                        },
+                    );
+
+                    warn!(
+                        "could not dial persistent peer {} (attempt {}), retrying in {:?}: {}",
+                        addr, attempts, backoff, err
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Dials as many bootnodes and then addresses of the address book, the best scored and then freshest first, as
+    // we are missing peers.
+    // An address that failed waits for its backoff and one that connected for dial_backoff, so a peer that keeps
+    // dropping us isn't dialed on every tick.
+    async fn dial_new_peers(&mut self, now: Instant) {
+        let missing = self.opts.min_peers.saturating_sub(self.connections.len());
+        if missing == 0 {
+            return;
+        }
+
+        let mut known: Vec<(&NetAddr, &KnownAddress)> = self
+            .address_book
+            .iter()
+            .filter(|(_, known)| now.saturating_duration_since(known.last_seen) <= MAX_ADDRESS_AGE)
+            .collect();
+        known.sort_by_key(|(_, known)| std::cmp::Reverse((known.score, known.last_seen)));
+        let learned = known
+            .into_iter()
+            .map(|(addr, _)| -> BTransport { Box::new(RemotePeer::new(addr.clone())) });
+
+        let candidates: Vec<BTransport> = self
+            .bootnodes
+            .iter()
+            .cloned()
+            .chain(learned)
+            .filter(|peer| {
+                let addr = peer.addr();
+                addr != self.transport.addr()
+                    && !self.is_connected(&addr)
+                    && self
+                        .dials
+                        .get(&addr)
+                        .is_none_or(|dial| dial.next_attempt <= now)
+            })
+            .take(missing)
+            .collect();
+
+        for peer in candidates {
+            let addr = peer.addr();
+            let attempts = match self.dial(peer, now).await {
+                Ok(()) => {
+                    self.adjust_score(&addr, 1);
+                    0
+                }
+                Err(err) => {
+                    let attempts = self.dials.get(&addr).map_or(0, |d| d.attempts) + 1;
+                    warn!("could not dial {} (attempt {}): {}", addr, attempts, err);
+                    self.adjust_score(&addr, -1);
+                    attempts
+                }
+            };
+            self.dials.insert(
+                addr,
+                Redial {
+                    attempts,
+                    next_attempt: now + self.backoff(attempts),
+                },
+            );
+        }
+    }
+
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempts);
+        self.opts
+            .dial_backoff
+            .saturating_mul(factor)
+            .min(self.opts.max_dial_backoff)
+    }
+
+    async fn ping_peers(&mut self, now: Instant) {
+        let mut pings = vec![];
+
+        for (addr, conn) in self.connections.iter_mut() {
+            let due = match conn.last_ping {
+                Some(last_ping) => now.duration_since(last_ping) >= self.opts.ping_interval,
+                None => true,
+            };
+
+            if due && conn.pending_ping.is_none() {
+                let nonce = rand::random();
+                conn.last_ping = Some(now);
+                conn.pending_ping = Some((nonce, now));
+                pings.push((addr.clone(), PingMessage::new(nonce)));
+            }
+        }
+
+        for (addr, ping) in pings {
+            if let Err(err) = self.send_ping(&addr, &ping).await {
+                warn!("could not ping {}: {}", addr, err);
+            }
+        }
+    }
+
+    // Asks the peers we dialed for addresses, right after connecting and then every pex_interval
+    async fn request_peers(&mut self, now: Instant) {
+        let mut due = vec![];
+
+        for (addr, conn) in self.connections.iter_mut() {
+            let is_due = match conn.last_get_peers {
+                Some(last) => now.duration_since(last) >= self.opts.pex_interval,
+                None => true,
+            };
+            if conn.direction == Direction::Outbound && is_due {
+                conn.last_get_peers = Some(now);
+                due.push(addr.clone());
+            }
+        }
+
+        for addr in due {
+            if let Err(err) = self.send_get_peers(&addr).await {
+                warn!("could not ask {} for peers: {}", addr, err);
+            }
+        }
+    }
+
+    async fn send_get_peers(&self, to: &NetAddr) -> Result<()> {
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&GetPeersMessage::default())?;
+
+        let version = message_version(&self.handshakes, to).await;
+        let msg = Message::new(MessageType::GetPeers, buf);
+        self.transport
+            .send_message(to, msg.encode_in(version)?)
+            .await
+    }
+
+    // Keeps the fresher of two sightings of an address
+    fn remember(&mut self, addr: NetAddr, last_seen: Instant) {
+        let entry = self.address_book.entry(addr).or_insert(KnownAddress {
+            last_seen,
+            score: 0,
+        });
+        entry.last_seen = entry.last_seen.max(last_seen);
+    }
+
+    // Only addresses of the address book are scored, bootnodes are always kept
+    fn adjust_score(&mut self, addr: &NetAddr, delta: i32) {
+        if let Some(known) = self.address_book.get_mut(addr) {
+            known.score = (known.score + delta).min(MAX_ADDRESS_SCORE);
+            if known.score <= MIN_ADDRESS_SCORE {
+                info!(
+                    "dropping {} from the address book, too many failed dials",
+                    addr
+                );
+                self.address_book.remove(addr);
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        while self.address_book.len() > MAX_ADDRESS_BOOK_SIZE {
+            let oldest = self
+                .address_book
+                .iter()
+                .min_by_key(|(_, known)| known.last_seen)
+                .map(|(addr, _)| addr.clone());
+            if let Some(addr) = oldest {
+                self.address_book.remove(&addr);
+            }
+        }
+    }
+
+    async fn send_ping(&self, to: &NetAddr, ping: &PingMessage) -> Result<()> {
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(ping)?;
+
+        let version = message_version(&self.handshakes, to).await;
+        let msg = Message::new(MessageType::Ping, buf);
+        self.transport
+            .send_message(to, msg.encode_in(version)?)
+            .await
+    }
+
+    fn is_persistent(&self, addr: &NetAddr) -> bool {
+        self.persistent_peer(addr).is_some()
+    }
+
+    fn persistent_peer(&self, addr: &NetAddr) -> Option<BTransport> {
+        self.opts
+            .persistent_peers
+            .iter()
+            .find(|peer| &peer.addr() == addr)
+            .cloned()
+    }
+
+    async fn add_connection(&mut self, addr: NetAddr, direction: Direction, now: Instant) {
+        self.connections.insert(
+            addr.clone(),
+            Connection {
+                direction,
+                last_seen: now,
+                last_ping: None,
+                last_get_peers: None,
+                pending_ping: None,
+                latency: None,
+            },
+        );
+        self.emit(PeerEvent::Connected { addr, direction }).await;
+    }
+
+    async fn emit(&self, event: PeerEvent) {
+        if let Err(err) = self.event_channel.0.send(event).await {
+            warn!("could not emit peer event: {err}");
+        }
+    }
+}
+
+// Resolves host:port names to bootnode addresses, names that don't resolve are skipped
+pub async fn resolve_dns_seeds(seeds: &[String]) -> Vec<BTransport> {
+    let mut addrs: Vec<NetAddr> = vec![];
+
+    for seed in seeds {
+        match lookup_host(seed.as_str()).await {
+            Ok(resolved) => addrs.extend(resolved.map(NetAddr::from)),
+            Err(err) => warn!("could not resolve DNS seed {}: {}", seed, err),
+        }
+    }
+    addrs.sort();
+    addrs.dedup();
+
+    addrs
+        .into_iter()
+        .map(|addr| -> BTransport { Box::new(RemotePeer::new(addr)) })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{default_rpc_decode_fn, DecodedMessageData, LocalTransport};
+
+    fn transport(addr: &str) -> BTransport {
+        Box::new(LocalTransport::new(addr.into()))
+    }
+
+    async fn next_event(cm: &ConnectionManager) -> Option<PeerEvent> {
+        cm.events().1.lock().await.try_recv().ok()
+    }
+
+    #[tokio::test]
+    async fn test_outbound_limit() -> Result<()> {
+        let opts = ConnectionManagerOpts {
+            max_outbound: 1,
+            ..Default::default()
+        };
+        let tr = transport("A");
+        let mut cm = ConnectionManager::new(tr.clone(), opts);
+        let now = Instant::now();
+
+        cm.dial(transport("B"), now).await?;
+        assert!(cm.dial(transport("C"), now).await.is_err());
+
+        assert_eq!(cm.count(Direction::Outbound), 1);
+        assert!(tr.peers().await.contains_key(&"B".into()));
+        assert_eq!(
+            next_event(&cm).await,
+            Some(PeerEvent::Connected {
+                addr: "B".into(),
+                direction: Direction::Outbound
+            })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inbound_limit() -> Result<()> {
+        let opts = ConnectionManagerOpts {
+            max_inbound: 1,
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("A"), opts);
+        let now = Instant::now();
+
+        assert!(cm.on_message(&"B".into(), now).await);
+        assert!(cm.on_message(&"B".into(), now).await);
+        assert!(!cm.on_message(&"C".into(), now).await);
+        assert_eq!(cm.connected(), vec![NetAddr::from("B")]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ban() -> Result<()> {
+        let mut cm = ConnectionManager::new(transport("A"), ConnectionManagerOpts::default());
+        let now = Instant::now();
+        let ban = cm.opts().ban_duration;
+
+        assert!(cm.on_message(&"B".into(), now).await);
+        cm.ban(&"B".into(), "oversized message", now).await?;
+        assert!(!cm.is_connected(&"B".into()));
+
+        // neither accepted nor dialed until the ban ends
+        assert!(!cm.on_message(&"B".into(), now + ban / 2).await);
+        assert!(cm.dial(transport("B"), now + ban / 2).await.is_err());
+        assert!(cm.connected().is_empty());
+
+        assert!(cm.on_message(&"B".into(), now + ban).await);
+        assert!(cm.is_connected(&"B".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_timed_out_peers() -> Result<()> {
+        let mut cm = ConnectionManager::new(transport("A"), ConnectionManagerOpts::default());
+        let now = Instant::now();
+        let timeout = cm.opts().peer_timeout;
+
+        cm.on_message(&"B".into(), now).await;
+        cm.on_message(&"C".into(), now).await;
+        cm.on_message(&"C".into(), now + timeout).await;
+        cm.tick(now + timeout + Duration::from_secs(1)).await?;
+
+        assert!(!cm.is_connected(&"B".into()));
+        assert!(cm.is_connected(&"C".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_redial_persistent_peers() -> Result<()> {
+        let opts = ConnectionManagerOpts {
+            persistent_peers: vec![transport("B")],
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("A"), opts);
+        let now = Instant::now();
+
+        cm.tick(now).await?;
+        assert!(cm.is_connected(&"B".into()));
+
+        cm.disconnect(&"B".into(), "test", now).await?;
+        assert!(!cm.is_connected(&"B".into()));
+
+        // the peer is re-dialed once the backoff elapsed
+        cm.tick(now).await?;
+        assert!(!cm.is_connected(&"B".into()));
+        cm.tick(now + cm.opts().dial_backoff).await?;
+        assert!(cm.is_connected(&"B".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_pong() -> Result<()> {
+        let tr_b = transport("B");
+        let mut cm = ConnectionManager::new(transport("A"), ConnectionManagerOpts::default());
+        let now = Instant::now();
+
+        cm.dial(tr_b.clone(), now).await?;
+        cm.tick(now).await?;
+
+        let rpc = tr_b.recv().await.unwrap();
+        let ping = match default_rpc_decode_fn(rpc)?.data {
+            DecodedMessageData::Ping(ping) => ping,
+            data => panic!("expected ping, got {data:?}"),
+        };
+
+        // a pong with the wrong nonce is ignored
+        let mut wrong = ping.pong();
+        wrong.nonce += 1;
+        assert_eq!(cm.on_pong(&"B".into(), &wrong, now), None);
+
+        let latency = Duration::from_millis(20);
+        assert_eq!(
+            cm.on_pong(&"B".into(), &ping.pong(), now + latency),
+            Some(latency)
+        );
+        assert_eq!(cm.peer_info()[0].latency, Some(latency));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_unresponsive_peers() -> Result<()> {
+        let mut cm = ConnectionManager::new(transport("A"), ConnectionManagerOpts::default());
+        let now = Instant::now();
+        let pong_timeout = cm.opts().pong_timeout;
+
+        cm.dial(transport("B"), now).await?;
+        cm.tick(now).await?;
+
+        // B keeps sending messages but never answers the ping
+        let later = now + pong_timeout + Duration::from_secs(1);
+        cm.on_message(&"B".into(), later).await;
+        cm.tick(later).await?;
+
+        assert!(!cm.is_connected(&"B".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dial_bootnodes() -> Result<()> {
+        let opts = ConnectionManagerOpts {
+            bootnodes: vec![transport("A"), transport("B"), transport("C")],
+            min_peers: 2,
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("A"), opts);
+        let now = Instant::now();
+
+        // we don't dial ourselves and stop at min_peers
+        cm.tick(now).await?;
+        assert_eq!(cm.count(Direction::Outbound), 2);
+        assert!(cm.is_connected(&"B".into()) && cm.is_connected(&"C".into()));
+
+        cm.add_bootnodes(vec![transport("D"), transport("B")]);
+        cm.disconnect(&"B".into(), "test", now).await?;
+        cm.tick(now).await?;
+        assert!(cm.is_connected(&"D".into()));
+        assert!(!cm.is_connected(&"B".into()));
+
+        // B is only dialed again after the backoff
+        cm.disconnect(&"D".into(), "test", now).await?;
+        cm.tick(now).await?;
+        assert_eq!(cm.count(Direction::Outbound), 1);
+        cm.tick(now + cm.opts().dial_backoff).await?;
+        assert_eq!(cm.count(Direction::Outbound), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_peers() -> Result<()> {
+        let tr_b = transport("B");
+        // shorter than the pong timeout, B never answers pings
+        let opts = ConnectionManagerOpts {
+            pex_interval: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("A"), opts);
+        let now = Instant::now();
+        let interval = cm.opts().pex_interval;
+
+        let get_peers_sent = || async {
+            let mut count = 0;
+            while let Ok(rpc) = tr_b.consume().1.lock().await.try_recv() {
+                if let DecodedMessageData::GetPeersMessage = default_rpc_decode_fn(rpc)?.data {
+                    count += 1;
+                }
+            }
+            Ok::<_, anyhow::Error>(count)
+        };
+
+        // inbound peers aren't asked
+        cm.on_message(&"C".into(), now).await;
+        cm.dial(tr_b.clone(), now).await?;
+        cm.tick(now).await?;
+        assert_eq!(get_peers_sent().await?, 1);
+
+        cm.tick(now + interval / 2).await?;
+        assert_eq!(get_peers_sent().await?, 0);
+        cm.tick(now + interval).await?;
+        assert_eq!(get_peers_sent().await?, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_address_book() -> Result<()> {
+        let opts = ConnectionManagerOpts {
+            min_peers: 1,
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("10.0.0.1:3000"), opts);
+        let now = Instant::now() + MAX_ADDRESS_AGE * 2;
+
+        let peer = |addr: &str, age_secs: u64| PeerAddr {
+            addr: addr.into(),
+            age_secs,
+        };
+        cm.add_addresses(
+            &[
+                peer("10.0.0.2:3000", 600),
+                peer("10.0.0.3:3000", 60),
+                // a name of a local transport, ourselves and an address nobody saw for too long
+                peer("B", 0),
+                peer("10.0.0.1:3000", 0),
+                peer("10.0.0.4:3000", MAX_ADDRESS_AGE.as_secs() + 1),
+            ],
+            now,
+        );
+        let mut known = cm.known_addresses();
+        known.sort();
+        assert_eq!(known, vec!["10.0.0.2:3000".into(), "10.0.0.3:3000".into()]);
+
+        // a fresher sighting replaces an older one
+        cm.add_addresses(&[peer("10.0.0.2:3000", 10)], now);
+        let mut sample = cm.peer_sample(&"10.0.0.3:3000".into(), now);
+        sample.sort_by(|a, b| a.addr.cmp(&b.addr));
+        assert_eq!(sample, vec![peer("10.0.0.2:3000", 10)]);
+
+        // the freshest address is dialed first
+        cm.tick(now).await?;
+        assert_eq!(cm.connected(), vec!["10.0.0.2:3000".into()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_address_book() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("projectx-peers-{}", rand::random::<u64>()));
+        let now = Instant::now() + MAX_ADDRESS_AGE * 2;
+        let unix_secs = 1_000_000;
+
+        let mut cm = ConnectionManager::new(transport("10.0.0.1:3000"), Default::default());
+        assert_eq!(cm.load_address_book(&path, now, unix_secs)?, 0);
+
+        cm.add_addresses(
+            &[
+                PeerAddr {
+                    addr: "10.0.0.2:3000".into(),
+                    age_secs: 600,
+                },
+                PeerAddr {
+                    addr: "10.0.0.3:3000".into(),
+                    age_secs: 60,
+                },
+            ],
+            now,
+        );
+        cm.save_address_book(&path, now, unix_secs)?;
+
+        // the restarted node loads the addresses, they aged while it was down
+        let later = now + Duration::from_secs(100);
+        let mut restarted = ConnectionManager::new(transport("10.0.0.1:3000"), Default::default());
+        assert_eq!(
+            restarted.load_address_book(&path, later, unix_secs + 100)?,
+            2
+        );
+        let mut saved = restarted.saved_addresses(later, unix_secs + 100);
+        saved.sort_by(|a, b| a.addr.cmp(&b.addr));
+        assert_eq!(
+            saved,
+            vec![
+                SavedAddress {
+                    addr: "10.0.0.2:3000".into(),
+                    last_seen: unix_secs - 600,
+                    score: 0,
+                },
+                SavedAddress {
+                    addr: "10.0.0.3:3000".into(),
+                    last_seen: unix_secs - 60,
+                    score: 0,
+                },
+            ]
+        );
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_address_scores() -> Result<()> {
+        let opts = ConnectionManagerOpts {
+            min_peers: 1,
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("10.0.0.1:3000"), opts);
+        let now = Instant::now() + MAX_ADDRESS_AGE * 2;
+        let unix_secs = 1_000_000;
+
+        let saved = |addr: &str, age: u64, score: i32| SavedAddress {
+            addr: addr.into(),
+            last_seen: unix_secs - age,
+            score,
+        };
+        cm.restore_addresses(
+            &[
+                saved("10.0.0.2:3000", 10, 0),
+                saved("10.0.0.3:3000", 600, 3),
+                // failed too often and nobody saw it for too long
+                saved("10.0.0.4:3000", 10, MIN_ADDRESS_SCORE),
+                saved("10.0.0.5:3000", MAX_ADDRESS_AGE.as_secs() + 1, 0),
+            ],
+            now,
+            unix_secs,
+        );
+        let mut known = cm.known_addresses();
+        known.sort();
+        assert_eq!(known, vec!["10.0.0.2:3000".into(), "10.0.0.3:3000".into()]);
+
+        // the best scored address is dialed first and its score goes up
+        cm.tick(now).await?;
+        assert_eq!(cm.connected(), vec!["10.0.0.3:3000".into()]);
+        let score = |cm: &ConnectionManager, addr: &str| {
+            cm.saved_addresses(now, unix_secs)
+                .into_iter()
+                .find(|saved| saved.addr == addr.into())
+                .map(|saved| saved.score)
+        };
+        assert_eq!(score(&cm, "10.0.0.3:3000"), Some(4));
+
+        // failed dials lower the score until the address is dropped
+        let opts = ConnectionManagerOpts {
+            min_peers: 1,
+            max_outbound: 0,
+            dial_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut cm = ConnectionManager::new(transport("10.0.0.1:3000"), opts);
+        cm.restore_addresses(&[saved("10.0.0.2:3000", 10, 0)], now, unix_secs);
+        cm.tick(now).await?;
+        assert_eq!(score(&cm, "10.0.0.2:3000"), Some(-1));
+        for _ in 0..4 {
+            cm.tick(now).await?;
+        }
+        assert!(cm.known_addresses().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dns_seeds() -> Result<()> {
+        let seeds = vec![
+            "localhost:3000".to_string(),
+            "localhost:3000".to_string(),
+            "no port".to_string(),
+        ];
+        let bootnodes = resolve_dns_seeds(&seeds).await;
+
+        assert!(!bootnodes.is_empty());
+        for bootnode in &bootnodes {
+            match bootnode.addr() {
+                NetAddr::Socket(addr) => {
+                    assert!(addr.ip().is_loopback());
+                    assert_eq!(addr.port(), 3000);
+                }
+                addr => panic!("expected a socket address, got {addr}"),
+            }
+        }
+        // the duplicate seed adds nothing
+        assert!(bootnodes.len() <= 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff() {
+        let cm = ConnectionManager::new(transport("A"), ConnectionManagerOpts::default());
+
+        assert_eq!(cm.backoff(0), Duration::from_secs(1));
+        assert_eq!(cm.backoff(3), Duration::from_secs(8));
+        assert_eq!(cm.backoff(100), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,184 @@
+/*
+Decoding a large block takes a while, so the server doesn't decode RPC messages in its loop anymore. The DecodePool
+decodes them on at most `workers` blocking threads and pushes the results into a priority queue (see priority.rs),
+which hands them to the server loop. The workers also pre-validate the decoded blocks, see block_verifier.rs.
+
+Both stages are bounded. If the queue is full the workers wait, and if all workers are busy decode() waits, which
+in turn fills the rpc channels of the server and slows down the transports.
+
+Senders of messages over the DecodeLimits are reported on the offenders channel, so the server can ban them.
+*/
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::error;
+
+use super::{
+    BlockVerifier, DecodedMessage, NetAddr, OversizedMessage, Priority, PrioritySender,
+    RPCDecodeFn, RPC,
+};
+
+pub const DEFAULT_DECODE_WORKERS: usize = 4;
+
+pub struct DecodePool {
+    decode_fn: Arc<RPCDecodeFn>,
+    verifier: BlockVerifier,
+    workers: Arc<Semaphore>,
+    queue: PrioritySender<DecodedMessage>,
+    offenders: Option<mpsc::Sender<(NetAddr, OversizedMessage)>>,
+}
+
+impl DecodePool {
+    pub fn new(
+        decode_fn: RPCDecodeFn,
+        verifier: BlockVerifier,
+        workers: usize,
+        queue: PrioritySender<DecodedMessage>,
+    ) -> Self {
+        Self {
+            decode_fn: Arc::new(decode_fn),
+            verifier,
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            queue,
+            offenders: None,
+        }
+    }
+
+    pub fn with_offenders(mut self, offenders: mpsc::Sender<(NetAddr, OversizedMessage)>) -> Self {
+        self.offenders = Some(offenders);
+        self
+    }
+
+    // Waits for a free worker and decodes rpc on it. Messages that can't be decoded or carry a block failing the
+    // pre-validation are logged and dropped.
+    pub async fn decode(&self, rpc: RPC) {
+        let Ok(permit) = self.workers.clone().acquire_owned().await else {
+            return;
+        };
+
+        let decode_fn = self.decode_fn.clone();
+        let verifier = self.verifier.clone();
+        let queue = self.queue.clone();
+        let offenders = self.offenders.clone();
+        let from = rpc.from.clone();
+        tokio::task::spawn(async move {
+            let decode = move || -> Result<DecodedMessage> {
+                let mut msg = decode_fn(rpc)?;
+                verifier.verify_message(&mut msg.data)?;
+                Ok(msg)
+            };
+            match tokio::task::spawn_blocking(decode).await {
+                Ok(Ok(msg)) => {
+                    queue.send(Priority::of(&msg.data), msg).await;
+                }
+                Ok(Err(err)) => {
+                    error!("RPC Decoding Error: {err}");
+                    if let (Some(offenders), Some(oversized)) =
+                        (offenders, err.downcast_ref::<OversizedMessage>())
+                    {
+                        let _ = offenders.send((from, oversized.clone())).await;
+                    }
+                }
+                Err(err) => error!("RPC decoder failed: {err}"),
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{Block, SigCache, Transaction},
+        network::{
+            default_rpc_decode_fn, priority_queue, rpc_decode_fn_with_limits, DecodeLimits,
+            DecodedMessageData, DropCounters, Message, MessageType, OverflowPolicies,
+            QueueCapacities, DEFAULT_MAX_BLOCK_SIZE,
+        },
+        test_utils::{encoded, random_block},
+        types::Hash,
+    };
+    use anyhow::Result;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_decode() -> Result<()> {
+        let (tx, mut rx) = priority_queue(
+            QueueCapacities::default(),
+            OverflowPolicies::default(),
+            DropCounters::default(),
+        );
+        let verifier = BlockVerifier::new(SigCache::default(), DEFAULT_MAX_BLOCK_SIZE);
+        let pool = DecodePool::new(Box::new(default_rpc_decode_fn), verifier, 2, tx);
+
+        let valid = Message::new(MessageType::Tx, encoded(&Transaction::new(vec![1, 2, 3]))?);
+        let block = random_block(1, Hash::default())?;
+        // the signature doesn't cover the changed header
+        let mut forged = block.clone();
+        forged.header.height = 2;
+        let payloads = [
+            Bytes::from_static(&[0xff, 0xff]),
+            valid.bytes()?,
+            Message::new(MessageType::Block, encoded(&block)?).bytes()?,
+            Message::new(MessageType::Block, encoded(&forged)?).bytes()?,
+        ];
+        for payload in payloads {
+            pool.decode(RPC {
+                from: "A".into(),
+                payload,
+            })
+            .await;
+        }
+        drop(pool);
+
+        // the message that can't be decoded and the forged block are dropped
+        let mut txx = vec![];
+        let mut blocks: Vec<Block> = vec![];
+        while let Some(msg) = rx.recv().await {
+            match msg.data {
+                DecodedMessageData::Tx(tx) => txx.push(tx),
+                DecodedMessageData::Block(block) => blocks.push(block),
+                _ => panic!("unexpected message"),
+            }
+        }
+        assert_eq!(txx.len(), 1);
+        assert_eq!(txx[0].data, vec![1, 2, 3]);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].header, block.header);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_report_oversized() -> Result<()> {
+        let (tx, _rx) = priority_queue(
+            QueueCapacities::default(),
+            OverflowPolicies::default(),
+            DropCounters::default(),
+        );
+        let (offenders_tx, mut offenders) = mpsc::channel(1);
+        let limits = DecodeLimits {
+            max_tx_size: 64,
+            ..Default::default()
+        };
+        let verifier = BlockVerifier::new(SigCache::default(), DEFAULT_MAX_BLOCK_SIZE);
+        let pool = DecodePool::new(rpc_decode_fn_with_limits(limits), verifier, 1, tx)
+            .with_offenders(offenders_tx);
+
+        let tx = Transaction::new(vec![0; 128]);
+        pool.decode(RPC {
+            from: "A".into(),
+            payload: Message::new(MessageType::Tx, encoded(&tx)?).bytes()?,
+        })
+        .await;
+
+        let (from, oversized) = offenders.recv().await.unwrap();
+        assert_eq!(from, NetAddr::from("A"));
+        assert_eq!(oversized.what, "transaction");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,840 @@
+/*
+The Go projectx nodes encode their messages with gob, which isn't specified outside of Go. A Server switched to the Go
+wire format (see use_go_wire_format) speaks the following encoding instead, a Go node joins a shared testnet by
+encoding its types the same way. Integers are big endian, the transports frame the messages as usual.
+
+    message      | type: u8 | data |
+    bytes        | length: u32 | bytes |
+    key          | present: u8 | compressed SEC1 point: 33 |
+    signature    | present: u8 | r: 32 | s: 32 |
+    header       | version: u32 | data hash: 32 | previous block hash: 32 | timestamp: i64 | height: u32 |
+    transaction  | data: bytes | to: key | value: u64 | from: key | signature | nonce: i64 |
+    block        | header | count: u32 | transaction... | validator: key | signature |
+
+    0x1 Tx         transaction
+    0x2 Block      block
+    0x3 GetBlocks  | from: u32 | to: u32 |
+    0x4 Status     | id: bytes | version: u32 | current height: u32 |
+    0x5 GetStatus  nothing
+    0x6 Blocks     | count: u32 | block... |
+
+The hashes follow the Go node: a header hashes to the SHA-256 of its encoding, the data hash of a block is the SHA-256
+of its encoded transactions and a transaction hashes to the SHA-256 of data | to | value | from | nonce, with the
+integers little endian and an absent key left out. A block signs its encoded header and a transaction its data. Like
+Go's ecdsa.Sign, the signed message is used as the digest, cut to 32 bytes or padded with zeros on the left. A block
+signature therefore only covers the version and the start of the data hash.
+
+Only these messages are translated. Pings, consensus messages and peer exchange are dropped, a transaction batch is
+sent as single transactions. Blocks and transactions keep the hashes and signatures of the chain they were made for,
+so both nodes learn each others heights and decode each others blocks and transactions, but only accept the ones
+following their own rules.
+*/
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::BufMut;
+use sha2::{Digest, Sha256};
+
+use super::{
+    transport::Transport, BTransport, BlocksMessage, Channel, ConnectionManagerOpts,
+    DecodedMessage, DecodedMessageData, GetBlocksMessage, Message, MessageType, NetAddr, Payload,
+    PeerId, ServerOpts, StatusMessage, TxBatchMessage, DEFAULT_GOSSIP_TTL, PROTOCOL_VERSION, RPC,
+};
+use crate::{
+    core::{Block, BlockSignature, Header, Transaction, TxKind},
+    crypto::{PrivateKey, PublicKey, Signature, PUBLIC_KEY_SIZE, SIGNATURE_SIZE},
+    types::Hash,
+};
+
+// the message type ids of the Go node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GoMessageType {
+    Tx = 0x1,
+    Block = 0x2,
+    GetBlocks = 0x3,
+    Status = 0x4,
+    GetStatus = 0x5,
+    Blocks = 0x6,
+}
+
+impl TryFrom<u8> for GoMessageType {
+    type Error = anyhow::Error;
+
+    fn try_from(id: u8) -> Result<Self> {
+        match id {
+            0x1 => Ok(Self::Tx),
+            0x2 => Ok(Self::Block),
+            0x3 => Ok(Self::GetBlocks),
+            0x4 => Ok(Self::Status),
+            0x5 => Ok(Self::GetStatus),
+            0x6 => Ok(Self::Blocks),
+            id => Err(anyhow!("unknown go message type {id:#x}")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GoHeader {
+    pub version: u32,
+    pub data_hash: Hash,
+    // zero for the genesis block
+    pub prev_block_hash: Hash,
+    pub timestamp: i64,
+    pub height: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GoTransaction {
+    pub data: Vec<u8>,
+    // the receiver of a value transfer
+    pub to: Option<PublicKey>,
+    pub value: u64,
+    pub from: Option<PublicKey>,
+    pub signature: Option<Signature>,
+    pub nonce: i64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GoBlock {
+    pub header: GoHeader,
+    pub transactions: Vec<GoTransaction>,
+    pub validator: Option<PublicKey>,
+    pub signature: Option<Signature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoStatusMessage {
+    pub id: String,
+    pub version: u32,
+    pub current_height: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GoMessage {
+    Tx(GoTransaction),
+    Block(GoBlock),
+    GetBlocks(GetBlocksMessage),
+    Status(GoStatusMessage),
+    GetStatus,
+    Blocks(Vec<GoBlock>),
+}
+
+impl GoHeader {
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(76);
+        self.encode(&mut buf);
+        buf
+    }
+
+    pub fn hash(&self) -> Hash {
+        Hash::from_digest(Sha256::digest(self.bytes()).into())
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(self.version);
+        buf.put_slice(&self.data_hash.into_bytes());
+        buf.put_slice(&self.prev_block_hash.into_bytes());
+        buf.put_i64(self.timestamp);
+        buf.put_u32(self.height);
+    }
+
+    fn decode(r: &mut GoReader) -> Result<Self> {
+        Ok(Self {
+            version: r.u32()?,
+            data_hash: r.hash()?,
+            prev_block_hash: r.hash()?,
+            timestamp: r.i64()?,
+            height: r.u32()?,
+        })
+    }
+}
+
+impl GoTransaction {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ..Self::default()
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        let mut buf = self.data.clone();
+        if let Some(to) = self.to {
+            buf.put_slice(&to.to_bytes());
+        }
+        buf.put_u64_le(self.value);
+        if let Some(from) = self.from {
+            buf.put_slice(&from.to_bytes());
+        }
+        buf.put_i64_le(self.nonce);
+        Hash::from_digest(Sha256::digest(buf).into())
+    }
+
+    pub fn sign(&mut self, private_key: &PrivateKey) -> Result<()> {
+        self.signature = Some(private_key.sign_prehash(&go_digest(&self.data))?);
+        self.from = Some(private_key.public_key());
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let (from, signature) = self
+            .from
+            .zip(self.signature)
+            .ok_or_else(|| anyhow!("go transaction has no signature"))?;
+        if !signature.verify_prehash(&go_digest(&self.data), &from) {
+            return Err(anyhow!("go transaction has invalid signature"));
+        }
+        Ok(())
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        put_bytes(buf, &self.data);
+        put_key(buf, self.to.as_ref());
+        buf.put_u64(self.value);
+        put_key(buf, self.from.as_ref());
+        put_signature(buf, self.signature.as_ref());
+        buf.put_i64(self.nonce);
+    }
+
+    fn decode(r: &mut GoReader) -> Result<Self> {
+        Ok(Self {
+            data: r.bytes()?.to_vec(),
+            to: r.key()?,
+            value: r.u64()?,
+            from: r.key()?,
+            signature: r.signature()?,
+            nonce: r.i64()?,
+        })
+    }
+}
+
+impl GoBlock {
+    pub fn new(header: GoHeader, transactions: Vec<GoTransaction>) -> Self {
+        Self {
+            header,
+            transactions,
+            validator: None,
+            signature: None,
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+
+    pub fn data_hash(transactions: &[GoTransaction]) -> Hash {
+        let mut buf = vec![];
+        for tx in transactions {
+            tx.encode(&mut buf);
+        }
+        Hash::from_digest(Sha256::digest(buf).into())
+    }
+
+    pub fn sign(&mut self, private_key: &PrivateKey) -> Result<()> {
+        self.signature = Some(private_key.sign_prehash(&go_digest(&self.header.bytes()))?);
+        self.validator = Some(private_key.public_key());
+        Ok(())
+    }
+
+    // The checks of the Go node: the signatures of the block and its transactions and the data hash
+    pub fn verify(&self) -> Result<()> {
+        let (validator, signature) = self
+            .validator
+            .zip(self.signature)
+            .ok_or_else(|| anyhow!("go block has no signature"))?;
+        if !signature.verify_prehash(&go_digest(&self.header.bytes()), &validator) {
+            return Err(anyhow!("go block has invalid signature"));
+        }
+        for tx in &self.transactions {
+            tx.verify()?;
+        }
+        let data_hash = Self::data_hash(&self.transactions);
+        if data_hash != self.header.data_hash {
+            return Err(anyhow!("go block has invalid data hash {data_hash}"));
+        }
+        Ok(())
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.header.encode(buf);
+        buf.put_u32(self.transactions.len() as u32);
+        for tx in &self.transactions {
+            tx.encode(buf);
+        }
+        put_key(buf, self.validator.as_ref());
+        put_signature(buf, self.signature.as_ref());
+    }
+
+    fn decode(r: &mut GoReader) -> Result<Self> {
+        let header = GoHeader::decode(r)?;
+        let transactions = r.list(GoTransaction::decode)?;
+        Ok(Self {
+            header,
+            transactions,
+            validator: r.key()?,
+            signature: r.signature()?,
+        })
+    }
+}
+
+impl GoMessage {
+    pub fn message_type(&self) -> GoMessageType {
+        match self {
+            GoMessage::Tx(_) => GoMessageType::Tx,
+            GoMessage::Block(_) => GoMessageType::Block,
+            GoMessage::GetBlocks(_) => GoMessageType::GetBlocks,
+            GoMessage::Status(_) => GoMessageType::Status,
+            GoMessage::GetStatus => GoMessageType::GetStatus,
+            GoMessage::Blocks(_) => GoMessageType::Blocks,
+        }
+    }
+
+    pub fn bytes(&self) -> Payload {
+        let mut buf = vec![self.message_type() as u8];
+        match self {
+            GoMessage::Tx(tx) => tx.encode(&mut buf),
+            GoMessage::Block(block) => block.encode(&mut buf),
+            GoMessage::GetBlocks(msg) => {
+                buf.put_u32(msg.from);
+                buf.put_u32(msg.to);
+            }
+            GoMessage::Status(msg) => {
+                put_bytes(&mut buf, msg.id.as_bytes());
+                buf.put_u32(msg.version);
+                buf.put_u32(msg.current_height);
+            }
+            GoMessage::GetStatus => {}
+            GoMessage::Blocks(blocks) => {
+                buf.put_u32(blocks.len() as u32);
+                for block in blocks {
+                    block.encode(&mut buf);
+                }
+            }
+        }
+        buf.into()
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        let mut r = GoReader { buf: payload };
+        let msg = match GoMessageType::try_from(r.u8()?)? {
+            GoMessageType::Tx => GoMessage::Tx(GoTransaction::decode(&mut r)?),
+            GoMessageType::Block => GoMessage::Block(GoBlock::decode(&mut r)?),
+            GoMessageType::GetBlocks => GoMessage::GetBlocks(GetBlocksMessage {
+                from: r.u32()?,
+                to: r.u32()?,
+            }),
+            GoMessageType::Status => GoMessage::Status(GoStatusMessage {
+                id: String::from_utf8(r.bytes()?.to_vec())?,
+                version: r.u32()?,
+                current_height: r.u32()?,
+            }),
+            GoMessageType::GetStatus => GoMessage::GetStatus,
+            GoMessageType::Blocks => GoMessage::Blocks(r.list(GoBlock::decode)?),
+        };
+        if !r.buf.is_empty() {
+            return Err(anyhow!("go message has {} trailing bytes", r.buf.len()));
+        }
+        Ok(msg)
+    }
+}
+
+impl TryFrom<&Transaction> for GoTransaction {
+    type Error = anyhow::Error;
+
+    fn try_from(tx: &Transaction) -> Result<Self> {
+        if !matches!(tx.kind, TxKind::Call) || !tx.input.is_empty() {
+            return Err(anyhow!(
+                "only plain call transactions can be sent to go nodes"
+            ));
+        }
+        Ok(Self {
+            data: tx.data.clone(),
+            to: None,
+            value: 0,
+            from: tx.from,
+            signature: tx.signature,
+            nonce: i64::try_from(tx.nonce)?,
+        })
+    }
+}
+
+impl TryFrom<GoTransaction> for Transaction {
+    type Error = anyhow::Error;
+
+    fn try_from(go_tx: GoTransaction) -> Result<Self> {
+        if go_tx.to.is_some() || go_tx.value != 0 {
+            return Err(anyhow!("value transfers of go nodes aren't supported"));
+        }
+        let mut tx = Transaction::new(go_tx.data);
+        tx.nonce = u64::try_from(go_tx.nonce)?;
+        tx.from = go_tx.from;
+        tx.signature = go_tx.signature;
+        Ok(tx)
+    }
+}
+
+// The epoch and the committee signatures have no place in a go block and are left out
+impl TryFrom<&Block> for GoBlock {
+    type Error = anyhow::Error;
+
+    fn try_from(block: &Block) -> Result<Self> {
+        let header = GoHeader {
+            version: block.header.version,
+            data_hash: block.header.data_hash,
+            prev_block_hash: block.header.prev_block_hash.unwrap_or_default(),
+            timestamp: i64::try_from(block.header.timestamp)?,
+            height: block.header.height,
+        };
+        let transactions = block
+            .transactions
+            .iter()
+            .map(GoTransaction::try_from)
+            .collect::<Result<_>>()?;
+        let proposer = block.signatures().first().copied();
+        Ok(Self {
+            header,
+            transactions,
+            validator: proposer.map(|sig| sig.validator),
+            signature: proposer.map(|sig| sig.signature),
+        })
+    }
+}
+
+impl TryFrom<GoBlock> for Block {
+    type Error = anyhow::Error;
+
+    fn try_from(go_block: GoBlock) -> Result<Self> {
+        let header = Header {
+            version: go_block.header.version,
+            data_hash: go_block.header.data_hash,
+            prev_block_hash: Some(go_block.header.prev_block_hash).filter(|hash| !hash.is_zero()),
+            timestamp: u128::try_from(go_block.header.timestamp)?,
+            height: go_block.header.height,
+            epoch: 0,
+            state_root: Hash::default(),
+        };
+        let transactions = go_block
+            .transactions
+            .into_iter()
+            .map(Transaction::try_from)
+            .collect::<Result<_>>()?;
+        let mut block = Block::new(header, transactions);
+        if let Some((validator, signature)) = go_block.validator.zip(go_block.signature) {
+            block.set_proposer(BlockSignature {
+                validator,
+                signature,
+            });
+        }
+        Ok(block)
+    }
+}
+
+// The RPCDecodeFn of a server using the go wire format
+pub fn go_rpc_decode_fn(rpc: RPC) -> Result<DecodedMessage> {
+    let data = match GoMessage::from_payload(&rpc.payload)? {
+        GoMessage::Tx(tx) => DecodedMessageData::Tx(tx.try_into()?),
+        GoMessage::Block(block) => DecodedMessageData::Block(block.try_into()?),
+        GoMessage::GetBlocks(msg) => DecodedMessageData::GetBlocksMessage(msg),
+        // go nodes don't negotiate versions or features or tell us how they see us
+        GoMessage::Status(msg) => DecodedMessageData::StatusMessage(StatusMessage {
+            features: 0,
+            ..StatusMessage::new(
+                msg.id,
+                PeerId::default(),
+                msg.current_height,
+                rpc.from.clone(),
+            )
+        }),
+        GoMessage::GetStatus => DecodedMessageData::GetStatusMessage,
+        GoMessage::Blocks(blocks) => DecodedMessageData::BlocksMessage(BlocksMessage {
+            blocks: blocks
+                .into_iter()
+                .map(Block::try_from)
+                .collect::<Result<_>>()?,
+            more: false,
+        }),
+    };
+    // go nodes don't gossip with a ttl, their blocks are relayed like new ones
+    Ok(DecodedMessage {
+        from: rpc.from,
+        origin: PeerId::default(),
+        ttl: DEFAULT_GOSSIP_TTL,
+        data,
+    })
+}
+
+// Translates a message of this node into the go messages carrying it, none if go nodes don't know its type
+pub fn to_go_messages(payload: &Payload) -> Result<Vec<GoMessage>> {
+    let msg = Message::from_payload(payload)?;
+    let messages = match msg.header {
+        MessageType::Tx => {
+            let tx: Transaction = bincode::deserialize(&msg.data)?;
+            vec![GoMessage::Tx((&tx).try_into()?)]
+        }
+        MessageType::TxBatch => {
+            let batch: TxBatchMessage = bincode::deserialize(&msg.data)?;
+            batch
+                .txx
+                .iter()
+                .map(|tx| Ok(GoMessage::Tx(tx.try_into()?)))
+                .collect::<Result<_>>()?
+        }
+        MessageType::Block => {
+            let block: Block = bincode::deserialize(&msg.data)?;
+            vec![GoMessage::Block((&block).try_into()?)]
+        }
+        MessageType::GetBlocks => vec![GoMessage::GetBlocks(bincode::deserialize(&msg.data)?)],
+        MessageType::Status => {
+            let status: StatusMessage = bincode::deserialize(&msg.data)?;
+            vec![GoMessage::Status(GoStatusMessage {
+                id: status.id,
+                version: PROTOCOL_VERSION,
+                current_height: status.current_height,
+            })]
+        }
+        MessageType::GetStatus => vec![GoMessage::GetStatus],
+        MessageType::Blocks => {
+            let msg: BlocksMessage = bincode::deserialize(&msg.data)?;
+            let blocks = msg
+                .blocks
+                .iter()
+                .map(GoBlock::try_from)
+                .collect::<Result<_>>()?;
+            vec![GoMessage::Blocks(blocks)]
+        }
+        _ => vec![],
+    };
+    Ok(messages)
+}
+
+// Sends the messages of a server in the go wire format, received messages are decoded by go_rpc_decode_fn
+#[derive(Debug, Clone)]
+pub struct GoCompatTransport {
+    inner: BTransport,
+}
+
+impl GoCompatTransport {
+    pub fn new(inner: BTransport) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl Transport for GoCompatTransport {
+    fn consume(&self) -> Channel<RPC> {
+        self.inner.consume()
+    }
+
+    async fn recv(&self) -> Option<RPC> {
+        self.inner.recv().await
+    }
+
+    async fn connect(&self, tr: Box<dyn Transport>) -> Result<()> {
+        self.inner.connect(tr).await
+    }
+
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()> {
+        self.inner.disconnect(addr).await
+    }
+
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()> {
+        for msg in to_go_messages(&payload)? {
+            self.inner.send_message(to, msg.bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn broadcast(&self, payload: Payload) -> Result<()> {
+        for msg in to_go_messages(&payload)? {
+            self.inner.broadcast(msg.bytes()).await?;
+        }
+        Ok(())
+    }
+
+    async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>> {
+        self.inner.peers().await
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.inner.addr()
+    }
+
+    fn addrs(&self) -> Vec<NetAddr> {
+        self.inner.addrs()
+    }
+}
+
+// Switches a server to the go wire format. Go nodes don't answer pings, so they aren't disconnected for missing pongs.
+pub fn use_go_wire_format(opts: &mut ServerOpts) {
+    opts.transport = Box::new(GoCompatTransport::new(opts.transport.clone()));
+    opts.rpc_decode_fn = Some(Box::new(go_rpc_decode_fn));
+    opts.connection_opts
+        .get_or_insert_with(ConnectionManagerOpts::default)
+        .pong_timeout = Duration::MAX;
+}
+
+// Go's ecdsa.Sign takes the message as the digest
+fn go_digest(msg: &[u8]) -> [u8; 32] {
+    let mut digest = [0; 32];
+    let len = msg.len().min(32);
+    digest[32 - len..].copy_from_slice(&msg[..len]);
+    digest
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+fn put_key(buf: &mut Vec<u8>, key: Option<&PublicKey>) {
+    buf.put_u8(key.is_some() as u8);
+    if let Some(key) = key {
+        buf.put_slice(&key.to_bytes());
+    }
+}
+
+fn put_signature(buf: &mut Vec<u8>, signature: Option<&Signature>) {
+    buf.put_u8(signature.is_some() as u8);
+    if let Some(signature) = signature {
+        buf.put_slice(&signature.to_bytes());
+    }
+}
+
+struct GoReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> GoReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(anyhow!("go message is truncated"));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn hash(&mut self) -> Result<Hash> {
+        Hash::try_from_bytes(self.take(32)?)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn present(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            flag => Err(anyhow!("invalid presence flag {flag}")),
+        }
+    }
+
+    fn key(&mut self) -> Result<Option<PublicKey>> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        Ok(Some(PublicKey::from_bytes(self.take(PUBLIC_KEY_SIZE)?)?))
+    }
+
+    fn signature(&mut self) -> Result<Option<Signature>> {
+        if !self.present()? {
+            return Ok(None);
+        }
+        Ok(Some(Signature::from_bytes(self.take(SIGNATURE_SIZE)?)?))
+    }
+
+    // A count followed by the items, the count doesn't reserve more memory than the rest of the message could fill
+    fn list<T>(&mut self, decode: fn(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let count = self.u32()? as usize;
+        let mut items = Vec::with_capacity(count.min(self.buf.len()));
+        for _ in 0..count {
+            items.push(decode(self)?);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        network::{LocalTransport, PingMessage, Server},
+        test_utils::{encoded, random_block, signed_tx},
+    };
+
+    fn go_block(key: &PrivateKey) -> Result<GoBlock> {
+        let mut tx = GoTransaction::new(b"foo".to_vec());
+        tx.nonce = 3;
+        tx.sign(key)?;
+        let header = GoHeader {
+            version: 1,
+            data_hash: GoBlock::data_hash(&[tx.clone()]),
+            prev_block_hash: Hash::random(),
+            timestamp: 42,
+            height: 7,
+        };
+        let mut block = GoBlock::new(header, vec![tx]);
+        block.sign(key)?;
+        Ok(block)
+    }
+
+    #[test]
+    fn test_go_encoding() -> Result<()> {
+        let header = GoHeader {
+            version: 1,
+            data_hash: Hash::from_digest([0xaa; 32]),
+            prev_block_hash: Hash::default(),
+            timestamp: -1,
+            height: 0x0102,
+        };
+        let mut expected = vec![0, 0, 0, 1];
+        expected.extend([0xaa; 32]);
+        expected.extend([0; 32]);
+        expected.extend([0xff; 8]);
+        expected.extend([0, 0, 1, 2]);
+        assert_eq!(header.bytes(), expected);
+        assert_eq!(
+            header.hash(),
+            Hash::from_digest(Sha256::digest(&expected).into())
+        );
+
+        let status = GoMessage::Status(GoStatusMessage {
+            id: "go".into(),
+            version: 1,
+            current_height: 9,
+        });
+        assert_eq!(
+            status.bytes().to_vec(),
+            vec![0x4, 0, 0, 0, 2, b'g', b'o', 0, 0, 0, 1, 0, 0, 0, 9]
+        );
+
+        let key = PrivateKey::generate();
+        let messages = vec![
+            GoMessage::Tx(go_block(&key)?.transactions[0].clone()),
+            GoMessage::Block(go_block(&key)?),
+            GoMessage::GetBlocks(GetBlocksMessage { from: 1, to: 5 }),
+            status,
+            GoMessage::GetStatus,
+            GoMessage::Blocks(vec![go_block(&key)?, GoBlock::default()]),
+        ];
+        for msg in messages {
+            let bytes = msg.bytes();
+            assert_eq!(GoMessage::from_payload(&bytes)?, msg);
+            assert!(GoMessage::from_payload(&bytes[..bytes.len() - 1]).is_err());
+        }
+        assert!(GoMessage::from_payload(&[0x7]).is_err());
+        assert!(GoMessage::from_payload(&[0x5, 0]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_go_signatures() -> Result<()> {
+        let key = PrivateKey::generate();
+        let block = go_block(&key)?;
+        block.verify()?;
+
+        let mut tampered = block.clone();
+        tampered.header.version += 1;
+        assert!(tampered.verify().is_err());
+
+        // the signature doesn't cover the rest of the header, as on the go node
+        let mut tampered = block.clone();
+        tampered.header.height += 1;
+        tampered.verify()?;
+
+        let mut tampered = block.clone();
+        tampered.transactions[0].data.push(1);
+        assert!(tampered.verify().is_err());
+
+        // a block survives the way through this node unchanged
+        let relayed = GoBlock::try_from(&Block::try_from(block.clone())?)?;
+        assert_eq!(relayed, block);
+        relayed.verify()?;
+
+        let mut tx = block.transactions[0].clone();
+        tx.value = 1;
+        assert!(Transaction::try_from(tx).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_go_messages() -> Result<()> {
+        let batch = TxBatchMessage {
+            txx: vec![signed_tx(vec![1])?, signed_tx(vec![2])?],
+        };
+        let payload = Message::new(MessageType::TxBatch, encoded(&batch)?).bytes()?;
+        let messages = to_go_messages(&payload)?;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[1], GoMessage::Tx(tx) if tx.data == vec![2]));
+
+        let mut block = random_block(3, Hash::random())?;
+        block.header.timestamp = 42;
+        let payload = Message::new(MessageType::Block, encoded(&block)?).bytes()?;
+        match &to_go_messages(&payload)?[..] {
+            [GoMessage::Block(go_block)] => {
+                assert_eq!(go_block.header.height, 3);
+                assert_eq!(go_block.validator.as_ref(), block.validator());
+            }
+            messages => panic!("expected a go block, got {messages:?}"),
+        }
+
+        let payload = Message::new(MessageType::Ping, encoded(&PingMessage::new(1))?).bytes()?;
+        assert!(to_go_messages(&payload)?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_status_exchange_with_go_node() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let go: BTransport = Box::new(LocalTransport::new("GO".into()));
+        tr.connect(go.clone()).await?;
+        go.connect(tr.clone()).await?;
+
+        let server = Server::builder()
+            .with_transport(tr)
+            .with_go_wire_format()
+            .start()
+            .await?;
+
+        go.send_message(&"A".into(), GoMessage::GetStatus.bytes())
+            .await?;
+        let status = time::timeout(Duration::from_secs(5), async {
+            loop {
+                let rpc = go.recv().await.ok_or(anyhow!("go transport closed"))?;
+                if let GoMessage::Status(status) = GoMessage::from_payload(&rpc.payload)? {
+                    return Ok::<_, anyhow::Error>(status);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(status.id, "A");
+        assert_eq!(status.current_height, 0);
+
+        server.shutdown().await
+    }
+}
@@ -0,0 +1,102 @@
+/*
+Health signals derived from what a node sees, for operators to watch and page on:
+- the chain lag is how many blocks we are behind the highest peer, as told by their status messages
+- the mempool ages are percentiles of how long the pooled transactions have been waiting
+A LagAlarm reports a ChainLagEvent on its channel when the lag exceeds its threshold and again once the node caught
+up. A stuck node is reported once, not on every status message.
+*/
+
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolAges {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainLagEvent {
+    // we are more than the threshold behind peer_height
+    Lagging { height: u32, peer_height: u32 },
+    // the lag is back within the threshold
+    Recovered { height: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct LagAlarm {
+    // number of blocks we may be behind the highest peer
+    pub threshold: u32,
+    // full channels drop the event, the server doesn't wait for the operator
+    pub events: mpsc::Sender<ChainLagEvent>,
+}
+
+// Number of blocks height is behind the highest of peer_heights, 0 without peers
+pub fn chain_lag(height: u32, peer_heights: impl IntoIterator<Item = u32>) -> u32 {
+    peer_heights
+        .into_iter()
+        .max()
+        .map_or(0, |peer_height| peer_height.saturating_sub(height))
+}
+
+// The nearest rank percentiles of the time since first_seen, both in nanoseconds since the unix epoch. None for an
+// empty mem_pool.
+pub fn mempool_ages(now: u128, first_seen: impl IntoIterator<Item = u128>) -> Option<MempoolAges> {
+    let mut ages: Vec<u128> = first_seen
+        .into_iter()
+        .map(|first_seen| now.saturating_sub(first_seen))
+        .collect();
+    if ages.is_empty() {
+        return None;
+    }
+    ages.sort_unstable();
+
+    let percentile = |p: usize| {
+        let rank = (ages.len() * p).div_ceil(100).max(1);
+        Duration::from_nanos(u64::try_from(ages[rank - 1]).unwrap_or(u64::MAX))
+    };
+    Some(MempoolAges {
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_lag() {
+        assert_eq!(chain_lag(10, []), 0);
+        assert_eq!(chain_lag(10, [4, 12, 15]), 5);
+        // peers behind us don't count
+        assert_eq!(chain_lag(10, [4]), 0);
+    }
+
+    #[test]
+    fn test_mempool_ages() {
+        assert_eq!(mempool_ages(100, []), None);
+
+        let secs = |s: u64| Duration::from_secs(s).as_nanos();
+        let now = secs(1000);
+        // waiting for 1..=100 seconds
+        let ages = mempool_ages(now, (1..=100).map(|s| now - secs(s))).unwrap();
+        assert_eq!(
+            ages,
+            MempoolAges {
+                p50: Duration::from_secs(50),
+                p90: Duration::from_secs(90),
+                p99: Duration::from_secs(99),
+            }
+        );
+
+        let ages = mempool_ages(now, [now - secs(7)]).unwrap();
+        assert_eq!(
+            (ages.p50, ages.p99),
+            (Duration::from_secs(7), Duration::from_secs(7))
+        );
+    }
+}
@@ -5,15 +5,10 @@ It is a simple in-memory transport that does not actually send messages over the
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use log::info;
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::RwLock;
 
-use super::{
-    new_channel,
-    transport::{NetAddr, Transport},
-    Channel, RPC,
-};
+use super::{new_channel, transport::Transport, Channel, NetAddr, Payload, RPC};
 
 #[derive(Debug, Clone)]
 pub struct LocalTransport {
@@ -48,20 +43,25 @@ impl Transport for LocalTransport {
         Ok(())
     }
 
-    async fn send_message(&self, to: &NetAddr, payload: Vec<u8>) -> Result<()> {
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()> {
+        self.peers.write().await.remove(addr);
+        Ok(())
+    }
+
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()> {
         if &self.addr == to {
             return Ok(());
         }
-        let peers = self.peers().await;
-        let peer =
-            peers
-                .get(to)
-                .ok_or(anyhow!("{} could not send message to {}", self.addr, to))?;
-
-        // println!("self_addr: {}, to: {}, Peer: {:?}", self.addr, to, peer);
-
-        peer.consume()
-            .0
+        // only the sender of the peer is cloned, the lock is released before sending
+        let sender = self
+            .peers
+            .read()
+            .await
+            .get(to)
+            .map(|peer| peer.consume().0)
+            .ok_or(anyhow!("{} could not send message to {}", self.addr, to))?;
+
+        sender
             .send(RPC {
                 from: self.addr.clone(),
                 payload,
@@ -71,9 +71,22 @@ impl Transport for LocalTransport {
         Ok(())
     }
 
-    async fn broadcast(&self, payload: Vec<u8>) -> Result<()> {
-        for peer in self.peers.read().await.iter() {
-            self.send_message(peer.0, payload.clone()).await?;
+    async fn broadcast(&self, payload: Payload) -> Result<()> {
+        let senders: Vec<_> = self
+            .peers
+            .read()
+            .await
+            .values()
+            .map(|peer| peer.consume().0)
+            .collect();
+
+        for sender in senders {
+            sender
+                .send(RPC {
+                    from: self.addr.clone(),
+                    payload: payload.clone(),
+                })
+                .await?;
         }
         Ok(())
     }
@@ -90,6 +103,7 @@ impl Transport for LocalTransport {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::Bytes;
 
     #[tokio::test]
     async fn test_connect() -> Result<()> {
@@ -105,6 +119,23 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_disconnect() -> Result<()> {
+        let tr_a = LocalTransport::new("A".into());
+        let tr_b = LocalTransport::new("B".into());
+
+        tr_a.connect(Box::new(tr_b.clone())).await?;
+        tr_a.disconnect(&tr_b.addr()).await?;
+
+        assert!(tr_a.peers().await.is_empty());
+        assert!(tr_a
+            .send_message(&tr_b.addr(), Bytes::from_static(&[1]))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_send_message() -> Result<()> {
         let mut tr_a = LocalTransport::new("A".into());
@@ -113,7 +144,7 @@ mod tests {
         tr_a.connect(Box::new(tr_b.clone())).await?;
         tr_b.connect(Box::new(tr_a.clone())).await?;
 
-        let msg = b"hello world!".to_vec();
+        let msg = Bytes::from_static(b"hello world!");
         tr_a.send_message(&tr_b.addr(), msg.clone()).await?;
 
         let rpc = tr_b.recv().await.unwrap();
@@ -132,7 +163,7 @@ mod tests {
         tr_a.connect(Box::new(tr_b.clone())).await?;
         tr_a.connect(Box::new(tr_c.clone())).await?;
 
-        let msg = b"foo".to_vec();
+        let msg = Bytes::from_static(b"foo");
         tr_a.broadcast(msg.clone()).await?;
 
         let rpc_b = tr_b.recv().await.unwrap();
@@ -141,6 +172,9 @@ mod tests {
         let rpc_c = tr_c.recv().await.unwrap();
         assert_eq!(rpc_c.payload, msg);
 
+        // every peer gets the same buffer
+        assert_eq!(rpc_b.payload.as_ptr(), rpc_c.payload.as_ptr());
+
         Ok(())
     }
 }
@@ -1,12 +1,82 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Serialize)]
+use super::{NetAddr, PeerId};
+use crate::{
+    core::{Block, Transaction},
+    types::Hash,
+};
+
+// PROTOCOL_VERSION is the newest wire protocol this node speaks, MIN_PROTOCOL_VERSION
+// the oldest one it still understands. Both are advertised in the StatusMessage so
+// peers can agree on the highest version they have in common.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+pub fn is_supported_version(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+// Returns the highest protocol version supported by us and by a peer advertising
+// the given range, or None if the ranges don't overlap.
+pub fn negotiate_version(min_version: u32, max_version: u32) -> Option<u32> {
+    let version = max_version.min(PROTOCOL_VERSION);
+
+    if version < min_version.max(MIN_PROTOCOL_VERSION) {
+        return None;
+    }
+    Some(version)
+}
+
+// Bits of the optional protocol features a node advertises in its StatusMessage. Message types of a feature are
+// only sent to peers advertising it, older nodes ignore the types they don't know.
+pub const SUPPORTED_FEATURES: u64 = FEATURE_BLOCK_ANNOUNCEMENTS;
+// new blocks may be announced with a NewBlockHashesMessage instead of being sent in full
+pub const FEATURE_BLOCK_ANNOUNCEMENTS: u64 = 1 << 0;
+
+// how often a node asks its peers for their status, it syncs from the ones that are ahead
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+// number of blocks a node sends in answer to a GetBlocksMessage
+pub const MAX_BLOCKS_PER_RESPONSE: u32 = 64;
+// encoded size of the blocks of one BlocksMessage
+pub const MAX_BLOCKS_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Asks for the blocks from..=to. A peer answers with at most MAX_BLOCKS_PER_RESPONSE of them, see BlocksMessage.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GetBlocksMessage {
     pub from: u32,
     // If to is 0 the maximum blocks will be returned
     pub to: u32,
 }
 
+// A chunk of the answer to a GetBlocksMessage, the blocks are in order and more is false in the last chunk
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlocksMessage {
+    pub blocks: Vec<Block>,
+    pub more: bool,
+}
+
+// Announces new blocks without their bodies, the receiver asks for the ones it doesn't have with a
+// GetBlocksMessage. Only sent to peers advertising FEATURE_BLOCK_ANNOUNCEMENTS.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewBlockHashesMessage {
+    pub blocks: Vec<BlockAnnouncement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockAnnouncement {
+    pub hash: Hash,
+    pub height: u32,
+}
+
+// Transactions gossiped together, see tx_batch.rs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TxBatchMessage {
+    pub txx: Vec<Transaction>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetStatusMessage {}
 
@@ -14,16 +84,114 @@ pub struct GetStatusMessage {}
 pub struct StatusMessage {
     // The id of the Server
     pub id: String,
+    pub peer_id: PeerId,
+    // The highest and lowest protocol versions the Server supports
     pub version: u32,
+    pub min_version: u32,
+    // see SUPPORTED_FEATURES
+    pub features: u64,
     pub current_height: u32,
+    // the lowest block the Server can send, above 0 for pruned nodes
+    pub lowest_block: u32,
+    // the address the GetStatus message came from, how the receiver of the status is seen from the outside
+    pub observed_addr: NetAddr,
 }
 
 impl StatusMessage {
-    pub fn new(id: String, version: u32, current_height: u32) -> Self {
+    pub fn new(id: String, peer_id: PeerId, current_height: u32, observed_addr: NetAddr) -> Self {
         Self {
             id,
-            version,
+            peer_id,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES,
             current_height,
+            lowest_block: 0,
+            observed_addr,
         }
     }
+
+    pub fn negotiate_version(&self) -> Option<u32> {
+        negotiate_version(self.min_version, self.version)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GetPeersMessage {}
+
+// An address of a node and how long ago the sender last heard from it. The age is relative, the clocks of two
+// nodes don't have to agree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerAddr {
+    pub addr: NetAddr,
+    pub age_secs: u64,
+}
+
+// The answer to a GetPeersMessage, a random sample of the addresses the sender knows
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeersMessage {
+    pub peers: Vec<PeerAddr>,
+}
+
+// Ping is sent periodically to every connected peer, the peer answers with a Pong carrying the same nonce.
+// The timestamp is the senders wall clock time in milliseconds and is echoed back unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PingMessage {
+    pub nonce: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PongMessage {
+    pub nonce: u64,
+    pub timestamp: u64,
+}
+
+impl PingMessage {
+    pub fn new(nonce: u64) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self { nonce, timestamp }
+    }
+
+    pub fn pong(&self) -> PongMessage {
+        PongMessage {
+            nonce: self.nonce,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+
+        // a newer peer that still understands our version falls back to ours
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION, PROTOCOL_VERSION + 5),
+            Some(PROTOCOL_VERSION)
+        );
+
+        // a peer that dropped support for our version is incompatible
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 5),
+            None
+        );
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION - 1), None);
+    }
+
+    #[test]
+    fn test_status_message_negotiate_version() {
+        let msg = StatusMessage::new("A".into(), PeerId::default(), 10, "B".into());
+        assert_eq!(msg.negotiate_version(), Some(PROTOCOL_VERSION));
+    }
 }
@@ -1,13 +1,59 @@
+mod block_production;
+mod block_verifier;
+mod broadcast;
+mod codec;
+mod connection_manager;
+mod decode_pool;
+mod go_compat;
+mod health;
 mod local_transport;
 mod message;
+mod multi_transport;
+mod nat;
+mod orphan_pool;
+mod peer;
+mod priority;
+#[cfg(feature = "quic")]
+mod quic_transport;
 mod rpc;
 mod server;
+mod server_builder;
+mod server_handle;
+mod supervisor;
+mod sync_status;
 mod transport;
+mod tx_batch;
 mod tx_pool;
+mod udp_transport;
+#[cfg(feature = "upnp")]
+mod upnp;
 
+pub use block_production::*;
+pub use block_verifier::*;
+pub use broadcast::{Broadcaster, OutboundOpts, SlowPeerPolicy};
+pub use codec::*;
+pub use connection_manager::*;
+pub use go_compat::*;
+pub use health::*;
 pub use local_transport::LocalTransport;
 pub use message::*;
+pub use multi_transport::MultiTransport;
+pub use nat::*;
+pub use orphan_pool::*;
+pub use peer::*;
+pub use priority::*;
+#[cfg(feature = "quic")]
+pub use quic_transport::*;
 pub use rpc::*;
 pub use server::Server;
 pub use server::ServerOpts;
+pub use server_builder::*;
+pub use server_handle::*;
+pub use supervisor::*;
+pub use sync_status::*;
 pub use transport::*;
+pub use tx_batch::*;
+pub use tx_pool::{SenderLimits, TxPool, FEE_HISTORY_BLOCKS};
+pub use udp_transport::*;
+#[cfg(feature = "upnp")]
+pub use upnp::*;
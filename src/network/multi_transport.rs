@@ -0,0 +1,235 @@
+/*
+MultiTransport lets a server listen on several transports at once, e.g. a LocalTransport for tests next to a
+socket transport for the LAN and another one for the WAN. To the server it's a single transport:
+- recv() returns the messages of all the transports
+- the peers of all the transports form one peer registry
+- a message to a peer goes through the transport the peer is connected to, broadcasts go through every transport
+- a new peer is dialed with the first transport that can reach its address: a LocalTransport reaches local names,
+  a socket transport the socket addresses of its IP version
+
+The first transport is the primary one, its address is the address of the MultiTransport.
+*/
+
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::{transport::Transport, BTransport, Channel, NetAddr, Payload, RPC};
+
+#[derive(Debug, Clone)]
+pub struct MultiTransport {
+    transports: Vec<BTransport>,
+    // index of the transport every dialed or heard from peer is connected through
+    routes: Arc<RwLock<HashMap<NetAddr, usize>>>,
+}
+
+impl MultiTransport {
+    pub fn new(transports: Vec<BTransport>) -> Result<Self> {
+        if transports.is_empty() {
+            return Err(anyhow!("a multi transport needs at least one transport"));
+        }
+        Ok(Self {
+            transports,
+            routes: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    pub fn transports(&self) -> &[BTransport] {
+        &self.transports
+    }
+
+    // The transport connected to the peer, transports a peer connected to by itself are looked up by their peers
+    async fn route(&self, addr: &NetAddr) -> Option<usize> {
+        if let Some(i) = self.routes.read().await.get(addr) {
+            return Some(*i);
+        }
+
+        for (i, tr) in self.transports.iter().enumerate() {
+            if tr.peers().await.contains_key(addr) {
+                self.routes.write().await.insert(addr.clone(), i);
+                return Some(i);
+            }
+        }
+        None
+    }
+}
+
+// Whether a transport listening on local can dial addr
+fn reaches(local: &NetAddr, addr: &NetAddr) -> bool {
+    match (local, addr) {
+        (NetAddr::Local(_), NetAddr::Local(_)) => true,
+        (NetAddr::Socket(local), NetAddr::Socket(addr)) => local.is_ipv4() == addr.is_ipv4(),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl Transport for MultiTransport {
+    fn consume(&self) -> Channel<RPC> {
+        self.transports[0].consume()
+    }
+
+    // Waits for the next message of any transport, returns None once all of them are closed
+    async fn recv(&self) -> Option<RPC> {
+        let mut pending: Vec<Pin<Box<dyn Future<Output = Option<RPC>> + Send + '_>>> =
+            self.transports.iter().map(|tr| tr.recv()).collect();
+
+        poll_fn(|cx| {
+            let mut i = 0;
+            while i < pending.len() {
+                match pending[i].as_mut().poll(cx) {
+                    Poll::Ready(Some(rpc)) => return Poll::Ready(Some(rpc)),
+                    // a closed transport, the others are still read
+                    Poll::Ready(None) => drop(pending.swap_remove(i)),
+                    Poll::Pending => i += 1,
+                }
+            }
+            if pending.is_empty() {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn connect(&self, tr: Box<dyn Transport>) -> Result<()> {
+        let addr = tr.addr();
+        let mut last_err = None;
+
+        for (i, local) in self.transports.iter().enumerate() {
+            if !reaches(&local.addr(), &addr) {
+                continue;
+            }
+            match local.connect(tr.clone()).await {
+                Ok(()) => {
+                    self.routes.write().await.insert(addr, i);
+                    return Ok(());
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("no transport can reach {addr}")))
+    }
+
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()> {
+        let route = self.routes.write().await.remove(addr);
+        match route {
+            Some(i) => self.transports[i].disconnect(addr).await,
+            None => {
+                for tr in &self.transports {
+                    tr.disconnect(addr).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()> {
+        let i = self
+            .route(to)
+            .await
+            .ok_or_else(|| anyhow!("no transport is connected to {to}"))?;
+        self.transports[i].send_message(to, payload).await
+    }
+
+    async fn broadcast(&self, payload: Payload) -> Result<()> {
+        for tr in &self.transports {
+            tr.broadcast(payload.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn broadcast_except(&self, payload: Payload, except: &NetAddr) -> Result<()> {
+        for tr in &self.transports {
+            tr.broadcast_except(payload.clone(), except).await?;
+        }
+        Ok(())
+    }
+
+    // A peer connected through several transports is listed once, with the first of them
+    async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>> {
+        let mut peers = HashMap::new();
+        for tr in self.transports.iter().rev() {
+            peers.extend(tr.peers().await);
+        }
+        peers
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.transports[0].addr()
+    }
+
+    fn addrs(&self) -> Vec<NetAddr> {
+        self.transports.iter().flat_map(|tr| tr.addrs()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{LocalTransport, RemotePeer};
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_multi_transport() -> Result<()> {
+        let lan = LocalTransport::new("LAN".into());
+        let wan = LocalTransport::new("WAN".into());
+        let multi = MultiTransport::new(vec![Box::new(lan.clone()), Box::new(wan.clone())])?;
+        assert_eq!(multi.addr(), lan.addr());
+        assert_eq!(multi.addrs(), vec![lan.addr(), wan.addr()]);
+
+        // B is dialed, C connects to the second transport by itself
+        let b = LocalTransport::new("B".into());
+        let c = LocalTransport::new("C".into());
+        multi.connect(Box::new(b.clone())).await?;
+        b.connect(Box::new(lan.clone())).await?;
+        wan.connect(Box::new(c.clone())).await?;
+        c.connect(Box::new(wan.clone())).await?;
+
+        let mut peers: Vec<NetAddr> = multi.peers().await.into_keys().collect();
+        peers.sort();
+        assert_eq!(peers, vec![b.addr(), c.addr()]);
+
+        multi
+            .send_message(&c.addr(), Bytes::from_static(&[1]))
+            .await?;
+        let rpc = c.recv().await.unwrap();
+        assert_eq!((rpc.from, &rpc.payload[..]), (wan.addr(), &[1][..]));
+
+        multi.broadcast(Bytes::from_static(&[2])).await?;
+        assert_eq!(b.recv().await.unwrap().from, lan.addr());
+        assert_eq!(c.recv().await.unwrap().from, wan.addr());
+
+        // messages of both transports are received
+        b.send_message(&lan.addr(), Bytes::from_static(&[3]))
+            .await?;
+        c.send_message(&wan.addr(), Bytes::from_static(&[4]))
+            .await?;
+        let mut received = [multi.recv().await.unwrap(), multi.recv().await.unwrap()];
+        received.sort_by_key(|rpc| rpc.payload.clone());
+        assert_eq!(received[0].from, b.addr());
+        assert_eq!(received[1].from, c.addr());
+
+        multi.disconnect(&c.addr()).await?;
+        assert!(multi
+            .send_message(&c.addr(), Bytes::from_static(&[5]))
+            .await
+            .is_err());
+
+        // no transport listens on a socket
+        let remote = RemotePeer::new("127.0.0.1:3000".into());
+        assert!(multi.connect(Box::new(remote)).await.is_err());
+
+        Ok(())
+    }
+}
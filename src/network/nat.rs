@@ -0,0 +1,333 @@
+/*
+Nodes behind a home router can't be dialed unless the router forwards a port to them. A PortMapper asks the
+router for such a mapping, with NAT-PMP (RFC 6886) or, with the upnp feature, UPnP IGD (see upnp.rs). Mappings
+expire after their lease and are renewed by the Server.
+
+The address we are reachable at is also learned from peers: every StatusMessage carries the address the sender
+received our GetStatus message from. ExternalAddrs trusts such an address once enough peers agree on it.
+
+NAT-PMP messages, all numbers are big endian:
+
+    external address request:  | version: u8 = 0 | opcode: u8 = 0 |
+    external address response: | 0 | 128 | result: u16 | epoch: u32 | address: [u8; 4] |
+    mapping request:           | 0 | opcode: u8 | 0: u16 | internal port: u16 | external port: u16 | lifetime: u32 |
+    mapping response:          | 0 | 128 + opcode | result: u16 | epoch: u32 | internal port: u16 |
+                               | external port: u16 | lifetime: u32 |
+
+The opcode of a mapping is 1 for UDP and 2 for TCP, a lifetime of 0 removes it.
+*/
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::{net::UdpSocket, time};
+
+use super::NetAddr;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+
+// number of peers that have to report the same address before it's used as our external address
+pub const MIN_OBSERVATIONS: usize = 2;
+
+// lease of a mapping, the Server renews it after half of it
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(3600);
+// wait before mapping again after the router refused or didn't answer
+pub const PORT_MAPPING_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// requests are sent again after 250ms, 500ms, ..., as recommended by the RFC
+const NAT_PMP_ATTEMPTS: u32 = 4;
+const NAT_PMP_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+#[async_trait]
+pub trait PortMapper: Send + Sync {
+    // Forwards the port of local on the router for lease, returns the address peers can dial
+    async fn map_port(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        lease: Duration,
+    ) -> Result<SocketAddr>;
+    async fn unmap_port(&self, protocol: Protocol, local: SocketAddr) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct PortMappingOpts {
+    pub mapper: Arc<dyn PortMapper>,
+    // protocol of the transport, UDP for the udp and quic transports
+    pub protocol: Protocol,
+    pub lease: Duration,
+}
+
+// The addresses peers observed us at and the address mapped on the router
+#[derive(Debug, Default)]
+pub struct ExternalAddrs {
+    observed: HashMap<NetAddr, NetAddr>,
+    mapped: Option<NetAddr>,
+}
+
+impl ExternalAddrs {
+    pub fn observe(&mut self, peer: NetAddr, observed: NetAddr) {
+        self.observed.insert(peer, observed);
+    }
+
+    // Drops the observation of a disconnected peer
+    pub fn forget(&mut self, peer: &NetAddr) {
+        self.observed.remove(peer);
+    }
+
+    pub fn set_mapped(&mut self, mapped: Option<NetAddr>) {
+        self.mapped = mapped;
+    }
+
+    // The address most peers observed if at least MIN_OBSERVATIONS of them agree, otherwise the mapped
+    // address. Peers see the outside of every NAT on the way, the router only knows about itself.
+    pub fn external(&self) -> Option<NetAddr> {
+        let mut counts: HashMap<&NetAddr, usize> = HashMap::new();
+        for addr in self.observed.values() {
+            *counts.entry(addr).or_default() += 1;
+        }
+
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count >= MIN_OBSERVATIONS)
+            // ties go to the smaller address, so the result doesn't depend on the order of the map
+            .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(b.cmp(a)))
+            .map(|(addr, _)| addr.clone())
+            .or_else(|| self.mapped.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NatPmp {
+    gateway: SocketAddr,
+}
+
+impl NatPmp {
+    pub fn new(gateway: SocketAddr) -> Self {
+        Self { gateway }
+    }
+
+    // Uses the gateway of the default route, only supported on Linux
+    pub fn default_gateway() -> Result<Self> {
+        let routes = std::fs::read_to_string("/proc/net/route")
+            .map_err(|err| anyhow!("could not read the routing table: {err}"))?;
+        let gateway = parse_default_gateway(&routes)?;
+        Ok(Self::new(SocketAddr::new(gateway.into(), NAT_PMP_PORT)))
+    }
+
+    async fn request(&self, request: &[u8], response_len: usize) -> Result<Vec<u8>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(self.gateway).await?;
+
+        let mut buf = [0u8; 16];
+        let mut timeout = NAT_PMP_INITIAL_TIMEOUT;
+        for _ in 0..NAT_PMP_ATTEMPTS {
+            socket.send(request).await?;
+            if let Ok(n) = time::timeout(timeout, socket.recv(&mut buf)).await {
+                let response = &buf[..n?];
+                check_response(request[1], response, response_len)?;
+                return Ok(response.to_vec());
+            }
+            timeout *= 2;
+        }
+        Err(anyhow!("no NAT-PMP response from {}", self.gateway))
+    }
+
+    pub async fn external_ip(&self) -> Result<Ipv4Addr> {
+        let response = self.request(&[0, 0], 12).await?;
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    async fn map(&self, protocol: Protocol, local: SocketAddr, lifetime: u32) -> Result<u16> {
+        let mut request = vec![0, protocol.nat_pmp_opcode(), 0, 0];
+        request.extend_from_slice(&local.port().to_be_bytes());
+        // the same port outside, if it's still free
+        let external_port = if lifetime == 0 { 0 } else { local.port() };
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+
+        let response = self.request(&request, 16).await?;
+        Ok(u16::from_be_bytes([response[10], response[11]]))
+    }
+}
+
+#[async_trait]
+impl PortMapper for NatPmp {
+    async fn map_port(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        lease: Duration,
+    ) -> Result<SocketAddr> {
+        let lifetime = lease.as_secs().clamp(1, u32::MAX as u64) as u32;
+        let port = self.map(protocol, local, lifetime).await?;
+        let ip = self.external_ip().await?;
+        Ok(SocketAddr::new(ip.into(), port))
+    }
+
+    async fn unmap_port(&self, protocol: Protocol, local: SocketAddr) -> Result<()> {
+        self.map(protocol, local, 0).await?;
+        Ok(())
+    }
+}
+
+impl Protocol {
+    fn nat_pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+}
+
+fn check_response(opcode: u8, response: &[u8], len: usize) -> Result<()> {
+    if response.len() < len || response[0] != 0 || response[1] != 128 + opcode {
+        return Err(anyhow!("malformed NAT-PMP response {:?}", response));
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(()),
+        1 => Err(anyhow!("the gateway doesn't support NAT-PMP version 0")),
+        2 => Err(anyhow!("NAT-PMP is disabled on the gateway")),
+        3 => Err(anyhow!("the gateway has no external address")),
+        4 => Err(anyhow!("the gateway is out of mappings")),
+        code => Err(anyhow!("NAT-PMP request failed with result code {code}")),
+    }
+}
+
+// The gateway of the default route in the format of /proc/net/route, addresses are hex encoded in the byte
+// order of the host
+fn parse_default_gateway(routes: &str) -> Result<Ipv4Addr> {
+    for line in routes.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16)?;
+        if gateway != 0 {
+            return Ok(Ipv4Addr::from(gateway.to_ne_bytes()));
+        }
+    }
+    Err(anyhow!("no default route"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers NAT-PMP requests like a router with the external address 203.0.113.7 that maps every port to
+    // the port + 1000
+    async fn fake_gateway() -> Result<SocketAddr> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = socket.local_addr()?;
+
+        tokio::task::spawn(async move {
+            let mut buf = [0u8; 16];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                let opcode = buf[1];
+                let mut response = vec![0, 128 + opcode, 0, 0, 0, 0, 0, 1];
+                if opcode == 0 && n == 2 {
+                    response.extend_from_slice(&[203, 0, 113, 7]);
+                } else {
+                    let internal = u16::from_be_bytes([buf[4], buf[5]]);
+                    response.extend_from_slice(&buf[4..6]);
+                    response.extend_from_slice(&(internal + 1000).to_be_bytes());
+                    response.extend_from_slice(&buf[8..12]);
+                }
+                let _ = socket.send_to(&response, from).await;
+            }
+        });
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp() -> Result<()> {
+        let nat = NatPmp::new(fake_gateway().await?);
+        let local: SocketAddr = "192.168.1.20:3000".parse()?;
+
+        let external = nat.map_port(Protocol::Udp, local, DEFAULT_LEASE).await?;
+        assert_eq!(external, "203.0.113.7:4000".parse()?);
+        nat.unmap_port(Protocol::Udp, local).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp_without_gateway() -> Result<()> {
+        // nobody listens on the port of a dropped socket
+        let gateway = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await?
+            .local_addr()?;
+        let nat = NatPmp::new(gateway);
+        assert!(nat.external_ip().await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_response() {
+        assert!(check_response(0, &[0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4], 12).is_ok());
+        assert!(check_response(0, &[0, 128, 0, 0], 12).is_err());
+        assert!(check_response(1, &[0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4], 12).is_err());
+
+        let err = check_response(0, &[0, 128, 0, 2, 0, 0, 0, 1, 1, 2, 3, 4], 12).unwrap_err();
+        assert_eq!(err.to_string(), "NAT-PMP is disabled on the gateway");
+    }
+
+    #[test]
+    fn test_parse_default_gateway() -> Result<()> {
+        let gateway = Ipv4Addr::new(192, 168, 1, 1);
+        let hex = format!("{:08X}", u32::from_ne_bytes(gateway.octets()));
+        let routes = format!(
+            "Iface\tDestination\tGateway\tFlags\n\
+             eth0\t0001A8C0\t00000000\t0001\n\
+             eth0\t00000000\t{hex}\t0003\n"
+        );
+        assert_eq!(parse_default_gateway(&routes)?, gateway);
+        assert!(parse_default_gateway("Iface\tDestination\tGateway\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_addrs() {
+        let mut addrs = ExternalAddrs::default();
+        let outside: NetAddr = "203.0.113.7:3000".into();
+        let mapped: NetAddr = "203.0.113.7:4000".into();
+        assert_eq!(addrs.external(), None);
+
+        // a single peer could lie about it
+        addrs.observe("A".into(), outside.clone());
+        assert_eq!(addrs.external(), None);
+        addrs.set_mapped(Some(mapped.clone()));
+        assert_eq!(addrs.external(), Some(mapped.clone()));
+
+        addrs.observe("B".into(), outside.clone());
+        addrs.observe("C".into(), "198.51.100.1:3000".into());
+        assert_eq!(addrs.external(), Some(outside));
+
+        // a new observation of a peer replaces its old one
+        addrs.forget(&"B".into());
+        addrs.observe("A".into(), "198.51.100.1:3000".into());
+        assert_eq!(addrs.external(), Some("198.51.100.1:3000".into()));
+
+        addrs.forget(&"A".into());
+        assert_eq!(addrs.external(), Some(mapped));
+    }
+}
@@ -0,0 +1,198 @@
+/*
+OrphanPool buffers blocks that arrived before their parent.
+Gossip doesn't guarantee ordering, so block n+2 can reach us before block n+1. Instead of dropping such a block
+we keep it keyed by the hash of the parent it is waiting for and connect it as soon as that parent got added.
+The pool is bounded, when it is full the oldest orphan is evicted.
+*/
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    core::{Block, BlockHasher},
+    types::Hash,
+};
+
+pub const DEFAULT_MAX_ORPHANS: usize = 256;
+
+pub struct OrphanPool {
+    // orphans by the hash of their missing parent
+    by_parent: HashMap<Hash, Vec<Block>>,
+    // (parent hash, block hash) of every orphan, oldest first
+    order: VecDeque<(Hash, Hash)>,
+    known: HashSet<Hash>,
+    max_orphans: usize,
+}
+
+impl OrphanPool {
+    pub fn new(max_orphans: usize) -> Self {
+        Self {
+            by_parent: HashMap::new(),
+            order: VecDeque::new(),
+            known: HashSet::new(),
+            max_orphans,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.known.contains(hash)
+    }
+
+    // Returns false if the block is already buffered
+    pub fn add(&mut self, mut block: Block) -> Result<bool> {
+        let parent = block
+            .header
+            .prev_block_hash
+            .ok_or_else(|| anyhow!("orphan block has no prev_block_hash"))?;
+        let hash = block.hash(Box::new(BlockHasher))?;
+
+        if self.known.contains(&hash) {
+            return Ok(false);
+        }
+
+        while self.len() >= self.max_orphans {
+            if !self.evict_oldest() {
+                break;
+            }
+        }
+
+        self.known.insert(hash);
+        self.order.push_back((parent, hash));
+        self.by_parent.entry(parent).or_default().push(block);
+
+        Ok(true)
+    }
+
+    // Removes and returns all orphans waiting for the given parent
+    pub fn take_children(&mut self, parent: &Hash) -> Vec<Block> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+
+        for child in &children {
+            self.known.remove(&child.cached_hash());
+        }
+        self.order.retain(|(p, _)| p != parent);
+
+        children
+    }
+
+    // Drops orphans that can't be connected anymore because the chain already reached their height
+    pub fn prune(&mut self, height: u32) {
+        for children in self.by_parent.values_mut() {
+            children.retain(|b| b.header.height > height);
+        }
+        self.by_parent.retain(|_, children| !children.is_empty());
+
+        let by_parent = &self.by_parent;
+        self.known = by_parent
+            .values()
+            .flatten()
+            .map(|b| b.cached_hash())
+            .collect();
+        let known = &self.known;
+        self.order.retain(|(_, hash)| known.contains(hash));
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let Some((parent, hash)) = self.order.pop_front() else {
+            return false;
+        };
+
+        if let Some(children) = self.by_parent.get_mut(&parent) {
+            children.retain(|b| b.cached_hash() != hash);
+            if children.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        self.known.remove(&hash);
+
+        true
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ORPHANS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::random_block;
+
+    #[test]
+    fn test_add_and_take_children() -> Result<()> {
+        let mut pool = OrphanPool::default();
+        let parent = Hash::random();
+
+        let mut a = random_block(2, parent)?;
+        let mut b = random_block(2, parent)?;
+        let c = random_block(3, a.hash(Box::new(BlockHasher))?)?;
+
+        assert!(pool.add(a.clone())?);
+        assert!(!pool.add(a.clone())?);
+        assert!(pool.add(b.clone())?);
+        assert!(pool.add(c)?);
+        assert_eq!(pool.len(), 3);
+        assert!(pool.contains(&b.hash(Box::new(BlockHasher))?));
+
+        let children = pool.take_children(&parent);
+        assert_eq!(children.len(), 2);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.take_children(&parent).is_empty());
+
+        assert_eq!(pool.take_children(&a.hash(Box::new(BlockHasher))?).len(), 1);
+        assert!(pool.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_oldest() -> Result<()> {
+        let mut pool = OrphanPool::new(2);
+
+        let mut blocks = vec![];
+        for i in 0..3 {
+            let mut b = random_block(i + 2, Hash::random())?;
+            b.hash(Box::new(BlockHasher))?;
+            pool.add(b.clone())?;
+            blocks.push(b);
+        }
+
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&blocks[0].cached_hash()));
+        assert!(pool.contains(&blocks[1].cached_hash()));
+        assert!(pool.contains(&blocks[2].cached_hash()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> Result<()> {
+        let mut pool = OrphanPool::default();
+        pool.add(random_block(2, Hash::random())?)?;
+        pool.add(random_block(5, Hash::random())?)?;
+
+        pool.prune(3);
+        assert_eq!(pool.len(), 1);
+
+        pool.prune(5);
+        assert!(pool.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_without_parent() {
+        assert!(OrphanPool::default().add(Block::genesis()).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+/*
+A peer is described by two different things:
+PeerId is the logical identity of a node. It is derived from the node's public key, so it stays the same no matter how we reach the node.
+NetAddr is the dial info, it tells a transport how to reach the node (an in-memory name for LocalTransport or a socket address).
+*/
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Display,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::{Direction, PROTOCOL_VERSION};
+use crate::{crypto::PublicKey, types::Address};
+
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct PeerId(Address);
+
+impl PeerId {
+    pub fn from_public_key(public_key: &PublicKey) -> Self {
+        Self(public_key.address())
+    }
+
+    pub fn address(&self) -> Address {
+        self.0
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum NetAddr {
+    // name of an in-memory LocalTransport
+    Local(String),
+    Socket(SocketAddr),
+}
+
+impl NetAddr {
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            NetAddr::Socket(addr) => Some(*addr),
+            NetAddr::Local(_) => None,
+        }
+    }
+}
+
+impl Display for NetAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetAddr::Local(name) => write!(f, "{name}"),
+            NetAddr::Socket(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+// Anything that parses as a socket address becomes NetAddr::Socket, everything else is
+// treated as the name of a LocalTransport.
+impl From<&str> for NetAddr {
+    fn from(s: &str) -> Self {
+        match s.parse::<SocketAddr>() {
+            Ok(addr) => NetAddr::Socket(addr),
+            Err(_) => NetAddr::Local(s.to_string()),
+        }
+    }
+}
+
+impl FromStr for NetAddr {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.into())
+    }
+}
+
+impl From<String> for NetAddr {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+impl From<SocketAddr> for NetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        NetAddr::Socket(addr)
+    }
+}
+
+// What the Server learned about a peer in the status handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub id: PeerId,
+    // protocol version negotiated with the peer
+    pub version: u32,
+    // the optional features the peer advertised, see SUPPORTED_FEATURES
+    pub features: u64,
+    // the height the peer told us in its last status
+    pub height: u32,
+    // the lowest block the peer can send us
+    pub lowest_block: u32,
+}
+
+// The protocol version of the messages to a peer: the one negotiated in the handshake, ours before it
+pub async fn message_version(
+    handshakes: &RwLock<HashMap<NetAddr, Handshake>>,
+    addr: &NetAddr,
+) -> u32 {
+    handshakes
+        .read()
+        .await
+        .get(addr)
+        .map_or(PROTOCOL_VERSION, |h| h.version)
+}
+
+// Snapshot of what we know about a connected peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: NetAddr,
+    pub direction: Direction,
+    pub last_seen: Instant,
+    // round trip time of the last answered ping
+    pub latency: Option<Duration>,
+    // id, version and features are only known after the status handshake
+    pub id: Option<PeerId>,
+    pub version: Option<u32>,
+    pub features: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    #[test]
+    fn test_net_addr_from_string() {
+        assert_eq!(NetAddr::from("LOCAL"), NetAddr::Local("LOCAL".into()));
+        assert_eq!(
+            NetAddr::from("127.0.0.1:3000".to_string()),
+            NetAddr::Socket("127.0.0.1:3000".parse().unwrap())
+        );
+        assert_eq!(NetAddr::from("[::1]:3000").to_string(), "[::1]:3000");
+    }
+
+    #[test]
+    fn test_peer_id_from_public_key() {
+        let private_key = PrivateKey::generate();
+        let id = PeerId::from_public_key(&private_key.public_key());
+
+        assert_eq!(id, PeerId::from_public_key(&private_key.public_key()));
+        assert_ne!(
+            id,
+            PeerId::from_public_key(&PrivateKey::generate().public_key())
+        );
+    }
+}
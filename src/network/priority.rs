@@ -0,0 +1,403 @@
+/*
+Messages are queued by priority, so a flood of transactions can't delay the blocks and status messages the node
+needs to stay in consensus. Every priority has its own bounded queue and receivers always take the most
+important message waiting.
+
+What happens when a queue is full is the OverflowPolicy of its priority. By default sending consensus and sync
+messages waits, which pushes back on the sender. Gossiped transactions drop the oldest one waiting instead:
+waiting for their queue would hold up every message behind them, a stale transaction is the least useful one and
+it's gossiped again by the other peers. Every dropped message is counted in the DropCounters of the queue.
+*/
+
+use std::{
+    collections::VecDeque,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+use tracing::debug;
+
+use super::{DecodedMessageData, MessageType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    // blocks, proposals and votes
+    Consensus,
+    // status, sync requests and keep alives
+    Sync,
+    // transactions and messages of unknown types
+    Tx,
+}
+
+impl Priority {
+    pub fn of_type(header: &MessageType) -> Self {
+        match header {
+            MessageType::Block | MessageType::Proposal | MessageType::Vote => Priority::Consensus,
+            MessageType::GetBlocks
+            | MessageType::Blocks
+            | MessageType::Status
+            | MessageType::GetStatus
+            | MessageType::Ping
+            | MessageType::Pong
+            | MessageType::GetPeers
+            | MessageType::Peers
+            | MessageType::NewBlockHashes => Priority::Sync,
+            MessageType::Tx | MessageType::TxBatch | MessageType::Unknown(_) => Priority::Tx,
+        }
+    }
+
+    pub fn of(data: &DecodedMessageData) -> Self {
+        match data {
+            DecodedMessageData::Block(_)
+            | DecodedMessageData::Proposal(_)
+            | DecodedMessageData::Vote(_) => Priority::Consensus,
+            DecodedMessageData::StatusMessage(_)
+            | DecodedMessageData::GetStatusMessage
+            | DecodedMessageData::GetBlocksMessage(_)
+            | DecodedMessageData::BlocksMessage(_)
+            | DecodedMessageData::Ping(_)
+            | DecodedMessageData::Pong(_)
+            | DecodedMessageData::GetPeersMessage
+            | DecodedMessageData::PeersMessage(_)
+            | DecodedMessageData::NewBlockHashes(_) => Priority::Sync,
+            DecodedMessageData::Tx(_)
+            | DecodedMessageData::TxBatch(_)
+            | DecodedMessageData::Unknown(_) => Priority::Tx,
+        }
+    }
+}
+
+// Number of messages each priority can buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueCapacities {
+    pub consensus: usize,
+    pub sync: usize,
+    pub tx: usize,
+}
+
+impl Default for QueueCapacities {
+    fn default() -> Self {
+        Self {
+            consensus: 256,
+            sync: 256,
+            tx: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // the sender waits until the receiver took a message
+    Block,
+    // the oldest message waiting is dropped to make room
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverflowPolicies {
+    pub consensus: OverflowPolicy,
+    pub sync: OverflowPolicy,
+    pub tx: OverflowPolicy,
+}
+
+impl Default for OverflowPolicies {
+    fn default() -> Self {
+        Self {
+            consensus: OverflowPolicy::Block,
+            sync: OverflowPolicy::Block,
+            tx: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+// Number of messages dropped because their queue was full, shared by every queue it's passed to
+#[derive(Debug, Clone, Default)]
+pub struct DropCounters {
+    counts: Arc<[AtomicU64; 3]>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DropCounts {
+    pub consensus: u64,
+    pub sync: u64,
+    pub tx: u64,
+}
+
+impl DropCounters {
+    pub fn counts(&self) -> DropCounts {
+        let [consensus, sync, tx] = self.counts.as_ref();
+        DropCounts {
+            consensus: consensus.load(Ordering::Relaxed),
+            sync: sync.load(Ordering::Relaxed),
+            tx: tx.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, priority: Priority) {
+        self.counts[priority as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn priority_queue<T>(
+    capacities: QueueCapacities,
+    policies: OverflowPolicies,
+    drops: DropCounters,
+) -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let lanes = Arc::new([
+        Lane::new(capacities.consensus, policies.consensus),
+        Lane::new(capacities.sync, policies.sync),
+        Lane::new(capacities.tx, policies.tx),
+    ]);
+
+    (
+        PrioritySender {
+            lanes: lanes.clone(),
+            drops,
+        },
+        PriorityReceiver { lanes },
+    )
+}
+
+// The queue of one priority
+#[derive(Debug)]
+struct Lane<T> {
+    state: Mutex<LaneState<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // notified when a message is queued or the last sender is gone
+    queued: Notify,
+    // notified when a message is taken or the receiver is gone
+    taken: Notify,
+}
+
+#[derive(Debug)]
+struct LaneState<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver: bool,
+}
+
+impl<T> Lane<T> {
+    fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::new(LaneState {
+                items: VecDeque::new(),
+                senders: 1,
+                receiver: true,
+            }),
+            capacity: capacity.max(1),
+            policy,
+            queued: Notify::new(),
+            taken: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LaneState<T>> {
+        self.state.lock().expect("priority queue lock poisoned")
+    }
+
+    // Returns false if the item can't be queued because the receiver is gone. Whether another item was dropped
+    // to make room is returned as well.
+    async fn send(&self, item: T) -> (bool, bool) {
+        loop {
+            // registered before the queue is checked, so a message taken in between isn't missed
+            let mut taken = pin!(self.taken.notified());
+            taken.as_mut().enable();
+            {
+                let mut state = self.lock();
+                if !state.receiver {
+                    return (false, false);
+                }
+                let full = state.items.len() >= self.capacity;
+                if !full || self.policy == OverflowPolicy::DropOldest {
+                    if full {
+                        state.items.pop_front();
+                    }
+                    state.items.push_back(item);
+                    self.queued.notify_one();
+                    return (true, full);
+                }
+            }
+            taken.await;
+        }
+    }
+
+    // None once all senders are gone and the queue is empty
+    async fn recv(&self) -> Option<T> {
+        loop {
+            let mut queued = pin!(self.queued.notified());
+            queued.as_mut().enable();
+            {
+                let mut state = self.lock();
+                if let Some(item) = state.items.pop_front() {
+                    self.taken.notify_one();
+                    return Some(item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            queued.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PrioritySender<T> {
+    lanes: Arc<[Lane<T>; 3]>,
+    drops: DropCounters,
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        for lane in self.lanes.iter() {
+            lane.lock().senders += 1;
+        }
+        Self {
+            lanes: self.lanes.clone(),
+            drops: self.drops.clone(),
+        }
+    }
+}
+
+impl<T> Drop for PrioritySender<T> {
+    fn drop(&mut self) {
+        for lane in self.lanes.iter() {
+            lane.lock().senders -= 1;
+            // wakes the receiver, which returns None once the queue is empty
+            lane.queued.notify_waiters();
+        }
+    }
+}
+
+impl<T> PrioritySender<T> {
+    // Returns false if the receiver is gone. A full queue waits or drops the oldest message, see OverflowPolicy.
+    pub async fn send(&self, priority: Priority, item: T) -> bool {
+        let (sent, dropped) = self.lanes[priority as usize].send(item).await;
+        if dropped {
+            debug!("{:?} queue is full, dropped the oldest message", priority);
+            self.drops.add(priority);
+        }
+        sent
+    }
+
+    pub fn drops(&self) -> &DropCounters {
+        &self.drops
+    }
+}
+
+#[derive(Debug)]
+pub struct PriorityReceiver<T> {
+    lanes: Arc<[Lane<T>; 3]>,
+}
+
+impl<T> Drop for PriorityReceiver<T> {
+    fn drop(&mut self) {
+        for lane in self.lanes.iter() {
+            lane.lock().receiver = false;
+            // blocked senders give up
+            lane.taken.notify_waiters();
+        }
+    }
+}
+
+impl<T> PriorityReceiver<T> {
+    // The most important item waiting, None once all senders are gone and the queues are empty
+    pub async fn recv(&mut self) -> Option<T> {
+        let [consensus, sync, tx] = self.lanes.as_ref();
+        tokio::select! {
+            biased;
+            Some(item) = consensus.recv() => Some(item),
+            Some(item) = sync.recv() => Some(item),
+            Some(item) = tx.recv() => Some(item),
+            else => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue<T>(capacities: QueueCapacities) -> (PrioritySender<T>, PriorityReceiver<T>) {
+        priority_queue(
+            capacities,
+            OverflowPolicies::default(),
+            DropCounters::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_priority_order() {
+        let (tx, mut rx) = queue(QueueCapacities::default());
+
+        assert!(tx.send(Priority::Tx, "tx").await);
+        assert!(tx.send(Priority::Sync, "status").await);
+        assert!(tx.send(Priority::Consensus, "block").await);
+
+        assert_eq!(rx.recv().await, Some("block"));
+        assert_eq!(rx.recv().await, Some("status"));
+        assert_eq!(rx.recv().await, Some("tx"));
+
+        drop(tx);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_full_tx_queue_drops_the_oldest() {
+        let (tx, mut rx) = queue(QueueCapacities {
+            consensus: 1,
+            sync: 1,
+            tx: 2,
+        });
+
+        for i in 0..5 {
+            assert!(tx.send(Priority::Tx, i).await);
+        }
+        assert_eq!(
+            tx.drops().counts(),
+            DropCounts {
+                tx: 3,
+                ..DropCounts::default()
+            }
+        );
+        // the transactions don't hold up a block
+        assert!(tx.send(Priority::Consensus, 10).await);
+        assert_eq!(rx.recv().await, Some(10));
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(rx.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_full_consensus_queue_blocks() {
+        let (tx, mut rx) = queue(QueueCapacities {
+            consensus: 1,
+            sync: 1,
+            tx: 1,
+        });
+
+        assert!(tx.send(Priority::Consensus, 1).await);
+        let sender = tx.clone();
+        let blocked = tokio::task::spawn(async move { sender.send(Priority::Consensus, 2).await });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!blocked.is_finished());
+
+        // taking a message makes room
+        assert_eq!(rx.recv().await, Some(1));
+        assert!(blocked.await.unwrap());
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(tx.drops().counts(), DropCounts::default());
+
+        // a sender waiting for a receiver that is gone gives up
+        assert!(tx.send(Priority::Consensus, 3).await);
+        let sender = tx.clone();
+        let blocked = tokio::task::spawn(async move { sender.send(Priority::Consensus, 4).await });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        drop(rx);
+        assert!(!blocked.await.unwrap());
+    }
+}
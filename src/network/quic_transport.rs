@@ -0,0 +1,383 @@
+/*
+QuicTransport connects nodes over QUIC (quinn). A connection multiplexes streams, is encrypted with TLS and
+survives a change of the peer's address (connection migration).
+
+Messages to a peer go over one of two unidirectional streams, so a large sync response doesn't hold up the
+gossip behind it:
+
+    sync:   blocks, status and sync requests
+    gossip: transactions, ping/pong, proposals and votes
+
+Every stream carries frames of the FrameCodec. Each node has a self signed certificate, the certificates aren't
+verified because nodes are identified by their keys and not by a certificate authority.
+
+Only built with the quic feature.
+*/
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use quinn::{
+    crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint, RecvStream, SendStream,
+    ServerConfig,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug, info};
+
+use super::{
+    new_channel, peek_message_type, transport::Transport, Channel, Frame, FrameCodec, MessageType,
+    NetAddr, Payload, RPC,
+};
+
+// the name in the certificate, see SkipServerVerification
+const SERVER_NAME: &str = "projectx";
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Sync,
+    Gossip,
+}
+
+impl StreamKind {
+    pub fn of(msg_type: &MessageType) -> Self {
+        match msg_type {
+            MessageType::Block
+            | MessageType::GetBlocks
+            | MessageType::Blocks
+            | MessageType::Status
+            | MessageType::GetStatus
+            | MessageType::GetPeers
+            | MessageType::Peers
+            | MessageType::NewBlockHashes => StreamKind::Sync,
+            MessageType::Tx
+            | MessageType::TxBatch
+            | MessageType::Ping
+            | MessageType::Pong
+            | MessageType::Proposal
+            | MessageType::Vote
+            | MessageType::Unknown(_) => StreamKind::Gossip,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerConnection {
+    conn: Connection,
+    streams: HashMap<StreamKind, Arc<Mutex<SendStream>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuicTransport {
+    addr: NetAddr,
+    endpoint: Endpoint,
+    consume_channel: Channel<RPC>,
+    peers: Arc<RwLock<HashMap<NetAddr, Box<dyn Transport>>>>,
+    // open connections by the address of the peer, dialed by us or by them
+    connections: Arc<Mutex<HashMap<SocketAddr, PeerConnection>>>,
+}
+
+impl QuicTransport {
+    // Binds the endpoint and starts accepting connections, a port of 0 picks a free port
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+
+        let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+        let server_config = ServerConfig::with_single_cert(vec![cert.cert.der().clone()], key)?;
+
+        let client_crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification(provider)))
+            .with_no_client_auth();
+        let client_config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+
+        let mut endpoint = Endpoint::server(server_config, addr)?;
+        endpoint.set_default_client_config(client_config);
+
+        let tr = Self {
+            addr: NetAddr::Socket(endpoint.local_addr()?),
+            endpoint,
+            consume_channel: new_channel(1024),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        let accepting = tr.clone();
+        tokio::task::spawn(async move { accepting.accept_loop().await });
+
+        info!("QUIC transport listening on {}", tr.addr);
+        Ok(tr)
+    }
+
+    async fn accept_loop(&self) {
+        while let Some(incoming) = self.endpoint.accept().await {
+            let tr = self.clone();
+            tokio::task::spawn(async move {
+                match incoming.await {
+                    Ok(conn) => tr.add_connection(conn).await,
+                    Err(err) => debug!("QUIC handshake failed: {err}"),
+                }
+            });
+        }
+    }
+
+    // Keeps the connection for sending and reads the streams the peer opens on it
+    async fn add_connection(&self, conn: Connection) {
+        let remote = conn.remote_address();
+        self.connections.lock().await.insert(
+            remote,
+            PeerConnection {
+                conn: conn.clone(),
+                streams: HashMap::new(),
+            },
+        );
+
+        let sender = self.consume_channel.0.clone();
+        tokio::task::spawn(async move {
+            while let Ok(stream) = conn.accept_uni().await {
+                let sender = sender.clone();
+                tokio::task::spawn(async move {
+                    if let Err(err) = read_frames(stream, remote, sender).await {
+                        debug!("QUIC stream from {remote} closed: {err}");
+                    }
+                });
+            }
+        });
+    }
+
+    // The stream of the given kind to the peer, the connection and the stream are opened on first use
+    async fn stream(&self, to: SocketAddr, kind: StreamKind) -> Result<Arc<Mutex<SendStream>>> {
+        if !self.connections.lock().await.contains_key(&to) {
+            let conn = self.endpoint.connect(to, SERVER_NAME)?.await?;
+            self.add_connection(conn).await;
+        }
+
+        let mut connections = self.connections.lock().await;
+        let peer = connections
+            .get_mut(&to)
+            .ok_or_else(|| anyhow!("connection to {to} was closed"))?;
+        if let Some(stream) = peer.streams.get(&kind) {
+            return Ok(stream.clone());
+        }
+
+        let stream = Arc::new(Mutex::new(peer.conn.open_uni().await?));
+        peer.streams.insert(kind, stream.clone());
+        Ok(stream)
+    }
+
+    async fn send(&self, to: SocketAddr, payload: Payload) -> Result<()> {
+        let msg_type = peek_message_type(&payload)?;
+        let stream = self.stream(to, StreamKind::of(&msg_type)).await?;
+
+        let mut buf = BytesMut::new();
+        FrameCodec::default().encode(Frame::new(msg_type.code(), payload), &mut buf)?;
+
+        let result = stream.lock().await.write_all(&buf).await;
+        if let Err(err) = result {
+            // the next message opens a new connection
+            self.connections.lock().await.remove(&to);
+            return Err(anyhow!("could not send to {to}: {err}"));
+        }
+        Ok(())
+    }
+}
+
+fn socket_addr(addr: &NetAddr) -> Result<SocketAddr> {
+    addr.socket_addr()
+        .ok_or_else(|| anyhow!("{addr} is not a socket address"))
+}
+
+async fn read_frames(
+    mut stream: RecvStream,
+    remote: SocketAddr,
+    sender: mpsc::Sender<RPC>,
+) -> Result<()> {
+    let mut codec = FrameCodec::default();
+    let mut buf = BytesMut::new();
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+
+    loop {
+        while let Some(frame) = codec.decode(&mut buf)? {
+            sender
+                .send(RPC {
+                    from: NetAddr::Socket(remote),
+                    payload: frame.payload,
+                })
+                .await?;
+        }
+
+        match stream.read(&mut chunk).await? {
+            Some(n) => buf.extend_from_slice(&chunk[..n]),
+            None => return Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for QuicTransport {
+    fn consume(&self) -> Channel<RPC> {
+        self.consume_channel.clone()
+    }
+
+    async fn recv(&self) -> Option<RPC> {
+        self.consume_channel.1.lock().await.recv().await
+    }
+
+    async fn connect(&self, tr: Box<dyn Transport>) -> Result<()> {
+        let addr = socket_addr(&tr.addr())?;
+        if !self.connections.lock().await.contains_key(&addr) {
+            let conn = self.endpoint.connect(addr, SERVER_NAME)?.await?;
+            self.add_connection(conn).await;
+        }
+        self.peers.write().await.insert(tr.addr(), tr);
+        Ok(())
+    }
+
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()> {
+        self.peers.write().await.remove(addr);
+        if let Some(peer) = self.connections.lock().await.remove(&socket_addr(addr)?) {
+            peer.conn.close(0_u32.into(), b"disconnect");
+        }
+        Ok(())
+    }
+
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()> {
+        if &self.addr == to {
+            return Ok(());
+        }
+        self.send(socket_addr(to)?, payload).await
+    }
+
+    async fn broadcast(&self, payload: Payload) -> Result<()> {
+        let peers: Vec<NetAddr> = self.peers.read().await.keys().cloned().collect();
+        for peer in peers {
+            self.send(socket_addr(&peer)?, payload.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>> {
+        self.peers.read().await.clone()
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.addr.clone()
+    }
+}
+
+// Accepts any certificate, the TLS signatures are still checked
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::network::Message;
+
+    fn bind() -> Result<QuicTransport> {
+        QuicTransport::bind("127.0.0.1:0".parse()?)
+    }
+
+    async fn recv(tr: &QuicTransport) -> Option<RPC> {
+        tokio::time::timeout(Duration::from_secs(2), tr.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[test]
+    fn test_stream_kind() {
+        assert_eq!(StreamKind::of(&MessageType::Block), StreamKind::Sync);
+        assert_eq!(StreamKind::of(&MessageType::GetStatus), StreamKind::Sync);
+        assert_eq!(StreamKind::of(&MessageType::Tx), StreamKind::Gossip);
+        assert_eq!(StreamKind::of(&MessageType::Vote), StreamKind::Gossip);
+    }
+
+    #[tokio::test]
+    async fn test_send_message() -> Result<()> {
+        let a = bind()?;
+        let b = bind()?;
+        a.connect(Box::new(b.clone())).await?;
+
+        // one message on each stream
+        let tx = Message::new(MessageType::Tx, vec![1, 2, 3]).bytes()?;
+        let block = Message::new(MessageType::Block, vec![4; 100_000]).bytes()?;
+        a.send_message(&b.addr(), block.clone()).await?;
+        a.send_message(&b.addr(), tx.clone()).await?;
+
+        let mut received = [recv(&b).await.unwrap(), recv(&b).await.unwrap()];
+        received.sort_by_key(|rpc| rpc.payload.len());
+        assert_eq!(received[0].payload, tx);
+        assert_eq!(received[1].payload, block);
+        assert_eq!(received[0].from, a.addr());
+
+        // b answers over the connection a opened
+        b.send_message(&a.addr(), tx.clone()).await?;
+        assert_eq!(recv(&a).await.unwrap().payload, tx);
+
+        // types added by newer nodes are passed on, the receiver decides what to do with them
+        let unknown = Message::new(MessageType::Unknown(0x1234), vec![]).bytes()?;
+        a.send_message(&b.addr(), unknown.clone()).await?;
+        assert_eq!(recv(&b).await.unwrap().payload, unknown);
+
+        Ok(())
+    }
+}
@@ -1,113 +1,404 @@
 // currently not using these traits because i couldn't get it to work with mutable references
 
-use super::{transport::NetAddr, GetBlocksMessage};
+use super::{
+    BlocksMessage, GetBlocksMessage, NetAddr, NewBlockHashesMessage, Payload, PeerId,
+    TxBatchMessage, DEFAULT_MAX_BLOCK_SIZE, DEFAULT_MAX_FRAME_SIZE,
+};
 use crate::{
-    core::{BincodeDecoder, BincodeEncoder, Block, Decoder, Encoder, Header, Transaction},
-    network::message::StatusMessage,
+    consensus::{Proposal, Vote},
+    core::{BincodeEncoder, Block, Encoder, Transaction, MAX_RAW_TX_SIZE},
+    network::message::{
+        is_supported_version, PeersMessage, PingMessage, PongMessage, StatusMessage,
+        PROTOCOL_VERSION,
+    },
 };
 use anyhow::{anyhow, Result};
-use log::debug;
-use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use bincode::Options;
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
 
-#[derive(Deserialize, Serialize, Debug)]
+// The type of a message is encoded as its u16 code. Codes added by newer nodes decode as Unknown, so an old node
+// ignores those messages instead of failing to read the envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
-    Tx = 0x01,
-    Block = 0x02,
-    GetBlocks = 0x03,
-    Status = 0x04,
-    GetStatus = 0x05,
+    Tx,
+    Block,
+    GetBlocks,
+    Status,
+    GetStatus,
+    Ping,
+    Pong,
+    Proposal,
+    Vote,
+    GetPeers,
+    Peers,
+    Blocks,
+    TxBatch,
+    NewBlockHashes,
+    Unknown(u16),
+}
+
+impl MessageType {
+    pub fn code(&self) -> u16 {
+        match self {
+            MessageType::Tx => 0x01,
+            MessageType::Block => 0x02,
+            MessageType::GetBlocks => 0x03,
+            MessageType::Status => 0x04,
+            MessageType::GetStatus => 0x05,
+            MessageType::Ping => 0x06,
+            MessageType::Pong => 0x07,
+            MessageType::Proposal => 0x08,
+            MessageType::Vote => 0x09,
+            MessageType::GetPeers => 0x0a,
+            MessageType::Peers => 0x0b,
+            MessageType::Blocks => 0x0c,
+            MessageType::TxBatch => 0x0d,
+            MessageType::NewBlockHashes => 0x0e,
+            MessageType::Unknown(code) => *code,
+        }
+    }
+}
+
+impl From<u16> for MessageType {
+    fn from(code: u16) -> Self {
+        match code {
+            0x01 => MessageType::Tx,
+            0x02 => MessageType::Block,
+            0x03 => MessageType::GetBlocks,
+            0x04 => MessageType::Status,
+            0x05 => MessageType::GetStatus,
+            0x06 => MessageType::Ping,
+            0x07 => MessageType::Pong,
+            0x08 => MessageType::Proposal,
+            0x09 => MessageType::Vote,
+            0x0a => MessageType::GetPeers,
+            0x0b => MessageType::Peers,
+            0x0c => MessageType::Blocks,
+            0x0d => MessageType::TxBatch,
+            0x0e => MessageType::NewBlockHashes,
+            code => MessageType::Unknown(code),
+        }
+    }
+}
+
+impl Serialize for MessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(u16::deserialize(deserializer)?.into())
+    }
 }
 
+// how many times a gossiped message is relayed at most, bounds its spreading in networks with cycles
+pub const DEFAULT_GOSSIP_TTL: u8 = 8;
+
 #[derive(Debug, Clone)]
 pub struct RPC {
     pub from: NetAddr,
-    pub payload: Vec<u8>,
+    pub payload: Payload,
 }
 
 #[derive(Debug)]
 pub enum DecodedMessageData {
     Tx(Transaction),
+    TxBatch(TxBatchMessage),
     Block(Block),
     StatusMessage(StatusMessage),
     GetStatusMessage,
     GetBlocksMessage(GetBlocksMessage),
+    BlocksMessage(BlocksMessage),
+    Ping(PingMessage),
+    Pong(PongMessage),
+    Proposal(Proposal),
+    Vote(Vote),
+    GetPeersMessage,
+    PeersMessage(PeersMessage),
+    NewBlockHashes(NewBlockHashesMessage),
+    // a message type this node doesn't know, the data isn't decoded
+    Unknown(u16),
+}
+
+impl DecodedMessageData {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            DecodedMessageData::Tx(_) => MessageType::Tx,
+            DecodedMessageData::TxBatch(_) => MessageType::TxBatch,
+            DecodedMessageData::Block(_) => MessageType::Block,
+            DecodedMessageData::StatusMessage(_) => MessageType::Status,
+            DecodedMessageData::GetStatusMessage => MessageType::GetStatus,
+            DecodedMessageData::GetBlocksMessage(_) => MessageType::GetBlocks,
+            DecodedMessageData::BlocksMessage(_) => MessageType::Blocks,
+            DecodedMessageData::Ping(_) => MessageType::Ping,
+            DecodedMessageData::Pong(_) => MessageType::Pong,
+            DecodedMessageData::Proposal(_) => MessageType::Proposal,
+            DecodedMessageData::Vote(_) => MessageType::Vote,
+            DecodedMessageData::GetPeersMessage => MessageType::GetPeers,
+            DecodedMessageData::PeersMessage(_) => MessageType::Peers,
+            DecodedMessageData::NewBlockHashes(_) => MessageType::NewBlockHashes,
+            DecodedMessageData::Unknown(code) => MessageType::Unknown(*code),
+        }
+    }
 }
 
 pub struct DecodedMessage {
     pub from: NetAddr,
+    // see Message
+    pub origin: PeerId,
+    pub ttl: u8,
     pub data: DecodedMessageData,
 }
 
 pub type RPCDecodeFn = Box<dyn Fn(RPC) -> Result<DecodedMessage> + Send + Sync>;
 
-pub fn default_rpc_decode_fn(mut rpc: RPC) -> Result<DecodedMessage> {
-    let mut msg = Message {
-        header: MessageType::Tx,
-        data: vec![],
-    };
+// The largest messages a node decodes, in bytes. They're checked while decoding, so an announced length can't make
+// the decoder allocate more. A transaction batch or a chunk of blocks may not be larger than max_message_size and
+// each of its transactions or blocks has to fit into its own limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_message_size: usize,
+    pub max_block_size: usize,
+    pub max_tx_size: usize,
+}
 
-    let mut cursor = Cursor::new(&mut rpc.payload);
-    let mut dec = BincodeDecoder::new(&mut cursor);
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            max_tx_size: MAX_RAW_TX_SIZE,
+        }
+    }
+}
+
+// The error of a message exceeding the DecodeLimits, the peer sending it is banned, see ConnectionManager::ban
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OversizedMessage {
+    // what exceeded its limit, e.g. "block"
+    pub what: &'static str,
+    pub limit: usize,
+}
+
+impl fmt::Display for OversizedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} exceeds the limit of {} bytes", self.what, self.limit)
+    }
+}
 
-    dec.decode(&mut msg)
+impl std::error::Error for OversizedMessage {}
+
+pub fn default_rpc_decode_fn(rpc: RPC) -> Result<DecodedMessage> {
+    decode_rpc(rpc, &DecodeLimits::default())
+}
+
+pub fn rpc_decode_fn_with_limits(limits: DecodeLimits) -> RPCDecodeFn {
+    Box::new(move |rpc| decode_rpc(rpc, &limits))
+}
+
+fn decode_rpc(rpc: RPC, limits: &DecodeLimits) -> Result<DecodedMessage> {
+    if rpc.payload.len() > limits.max_message_size {
+        return Err(OversizedMessage {
+            what: "message",
+            limit: limits.max_message_size,
+        }
+        .into());
+    }
+    let msg = Message::from_payload(&rpc.payload)
         .map_err(|err| anyhow!("invalid message header! error: {}", err))?;
 
+    if !is_supported_version(msg.version) {
+        return Err(anyhow!(
+            "unsupported protocol version {} from {}",
+            msg.version,
+            rpc.from
+        ));
+    }
+
+    // Every protocol version gets its own decoder so payload layouts can change
+    // without breaking peers that negotiated an older version.
+    let (origin, ttl) = (msg.origin, msg.ttl);
+    let data = match msg.version {
+        1 => decode_message_v1(msg, limits)?,
+        version => return Err(anyhow!("no decoder for protocol version {version}")),
+    };
+
+    Ok(DecodedMessage {
+        from: rpc.from,
+        origin,
+        ttl,
+        data,
+    })
+}
+
+// Reads the type of a message without decoding its data
+pub fn peek_message_type(payload: &[u8]) -> Result<MessageType> {
+    // version and header are the first fields of Message
+    let (_version, header): (u32, MessageType) = bincode::deserialize(payload)?;
+    Ok(header)
+}
+
+fn decode_message_v1(msg: Message, limits: &DecodeLimits) -> Result<DecodedMessageData> {
+    let data = &msg.data[..];
+    let max = limits.max_message_size;
+
     match msg.header {
         MessageType::Tx => {
-            let mut tx = Transaction::new(vec![]);
-            let mut cursor = Cursor::new(&mut msg.data);
-            let mut dec = BincodeDecoder::new(&mut cursor);
-            dec.decode(&mut tx)?;
-            Ok(DecodedMessage {
-                from: rpc.from.clone(),
-                data: DecodedMessageData::Tx(tx),
-            })
+            let tx = decode_limited(data, "transaction", limits.max_tx_size)?;
+            Ok(DecodedMessageData::Tx(tx))
+        }
+        MessageType::TxBatch => {
+            let message: TxBatchMessage = decode_limited(data, "transaction batch", max)?;
+            for tx in &message.txx {
+                check_size(tx, "transaction", limits.max_tx_size)?;
+            }
+            Ok(DecodedMessageData::TxBatch(message))
         }
         MessageType::Block => {
-            let mut block = Block::new(Header::default(), vec![]);
-            let mut cursor = Cursor::new(&mut msg.data);
-            let mut dec = BincodeDecoder::new(&mut cursor);
-            dec.decode(&mut block)?;
-            Ok(DecodedMessage {
-                from: rpc.from.clone(),
-                data: DecodedMessageData::Block(block),
-            })
+            let block = decode_limited(data, "block", limits.max_block_size)?;
+            Ok(DecodedMessageData::Block(block))
         }
-        MessageType::GetStatus => Ok(DecodedMessage {
-            from: rpc.from.clone(),
-            data: DecodedMessageData::GetStatusMessage,
-        }),
-        MessageType::Status => {
-            let mut message = StatusMessage::new("".into(), 0, 0);
-            let mut cursor = Cursor::new(&mut msg.data);
-            let mut dec = BincodeDecoder::new(&mut cursor);
-            dec.decode(&mut message)?;
-            Ok(DecodedMessage {
-                from: rpc.from.clone(),
-                data: DecodedMessageData::StatusMessage(message),
-            })
+        MessageType::GetBlocks => Ok(DecodedMessageData::GetBlocksMessage(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::Blocks => {
+            let message: BlocksMessage = decode_limited(data, "blocks message", max)?;
+            for block in &message.blocks {
+                check_size(block, "block", limits.max_block_size)?;
+            }
+            Ok(DecodedMessageData::BlocksMessage(message))
+        }
+        MessageType::GetStatus => Ok(DecodedMessageData::GetStatusMessage),
+        MessageType::Status => Ok(DecodedMessageData::StatusMessage(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::Ping => Ok(DecodedMessageData::Ping(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::Pong => Ok(DecodedMessageData::Pong(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::Proposal => {
+            let proposal: Proposal = decode_limited(data, "proposal", max)?;
+            check_size(&proposal.block, "block", limits.max_block_size)?;
+            Ok(DecodedMessageData::Proposal(proposal))
         }
-        // MessageType::Block => {}
-        _ => Err(anyhow!("unhandled message type")),
+        MessageType::Vote => Ok(DecodedMessageData::Vote(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::GetPeers => Ok(DecodedMessageData::GetPeersMessage),
+        MessageType::Peers => Ok(DecodedMessageData::PeersMessage(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::NewBlockHashes => Ok(DecodedMessageData::NewBlockHashes(decode_limited(
+            data, "message", max,
+        )?)),
+        MessageType::Unknown(code) => Ok(DecodedMessageData::Unknown(code)),
+    }
+}
+
+// Decodes like the BincodeDecoder, but fails with an OversizedMessage once more than limit bytes were read.
+// bincode ignores the limit when deserializing a slice, so the data is read through a reader.
+fn decode_limited<T: DeserializeOwned>(
+    mut data: &[u8],
+    what: &'static str,
+    limit: usize,
+) -> Result<T> {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+        .deserialize_from(&mut data)
+        .map_err(|err| match *err {
+            bincode::ErrorKind::SizeLimit => OversizedMessage { what, limit }.into(),
+            err => anyhow!(err),
+        })
+}
+
+fn check_size<T: Serialize>(t: &T, what: &'static str, limit: usize) -> Result<()> {
+    if bincode::serialized_size(t)? > limit as u64 {
+        return Err(OversizedMessage { what, limit }.into());
     }
+    Ok(())
 }
 
-#[derive(Deserialize, Serialize)]
+// The version has to stay the first field, so it can be read before the rest of
+// the envelope is interpreted.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Message {
+    pub version: u32,
     pub header: MessageType,
-    pub data: Vec<u8>,
+    // the node that first sent a gossiped message, the default id for a message to a single peer
+    pub origin: PeerId,
+    // how many more times a gossiped message is relayed, 0 for a message to a single peer
+    pub ttl: u8,
+    pub data: Bytes,
 }
 
 impl Message {
-    pub fn new(header: MessageType, data: Vec<u8>) -> Self {
-        Self { header, data }
+    pub fn new(header: MessageType, data: impl Into<Bytes>) -> Self {
+        Self::with_version(PROTOCOL_VERSION, header, data)
+    }
+
+    pub fn with_version(version: u32, header: MessageType, data: impl Into<Bytes>) -> Self {
+        Self {
+            version,
+            header,
+            origin: PeerId::default(),
+            ttl: 0,
+            data: data.into(),
+        }
     }
 
-    pub fn bytes(&self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
+    // Marks the message as gossip of origin, which is relayed ttl more times
+    pub fn with_gossip(mut self, origin: PeerId, ttl: u8) -> Self {
+        self.origin = origin;
+        self.ttl = ttl;
+        self
+    }
+
+    // Reads the envelope of a message. The data isn't copied, it's a slice of payload.
+    pub fn from_payload(payload: &Bytes) -> Result<Self> {
+        // data is serialized as its length followed by the bytes
+        let (version, header, origin, ttl, len): (u32, MessageType, PeerId, u8, u64) =
+            bincode::deserialize(payload)?;
+        let start = bincode::serialized_size(&(version, &header, origin, ttl, len))? as usize;
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| start.checked_add(len))
+            .filter(|end| *end <= payload.len())
+            .ok_or(anyhow!("message data is truncated"))?;
+
+        Ok(Self {
+            version,
+            header,
+            origin,
+            ttl,
+            data: payload.slice(start..end),
+        })
+    }
+
+    // Encodes the message in version, e.g. the protocol version negotiated with the peer it goes to. Like the
+    // decoders every version gets its own encoder, see decode_message.
+    pub fn encode_in(&self, version: u32) -> Result<Bytes> {
+        match version {
+            1 => Message {
+                version,
+                ..self.clone()
+            }
+            .bytes(),
+            version => Err(anyhow!("no encoder for protocol version {version}")),
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Bytes> {
+        let mut buf = Vec::with_capacity(self.data.len() + 16);
         BincodeEncoder::new(&mut buf).encode(&self)?;
-        Ok(buf)
+        Ok(buf.into())
     }
 }
 
@@ -128,3 +419,222 @@ impl<'a> DefaultRPCHandler<'a> {
 pub trait RPCProcessor {
     fn process_transaction(&mut self, net_addr: &NetAddr, tx: Transaction) -> Result<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::Header,
+        network::{PeerAddr, Priority},
+        test_utils::{encoded, random_block},
+        types::Hash,
+    };
+
+    fn rpc(msg: &Message) -> Result<RPC> {
+        Ok(RPC {
+            from: "A".into(),
+            payload: msg.bytes()?,
+        })
+    }
+
+    #[test]
+    fn test_decode_status_message() -> Result<()> {
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&StatusMessage::new(
+            "A".into(),
+            PeerId::default(),
+            7,
+            "B".into(),
+        ))?;
+        let msg = Message::new(MessageType::Status, buf);
+
+        let decoded = default_rpc_decode_fn(rpc(&msg)?)?;
+        assert_eq!(decoded.from, NetAddr::from("A"));
+        match decoded.data {
+            DecodedMessageData::StatusMessage(status) => {
+                assert_eq!(status.current_height, 7);
+                assert_eq!(status.observed_addr, NetAddr::from("B"));
+                assert_eq!(status.version, PROTOCOL_VERSION);
+            }
+            data => panic!("expected status message, got {data:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_peers_message() -> Result<()> {
+        let peers = PeersMessage {
+            peers: vec![PeerAddr {
+                addr: "10.0.0.2:3000".into(),
+                age_secs: 60,
+            }],
+        };
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&peers)?;
+        let msg = Message::new(MessageType::Peers, buf);
+
+        match default_rpc_decode_fn(rpc(&msg)?)?.data {
+            DecodedMessageData::PeersMessage(decoded) => assert_eq!(decoded, peers),
+            data => panic!("expected peers message, got {data:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_blocks_messages() -> Result<()> {
+        let get_blocks = GetBlocksMessage { from: 3, to: 90 };
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&get_blocks)?;
+        let msg = Message::new(MessageType::GetBlocks, buf);
+
+        match default_rpc_decode_fn(rpc(&msg)?)?.data {
+            DecodedMessageData::GetBlocksMessage(decoded) => assert_eq!(decoded, get_blocks),
+            data => panic!("expected get blocks message, got {data:?}"),
+        }
+
+        let block = random_block(3, Hash::random())?;
+        let blocks = BlocksMessage {
+            blocks: vec![block.clone()],
+            more: true,
+        };
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&blocks)?;
+        let msg = Message::new(MessageType::Blocks, buf);
+
+        match default_rpc_decode_fn(rpc(&msg)?)?.data {
+            DecodedMessageData::BlocksMessage(decoded) => {
+                assert_eq!(decoded.blocks.len(), 1);
+                assert_eq!(decoded.blocks[0].header, block.header);
+                assert!(decoded.more);
+            }
+            data => panic!("expected blocks message, got {data:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek_message_type() -> Result<()> {
+        let msg = Message::new(MessageType::Block, vec![1, 2, 3]);
+        assert!(matches!(
+            peek_message_type(&msg.bytes()?)?,
+            MessageType::Block
+        ));
+        assert!(peek_message_type(&[1, 0]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_from_payload() -> Result<()> {
+        let payload = Message::new(MessageType::Block, vec![1, 2, 3]).bytes()?;
+        let msg = Message::from_payload(&payload)?;
+        assert!(matches!(msg.header, MessageType::Block));
+        assert_eq!(msg.data, vec![1, 2, 3]);
+        // the data points into the payload
+        assert_eq!(msg.data.as_ptr(), payload[payload.len() - 3..].as_ptr());
+
+        assert!(Message::from_payload(&payload.slice(..payload.len() - 1)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gossip_envelope() -> Result<()> {
+        let origin = PeerId::from_public_key(&crate::crypto::PrivateKey::generate().public_key());
+        let msg = Message::new(MessageType::GetStatus, vec![]).with_gossip(origin, 5);
+        assert!(matches!(
+            peek_message_type(&msg.bytes()?)?,
+            MessageType::GetStatus
+        ));
+
+        let decoded = default_rpc_decode_fn(rpc(&msg)?)?;
+        assert_eq!((decoded.origin, decoded.ttl), (origin, 5));
+        let decoded = default_rpc_decode_fn(rpc(&Message::new(MessageType::GetStatus, vec![]))?)?;
+        assert_eq!((decoded.origin, decoded.ttl), (PeerId::default(), 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_message_type() -> Result<()> {
+        // the type follows the version as a little endian u16
+        let payload = Message::new(MessageType::Unknown(0x1234), vec![1, 2, 3]).bytes()?;
+        assert_eq!(payload[4..6], [0x34, 0x12]);
+        assert_eq!(peek_message_type(&payload)?, MessageType::Unknown(0x1234));
+
+        let decoded = default_rpc_decode_fn(RPC {
+            from: "A".into(),
+            payload,
+        })?;
+        assert!(matches!(decoded.data, DecodedMessageData::Unknown(0x1234)));
+        assert_eq!(Priority::of(&decoded.data), Priority::Tx);
+
+        for code in 0x01..=0x0d {
+            assert_eq!(MessageType::from(code).code(), code);
+            assert_ne!(MessageType::from(code), MessageType::Unknown(code));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_limits() -> Result<()> {
+        let limits = DecodeLimits {
+            max_message_size: 4096,
+            max_block_size: 2048,
+            max_tx_size: 256,
+        };
+        let decode = rpc_decode_fn_with_limits(limits);
+        let oversized = |msg: &Message| -> Result<Option<OversizedMessage>> {
+            Ok(decode(rpc(msg)?)
+                .err()
+                .and_then(|err| err.downcast_ref::<OversizedMessage>().cloned()))
+        };
+        let small = Transaction::new(vec![1; 16]);
+        let large = Transaction::new(vec![1; 512]);
+        let msg = Message::new(MessageType::Tx, encoded(&small)?);
+        assert!(matches!(
+            decode(rpc(&msg)?)?.data,
+            DecodedMessageData::Tx(_)
+        ));
+        let msg = Message::new(MessageType::Tx, encoded(&large)?);
+        assert_eq!(
+            oversized(&msg)?,
+            Some(OversizedMessage {
+                what: "transaction",
+                limit: 256
+            })
+        );
+
+        // every transaction of a batch has to fit
+        let batch = TxBatchMessage {
+            txx: vec![small.clone(), large],
+        };
+        let msg = Message::new(MessageType::TxBatch, encoded(&batch)?);
+        assert_eq!(oversized(&msg)?.map(|o| o.what), Some("transaction"));
+
+        let block = Block::new(Header::default(), vec![small; 64]);
+        let msg = Message::new(MessageType::Block, encoded(&block)?);
+        assert_eq!(oversized(&msg)?.map(|o| o.what), Some("block"));
+
+        // the payload is rejected before it's decoded
+        let msg = Message::new(MessageType::GetStatus, vec![0; 8192]);
+        assert_eq!(oversized(&msg)?.map(|o| o.what), Some("message"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_unsupported_version() -> Result<()> {
+        let msg = Message::with_version(PROTOCOL_VERSION + 1, MessageType::GetStatus, vec![]);
+        assert!(default_rpc_decode_fn(rpc(&msg)?).is_err());
+
+        let msg = Message::with_version(0, MessageType::GetStatus, vec![]);
+        assert!(default_rpc_decode_fn(rpc(&msg)?).is_err());
+
+        Ok(())
+    }
+}
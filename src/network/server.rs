@@ -1,43 +1,206 @@
 use anyhow::{anyhow, Result};
-use log::{debug, error, info, warn};
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::PathBuf,
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
+#[cfg(feature = "api")]
+use crate::api::ApiOpts;
 use crate::{
-    core::{BincodeEncoder, Block, BlockHasher, Blockchain, Encoder, Transaction, TxHasher},
+    consensus::{Action, ConsensusEngine, ConsensusMessage, ConsensusOpts, Proposal, Timeout},
+    core::{
+        BClock, BincodeEncoder, Block, BlockHasher, BlockValidator, Blockchain, Checkpoints,
+        DoubleSignDetector, Encoder, Evidence, FileStore, GenesisConfig, Hasher, NodeMode, Pruning,
+        SigCache, SledStateStore, State, SystemClock, Transaction, TxHasher, TxStatus,
+    },
     crypto::PrivateKey,
     network::DecodedMessageData,
+    types::Hash,
 };
-use tokio::{sync::Mutex, time};
+use tokio::sync::{mpsc, Mutex, Notify, RwLock};
 
 use super::{
-    default_rpc_decode_fn,
-    message::{GetStatusMessage, StatusMessage},
-    new_channel,
-    transport::NetAddr,
-    tx_pool::TxPool,
-    BTransport, Channel, DecodedMessage, GetBlocksMessage, Message, MessageType, RPCDecodeFn,
-    Transport, RPC,
+    block_production::{BlockProducer, BlockProductionPolicy, DEFAULT_MAX_PRODUCTION_LAG},
+    broadcast::{Broadcaster, OutboundOpts},
+    connection_manager::{
+        resolve_dns_seeds, ConnectionManager, ConnectionManagerOpts, PeerEvent,
+        ADDRESS_BOOK_SAVE_INTERVAL,
+    },
+    decode_pool::{DecodePool, DEFAULT_DECODE_WORKERS},
+    health::{chain_lag, ChainLagEvent, LagAlarm},
+    message::{
+        BlockAnnouncement, GetStatusMessage, NewBlockHashesMessage, PeersMessage, PingMessage,
+        StatusMessage,
+    },
+    message_version, new_channel,
+    orphan_pool::OrphanPool,
+    peek_message_type, priority_queue, rpc_decode_fn_with_limits,
+    sync_status::{SyncStatus, SyncTargets},
+    tx_batch::{next_tx_batch, DEFAULT_TX_BATCH_INTERVAL, MAX_TX_BATCH_SIZE},
+    BTransport, BlockVerifier, BlocksMessage, Channel, DecodeLimits, DecodedMessage, DropCounters,
+    ExternalAddrs, GetBlocksMessage, Handshake, Message, MessageType, NetAddr, NodeInfo,
+    OverflowPolicies, OversizedMessage, PeerId, PeerInfo, PortMappingOpts, Priority,
+    PriorityReceiver, PrioritySender, QueueCapacities, RPCDecodeFn, RemotePeer, SenderLimits,
+    ServerCommand, ServerHandle, TaskSupervisor, Transport, TxBatchMessage, TxPool,
+    BLOCK_TIME_WINDOW, DEFAULT_GOSSIP_TTL, DEFAULT_MAX_BLOCK_SIZE, DEFAULT_SYNC_INTERVAL,
+    MAX_BLOCKS_CHUNK_SIZE, MAX_BLOCKS_PER_RESPONSE, PORT_MAPPING_RETRY_INTERVAL, PROTOCOL_VERSION,
+    RPC,
 };
 
 pub struct ServerOpts {
     pub rpc_decode_fn: Option<RPCDecodeFn>,
-    pub transports: Vec<BTransport>,
     pub private_key: Option<PrivateKey>,
     pub block_time: Option<Duration>,
+    pub block_production: Option<BlockProductionPolicy>,
+    // If false the validator doesn't create blocks without transactions, unless the chain
+    // has been idle for longer than max_idle_interval
+    pub produce_empty_blocks: bool,
+    pub max_idle_interval: Option<Duration>,
+    // how many blocks the chain may be behind the peers we sync from for the validator to produce blocks
+    pub max_production_lag: Option<u32>,
+    pub connection_opts: Option<ConnectionManagerOpts>,
+    // number of RPC messages decoded in parallel
+    pub decode_workers: Option<usize>,
+    // capacities of the queues of received and of decoded messages
+    pub queue_capacities: Option<QueueCapacities>,
+    // what a full queue does, by default gossiped transactions are dropped and everything else waits
+    pub queue_overflow: Option<OverflowPolicies>,
+    // capacity of the outbound queue of every peer and what happens to a peer that can't keep up
+    pub outbound: Option<OutboundOpts>,
+    // how long new transactions are collected before they are gossiped in one batch
+    pub tx_batch_interval: Option<Duration>,
+    // how often the peers are asked for their height, a node that fell behind syncs from the ones ahead
+    pub sync_interval: Option<Duration>,
+    // reports when the chain falls behind the peers by more than its threshold, none by default
+    pub lag_alarm: Option<LagAlarm>,
+    // blocks with more bytes are rejected before they reach the chain
+    pub max_block_size: Option<usize>,
+    // largest messages, blocks and transactions decoded, peers sending larger ones are banned.
+    // The block limit defaults to max_block_size.
+    pub decode_limits: Option<DecodeLimits>,
+    // how much of the mem_pool a single sender can fill
+    pub sender_limits: Option<SenderLimits>,
+    // keep only the newest headers in memory, everything stays in memory if None
+    pub pruning: Option<Pruning>,
+    // whether the old blocks and receipts are kept, archival by default
+    pub mode: Option<NodeMode>,
+    // blocks up to the latest checkpoint are final
+    pub checkpoints: Option<Checkpoints>,
+    // the funded accounts the chain starts with, none by default
+    pub genesis: Option<GenesisConfig>,
+    // Blocks need the signatures of 2/3 of the staked validators. Members of the set decide blocks with
+    // the consensus engine instead of producing them in the validator_loop.
+    pub consensus: Option<ConsensusOpts>,
+    // time of block production, consensus timeouts and the mem_pool, tests pass a ManualClock
+    pub clock: Option<BClock>,
+    // forwards the port of the transport on the router, only for transports bound to a socket
+    pub port_mapping: Option<PortMappingOpts>,
+    #[cfg(feature = "api")]
+    pub api: Option<ApiOpts>,
     pub id: String,
+    // The transport the server listens, sends and broadcasts on. A MultiTransport listens on several transports.
     pub transport: BTransport,
 }
 
+impl ServerOpts {
+    // A non validator broadcasting through its own transport, everything else is defaulted in Server::new
+    pub fn new(id: impl Into<String>, transport: BTransport) -> Self {
+        Self {
+            rpc_decode_fn: None,
+            private_key: None,
+            block_time: None,
+            block_production: None,
+            produce_empty_blocks: true,
+            max_idle_interval: None,
+            max_production_lag: None,
+            connection_opts: None,
+            decode_workers: None,
+            queue_capacities: None,
+            queue_overflow: None,
+            outbound: None,
+            tx_batch_interval: None,
+            sync_interval: None,
+            lag_alarm: None,
+            max_block_size: None,
+            decode_limits: None,
+            sender_limits: None,
+            pruning: None,
+            mode: None,
+            checkpoints: None,
+            genesis: None,
+            consensus: None,
+            clock: None,
+            port_mapping: None,
+            #[cfg(feature = "api")]
+            api: None,
+            id: id.into(),
+            transport,
+        }
+    }
+}
+
+// How a block added to the chain is passed on
+#[derive(Debug, Clone)]
+enum Relay {
+    // a block of ours or one we didn't get as gossip, sent to every peer as gossip of ours
+    All,
+    // a gossiped block, sent on with one hop less to every peer but the one it came from
+    Gossip {
+        from: NetAddr,
+        origin: PeerId,
+        ttl: u8,
+    },
+}
+
 pub struct Server {
     pub opts: ServerOpts,
     mem_pool: Arc<Mutex<TxPool>>,
-    chain: Arc<Mutex<Blockchain>>,
+    // notified whenever a new transaction enters the mem_pool
+    tx_notify: Arc<Notify>,
+    // blocks are added under the write lock, everything else only reads
+    chain: Arc<RwLock<Blockchain>>,
+    // blocks received before their parent
+    orphans: Arc<Mutex<OrphanPool>>,
     is_validator: bool,
-    rpc_channel: Channel<RPC>,
+    // set if this node is a member of the validator set of ServerOpts.consensus
+    consensus: Option<ConsensusEngine>,
+    // consensus timeouts that fired, handled by the server loop
+    consensus_timeouts: Channel<Timeout>,
+    // signatures of the recent blocks, to catch validators signing two blocks at the same height
+    double_signs: DoubleSignDetector,
+    // received messages by priority, filled by the transports
+    rpc_queue: (PrioritySender<RPC>, Arc<Mutex<PriorityReceiver<RPC>>>),
+    // messages dropped by the queues of received and of decoded messages
+    dropped: DropCounters,
+    // pre-validates the received blocks on the decode workers
+    block_verifier: BlockVerifier,
+    // the background tasks, their health is reported with the node_info
+    tasks: TaskSupervisor,
     quit_channel: Channel<()>,
+    // requests of ServerHandles, answered by the server loop
+    command_channel: Channel<ServerCommand>,
+    // transactions that entered the mem_pool and wait to be gossiped, see tx_batch_loop
+    tx_batch_channel: Channel<Transaction>,
+    peer_id: PeerId,
+    conn_manager: Arc<Mutex<ConnectionManager>>,
+    // sends to the peers of the conn_manager, each through its own outbound queue
+    broadcaster: Broadcaster,
+    // peers we completed the status handshake with
+    handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>,
+    // where peers and the router say we can be reached
+    external_addrs: Arc<RwLock<ExternalAddrs>>,
+    // peers we are sending blocks to, see process_get_blocks_message
+    block_streams: Arc<Mutex<HashSet<NetAddr>>>,
+    // heights of the peers we are syncing from, see sync_status
+    sync_targets: SyncTargets,
+    // whether the lag_alarm reported us lagging, see check_chain_lag
+    lagging: bool,
+    clock: BClock,
 }
 
 impl Server {
@@ -46,278 +209,1560 @@ impl Server {
             opts.block_time = Some(Duration::from_secs(5));
         }
 
+        if opts.max_production_lag.is_none() {
+            opts.max_production_lag = Some(DEFAULT_MAX_PRODUCTION_LAG);
+        }
+
+        if opts.block_production.is_none() {
+            opts.block_production = Some(BlockProductionPolicy::default());
+        }
+
+        if opts.connection_opts.is_none() {
+            opts.connection_opts = Some(ConnectionManagerOpts::default());
+        }
+
+        if opts.decode_workers.is_none() {
+            opts.decode_workers = Some(DEFAULT_DECODE_WORKERS);
+        }
+
+        if opts.tx_batch_interval.is_none() {
+            opts.tx_batch_interval = Some(DEFAULT_TX_BATCH_INTERVAL);
+        }
+
+        if opts.sync_interval.is_none() {
+            opts.sync_interval = Some(DEFAULT_SYNC_INTERVAL);
+        }
+
+        if opts.max_block_size.is_none() {
+            opts.max_block_size = Some(DEFAULT_MAX_BLOCK_SIZE);
+        }
+
+        if opts.decode_limits.is_none() {
+            opts.decode_limits = Some(DecodeLimits {
+                max_block_size: opts.max_block_size.unwrap(),
+                ..Default::default()
+            });
+        }
+
         if opts.rpc_decode_fn.is_none() {
-            opts.rpc_decode_fn = Some(Box::new(default_rpc_decode_fn));
+            opts.rpc_decode_fn = Some(rpc_decode_fn_with_limits(opts.decode_limits.unwrap()));
+        }
+
+        if opts.sender_limits.is_none() {
+            opts.sender_limits = Some(SenderLimits::default());
+        }
+
+        if opts.queue_capacities.is_none() {
+            opts.queue_capacities = Some(QueueCapacities::default());
+        }
+        if opts.queue_overflow.is_none() {
+            opts.queue_overflow = Some(OverflowPolicies::default());
         }
+        let dropped = DropCounters::default();
+        let (rpc_tx, rpc_rx) = priority_queue(
+            opts.queue_capacities.unwrap(),
+            opts.queue_overflow.unwrap(),
+            dropped.clone(),
+        );
+
+        let clock = opts.clock.get_or_insert_with(SystemClock::shared).clone();
 
-        let bc = Blockchain::new(opts.id.clone(), Block::genesis()).await?;
-        let chain = Arc::new(Mutex::new(bc));
+        let genesis = opts.genesis.get_or_insert_with(GenesisConfig::new).clone();
+        let mut bc = match &opts.pruning {
+            Some(pruning) => {
+                let mut bc =
+                    Blockchain::with_pruning(opts.id.clone(), genesis.block(), pruning).await?;
+                let state_store = SledStateStore::open(FileStore::state_path(&pruning.path))?;
+                bc.set_state_store(Box::new(state_store))?;
+                bc
+            }
+            None => Blockchain::new(opts.id.clone(), genesis.block()).await?,
+        };
+        bc.apply_genesis(&genesis).await?;
+        if let Some(checkpoints) = &opts.checkpoints {
+            bc.set_checkpoints(checkpoints.clone());
+        }
+        bc.set_clock(clock.clone());
+        let sig_cache = SigCache::default();
+        bc.set_sig_cache(sig_cache.clone());
+        let mut mem_pool = TxPool::with_clock(100, clock.clone());
+        mem_pool.set_sig_cache(sig_cache.clone());
+        mem_pool.set_included_txs(bc.included_txs());
+        mem_pool.set_sender_limits(opts.sender_limits.unwrap());
+        let block_verifier = BlockVerifier::new(sig_cache, opts.max_block_size.unwrap());
+
+        if let Some(consensus_opts) = &opts.consensus {
+            bc.set_epoch_length(consensus_opts.epoch_length);
+            bc.add_genesis_validators(&consensus_opts.validators);
+            bc.set_validator(Box::new(BlockValidator::with_staked_validators()));
+        }
+        // continue the chain stored by the last run
+        if opts.pruning.is_some() {
+            bc.recover().await?;
+        }
+        bc.set_mode(*opts.mode.get_or_insert_with(NodeMode::default))
+            .await?;
+
+        let mut consensus = None;
+        if let Some(consensus_opts) = &opts.consensus {
+            let validators = bc.validator_set();
+            if let Some(key) = opts
+                .private_key
+                .as_ref()
+                .filter(|key| validators.contains(&key.public_key()))
+            {
+                consensus = Some(ConsensusEngine::new(
+                    validators,
+                    key.clone(),
+                    consensus_opts.timeouts,
+                )?);
+            }
+        }
+        let chain = Arc::new(RwLock::new(bc));
+
+        // Validators are identified by their signing key, other nodes get a random identity
+        let node_key = opts
+            .private_key
+            .clone()
+            .unwrap_or_else(PrivateKey::generate);
+        let peer_id = PeerId::from_public_key(&node_key.public_key());
+
+        let handshakes = Arc::new(RwLock::new(HashMap::new()));
+        let conn_manager = ConnectionManager::new(
+            opts.transport.clone(),
+            opts.connection_opts.clone().unwrap(),
+        )
+        .with_handshakes(handshakes.clone());
+        let conn_manager = Arc::new(Mutex::new(conn_manager));
+        let broadcaster = Broadcaster::new(
+            opts.transport.clone(),
+            conn_manager.clone(),
+            *opts.outbound.get_or_insert_with(OutboundOpts::default),
+            clock.clone(),
+        )
+        .with_handshakes(handshakes.clone());
 
         Ok(Self {
             chain,
-            rpc_channel: new_channel(1024),
-            mem_pool: Arc::new(Mutex::new(TxPool::new(100))),
+            rpc_queue: (rpc_tx, Arc::new(Mutex::new(rpc_rx))),
+            dropped,
+            block_verifier,
+            tasks: TaskSupervisor::new(opts.id.clone()),
+            mem_pool: Arc::new(Mutex::new(mem_pool)),
+            tx_notify: Arc::new(Notify::new()),
+            orphans: Arc::new(Mutex::new(OrphanPool::default())),
             quit_channel: new_channel(1),
-            is_validator: opts.private_key.is_some(),
+            command_channel: new_channel(64),
+            tx_batch_channel: new_channel(MAX_TX_BATCH_SIZE * 4),
+            is_validator: opts.private_key.is_some() && opts.consensus.is_none(),
+            consensus,
+            consensus_timeouts: new_channel(64),
+            double_signs: DoubleSignDetector::new(),
+            peer_id,
+            conn_manager,
+            broadcaster,
+            handshakes,
+            external_addrs: Arc::new(RwLock::new(ExternalAddrs::default())),
+            block_streams: Arc::new(Mutex::new(HashSet::new())),
+            sync_targets: SyncTargets::new(),
+            lagging: false,
+            clock,
             opts,
         })
     }
 
-    pub async fn get_status_from_transports(
-        self_tr: BTransport,
-        transports: Vec<BTransport>,
-    ) -> Result<()> {
-        for tr in transports {
-            if tr.addr() != self_tr.addr() {
-                if let Err(err) = Self::send_get_status_message(&self_tr, &tr.addr()).await {
-                    error!("Send get_status_message error: {:?}", err);
-                }
+    // Asks the peers we're connected to at startup for their status
+    pub async fn get_status_from_peers(tr: BTransport) -> Result<()> {
+        for addr in tr.peers().await.into_keys() {
+            if let Err(err) = Self::send_get_status_message(&tr, &addr, PROTOCOL_VERSION).await {
+                error!("Send get_status_message error: {:?}", err);
             }
         }
         Ok(())
     }
 
     pub async fn start(&mut self) -> Result<()> {
-        // println!("{:?}", self.opts.transports);
-        self.init_transports();
+        // the tasks spawned for this server are aborted when it shuts down
+        self.init_transport();
         {
-            let transports = self.opts.transports.clone();
             let tr = self.opts.transport.clone();
-            tokio::task::spawn(async move {
-                Self::get_status_from_transports(tr, transports)
-                    .await
-                    .unwrap();
+            self.tasks
+                .spawn("status requests", Self::get_status_from_peers(tr));
+        }
+
+        let dns_seeds = self
+            .opts
+            .connection_opts
+            .as_ref()
+            .unwrap()
+            .dns_seeds
+            .clone();
+        if !dns_seeds.is_empty() {
+            let id = self.opts.id.clone();
+            let cm = self.conn_manager.clone();
+            self.tasks.spawn("dns seeds", async move {
+                let bootnodes = resolve_dns_seeds(&dns_seeds).await;
+                info!(
+                    "ID={} resolved {} bootnodes from {} DNS seeds",
+                    id,
+                    bootnodes.len(),
+                    dns_seeds.len()
+                );
+                cm.lock().await.add_bootnodes(bootnodes);
+                Ok(())
+            });
+        }
+        if let Some(path) = self.address_book_path() {
+            let loaded = self.conn_manager.lock().await.load_address_book(
+                &path,
+                self.clock.now(),
+                self.clock.unix_secs(),
+            );
+            match loaded {
+                Ok(n) => info!(
+                    "ID={} loaded {} addresses from {}",
+                    self.opts.id,
+                    n,
+                    path.display()
+                ),
+                Err(err) => warn!(
+                    "ID={} could not load the address book from {}: {}",
+                    self.opts.id,
+                    path.display(),
+                    err
+                ),
+            }
+        }
+        {
+            let cm = self.conn_manager.clone();
+            let tick_interval = self.opts.connection_opts.as_ref().unwrap().tick_interval;
+            let address_book_path = self.address_book_path();
+            let clock = self.clock.clone();
+            self.tasks.spawn("connection manager", async move {
+                Self::connection_manager_loop(cm, tick_interval, address_book_path, clock).await;
+                Ok(())
+            });
+        }
+        {
+            let id = self.opts.id.clone();
+            let events = self.conn_manager.lock().await.events();
+            let handshakes = self.handshakes.clone();
+            let external_addrs = self.external_addrs.clone();
+            let sync_targets = self.sync_targets.clone();
+            let tr = self.opts.transport.clone();
+            self.tasks.spawn("peer events", async move {
+                Self::peer_event_loop(id, events, handshakes, external_addrs, sync_targets, tr)
+                    .await;
+                Ok(())
+            });
+        }
+
+        if let (Some(opts), NetAddr::Socket(local)) =
+            (self.opts.port_mapping.clone(), self.opts.transport.addr())
+        {
+            let id = self.opts.id.clone();
+            let external_addrs = self.external_addrs.clone();
+            let clock = self.clock.clone();
+            self.tasks.spawn("port mapping", async move {
+                Self::port_mapping_loop(id, opts, local, external_addrs, clock).await;
+                Ok(())
+            });
+        }
+
+        {
+            let tr = self.opts.transport.clone();
+            let handshakes = self.handshakes.clone();
+            let interval = self.opts.sync_interval.unwrap();
+            let clock = self.clock.clone();
+            self.tasks.spawn("status polling", async move {
+                Self::status_loop(tr, handshakes, interval, clock).await;
+                Ok(())
+            });
+        }
+        {
+            let txx = self.tx_batch_channel.1.clone();
+            let peers = self.broadcaster();
+            let interval = self.opts.tx_batch_interval.unwrap();
+            self.tasks.spawn("tx batches", async move {
+                Self::tx_batch_loop(txx, peers, interval).await;
+                Ok(())
+            });
+        }
+
+        #[cfg(feature = "api")]
+        if let Some(opts) = self.opts.api.clone() {
+            let id = self.opts.id.clone();
+            let handle = self.handle();
+            self.tasks.spawn("api", async move {
+                info!("ID={} serving the API on {}", id, opts.addr);
+                crate::api::serve(opts, handle).await
             });
         }
 
         if self.is_validator {
-            let block_time = self.opts.block_time.unwrap();
+            let producer = BlockProducer {
+                policy: self.opts.block_production.unwrap(),
+                block_time: self.opts.block_time.unwrap(),
+                produce_empty_blocks: self.opts.produce_empty_blocks,
+                max_idle_interval: self.opts.max_idle_interval,
+                max_lag: self.opts.max_production_lag.unwrap(),
+            };
             let bc = self.chain.clone();
             let private_key = self.opts.private_key.as_ref().unwrap().clone();
             let tx_pool = self.mem_pool.clone();
-            let transports = self.opts.transports.clone();
-            tokio::task::spawn(async move {
-                Self::validator_loop(bc, tx_pool, private_key, block_time, transports).await;
+            let tx_notify = self.tx_notify.clone();
+            let peers = self.broadcaster();
+            let sync_targets = self.sync_targets.clone();
+            self.tasks.spawn_critical("validator loop", move || {
+                Self::validator_loop(
+                    bc.clone(),
+                    tx_pool.clone(),
+                    private_key.clone(),
+                    producer,
+                    tx_notify.clone(),
+                    peers.clone(),
+                    sync_targets.clone(),
+                )
+            });
+        }
+
+        // RPC messages are decoded outside of the server loop, which gets them by priority
+        let (queue_tx, mut queue) = priority_queue(
+            self.opts.queue_capacities.unwrap(),
+            self.opts.queue_overflow.unwrap(),
+            self.dropped.clone(),
+        );
+        {
+            let decode_fn = self
+                .opts
+                .rpc_decode_fn
+                .take()
+                .unwrap_or_else(|| rpc_decode_fn_with_limits(self.opts.decode_limits.unwrap()));
+            let (offenders_tx, offenders) = mpsc::channel(1024);
+            let pool = DecodePool::new(
+                decode_fn,
+                self.block_verifier.clone(),
+                self.opts.decode_workers.unwrap(),
+                queue_tx,
+            )
+            .with_offenders(offenders_tx);
+            let rpc_rx = self.rpc_queue.1.clone();
+            let cm = self.conn_manager.clone();
+            let clock = self.clock.clone();
+            self.tasks.spawn("decode loop", async move {
+                Self::decode_loop(rpc_rx, offenders, cm, pool, clock).await;
+                Ok(())
             });
         }
 
+        // Only used here, holding the lock for the lifetime of the loop lets select! poll it
+        // without locking on every iteration.
+        let quit_rx = self.quit_channel.1.clone();
+        let mut quit_rx = quit_rx.lock().await;
+        let timeout_rx = self.consensus_timeouts.1.clone();
+        let mut timeout_rx = timeout_rx.lock().await;
+        let command_rx = self.command_channel.1.clone();
+        let mut command_rx = command_rx.lock().await;
+
+        if self.consensus.is_some() {
+            let height = self.chain.read().await.height().await;
+            self.follow_chain(height).await;
+        }
+
         loop {
-            // Waits for an RPC message to arrive and then proccesses it with the dynamic function that's passed
-            let opt_rpc =
-                (|| async { return self.rpc_channel.1.lock().await.recv().await })().await;
-
-            if let Some(rpc) = opt_rpc {
-                if let Some(rpc_decode_fn) = self.opts.rpc_decode_fn.as_mut() {
-                    match rpc_decode_fn(rpc) {
-                        Ok(msg) => {
-                            debug!(
-                                "ID={} RPC Message incoming from: {}, data: {:?}",
-                                &self.opts.id, msg.from, msg.data
-                            );
-
-                            // if self.opts.transport.addr() == msg.from {
-                            //     warn!("ID={} Message from self, ignoring", &self.opts.id);
-                            //     continue;
-                            // }
-
-                            if let Err(err) = self.process_message(msg).await {
-                                if err.to_string() != "block already known" {
-                                    error!("ID={} error processing message: {}", self.opts.id, err);
-                                }
-                            };
-                        }
-                        Err(err) => error!("RPC Decoding Error: {err}"),
-                    }
+            tokio::select! {
+                // checked first, so a flood of messages can't delay the shutdown
+                biased;
+                _ = quit_rx.recv() => break,
+                Some(timeout) = timeout_rx.recv() => {
+                    let actions = match &mut self.consensus {
+                        Some(engine) => engine.on_timeout(timeout),
+                        None => vec![],
+                    };
+                    self.run_consensus(actions).await;
                 }
-            } else if self.quit_channel.1.lock().await.recv().await.is_some() {
-                break;
+                Some(command) = command_rx.recv() => self.handle_command(command).await,
+                msg = queue.recv() => match msg {
+                    Some(msg) => self.handle_message(msg).await,
+                    None => break,
+                },
             }
         }
 
+        self.tasks.abort_all();
+        self.save_address_book().await;
+        if let (Some(opts), NetAddr::Socket(local)) =
+            (&self.opts.port_mapping, self.opts.transport.addr())
+        {
+            if let Err(err) = opts.mapper.unmap_port(opts.protocol, local).await {
+                warn!(
+                    "ID={} could not remove the port mapping: {}",
+                    self.opts.id, err
+                );
+            }
+        }
         info!("Server is shutting down");
         Ok(())
     }
 
-    pub async fn validator_loop(
-        bc: Arc<Mutex<Blockchain>>,
-        tx_pool: Arc<Mutex<TxPool>>,
-        private_key: PrivateKey,
-        block_time: Duration,
-        transports: Vec<BTransport>,
+    // Hands the RPC messages of accepted peers to the decode pool and bans the peers it reports for oversized
+    // messages
+    async fn decode_loop(
+        rpc_rx: Arc<Mutex<PriorityReceiver<RPC>>>,
+        mut offenders: mpsc::Receiver<(NetAddr, OversizedMessage)>,
+        cm: Arc<Mutex<ConnectionManager>>,
+        pool: DecodePool,
+        clock: BClock,
     ) {
-        let mut ticker = time::interval(block_time);
-
-        info!(
-            "Starting validator loop with block_time {}",
-            block_time.as_secs()
-        );
+        let mut rpc_rx = rpc_rx.lock().await;
 
         loop {
-            ticker.tick().await;
-            let mut bc = bc.lock().await;
-            let mut tx_pool = tx_pool.lock().await;
-            if let Err(err) = Self::create_new_block(
-                &mut bc,
-                &mut tx_pool,
-                private_key.clone(),
-                transports.clone(),
-            )
-            .await
-            {
-                error!("Error creating a new block: {}", err);
+            tokio::select! {
+                Some((addr, oversized)) = offenders.recv() => {
+                    let reason = oversized.to_string();
+                    if let Err(err) = cm.lock().await.ban(&addr, &reason, clock.now()).await {
+                        warn!("could not ban peer {}: {}", addr, err);
+                    }
+                }
+                rpc = rpc_rx.recv() => {
+                    let Some(rpc) = rpc else { break };
+                    let accepted = cm.lock().await.on_message(&rpc.from, clock.now()).await;
+                    if accepted {
+                        pool.decode(rpc).await;
+                    }
+                }
             }
         }
     }
 
-    // Send and Broadcast functions
-
-    async fn send_get_status_message(tr: &BTransport, to: &NetAddr) -> Result<()> {
-        let status_msg = GetStatusMessage {};
-        let mut buf = vec![];
-        BincodeEncoder::new(&mut buf).encode(&status_msg)?;
+    async fn handle_message(&mut self, msg: DecodedMessage) {
+        debug!(
+            "ID={} RPC Message incoming from: {}, data: {:?}",
+            &self.opts.id, msg.from, msg.data
+        );
 
-        let msg = Message::new(MessageType::GetStatus, buf);
-        tr.send_message(to, msg.bytes()?).await?;
+        // our own gossip can come back to us through a cycle of peers
+        let from_self =
+            msg.origin == self.peer_id || self.opts.transport.addrs().contains(&msg.from);
+        if from_self {
+            debug!("ID={} Message from self, ignoring", &self.opts.id);
+            return;
+        }
 
-        Ok(())
+        // everything logged while processing the message carries the peer and the message type
+        let span = info_span!(
+            "message",
+            id = %self.opts.id,
+            peer = %msg.from,
+            kind = ?msg.data.message_type()
+        );
+        if let Err(err) = self.process_message(msg).instrument(span).await {
+            if err.to_string() != "block already known" {
+                error!("ID={} error processing message: {}", self.opts.id, err);
+            }
+        };
     }
 
-    pub async fn broadcast_block(transports: &Vec<BTransport>, b: &Block) -> Result<()> {
-        let mut buf: Vec<u8> = Vec::new();
-        b.encode(&mut BincodeEncoder::new(&mut buf))?;
+    // Sending on it makes start() return, the loops it spawned are stopped
+    pub fn quit_sender(&self) -> mpsc::Sender<()> {
+        self.quit_channel.0.clone()
+    }
 
-        let msg = Message::new(MessageType::Block, buf);
+    // Sends to the peers of the connection manager through the transport
+    fn broadcaster(&self) -> Broadcaster {
+        self.broadcaster.clone()
+    }
 
-        Self::broadcast(transports, msg.bytes()?).await?;
+    // A handle to drive the server once it is started, it can be cloned and sent to other tasks
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.command_channel.0.clone(), self.quit_sender())
+    }
 
-        Ok(())
+    // The receiver of a command may be gone, nobody is waiting for the answer then
+    async fn handle_command(&mut self, command: ServerCommand) {
+        match command {
+            ServerCommand::SubmitTransaction(tx, result) => {
+                let from = self.opts.transport.addr();
+                let _ = result.send(self.process_transaction(&from, *tx).await);
+            }
+            ServerCommand::GetHeight(result) => {
+                let _ = result.send(self.chain.read().await.height().await);
+            }
+            ServerCommand::GetNonce(address, result) => {
+                let _ = result.send(self.chain.read().await.state().nonce(&address));
+            }
+            ServerCommand::GetPeerCount(result) => {
+                let _ = result.send(self.conn_manager.lock().await.connected().len());
+            }
+            ServerCommand::GetNodeInfo(result) => {
+                let _ = result.send(self.node_info().await);
+            }
+            ServerCommand::GetPeers(result) => {
+                let _ = result.send(self.peer_info().await);
+            }
+            ServerCommand::AddPeer(addr, result) => {
+                let peer: BTransport = Box::new(RemotePeer::new(addr));
+                let now = self.clock.now();
+                let _ = result.send(self.conn_manager.lock().await.dial(peer, now).await);
+            }
+            ServerCommand::RemovePeer(addr, result) => {
+                let _ = result.send(self.remove_peer(&addr).await);
+            }
+            ServerCommand::GetMempool(result) => {
+                let _ = result.send(self.mem_pool.lock().await.all_cloned());
+            }
+            ServerCommand::GetPoolContent(result) => {
+                let _ = result.send(self.mem_pool.lock().await.pool_content());
+            }
+            ServerCommand::EstimateFee(target_blocks, result) => {
+                let _ = result.send(self.mem_pool.lock().await.estimate_fee(target_blocks));
+            }
+            ServerCommand::GetTxStatus(hash, result) => {
+                let _ = result.send(self.tx_status(&hash).await);
+            }
+            ServerCommand::GetReceipt(hash, result) => {
+                let _ = result.send(self.chain.read().await.receipt(&hash).cloned());
+            }
+            ServerCommand::GetSyncStatus(result) => {
+                let _ = result.send(self.sync_status().await);
+            }
+            ServerCommand::GetBalance(address, height, result) => {
+                let _ = result.send(
+                    self.read_state(height, |state| state.balance(&address))
+                        .await,
+                );
+            }
+            ServerCommand::GetStorage(contract, key, height, result) => {
+                let value = self
+                    .read_state(height, |state| state.get(&contract, &key).ok())
+                    .await;
+                let _ = result.send(value);
+            }
+        }
     }
 
-    pub async fn broadcast(transports: &Vec<BTransport>, payload: Vec<u8>) -> Result<()> {
-        for tr in transports {
-            tr.broadcast(payload.clone()).await?;
+    // Reads from the state after the block at height, the current state if None
+    async fn read_state<T>(
+        &self,
+        height: Option<u32>,
+        read: impl FnOnce(&State) -> T,
+    ) -> Result<T> {
+        let chain = self.chain.read().await;
+        match height {
+            Some(height) => Ok(read(&chain.state_at(height).await?)),
+            None => Ok(read(chain.state())),
         }
-        Ok(())
     }
 
-    pub async fn broadcast_tx(transports: &Vec<BTransport>, tx: &Transaction) -> Result<()> {
-        let mut buf: Vec<u8> = Vec::new();
-        tx.encode(&mut BincodeEncoder::new(&mut buf))?;
+    pub async fn node_info(&self) -> NodeInfo {
+        let (height, head, block_time_average, mode, lowest_block) = {
+            let chain = self.chain.read().await;
+            let height = chain.height().await;
+            let head = chain.get_hash(height).await.unwrap_or_default();
+            let block_time_average = chain
+                .block_time_average(BLOCK_TIME_WINDOW)
+                .await
+                .unwrap_or_default();
+            (
+                height,
+                head,
+                block_time_average,
+                chain.mode(),
+                chain.lowest_block(),
+            )
+        };
+        let (mempool_size, mempool_ages) = {
+            let mem_pool = self.mem_pool.lock().await;
+            (mem_pool.len(), mem_pool.ages())
+        };
 
-        let msg = Message::new(MessageType::Tx, buf);
-        Self::broadcast(transports, msg.bytes()?).await?;
-        //let buf: Vec<u8> = Vec::new();
+        NodeInfo {
+            id: self.opts.id.clone(),
+            peer_id: self.peer_id,
+            protocol_version: PROTOCOL_VERSION,
+            height,
+            head,
+            validator: self.is_validator || self.consensus.is_some(),
+            external_addr: self.external_addr().await,
+            peer_count: self.conn_manager.lock().await.connected().len(),
+            mempool_size,
+            mempool_ages,
+            chain_lag: self.chain_lag().await,
+            block_time_average,
+            mode,
+            lowest_block,
+            dropped_messages: self.dropped.counts(),
+            tasks: self.tasks.health(),
+        }
+    }
+
+    async fn remove_peer(&self, addr: &NetAddr) -> Result<()> {
+        let mut cm = self.conn_manager.lock().await;
+        if !cm.is_connected(addr) {
+            return Err(anyhow!("{addr} is not a peer"));
+        }
+        cm.disconnect(addr, "removed by the operator", self.clock.now())
+            .await?;
+        cm.forget(addr);
         Ok(())
     }
 
-    // Process functions
+    pub async fn validator_loop(
+        bc: Arc<RwLock<Blockchain>>,
+        tx_pool: Arc<Mutex<TxPool>>,
+        private_key: PrivateKey,
+        producer: BlockProducer,
+        tx_notify: Arc<Notify>,
+        peers: Broadcaster,
+        sync_targets: SyncTargets,
+    ) -> Result<()> {
+        // the clock of the chain times the blocks as well
+        let (id, clock) = {
+            let bc = bc.read().await;
+            (bc.server_id.clone(), bc.clock())
+        };
+        let mut next_tick = clock.now();
+        let mut last_block = clock.now();
 
-    pub async fn process_message(&mut self, msg: DecodedMessage) -> Result<()> {
-        match msg.data {
-            DecodedMessageData::Tx(tx) => self.process_transaction(&msg.from, tx).await,
-            DecodedMessageData::Block(block) => self.process_block(block).await,
-            DecodedMessageData::StatusMessage(message) => {
-                self.process_status_message(&msg.from, message).await
+        info!(
+            "Starting validator loop with block_time {} and policy {:?}",
+            producer.block_time.as_secs(),
+            producer.policy
+        );
+
+        loop {
+            match producer.policy {
+                BlockProductionPolicy::Interval => {
+                    clock.sleep_until(next_tick).await;
+                    next_tick += producer.block_time;
+                }
+                BlockProductionPolicy::TxTriggered { .. } => {
+                    let pending_count = tx_pool.lock().await.pending_count();
+                    match producer.next_deadline(pending_count, last_block) {
+                        Some(deadline) => {
+                            tokio::select! {
+                                _ = clock.sleep_until(deadline) => {}
+                                _ = tx_notify.notified() => {}
+                            }
+                        }
+                        None => tx_notify.notified().await,
+                    }
+                }
             }
-            DecodedMessageData::GetStatusMessage => {
-                let id = self.opts.id.clone();
-                let tr = self.opts.transport.clone();
-                let bc = self.chain.clone();
-                let from = msg.from;
-                tokio::task::spawn(async move {
-                    Self::process_get_status_message(&id, tr, bc, &from).await
-                });
-                Ok(())
+
+            let mut tx_pool = tx_pool.lock().await;
+
+            if !producer.should_produce(
+                tx_pool.pending_count(),
+                tx_pool.pending_bytes(),
+                clock.now() - last_block,
+            ) {
+                debug!("ID={} not producing a block yet", id);
+                continue;
             }
-            DecodedMessageData::GetBlocksMessage(get_block_message) => {
-                self.process_get_blocks_message(&msg.from, &get_block_message)
+            let mut bc = bc.write().await;
+            let status = sync_targets.status(bc.height().await);
+            if !producer.in_sync(status) {
+                debug!("ID={} not producing a block while {:?}", id, status);
+                continue;
+            }
+
+            if let Err(err) =
+                Self::create_new_block(&mut bc, &mut tx_pool, private_key.clone(), peers.clone())
                     .await
+            {
+                error!("Error creating a new block: {}", err);
             }
+            // also reset after errors, otherwise a TxTriggered producer would retry in a busy loop
+            last_block = clock.now();
         }
     }
 
-    async fn process_get_blocks_message(
-        &mut self,
-        from: &NetAddr,
-        data: &GetBlocksMessage,
-    ) -> Result<()> {
-        println!("got get blocks message => {}", data.to);
+    // Status of a transaction submitted to this node or seen on the network
+    pub async fn tx_status(&self, hash: &Hash) -> TxStatus {
+        match self.chain.read().await.tx_status(hash) {
+            TxStatus::Unknown => self.mem_pool.lock().await.status(hash),
+            status => status,
+        }
+    }
 
-        Ok(())
+    // Whether we are catching up with peers that told us they are ahead
+    pub async fn sync_status(&self) -> SyncStatus {
+        let height = self.chain.read().await.height().await;
+        self.sync_targets.status(height)
     }
 
-    pub async fn process_get_status_message(
-        id: &str,
-        tr: BTransport,
-        bc: Arc<Mutex<Blockchain>>,
-        from: &NetAddr,
-    ) -> Result<()> {
-        info!("ID={}, Received get_status_message from {}", id, from);
-        let height = bc.lock().await.height().await;
+    // Number of blocks we are behind the highest peer, as told by the last status of every peer
+    pub async fn chain_lag(&self) -> u32 {
+        let height = self.chain.read().await.height().await;
+        let handshakes = self.handshakes.read().await;
+        chain_lag(height, handshakes.values().map(|h| h.height))
+    }
 
-        //TODO: get version from somewhere
-        let status_msg = StatusMessage::new(id.to_string(), 0, height);
+    // Reports crossing the threshold of the lag_alarm, in either direction
+    async fn check_chain_lag(&mut self) {
+        let Some(alarm) = &self.opts.lag_alarm else {
+            return;
+        };
+        let height = self.chain.read().await.height().await;
+        let peer_height = self
+            .handshakes
+            .read()
+            .await
+            .values()
+            .map(|h| h.height)
+            .max()
+            .unwrap_or(0);
+        let lagging = chain_lag(height, [peer_height]) > alarm.threshold;
+        if lagging == self.lagging {
+            return;
+        }
+        self.lagging = lagging;
 
-        let mut buf = vec![];
-        BincodeEncoder::new(&mut buf).encode(&status_msg)?;
+        let event = if lagging {
+            warn!(
+                "ID={} chain lag alarm, our height: {}, peer height: {}",
+                self.opts.id, height, peer_height
+            );
+            ChainLagEvent::Lagging {
+                height,
+                peer_height,
+            }
+        } else {
+            info!(
+                "ID={} caught up with the peers at height {}",
+                self.opts.id, height
+            );
+            ChainLagEvent::Recovered { height }
+        };
+        if alarm.events.try_send(event).is_err() {
+            debug!("ID={} dropping chain lag event {:?}", self.opts.id, event);
+        }
+    }
 
-        let msg = Message::new(MessageType::Status, buf);
-        info!("ID={}, sending status message to {}", id, from);
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
 
-        let to = from.clone();
+    pub fn chain(&self) -> Arc<RwLock<Blockchain>> {
+        self.chain.clone()
+    }
 
-        tokio::task::spawn(async move {
-            tr.send_message(&to, msg.bytes().unwrap()).await.unwrap();
-        });
+    pub async fn peer_version(&self, addr: &NetAddr) -> Option<u32> {
+        self.handshakes.read().await.get(addr).map(|h| h.version)
+    }
 
-        Ok(())
+    // Whether the peer advertised all the features, messages of optional features are only sent to peers that do
+    pub async fn peer_supports(&self, addr: &NetAddr, features: u64) -> bool {
+        self.handshakes
+            .read()
+            .await
+            .get(addr)
+            .is_some_and(|h| h.features & features == features)
     }
 
-    pub async fn process_status_message(
-        &mut self,
-        from: &NetAddr,
-        msg: StatusMessage,
-    ) -> Result<()> {
-        let our_height = self.chain.lock().await.height().await;
-        info!(
-            "ID={}, height: {}, received status message from: {}, height: {}",
-            self.opts.id, our_height, from, msg.current_height
-        );
+    pub async fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers = self.conn_manager.lock().await.peer_info();
+        let handshakes = self.handshakes.read().await;
 
-        if msg.current_height <= our_height {
-            warn!(
-                "ID={} cannot sync block_height too low our height: {}, their height: {}, addr: {}",
-                self.opts.id, our_height, msg.current_height, from
-            );
-            return Ok(());
+        for peer in peers.iter_mut() {
+            if let Some(handshake) = handshakes.get(&peer.addr) {
+                peer.id = Some(handshake.id);
+                peer.version = Some(handshake.version);
+                peer.features = Some(handshake.features);
+            }
         }
-        info!(
-            "ID={} syncing block_height our height: {}, their height: {}, addr: {}",
-            self.opts.id, our_height, msg.current_height, from
-        );
+        peers
+    }
 
-        // In this case we are behind and need to sync
-        let get_blocks_msg = GetBlocksMessage {
-            from: our_height + 1,
-            to: msg.current_height,
-        };
+    // The address peers can dial us at, once enough peers reported it or the router mapped our port
+    pub async fn external_addr(&self) -> Option<NetAddr> {
+        self.external_addrs.read().await.external()
+    }
 
-        let mut buf = vec![];
-        BincodeEncoder::new(&mut buf).encode(&get_blocks_msg)?;
+    pub async fn connected_peers(&self) -> Vec<NetAddr> {
+        self.conn_manager.lock().await.connected()
+    }
 
-        let msg = Message::new(MessageType::GetBlocks, buf);
+    async fn connection_manager_loop(
+        cm: Arc<Mutex<ConnectionManager>>,
+        tick_interval: Duration,
+        address_book_path: Option<PathBuf>,
+        clock: BClock,
+    ) {
+        let mut next_tick = clock.now();
+        let mut next_save = next_tick + ADDRESS_BOOK_SAVE_INTERVAL;
 
-        let tr = self.opts.transport.clone();
-        let to = from.to_owned();
+        loop {
+            clock.sleep_until(next_tick).await;
+            next_tick += tick_interval;
+            let now = clock.now();
+            let mut cm = cm.lock().await;
+            if let Err(err) = cm.tick(now).await {
+                error!("Connection manager error: {}", err);
+            }
 
-        tokio::task::spawn(async move {
-            tr.send_message(&to, msg.bytes().unwrap()).await.unwrap();
+            if let Some(path) = address_book_path.as_deref().filter(|_| now >= next_save) {
+                next_save = now + ADDRESS_BOOK_SAVE_INTERVAL;
+                if let Err(err) = cm.save_address_book(path, now, clock.unix_secs()) {
+                    error!(
+                        "could not save the address book to {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    fn address_book_path(&self) -> Option<PathBuf> {
+        self.opts
+            .connection_opts
+            .as_ref()?
+            .address_book_path
+            .clone()
+    }
+
+    async fn save_address_book(&self) {
+        if let Some(path) = self.address_book_path() {
+            let saved = self.conn_manager.lock().await.save_address_book(
+                &path,
+                self.clock.now(),
+                self.clock.unix_secs(),
+            );
+            if let Err(err) = saved {
+                error!(
+                    "ID={} could not save the address book to {}: {}",
+                    self.opts.id,
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    // Maps the port and renews the mapping after half of its lease, failed attempts are retried
+    async fn port_mapping_loop(
+        id: String,
+        opts: PortMappingOpts,
+        local: SocketAddr,
+        external_addrs: Arc<RwLock<ExternalAddrs>>,
+        clock: BClock,
+    ) {
+        loop {
+            let renew_after = match opts.mapper.map_port(opts.protocol, local, opts.lease).await {
+                Ok(external) => {
+                    info!("ID={} port {} is mapped to {}", id, local, external);
+                    external_addrs
+                        .write()
+                        .await
+                        .set_mapped(Some(external.into()));
+                    opts.lease / 2
+                }
+                Err(err) => {
+                    warn!("ID={} could not map port {}: {}", id, local, err);
+                    external_addrs.write().await.set_mapped(None);
+                    PORT_MAPPING_RETRY_INTERVAL
+                }
+            };
+            clock.sleep(renew_after).await;
+        }
+    }
+
+    // Asks the peers for their status every interval, the answers of peers ahead of us start a sync, see
+    // process_status_message. A node that missed blocks while it was cut off catches up this way.
+    async fn status_loop(
+        tr: BTransport,
+        handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>,
+        interval: Duration,
+        clock: BClock,
+    ) {
+        loop {
+            clock.sleep(interval).await;
+            for addr in tr.peers().await.into_keys() {
+                let version = message_version(&handshakes, &addr).await;
+                if let Err(err) = Self::send_get_status_message(&tr, &addr, version).await {
+                    debug!("Send get_status_message error: {:?}", err);
+                }
+            }
+        }
+    }
+
+    async fn peer_event_loop(
+        id: String,
+        events: Channel<PeerEvent>,
+        handshakes: Arc<RwLock<HashMap<NetAddr, Handshake>>>,
+        external_addrs: Arc<RwLock<ExternalAddrs>>,
+        sync_targets: SyncTargets,
+        tr: BTransport,
+    ) {
+        let mut events = events.1.lock().await;
+
+        while let Some(event) = events.recv().await {
+            match event {
+                PeerEvent::Connected { addr, direction } => {
+                    info!("ID={} peer {} connected ({:?})", id, addr, direction);
+
+                    // a peer that reconnects may have gone on without us, both sides ask for the status
+                    let version = message_version(&handshakes, &addr).await;
+                    if let Err(err) = Self::send_get_status_message(&tr, &addr, version).await {
+                        error!("Send get_status_message error: {:?}", err);
+                    }
+                }
+                PeerEvent::Disconnected { addr, reason } => {
+                    info!("ID={} peer {} disconnected: {}", id, addr, reason);
+                    handshakes.write().await.remove(&addr);
+                    external_addrs.write().await.forget(&addr);
+                    sync_targets.remove(&addr);
+                }
+            }
+        }
+    }
+
+    // Send and Broadcast functions
+
+    async fn send_get_status_message(tr: &BTransport, to: &NetAddr, version: u32) -> Result<()> {
+        let status_msg = GetStatusMessage {};
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&status_msg)?;
+
+        let msg = Message::new(MessageType::GetStatus, buf);
+        tr.send_message(to, msg.encode_in(version)?).await?;
+
+        Ok(())
+    }
+
+    // Gossips a block of origin, it's relayed ttl more times. Most peers only get it announced, see Broadcaster.
+    pub async fn broadcast_block(
+        peers: &Broadcaster,
+        b: &Block,
+        origin: PeerId,
+        ttl: u8,
+    ) -> Result<()> {
+        let block = Self::block_message(b, origin, ttl)?;
+        let announcement = Self::announcement_message(b)?;
+        peers.broadcast_block(block, announcement, None).await
+    }
+
+    // Passes a gossiped block on to every peer but the one it came from
+    async fn relay_block(
+        peers: &Broadcaster,
+        b: &Block,
+        from: &NetAddr,
+        origin: PeerId,
+        ttl: u8,
+    ) -> Result<()> {
+        let block = Self::block_message(b, origin, ttl)?;
+        let announcement = Self::announcement_message(b)?;
+        peers.broadcast_block(block, announcement, Some(from)).await
+    }
+
+    fn announcement_message(b: &Block) -> Result<Message> {
+        let announcement = NewBlockHashesMessage {
+            blocks: vec![BlockAnnouncement {
+                hash: BlockHasher.hash(&b.header)?,
+                height: b.header.height,
+            }],
+        };
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&announcement)?;
+
+        Ok(Message::new(MessageType::NewBlockHashes, buf))
+    }
+
+    fn block_message(b: &Block, origin: PeerId, ttl: u8) -> Result<Message> {
+        let mut buf: Vec<u8> = Vec::new();
+        b.encode(&mut BincodeEncoder::new(&mut buf))?;
+
+        Ok(Message::new(MessageType::Block, buf).with_gossip(origin, ttl))
+    }
+
+    pub async fn broadcast_consensus_message(
+        peers: &Broadcaster,
+        msg: &ConsensusMessage,
+    ) -> Result<()> {
+        let mut buf: Vec<u8> = Vec::new();
+        let msg = match msg {
+            ConsensusMessage::Proposal(proposal) => {
+                BincodeEncoder::new(&mut buf).encode(proposal)?;
+                Message::new(MessageType::Proposal, buf)
+            }
+            ConsensusMessage::Vote(vote) => {
+                BincodeEncoder::new(&mut buf).encode(vote)?;
+                Message::new(MessageType::Vote, buf)
+            }
+        };
+        peers.broadcast(msg).await
+    }
+
+    pub async fn broadcast_tx(peers: &Broadcaster, tx: &Transaction) -> Result<()> {
+        let mut buf: Vec<u8> = Vec::new();
+        tx.encode(&mut BincodeEncoder::new(&mut buf))?;
+
+        let msg = Message::new(MessageType::Tx, buf);
+        peers.broadcast(msg).await?;
+        //let buf: Vec<u8> = Vec::new();
+        Ok(())
+    }
+
+    pub async fn broadcast_tx_batch(peers: &Broadcaster, txx: Vec<Transaction>) -> Result<()> {
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&TxBatchMessage { txx })?;
+
+        let msg = Message::new(MessageType::TxBatch, buf);
+        peers.broadcast(msg).await
+    }
+
+    // Gossips the transactions queued by process_transaction in batches
+    async fn tx_batch_loop(
+        txx: Arc<Mutex<mpsc::Receiver<Transaction>>>,
+        peers: Broadcaster,
+        interval: Duration,
+    ) {
+        let mut txx = txx.lock().await;
+        while let Some(batch) = next_tx_batch(&mut txx, interval, MAX_TX_BATCH_SIZE).await {
+            if let Err(err) = Self::broadcast_tx_batch(&peers, batch).await {
+                error!("Error broadcasting tx batch: {err}");
+            }
+        }
+    }
+
+    // Process functions
+
+    pub async fn process_message(&mut self, msg: DecodedMessage) -> Result<()> {
+        match msg.data {
+            DecodedMessageData::Tx(tx) => self.process_transaction(&msg.from, tx).await,
+            DecodedMessageData::TxBatch(batch) => {
+                self.process_tx_batch_message(&msg.from, batch).await
+            }
+            DecodedMessageData::Block(block) => {
+                let relay = Relay::Gossip {
+                    from: msg.from,
+                    origin: msg.origin,
+                    ttl: msg.ttl,
+                };
+                self.process_relayed_block(block, relay).await
+            }
+            DecodedMessageData::StatusMessage(message) => {
+                self.process_status_message(&msg.from, message).await
+            }
+            DecodedMessageData::GetStatusMessage => {
+                let id = self.opts.id.clone();
+                let peer_id = self.peer_id;
+                let tr = self.opts.transport.clone();
+                let bc = self.chain.clone();
+                let from = msg.from;
+                let version = message_version(&self.handshakes, &from).await;
+                self.tasks.spawn("send status", async move {
+                    Self::process_get_status_message(&id, peer_id, tr, bc, &from, version).await
+                });
+                Ok(())
+            }
+            DecodedMessageData::Ping(ping) => self.process_ping(&msg.from, ping).await,
+            DecodedMessageData::Pong(pong) => {
+                if let Some(latency) =
+                    self.conn_manager
+                        .lock()
+                        .await
+                        .on_pong(&msg.from, &pong, self.clock.now())
+                {
+                    debug!("ID={} latency to {}: {:?}", self.opts.id, msg.from, latency);
+                }
+                Ok(())
+            }
+            DecodedMessageData::GetPeersMessage => self.process_get_peers_message(&msg.from).await,
+            DecodedMessageData::PeersMessage(peers) => {
+                self.conn_manager
+                    .lock()
+                    .await
+                    .add_addresses(&peers.peers, self.clock.now());
+                Ok(())
+            }
+            DecodedMessageData::GetBlocksMessage(get_block_message) => {
+                self.process_get_blocks_message(&msg.from, &get_block_message)
+                    .await
+            }
+            DecodedMessageData::BlocksMessage(blocks) => {
+                self.process_blocks_message(&msg.from, blocks).await
+            }
+            DecodedMessageData::Proposal(proposal) => self.process_proposal(proposal).await,
+            DecodedMessageData::Vote(vote) => {
+                let actions = match &mut self.consensus {
+                    Some(engine) => engine.on_vote(vote),
+                    None => vec![],
+                };
+                self.run_consensus(actions).await;
+                Ok(())
+            }
+            DecodedMessageData::NewBlockHashes(announcement) => {
+                self.process_new_block_hashes(&msg.from, announcement).await
+            }
+            // sent by a newer node, peers only send the types of the features we advertise on purpose
+            DecodedMessageData::Unknown(code) => {
+                debug!(
+                    "ID={} ignoring message of unknown type {:#06x} from {}",
+                    self.opts.id, code, msg.from
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn process_proposal(&mut self, mut proposal: Proposal) -> Result<()> {
+        match &self.consensus {
+            Some(engine) if engine.height() == proposal.height => {}
+            _ => return Ok(()),
+        }
+
+        // the proposal has to extend our chain, the signatures of the other validators come later
+        {
+            let bc = self.chain.read().await;
+            let height = bc.height().await;
+            let header = &proposal.block.header;
+            if header.height != height + 1
+                || header.prev_block_hash != Some(bc.get_hash(height).await?)
+            {
+                return Err(anyhow!(
+                    "proposal for height {} doesn't extend our chain",
+                    header.height
+                ));
+            }
+        }
+        for tx in &mut proposal.block.transactions {
+            if !tx.has_cached_hash() {
+                tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            }
+        }
+        let sig_cache = self.chain.read().await.sig_cache().clone();
+        proposal.block.verify_cached(&sig_cache)?;
+
+        let actions = match &mut self.consensus {
+            Some(engine) => engine.on_proposal(proposal),
+            None => vec![],
+        };
+        self.run_consensus(actions).await;
+        Ok(())
+    }
+
+    // Carries out the actions of the consensus engine, which can lead to more actions
+    async fn run_consensus(&mut self, actions: Vec<Action>) {
+        let mut actions = VecDeque::from(actions);
+
+        while let Some(action) = actions.pop_front() {
+            match action {
+                Action::BuildProposal { height, round } => {
+                    match self.build_proposal(height).await {
+                        Ok(block) => {
+                            if let Some(engine) = &mut self.consensus {
+                                actions.extend(engine.propose(block));
+                            }
+                        }
+                        Err(err) => error!(
+                            "ID={} could not build a proposal for height {} in round {}: {}",
+                            self.opts.id, height, round, err
+                        ),
+                    }
+                }
+                Action::Broadcast(msg) => {
+                    let peers = self.broadcaster();
+                    self.tasks.spawn("broadcast consensus message", async move {
+                        Self::broadcast_consensus_message(&peers, &msg).await
+                    });
+                }
+                Action::ScheduleTimeout(timeout, after) => {
+                    let timeouts = self.consensus_timeouts.0.clone();
+                    let clock = self.clock.clone();
+                    self.tasks.spawn("consensus timeout", async move {
+                        clock.sleep(after).await;
+                        let _ = timeouts.send(timeout).await;
+                        Ok(())
+                    });
+                }
+                Action::Commit(block) => {
+                    let height = block.header.height;
+                    info!(
+                        "ID={} Consensus committed the block with height {}",
+                        self.opts.id, height
+                    );
+                    if let Err(err) = self.connect_block(block, Relay::All).await {
+                        error!(
+                            "ID={} Error adding the committed block {}: {}",
+                            self.opts.id, height, err
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    async fn build_proposal(&self, height: u32) -> Result<Block> {
+        let mut block = {
+            let txx = self.mem_pool.lock().await.pending_cloned();
+            self.chain.read().await.next_block(height, txx).await?
+        };
+        let key = self
+            .opts
+            .private_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("can't sign a proposal without a private key"))?;
+        block.sign(key)?;
+        Ok(block)
+    }
+
+    // Moves the consensus engine to the height after the head of the chain, once blocks arrived that
+    // it didn't decide itself
+    async fn follow_chain(&mut self, height: u32) {
+        let actions = match &mut self.consensus {
+            Some(engine) if engine.height() <= height => engine.start_height(height + 1),
+            _ => return,
+        };
+        self.run_consensus(actions).await;
+    }
+
+    async fn process_ping(&self, from: &NetAddr, ping: PingMessage) -> Result<()> {
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&ping.pong())?;
+        let version = message_version(&self.handshakes, from).await;
+        let msg = Message::new(MessageType::Pong, buf);
+
+        let tr = self.opts.transport.clone();
+        let to = from.clone();
+        let payload = msg.encode_in(version)?;
+        self.tasks.spawn(
+            "send pong",
+            async move { tr.send_message(&to, payload).await },
+        );
+
+        Ok(())
+    }
+
+    async fn process_get_peers_message(&self, from: &NetAddr) -> Result<()> {
+        let peers = self
+            .conn_manager
+            .lock()
+            .await
+            .peer_sample(from, self.clock.now());
+        debug!(
+            "ID={} sending {} addresses to {}",
+            self.opts.id,
+            peers.len(),
+            from
+        );
+
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&PeersMessage { peers })?;
+        let version = message_version(&self.handshakes, from).await;
+        let msg = Message::new(MessageType::Peers, buf);
+
+        let tr = self.opts.transport.clone();
+        let to = from.clone();
+        let payload = msg.encode_in(version)?;
+        self.tasks.spawn(
+            "send peers",
+            async move { tr.send_message(&to, payload).await },
+        );
+
+        Ok(())
+    }
+
+    // Streams at most MAX_BLOCKS_PER_RESPONSE blocks in chunks, the peer asks again for the rest. A peer is sent one
+    // range at a time.
+    async fn process_get_blocks_message(
+        &mut self,
+        from: &NetAddr,
+        data: &GetBlocksMessage,
+    ) -> Result<()> {
+        let height = self.chain.read().await.height().await;
+        let to = match data.to {
+            0 => height,
+            to => to.min(height),
+        };
+        if data.from > to {
+            return Err(anyhow!(
+                "{} asked for blocks {}..={}, our height is {}",
+                from,
+                data.from,
+                data.to,
+                height
+            ));
+        }
+        let lowest_block = self.chain.read().await.lowest_block();
+        if data.from < lowest_block {
+            return Err(anyhow!(
+                "{} asked for blocks {}..={}, we only keep the blocks from {}",
+                from,
+                data.from,
+                data.to,
+                lowest_block
+            ));
+        }
+        if !self.block_streams.lock().await.insert(from.clone()) {
+            return Err(anyhow!("already sending blocks to {from}"));
+        }
+
+        let last = to.min(data.from.saturating_add(MAX_BLOCKS_PER_RESPONSE - 1));
+        debug!(
+            "ID={} sending blocks {}..={} to {}",
+            self.opts.id, data.from, last, from
+        );
+
+        let chain = self.chain.clone();
+        let tr = self.opts.transport.clone();
+        let streams = self.block_streams.clone();
+        let to = from.clone();
+        let range = data.from..=last;
+        let version = message_version(&self.handshakes, from).await;
+
+        self.tasks.spawn("send blocks", async move {
+            let result =
+                Self::stream_blocks(&chain, &tr, &to, version, range, MAX_BLOCKS_CHUNK_SIZE).await;
+            streams.lock().await.remove(&to);
+            result
         });
 
-        Ok(())
+        Ok(())
+    }
+
+    // Sends the blocks in BlocksMessages of at most chunk_size bytes, a larger block is sent alone. Only one chunk
+    // is read from the store at a time and the next one is read once the transport took the previous one.
+    async fn stream_blocks(
+        chain: &RwLock<Blockchain>,
+        tr: &BTransport,
+        to: &NetAddr,
+        version: u32,
+        range: RangeInclusive<u32>,
+        chunk_size: u64,
+    ) -> Result<()> {
+        let (mut next, last) = range.into_inner();
+        while next <= last {
+            let mut blocks = vec![];
+            let mut size = 0;
+            {
+                let chain = chain.read().await;
+                while next <= last {
+                    let block = chain.get_block(next).await?;
+                    let block_size = bincode::serialized_size(&block)?;
+                    if !blocks.is_empty() && size + block_size > chunk_size {
+                        break;
+                    }
+                    size += block_size;
+                    blocks.push(block);
+                    next += 1;
+                }
+            }
+
+            let msg = BlocksMessage {
+                blocks,
+                more: next <= last,
+            };
+            let mut buf = vec![];
+            BincodeEncoder::new(&mut buf).encode(&msg)?;
+            let msg = Message::new(MessageType::Blocks, buf);
+            tr.send_message(to, msg.encode_in(version)?).await?;
+        }
+        Ok(())
+    }
+
+    // Adds the blocks we don't have yet. After the last chunk the next range is requested until we reached the
+    // height the peer told us in its status.
+    async fn process_blocks_message(&mut self, from: &NetAddr, msg: BlocksMessage) -> Result<()> {
+        for block in msg.blocks {
+            if block.header.height <= self.chain.read().await.height().await {
+                continue;
+            }
+            if let Err(err) = self.process_block(block).await {
+                self.sync_targets.remove(from);
+                return Err(err);
+            }
+        }
+        if msg.more {
+            return Ok(());
+        }
+
+        let Some(target) = self.sync_targets.get(from) else {
+            return Ok(());
+        };
+        let height = self.chain.read().await.height().await;
+        if height < target {
+            self.send_get_blocks_message(from, height + 1, target)
+                .await?;
+        } else {
+            self.sync_targets.remove(from);
+        }
+        Ok(())
+    }
+
+    // Asks the announcing peer for the blocks up to the highest one announced that we don't have. A peer we're
+    // already syncing from sends them anyway.
+    async fn process_new_block_hashes(
+        &mut self,
+        from: &NetAddr,
+        msg: NewBlockHashesMessage,
+    ) -> Result<()> {
+        // announcements keep the height of the peer current between its status messages
+        let mut lowest_block = 0;
+        if let Some(handshake) = self.handshakes.write().await.get_mut(from) {
+            if let Some(height) = msg.blocks.iter().map(|b| b.height).max() {
+                handshake.height = handshake.height.max(height);
+            }
+            lowest_block = handshake.lowest_block;
+        }
+        if self.sync_targets.contains(from) {
+            return Ok(());
+        }
+        let (our_height, unknown) = {
+            let chain = self.chain.read().await;
+            let unknown: Vec<BlockAnnouncement> = msg
+                .blocks
+                .into_iter()
+                .filter(|b| chain.height_of(&b.hash).is_none())
+                .collect();
+            (chain.height().await, unknown)
+        };
+        let Some(target) = unknown
+            .iter()
+            .map(|b| b.height)
+            .filter(|height| *height > our_height)
+            .max()
+        else {
+            return Ok(());
+        };
+        // a pruned peer can't send the blocks below the announced ones, we sync from another one
+        if lowest_block > our_height + 1 {
+            return Ok(());
+        }
+
+        debug!(
+            "ID={} {} announced block {}, asking for blocks {}..={}",
+            self.opts.id,
+            from,
+            target,
+            our_height + 1,
+            target
+        );
+        self.sync_targets.insert(from.clone(), target);
+        self.send_get_blocks_message(from, our_height + 1, target)
+            .await
+    }
+
+    async fn send_get_blocks_message(&self, to: &NetAddr, from: u32, to_height: u32) -> Result<()> {
+        let get_blocks_msg = GetBlocksMessage {
+            from,
+            to: to_height,
+        };
+
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&get_blocks_msg)?;
+
+        let version = message_version(&self.handshakes, to).await;
+        let msg = Message::new(MessageType::GetBlocks, buf);
+
+        let tr = self.opts.transport.clone();
+        let to = to.clone();
+        let payload = msg.encode_in(version)?;
+        self.tasks.spawn("send get blocks message", async move {
+            tr.send_message(&to, payload).await
+        });
+
+        Ok(())
+    }
+
+    pub async fn process_get_status_message(
+        id: &str,
+        peer_id: PeerId,
+        tr: BTransport,
+        bc: Arc<RwLock<Blockchain>>,
+        from: &NetAddr,
+        version: u32,
+    ) -> Result<()> {
+        info!("ID={}, Received get_status_message from {}", id, from);
+        let (height, lowest_block) = {
+            let bc = bc.read().await;
+            (bc.height().await, bc.lowest_block())
+        };
+
+        let status_msg = StatusMessage {
+            lowest_block,
+            ..StatusMessage::new(id.to_string(), peer_id, height, from.clone())
+        };
+
+        let mut buf = vec![];
+        BincodeEncoder::new(&mut buf).encode(&status_msg)?;
+
+        let msg = Message::new(MessageType::Status, buf);
+        info!("ID={}, sending status message to {}", id, from);
+
+        tr.send_message(from, msg.encode_in(version)?).await
+    }
+
+    pub async fn process_status_message(
+        &mut self,
+        from: &NetAddr,
+        msg: StatusMessage,
+    ) -> Result<()> {
+        let version = msg.negotiate_version().ok_or_else(|| {
+            anyhow!(
+                "incompatible protocol version with {}, they support {}..={}",
+                from,
+                msg.min_version,
+                msg.version
+            )
+        })?;
+        self.handshakes.write().await.insert(
+            from.clone(),
+            Handshake {
+                id: msg.peer_id,
+                version,
+                features: msg.features,
+                height: msg.current_height,
+                lowest_block: msg.lowest_block,
+            },
+        );
+        self.check_chain_lag().await;
+        self.external_addrs
+            .write()
+            .await
+            .observe(from.clone(), msg.observed_addr.clone());
+
+        let our_height = self.chain.read().await.height().await;
+        info!(
+            "ID={}, height: {}, received status message from: {}, height: {}",
+            self.opts.id, our_height, from, msg.current_height
+        );
+
+        if msg.current_height <= our_height {
+            // we caught up with it, from it or from others
+            self.sync_targets.remove(from);
+            warn!(
+                "ID={} cannot sync block_height too low our height: {}, their height: {}, addr: {}",
+                self.opts.id, our_height, msg.current_height, from
+            );
+            return Ok(());
+        }
+        // a pruned peer can't send the blocks we're missing
+        if msg.lowest_block > our_height + 1 {
+            warn!(
+                "ID={} cannot sync from {}, it only keeps the blocks from {}, our height: {}",
+                self.opts.id, from, msg.lowest_block, our_height
+            );
+            return Ok(());
+        }
+        info!(
+            "ID={} syncing block_height our height: {}, their height: {}, addr: {}",
+            self.opts.id, our_height, msg.current_height, from
+        );
+
+        // In this case we are behind and need to sync
+        self.sync_targets.insert(from.clone(), msg.current_height);
+        self.send_get_blocks_message(from, our_height + 1, msg.current_height)
+            .await
     }
 
-    pub async fn process_block(&mut self, mut block: Block) -> Result<()> {
+    pub async fn process_block(&mut self, block: Block) -> Result<()> {
+        self.process_relayed_block(block, Relay::All).await
+    }
+
+    async fn process_relayed_block(&mut self, mut block: Block, relay: Relay) -> Result<()> {
         {
             for tx in &mut block.transactions {
                 if !tx.has_cached_hash() {
@@ -327,21 +1772,148 @@ impl Server {
         }
         // info!("Received block: {}", block.hash(Box::new(BlockHasher)));
 
-        {
-            self.chain.lock().await.add_block(&mut block).await?;
+        let our_height = self.chain.read().await.height().await;
+        if block.header.height > our_height + 1 {
+            // the parent is still missing, keep the block until it arrives
+            let height = block.header.height;
+            if self.orphans.lock().await.add(block)? {
+                debug!(
+                    "ID={} Buffered orphan block with height {} (our_height: {})",
+                    self.opts.id, height, our_height
+                );
+            }
+            return Ok(());
         }
 
-        let transports = self.opts.transports.clone();
+        let hash = self.connect_block(block, relay).await?;
 
-        tokio::task::spawn(async move {
-            if let Err(err) = Self::broadcast_block(&transports, &block).await {
-                error!("Error broadcasting block: {err}");
+        // connect the orphans that were waiting for this block (and their descendants), who sent them isn't kept
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            let children = self.orphans.lock().await.take_children(&parent);
+            for child in children {
+                match self.connect_block(child, Relay::All).await {
+                    Ok(hash) => parents.push(hash),
+                    Err(err) => debug!("ID={} Dropping orphan block: {err}", self.opts.id),
+                }
             }
-        });
+        }
+
+        let height = self.chain.read().await.height().await;
+        self.orphans.lock().await.prune(height);
+        self.follow_chain(height).await;
+        self.check_chain_lag().await;
 
         Ok(())
     }
 
+    // Adds a block whose parent is known to the chain and relays it, returns the hash of the block
+    async fn connect_block(&mut self, mut block: Block, relay: Relay) -> Result<Hash> {
+        // a block conflicting with ours is rejected below, its signatures are checked first
+        self.detect_double_signs(&block).await;
+        {
+            self.chain.write().await.add_block(&mut block).await?;
+        }
+        let hash = block.hash(Box::new(BlockHasher))?;
+        self.update_validators().await;
+
+        // the transactions of the block are mined and must not be proposed again
+        let hashes: Vec<Hash> = block.transactions.iter().map(|tx| tx.hash()).collect();
+        {
+            let mut mem_pool = self.mem_pool.lock().await;
+            mem_pool.remove_batch(&hashes);
+            mem_pool.record_block(&block);
+        }
+
+        let peers = self.broadcaster();
+        match relay {
+            Relay::All => {
+                let origin = self.peer_id;
+                self.tasks.spawn("broadcast block", async move {
+                    Self::broadcast_block(&peers, &block, origin, DEFAULT_GOSSIP_TTL).await
+                });
+            }
+            Relay::Gossip { ttl: 0, .. } => {
+                debug!(
+                    "ID={} Not relaying block {}, its ttl ran out",
+                    self.opts.id, hash
+                );
+            }
+            Relay::Gossip { from, origin, ttl } => {
+                self.tasks.spawn("relay block", async move {
+                    Self::relay_block(&peers, &block, &from, origin, ttl - 1).await
+                });
+            }
+        }
+
+        Ok(hash)
+    }
+
+    async fn detect_double_signs(&mut self, block: &Block) {
+        let evidence = match self.double_signs.observe(block) {
+            Ok(evidence) => evidence,
+            Err(err) => {
+                debug!(
+                    "ID={} Could not check the block signatures: {err}",
+                    self.opts.id
+                );
+                return;
+            }
+        };
+
+        for evidence in evidence {
+            warn!(
+                "ID={} Validator {} signed two blocks with height {}",
+                self.opts.id,
+                evidence.validator().address(),
+                block.header.height
+            );
+            if let Err(err) = self.submit_evidence(evidence).await {
+                error!("ID={} Error submitting evidence: {err}", self.opts.id);
+            }
+        }
+    }
+
+    // Adds a transaction with the evidence to the mem_pool and broadcasts it
+    async fn submit_evidence(&mut self, evidence: Evidence) -> Result<()> {
+        let key = self
+            .opts
+            .private_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("can't sign evidence without a private key"))?;
+
+        let mut tx = Transaction::new_evidence(evidence);
+        tx.sign(key);
+        let from = self.opts.transport.addr();
+        self.process_transaction(&from, tx).await
+    }
+
+    // The consensus follows the validator set in the state of the chain. We join it once our key
+    // staked enough and leave it when the stake is gone.
+    async fn update_validators(&mut self) {
+        let (Some(opts), Some(key)) = (&self.opts.consensus, &self.opts.private_key) else {
+            return;
+        };
+        let validators = self.chain.read().await.validator_set();
+
+        if !validators.contains(&key.public_key()) {
+            if self.consensus.take().is_some() {
+                warn!(
+                    "ID={} We lost our stake, leaving the consensus",
+                    self.opts.id
+                );
+            }
+            return;
+        }
+        match &mut self.consensus {
+            Some(engine) => engine.set_validators(validators),
+            None => {
+                info!("ID={} Joining the consensus", self.opts.id);
+                self.consensus = ConsensusEngine::new(validators, key.clone(), opts.timeouts).ok();
+            }
+        }
+    }
+
     pub async fn process_transaction(
         &mut self,
         net_addr: &NetAddr,
@@ -357,8 +1929,17 @@ impl Server {
             return Ok(());
         }
 
-        tx.verify()?;
-        tx.set_first_seen(Instant::now().elapsed().as_nanos());
+        // rejected before it's relayed, peers would reject it as well
+        mem_pool.verify(&tx)?;
+        {
+            let chain = self.chain.read().await;
+            chain.check_transaction(&tx)?;
+            // a nonce ahead of the chain queues the transaction until the ones before it arrive
+            if let Some(from) = &tx.from {
+                let sender = from.address();
+                mem_pool.set_account_nonce(sender, chain.state().nonce(&sender));
+            }
+        }
 
         info!(
             "ID={} Adding new tx {} to mem_pool (pending_count: {})",
@@ -367,19 +1948,38 @@ impl Server {
             mem_pool.pending_count()
         );
 
-        // TODO: broadcast this tx to peers
-
-        let transports = self.opts.transports.clone();
+        // added before broadcasting, a replacement with a too low fee is neither kept nor relayed
         let tx_clone = tx.clone();
+        mem_pool.add_received(tx)?;
 
-        tokio::task::spawn(async move {
-            if let Err(err) = Self::broadcast_tx(&transports, &tx_clone).await {
-                error!("Error broadcasting tx: {err}");
-            }
-        });
+        // gossiped with the next batch, if the queue is full only this node can put it into a block
+        if let Err(err) = self.tx_batch_channel.0.try_send(tx_clone) {
+            warn!("ID={} not gossiping tx {}: {}", self.opts.id, hash, err);
+        }
+        self.tx_notify.notify_one();
 
-        mem_pool.add(tx)?;
+        Ok(())
+    }
 
+    // Every transaction of the batch is processed on its own, an invalid one doesn't reject the others
+    async fn process_tx_batch_message(
+        &mut self,
+        from: &NetAddr,
+        batch: TxBatchMessage,
+    ) -> Result<()> {
+        if batch.txx.len() > MAX_TX_BATCH_SIZE {
+            return Err(anyhow!(
+                "{} sent a batch of {} transactions, at most {} are allowed",
+                from,
+                batch.txx.len(),
+                MAX_TX_BATCH_SIZE
+            ));
+        }
+        for tx in batch.txx {
+            if let Err(err) = self.process_transaction(from, tx).await {
+                debug!("ID={} rejected tx from {}: {}", self.opts.id, from, err);
+            }
+        }
         Ok(())
     }
 
@@ -387,17 +1987,15 @@ impl Server {
         bc: &mut Blockchain,
         tx_pool: &mut TxPool,
         private_key: PrivateKey,
-        transports: Vec<BTransport>,
+        peers: Broadcaster,
     ) -> Result<()> {
-        let prev_header = bc.get_header(bc.height().await).await?;
-
         // For now we're going to use all transactions that are in the mempool
         // Later on when we know the internal structure of our transaction
         // we will implement some kind of complexity function
         // to determine how many transactions can be inculded in a block
         let txx = tx_pool.pending_cloned();
 
-        let mut block = Block::from_prev_header(prev_header, txx)?;
+        let mut block = bc.next_block(bc.height().await + 1, txx).await?;
         info!(
             "ID={} Creating new block with height {}",
             bc.server_id, block.header.height
@@ -406,12 +2004,15 @@ impl Server {
         block.sign(&private_key)?;
         bc.add_block(&mut block).await?;
 
-        //TODO: pending pool of tx should only reflect on validator nodes
-        // Right now "normal nodes" don't have their pending pool cleared
-        tx_pool.clear_pending();
+        let hashes: Vec<Hash> = block.transactions.iter().map(|tx| tx.hash()).collect();
+        tx_pool.remove_batch(&hashes);
+        tx_pool.record_block(&block);
 
+        let origin = PeerId::from_public_key(&private_key.public_key());
         tokio::task::spawn(async move {
-            if let Err(err) = Self::broadcast_block(&transports, &block).await {
+            if let Err(err) =
+                Self::broadcast_block(&peers, &block, origin, DEFAULT_GOSSIP_TTL).await
+            {
                 error!("Error broadcasting block: {err}");
             }
         });
@@ -419,18 +2020,718 @@ impl Server {
         Ok(())
     }
 
-    fn init_transports(&self) {
-        for tr in self.opts.transports.clone().into_iter() {
-            let rpc_channel = self.rpc_channel.clone();
-            tokio::task::spawn(async move {
-                loop {
-                    if let Some(rpc) = tr.recv().await {
-                        if let Err(err) = rpc_channel.0.send(rpc).await {
-                            println!("RPC Error: {err}");
-                        }
-                    }
+    // Reads the messages of the transport into the rpc_queue, the reader is restarted if the transport closed
+    fn init_transport(&self) {
+        let tr = self.opts.transport.clone();
+        let name = format!("transport reader {}", tr.addr());
+        let rpc_tx = self.rpc_queue.0.clone();
+        self.tasks.spawn_critical(&name, move || {
+            let tr = tr.clone();
+            let rpc_tx = rpc_tx.clone();
+            async move {
+                while let Some(rpc) = tr.recv().await {
+                    // messages without a valid header get the lowest priority
+                    let priority = peek_message_type(&rpc.payload)
+                        .map(|header| Priority::of_type(&header))
+                        .unwrap_or(Priority::Tx);
+                    rpc_tx.send(priority, rpc).await;
                 }
-            });
+                Err(anyhow!("the transport closed"))
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        core::ManualClock,
+        network::{default_rpc_decode_fn, LocalTransport, PongMessage, MIN_PROTOCOL_VERSION},
+        simulator::{SimOpts, Simulation},
+        test_utils::random_tx,
+    };
+
+    fn opts(id: &str, tr: BTransport) -> ServerOpts {
+        ServerOpts::new(id, tr)
+    }
+
+    #[tokio::test]
+    async fn test_quit() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let mut s = Server::new(ServerOpts {
+            private_key: Some(PrivateKey::generate()),
+            ..opts("A", tr)
+        })
+        .await?;
+
+        let quit = s.quit_sender();
+        let server = tokio::task::spawn(async move { s.start().await });
+
+        // the server is idle, nothing arrives on the rpc channel
+        time::sleep(Duration::from_millis(50)).await;
+        quit.send(()).await?;
+        time::timeout(Duration::from_secs(1), server).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_validator_loop_follows_the_clock() -> Result<()> {
+        let clock = Arc::new(ManualClock::new());
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let mut s = Server::new(ServerOpts {
+            private_key: Some(PrivateKey::generate()),
+            block_time: Some(Duration::from_secs(5)),
+            clock: Some(clock.clone()),
+            ..opts("A", tr)
+        })
+        .await?;
+        let chain = s.chain.clone();
+        let quit = s.quit_sender();
+        let server = tokio::task::spawn(async move { s.start().await });
+
+        let wait_for_height = |height: u32| {
+            let chain = chain.clone();
+            time::timeout(Duration::from_secs(1), async move {
+                while chain.read().await.height().await < height {
+                    time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+        };
+
+        // the first block is produced right away, the next one once block_time passed on the clock
+        wait_for_height(1).await?;
+        clock.advance(Duration::from_secs(4));
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(chain.read().await.height().await, 1);
+
+        clock.advance(Duration::from_secs(1));
+        wait_for_height(2).await?;
+        let header = chain.read().await.get_header(2).await?;
+        assert_eq!(header.timestamp, Duration::from_secs(5).as_nanos());
+
+        quit.send(()).await?;
+        time::timeout(Duration::from_secs(1), server).await???;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_blocks_while_syncing() -> Result<()> {
+        let clock = Arc::new(ManualClock::new());
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let mut s = Server::new(ServerOpts {
+            private_key: Some(PrivateKey::generate()),
+            block_time: Some(Duration::from_secs(5)),
+            clock: Some(clock.clone()),
+            ..opts("A", tr)
+        })
+        .await?;
+        // a peer told us it is at height 10
+        s.sync_targets.insert("B".into(), 10);
+        assert_eq!(
+            s.sync_status().await,
+            SyncStatus::Syncing {
+                current: 0,
+                target: 10,
+                peers: 1,
+            }
+        );
+        let chain = s.chain.clone();
+        let sync_targets = s.sync_targets.clone();
+        let quit = s.quit_sender();
+        let server = tokio::task::spawn(async move { s.start().await });
+
+        time::sleep(Duration::from_millis(50)).await;
+        clock.advance(Duration::from_secs(5));
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(chain.read().await.height().await, 0);
+
+        // done syncing, blocks are produced again
+        sync_targets.remove(&"B".into());
+        clock.advance(Duration::from_secs(5));
+        time::timeout(Duration::from_secs(1), async {
+            while chain.read().await.height().await < 1 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await?;
+
+        quit.send(()).await?;
+        time::timeout(Duration::from_secs(1), server).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_production_lag() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let mut s = Server::new(ServerOpts {
+            private_key: Some(PrivateKey::generate()),
+            max_production_lag: Some(10),
+            ..opts("A", tr)
+        })
+        .await?;
+        // close enough to the peer, the first block is produced right away
+        s.sync_targets.insert("B".into(), 10);
+        let chain = s.chain.clone();
+        let quit = s.quit_sender();
+        let server = tokio::task::spawn(async move { s.start().await });
+
+        time::timeout(Duration::from_secs(1), async {
+            while chain.read().await.height().await < 1 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await?;
+
+        quit.send(()).await?;
+        time::timeout(Duration::from_secs(1), server).await???;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_external_addr_from_status_messages() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let mut s = Server::new(opts("A", tr)).await?;
+        let outside: NetAddr = "203.0.113.7:3000".into();
+
+        for peer in ["B", "C"] {
+            assert_eq!(s.external_addr().await, None);
+            let status = StatusMessage::new(peer.into(), PeerId::default(), 0, outside.clone());
+            s.process_status_message(&peer.into(), status).await?;
+        }
+        assert_eq!(s.external_addr().await, Some(outside));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_in_pages() -> Result<()> {
+        let tr_a: BTransport = Box::new(LocalTransport::new("A".into()));
+        let tr_b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr_a.connect(tr_b.clone()).await?;
+        tr_b.connect(tr_a.clone()).await?;
+
+        // more blocks than fit into one answer
+        let height = MAX_BLOCKS_PER_RESPONSE + 10;
+        let mut a = Server::new(opts("A", tr_a.clone())).await?;
+        {
+            let mut chain = a.chain.write().await;
+            let key = PrivateKey::generate();
+            for h in 1..=height {
+                let mut block = chain.next_block(h, vec![]).await?;
+                block.sign(&key)?;
+                chain.add_block(&mut block).await?;
+            }
+        }
+        let a_handle = a.handle();
+        let a_task = tokio::task::spawn(async move { a.start().await });
+
+        // B learns the height of A and asks it for the blocks
+        let mut b = Server::new(opts("B", tr_b)).await?;
+        let status = StatusMessage::new("A".into(), PeerId::default(), height, "B".into());
+        b.process_status_message(&"A".into(), status).await?;
+        assert_eq!(
+            b.sync_status().await,
+            SyncStatus::Syncing {
+                current: 0,
+                target: height,
+                peers: 1,
+            }
+        );
+        let b_handle = b.handle();
+        let b_task = tokio::task::spawn(async move { b.start().await });
+
+        time::timeout(Duration::from_secs(20), async {
+            while b_handle.get_height().await? < height {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+        assert_eq!(b_handle.get_sync_status().await?, SyncStatus::Synced);
+
+        for (handle, task) in [(b_handle, b_task), (a_handle, a_task)] {
+            handle.shutdown().await;
+            task.await??;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pruned_node() -> Result<()> {
+        let tr_a: BTransport = Box::new(LocalTransport::new("A".into()));
+        let tr_b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr_a.connect(tr_b.clone()).await?;
+        tr_b.connect(tr_a.clone()).await?;
+
+        let mut a = Server::new(ServerOpts {
+            mode: Some(NodeMode::Pruned { keep_blocks: 5 }),
+            ..opts("A", tr_a)
+        })
+        .await?;
+        {
+            let mut chain = a.chain.write().await;
+            let key = PrivateKey::generate();
+            for h in 1..=10 {
+                let mut block = chain.next_block(h, vec![]).await?;
+                block.sign(&key)?;
+                chain.add_block(&mut block).await?;
+            }
+            assert_eq!(chain.lowest_block(), 6);
+        }
+        assert_eq!(a.node_info().await.lowest_block, 6);
+
+        // the pruned blocks aren't served
+        let get_blocks = |from| GetBlocksMessage { from, to: 0 };
+        assert!(a
+            .process_get_blocks_message(&"B".into(), &get_blocks(1))
+            .await
+            .is_err());
+        a.process_get_blocks_message(&"B".into(), &get_blocks(6))
+            .await?;
+
+        // B needs blocks A doesn't have anymore, it waits for another peer
+        let mut b = Server::new(opts("B", tr_b)).await?;
+        let status = StatusMessage {
+            lowest_block: 6,
+            ..StatusMessage::new("A".into(), PeerId::default(), 10, "B".into())
+        };
+        b.process_status_message(&"A".into(), status.clone())
+            .await?;
+        assert!(b.sync_targets.is_empty());
+        {
+            let mut chain = b.chain.write().await;
+            let key = PrivateKey::generate();
+            for h in 1..=5 {
+                let mut block = chain.next_block(h, vec![]).await?;
+                block.sign(&key)?;
+                chain.add_block(&mut block).await?;
+            }
+        }
+        b.process_status_message(&"A".into(), status).await?;
+        assert_eq!(b.sync_targets.get(&"A".into()), Some(10));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_status_polling_resyncs() -> Result<()> {
+        let tr_a: BTransport = Box::new(LocalTransport::new("A".into()));
+        let tr_b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr_a.connect(tr_b.clone()).await?;
+        tr_b.connect(tr_a.clone()).await?;
+
+        let mut a = Server::new(opts("A", tr_a.clone())).await?;
+        let a_chain = a.chain();
+        let a_handle = a.handle();
+        let a_task = tokio::task::spawn(async move { a.start().await });
+
+        let clock = Arc::new(ManualClock::new());
+        let mut b = Server::new(ServerOpts {
+            sync_interval: Some(Duration::from_secs(10)),
+            clock: Some(clock.clone()),
+            ..opts("B", tr_b)
+        })
+        .await?;
+        let b_handle = b.handle();
+        let b_task = tokio::task::spawn(async move { b.start().await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        // B misses the blocks and nothing new arrives, only asking for the status again catches it up
+        {
+            let mut chain = a_chain.write().await;
+            let key = PrivateKey::generate();
+            for h in 1..=3 {
+                let mut block = chain.next_block(h, vec![]).await?;
+                block.sign(&key)?;
+                chain.add_block(&mut block).await?;
+            }
+        }
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(b_handle.get_height().await?, 0);
+        clock.advance(Duration::from_secs(10));
+        time::timeout(Duration::from_secs(5), async {
+            while b_handle.get_height().await? < 3 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+
+        for (handle, task) in [(b_handle, b_task), (a_handle, a_task)] {
+            handle.shutdown().await;
+            task.await??;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_blocks_in_chunks() -> Result<()> {
+        let tr_a: BTransport = Box::new(LocalTransport::new("A".into()));
+        let tr_b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr_a.connect(tr_b.clone()).await?;
+
+        let s = Server::new(opts("A", tr_a.clone())).await?;
+        let mut size = 0;
+        {
+            let mut chain = s.chain.write().await;
+            let key = PrivateKey::generate();
+            for h in 1..=10 {
+                let mut block = chain.next_block(h, vec![random_tx()]).await?;
+                block.sign(&key)?;
+                chain.add_block(&mut block).await?;
+                size = size.max(bincode::serialized_size(&block)?);
+            }
+        }
+
+        // two blocks fit into a chunk, the transport takes one message at a time
+        let chain = s.chain.clone();
+        let stream = tokio::task::spawn(async move {
+            let to = "B".into();
+            Server::stream_blocks(&chain, &tr_a, &to, PROTOCOL_VERSION, 2..=8, size * 2).await
+        });
+        let mut heights = vec![];
+        loop {
+            let rpc = time::timeout(Duration::from_secs(1), tr_b.recv())
+                .await?
+                .ok_or_else(|| anyhow!("transport closed"))?;
+            let DecodedMessageData::BlocksMessage(msg) = default_rpc_decode_fn(rpc)?.data else {
+                panic!("expected a blocks message");
+            };
+            assert!(msg.blocks.len() <= 2);
+            heights.extend(msg.blocks.iter().map(|b| b.header.height));
+            if !msg.more {
+                break;
+            }
+        }
+        stream.await??;
+        assert_eq!(heights, (2..=8).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_txs_are_gossiped_in_batches() -> Result<()> {
+        let tr_a: BTransport = Box::new(LocalTransport::new("A".into()));
+        let tr_b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr_a.connect(tr_b.clone()).await?;
+
+        // a long window, so the transactions can't end up in different batches
+        let mut a = Server::new(ServerOpts {
+            tx_batch_interval: Some(Duration::from_millis(500)),
+            ..opts("A", tr_a)
+        })
+        .await?;
+        // only the peers of the connection manager get broadcasts
+        a.conn_manager
+            .lock()
+            .await
+            .on_message(&tr_b.addr(), a.clock.now())
+            .await;
+        let handle = a.handle();
+        let server = tokio::task::spawn(async move { a.start().await });
+        for _ in 0..3 {
+            handle.submit_transaction(random_tx()).await?;
         }
+
+        let batch = time::timeout(Duration::from_secs(2), async {
+            loop {
+                let rpc = tr_b
+                    .recv()
+                    .await
+                    .ok_or_else(|| anyhow!("transport closed"))?;
+                if let DecodedMessageData::TxBatch(batch) = default_rpc_decode_fn(rpc)?.data {
+                    return Ok::<_, anyhow::Error>(batch);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(batch.txx.len(), 3);
+
+        handle.shutdown().await;
+        server.await?
+    }
+
+    #[tokio::test]
+    async fn test_chain_reads_are_shared() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let s = Server::new(opts("A", tr)).await?;
+
+        // a reader doesn't wait for another reader
+        let chain = s.chain.read().await;
+        let status = time::timeout(Duration::from_secs(1), s.tx_status(&Hash::random())).await?;
+        assert_eq!(status, TxStatus::Unknown);
+
+        // but a writer waits for both
+        assert!(s.chain.try_write().is_err());
+        drop(chain);
+        assert!(s.chain.try_write().is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_double_sign_evidence() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let mut s = Server::new(ServerOpts {
+            private_key: Some(PrivateKey::generate()),
+            ..opts("A", tr)
+        })
+        .await?;
+
+        // the offender signs two different blocks with height 1
+        let offender = PrivateKey::generate();
+        let genesis = s.chain.read().await.get_header(0).await?;
+        let mut a = Block::from_prev_header(genesis, vec![])?;
+        let mut b = Block::from_prev_header(genesis, vec![random_tx()])?;
+        a.sign(&offender)?;
+        b.sign(&offender)?;
+
+        s.process_block(a).await?;
+        assert!(s.process_block(b).await.is_err());
+
+        let pending = s.mem_pool.lock().await.pending_cloned();
+        assert_eq!(pending.len(), 1);
+        let evidence = pending[0].evidence().unwrap();
+        assert_eq!(evidence.validator(), &offender.public_key());
+        evidence.verify()?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_consensus() -> Result<()> {
+        let sim = Simulation::start(SimOpts {
+            epoch_length: 2,
+            ..SimOpts::default()
+        })
+        .await?;
+        sim.wait_for_height(3, Duration::from_secs(10)).await?;
+
+        // every validator committed the same blocks
+        assert!(sim.assert_converged().await? >= 3);
+
+        sim.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_gossip_relay() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let b: BTransport = Box::new(LocalTransport::new("B".into()));
+        let c: BTransport = Box::new(LocalTransport::new("C".into()));
+        tr.connect(b.clone()).await?;
+        tr.connect(c.clone()).await?;
+        let mut s = Server::new(opts("A", tr)).await?;
+        for peer in [&b, &c] {
+            let now = s.clock.now();
+            s.conn_manager
+                .lock()
+                .await
+                .on_message(&peer.addr(), now)
+                .await;
+        }
+
+        let key = PrivateKey::generate();
+        let origin = PeerId::from_public_key(&key.public_key());
+        let gossip = |s: &Server, ttl: u8, origin: PeerId| {
+            let chain = s.chain.clone();
+            let key = key.clone();
+            async move {
+                let chain = chain.read().await;
+                let mut block = chain.next_block(chain.height().await + 1, vec![]).await?;
+                block.sign(&key)?;
+                Ok::<_, anyhow::Error>(DecodedMessage {
+                    from: "B".into(),
+                    origin,
+                    ttl,
+                    data: DecodedMessageData::Block(block),
+                })
+            }
+        };
+
+        // our own gossip coming back is ignored
+        let msg = gossip(&s, 3, s.peer_id).await?;
+        s.handle_message(msg).await;
+        assert_eq!(s.chain.read().await.height().await, 0);
+
+        // relayed with one hop less, but not back to B
+        let msg = gossip(&s, 3, origin).await?;
+        s.process_message(msg).await?;
+        let rpc = time::timeout(Duration::from_secs(1), c.recv())
+            .await?
+            .ok_or_else(|| anyhow!("C got nothing"))?;
+        let relayed = Message::from_payload(&rpc.payload)?;
+        assert!(matches!(relayed.header, MessageType::Block));
+        assert_eq!((relayed.origin, relayed.ttl), (origin, 2));
+        assert!(time::timeout(Duration::from_millis(50), b.recv())
+            .await
+            .is_err());
+
+        // a block whose ttl ran out is added, but not relayed
+        let msg = gossip(&s, 0, origin).await?;
+        s.process_message(msg).await?;
+        assert_eq!(s.chain.read().await.height().await, 2);
+        assert!(time::timeout(Duration::from_millis(50), c.recv())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_block_announcements() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr.connect(b.clone()).await?;
+        let mut s = Server::new(opts("A", tr)).await?;
+        let genesis = s.chain.read().await.get_hash(0).await?;
+        let announce = |hash: Hash, height: u32| DecodedMessage {
+            from: "B".into(),
+            origin: PeerId::default(),
+            ttl: 0,
+            data: DecodedMessageData::NewBlockHashes(NewBlockHashesMessage {
+                blocks: vec![BlockAnnouncement { hash, height }],
+            }),
+        };
+
+        // a block we have
+        s.process_message(announce(genesis, 0)).await?;
+        assert!(time::timeout(Duration::from_millis(50), b.recv())
+            .await
+            .is_err());
+
+        // B is asked for the blocks up to the announced one
+        s.process_message(announce(Hash::random(), 3)).await?;
+        let rpc = time::timeout(Duration::from_secs(1), b.recv())
+            .await?
+            .ok_or_else(|| anyhow!("B got nothing"))?;
+        let msg = Message::from_payload(&rpc.payload)?;
+        assert!(matches!(msg.header, MessageType::GetBlocks));
+        let get_blocks: GetBlocksMessage = bincode::deserialize(&msg.data)?;
+        assert_eq!(get_blocks, GetBlocksMessage { from: 1, to: 3 });
+
+        // B sends them anyway
+        s.process_message(announce(Hash::random(), 4)).await?;
+        assert!(time::timeout(Duration::from_millis(50), b.recv())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_messages_in_negotiated_version() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr.connect(b.clone()).await?;
+        let mut s = Server::new(opts("A", tr)).await?;
+        s.conn_manager
+            .lock()
+            .await
+            .on_message(&b.addr(), s.clock.now())
+            .await;
+
+        let handshake = |version: u32| Handshake {
+            id: PeerId::default(),
+            version,
+            features: 0,
+            height: 0,
+            lowest_block: 0,
+        };
+        let version = MIN_PROTOCOL_VERSION;
+        s.handshakes
+            .write()
+            .await
+            .insert(b.addr(), handshake(version));
+        let recv = || async {
+            let rpc = time::timeout(Duration::from_millis(500), b.recv())
+                .await?
+                .ok_or_else(|| anyhow!("B got nothing"))?;
+            Message::from_payload(&rpc.payload)
+        };
+        let ping = || DecodedMessage {
+            from: b.addr(),
+            origin: PeerId::default(),
+            ttl: 0,
+            data: DecodedMessageData::Ping(PingMessage::new(7)),
+        };
+
+        // a reply to B alone
+        s.process_message(ping()).await?;
+        let msg = recv().await?;
+        assert!(matches!(msg.header, MessageType::Pong));
+        assert_eq!(msg.version, version);
+        let pong: PongMessage = bincode::deserialize(&msg.data)?;
+        assert_eq!(pong.nonce, 7);
+
+        // a broadcast to every peer
+        let tx = random_tx();
+        Server::broadcast_tx(&s.broadcaster(), &tx).await?;
+        let msg = recv().await?;
+        assert!(matches!(msg.header, MessageType::Tx));
+        assert_eq!(msg.version, version);
+        let received: Transaction = bincode::deserialize(&msg.data)?;
+        assert_eq!(received.data, tx.data);
+
+        // nothing is sent in a version this node has no encoder for
+        s.handshakes
+            .write()
+            .await
+            .insert(b.addr(), handshake(PROTOCOL_VERSION + 1));
+        assert!(s.process_message(ping()).await.is_err());
+        Server::broadcast_tx(&s.broadcaster(), &tx).await?;
+        assert!(recv().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lag_alarm() -> Result<()> {
+        let tr: BTransport = Box::new(LocalTransport::new("A".into()));
+        let b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr.connect(b.clone()).await?;
+        let (events_tx, mut events) = mpsc::channel(8);
+        let mut s = Server::new(ServerOpts {
+            lag_alarm: Some(LagAlarm {
+                threshold: 2,
+                events: events_tx,
+            }),
+            ..opts("A", tr)
+        })
+        .await?;
+        let status = |height| StatusMessage::new("B".into(), PeerId::default(), height, "A".into());
+
+        // within the threshold
+        s.process_status_message(&"B".into(), status(2)).await?;
+        assert_eq!(s.chain_lag().await, 2);
+        assert!(events.try_recv().is_err());
+
+        s.process_status_message(&"B".into(), status(5)).await?;
+        assert_eq!(s.chain_lag().await, 5);
+        assert_eq!(
+            events.try_recv()?,
+            ChainLagEvent::Lagging {
+                height: 0,
+                peer_height: 5
+            }
+        );
+        // reported once
+        s.process_status_message(&"B".into(), status(6)).await?;
+        assert!(events.try_recv().is_err());
+
+        let key = PrivateKey::generate();
+        for height in 1..=4 {
+            let mut block = s.chain.read().await.next_block(height, vec![]).await?;
+            block.sign(&key)?;
+            s.process_block(block).await?;
+        }
+        assert_eq!(s.chain_lag().await, 2);
+        assert_eq!(events.try_recv()?, ChainLagEvent::Recovered { height: 4 });
+
+        Ok(())
     }
 }
@@ -0,0 +1,300 @@
+/*
+ServerBuilder assembles the ServerOpts of a node, so an application embedding one doesn't have to spell out every
+option. Only a transport is required, everything not set gets the defaults of Server::new. A server given several
+transports listens on all of them through a MultiTransport.
+
+start() runs the server in a task and returns a RunningServer, the application reads the height, submits
+transactions and shuts the node down through it or through the ServerHandles it hands out.
+*/
+
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use super::{
+    use_go_wire_format, BTransport, ConnectionManagerOpts, MultiTransport, PortMappingOpts, Server,
+    ServerHandle, ServerOpts,
+};
+#[cfg(feature = "api")]
+use crate::api::ApiOpts;
+use crate::{
+    consensus::ConsensusOpts,
+    core::{BClock, Checkpoints, GenesisConfig, NodeMode, Pruning, Transaction},
+    crypto::PrivateKey,
+};
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    id: Option<String>,
+    transports: Vec<BTransport>,
+    private_key: Option<PrivateKey>,
+    block_time: Option<Duration>,
+    produce_empty_blocks: Option<bool>,
+    connection_opts: Option<ConnectionManagerOpts>,
+    pruning: Option<Pruning>,
+    mode: Option<NodeMode>,
+    checkpoints: Option<Checkpoints>,
+    genesis: Option<GenesisConfig>,
+    consensus: Option<ConsensusOpts>,
+    clock: Option<BClock>,
+    port_mapping: Option<PortMappingOpts>,
+    go_wire_format: bool,
+    #[cfg(feature = "api")]
+    api: Option<ApiOpts>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+impl ServerBuilder {
+    // Used in the logs, the address of the transport if not set
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    // Adds a transport the server listens on, the first one is the primary transport
+    pub fn with_transport(mut self, transport: BTransport) -> Self {
+        self.transports.push(transport);
+        self
+    }
+
+    // Makes the node a validator signing blocks with the key
+    pub fn with_validator_key(mut self, private_key: PrivateKey) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    pub fn with_block_time(mut self, block_time: Duration) -> Self {
+        self.block_time = Some(block_time);
+        self
+    }
+
+    pub fn with_empty_blocks(mut self, produce_empty_blocks: bool) -> Self {
+        self.produce_empty_blocks = Some(produce_empty_blocks);
+        self
+    }
+
+    pub fn with_connection_opts(mut self, connection_opts: ConnectionManagerOpts) -> Self {
+        self.connection_opts = Some(connection_opts);
+        self
+    }
+
+    // Stores the headers in a file and keeps only the newest ones in memory
+    pub fn with_storage(mut self, pruning: Pruning) -> Self {
+        self.pruning = Some(pruning);
+        self
+    }
+
+    // An archival node keeps every block and receipt, a pruned one only the newest
+    pub fn with_mode(mut self, mode: NodeMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    // Starts the chain with the funded accounts of the config, every node of the network needs the same one
+    pub fn with_genesis(mut self, genesis: GenesisConfig) -> Self {
+        self.genesis = Some(genesis);
+        self
+    }
+
+    pub fn with_consensus(mut self, consensus: ConsensusOpts) -> Self {
+        self.consensus = Some(consensus);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: BClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn with_port_mapping(mut self, port_mapping: PortMappingOpts) -> Self {
+        self.port_mapping = Some(port_mapping);
+        self
+    }
+
+    // Talks to Go projectx nodes instead of nodes of this crate, see go_compat.rs
+    pub fn with_go_wire_format(mut self) -> Self {
+        self.go_wire_format = true;
+        self
+    }
+
+    // Serves the API on localhost, with_api configures everything else
+    #[cfg(feature = "api")]
+    pub fn with_api_port(self, port: u16) -> Self {
+        self.with_api(ApiOpts::new(([127, 0, 0, 1], port).into()))
+    }
+
+    #[cfg(feature = "api")]
+    pub fn with_api(mut self, api: ApiOpts) -> Self {
+        self.api = Some(api);
+        self
+    }
+
+    pub fn opts(self) -> Result<ServerOpts> {
+        let mut transports = self.transports;
+        let transport: BTransport = match transports.len() {
+            0 => return Err(anyhow!("a server needs a transport")),
+            1 => transports.remove(0),
+            _ => Box::new(MultiTransport::new(transports)?),
+        };
+        let id = self.id.unwrap_or_else(|| transport.addr().to_string());
+
+        let mut opts = ServerOpts::new(id, transport);
+        opts.private_key = self.private_key;
+        opts.block_time = self.block_time;
+        if let Some(produce_empty_blocks) = self.produce_empty_blocks {
+            opts.produce_empty_blocks = produce_empty_blocks;
+        }
+        opts.connection_opts = self.connection_opts;
+        opts.pruning = self.pruning;
+        opts.mode = self.mode;
+        opts.checkpoints = self.checkpoints;
+        opts.genesis = self.genesis;
+        opts.consensus = self.consensus;
+        opts.clock = self.clock;
+        opts.port_mapping = self.port_mapping;
+        #[cfg(feature = "api")]
+        {
+            opts.api = self.api;
+        }
+        if self.go_wire_format {
+            use_go_wire_format(&mut opts);
+        }
+        Ok(opts)
+    }
+
+    pub async fn build(self) -> Result<Server> {
+        Server::new(self.opts()?).await
+    }
+
+    pub async fn start(self) -> Result<RunningServer> {
+        let mut server = self.build().await?;
+        let running = RunningServer {
+            handle: server.handle(),
+            task: tokio::task::spawn(async move { server.start().await }),
+        };
+        Ok(running)
+    }
+}
+
+pub struct RunningServer {
+    handle: ServerHandle,
+    task: JoinHandle<Result<()>>,
+}
+
+impl RunningServer {
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    pub async fn height(&self) -> Result<u32> {
+        self.handle.get_height().await
+    }
+
+    // Adds the transaction to the mem_pool and broadcasts it, fails if it's invalid
+    pub async fn submit_tx(&self, tx: Transaction) -> Result<()> {
+        self.handle.submit_transaction(tx).await
+    }
+
+    // Stops the server and waits until it is shut down, returns the error the server stopped with
+    pub async fn shutdown(self) -> Result<()> {
+        self.handle.shutdown().await;
+        self.task.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        network::{LocalTransport, Message, MessageType, Transport},
+        test_utils::random_tx,
+    };
+
+    #[tokio::test]
+    async fn test_builder_needs_a_transport() {
+        assert!(Server::builder().build().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_transports() -> Result<()> {
+        let lan = LocalTransport::new("LAN".into());
+        let wan = LocalTransport::new("WAN".into());
+        let b = LocalTransport::new("B".into());
+        let c = LocalTransport::new("C".into());
+        for (local, peer) in [(&lan, &b), (&wan, &c)] {
+            local.connect(Box::new(peer.clone())).await?;
+            peer.connect(Box::new(local.clone())).await?;
+        }
+
+        let node = Server::builder()
+            .with_transport(Box::new(lan.clone()))
+            .with_transport(Box::new(wan.clone()))
+            .start()
+            .await?;
+
+        // the peers of both transports are asked for their status at the start
+        for peer in [&b, &c] {
+            let rpc = time::timeout(Duration::from_secs(1), peer.recv())
+                .await?
+                .unwrap();
+            assert_eq!(
+                Message::from_payload(&rpc.payload)?.header,
+                MessageType::GetStatus
+            );
+        }
+
+        // an answer goes through the transport the request came in on, C also gets asked for its status as a new
+        // inbound peer
+        let get_status = Message::new(MessageType::GetStatus, vec![]).bytes()?;
+        c.send_message(&wan.addr(), get_status).await?;
+        time::timeout(Duration::from_secs(1), async {
+            loop {
+                let rpc = c.recv().await.unwrap();
+                assert_eq!(rpc.from, wan.addr());
+                if Message::from_payload(&rpc.payload)?.header == MessageType::Status {
+                    return Ok::<_, anyhow::Error>(());
+                }
+            }
+        })
+        .await??;
+
+        time::timeout(Duration::from_secs(1), node.shutdown()).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_running_server() -> Result<()> {
+        let node = Server::builder()
+            .with_transport(Box::new(LocalTransport::new("A".into())))
+            .with_validator_key(PrivateKey::generate())
+            .with_block_time(Duration::from_millis(50))
+            .start()
+            .await?;
+
+        node.submit_tx(random_tx()).await?;
+        assert!(node.submit_tx(Transaction::new(vec![1])).await.is_err());
+
+        time::timeout(Duration::from_secs(2), async {
+            while node.height().await? < 2 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+
+        time::timeout(Duration::from_secs(1), node.shutdown()).await??;
+        Ok(())
+    }
+}
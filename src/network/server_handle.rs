@@ -0,0 +1,248 @@
+/*
+A ServerHandle drives a running Server from the application embedding it or from a test, without going through a
+transport. Every request is a ServerCommand sent to the server loop, which answers on a oneshot channel, so the
+handle never touches the state of the server itself.
+*/
+
+use anyhow::{anyhow, Result};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{DropCounts, MempoolAges, NetAddr, PeerId, PeerInfo, SyncStatus, TaskHealth};
+use crate::{
+    core::{NodeMode, Receipt, Transaction, TxStatus},
+    types::{Address, Hash},
+};
+
+// number of blocks NodeInfo::block_time_average is taken over
+pub const BLOCK_TIME_WINDOW: u32 = 100;
+
+#[derive(Debug)]
+pub enum ServerCommand {
+    // processed like a transaction received from a peer
+    SubmitTransaction(Box<Transaction>, oneshot::Sender<Result<()>>),
+    GetHeight(oneshot::Sender<u32>),
+    // the nonce the chain tip expects next from the address
+    GetNonce(Address, oneshot::Sender<u64>),
+    GetPeerCount(oneshot::Sender<usize>),
+    GetNodeInfo(oneshot::Sender<NodeInfo>),
+    GetPeers(oneshot::Sender<Vec<PeerInfo>>),
+    // dials the address, the peer isn't re-dialed once it disconnects
+    AddPeer(NetAddr, oneshot::Sender<Result<()>>),
+    RemovePeer(NetAddr, oneshot::Sender<Result<()>>),
+    // the transactions of the mem_pool, the oldest first
+    GetMempool(oneshot::Sender<Vec<Transaction>>),
+    // the transactions of the mem_pool grouped by sender, see TxPool::pool_content
+    GetPoolContent(oneshot::Sender<BTreeMap<Address, Vec<Transaction>>>),
+    // a fee to be included within the number of blocks, see TxPool::estimate_fee
+    EstimateFee(u32, oneshot::Sender<u64>),
+    // whether the transaction is pending, dropped or in a block and since when
+    GetTxStatus(Hash, oneshot::Sender<TxStatus>),
+    // the receipt of a transaction in a block
+    GetReceipt(Hash, oneshot::Sender<Option<Receipt>>),
+    GetSyncStatus(oneshot::Sender<SyncStatus>),
+    // the balance of the address after the block at the height, after the chain tip if None
+    GetBalance(Address, Option<u32>, oneshot::Sender<Result<u64>>),
+    // the value of a key of the contract data, see GetBalance for the height
+    GetStorage(
+        Address,
+        Vec<u8>,
+        Option<u32>,
+        oneshot::Sender<Result<Option<Vec<u8>>>>,
+    ),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: String,
+    pub peer_id: PeerId,
+    pub protocol_version: u32,
+    pub height: u32,
+    pub head: Hash,
+    pub validator: bool,
+    // where peers and the router say we can be reached
+    pub external_addr: Option<NetAddr>,
+    pub peer_count: usize,
+    pub mempool_size: usize,
+    // how long the pooled transactions have been waiting, None for an empty mem_pool
+    pub mempool_ages: Option<MempoolAges>,
+    // number of blocks we are behind the highest peer
+    pub chain_lag: u32,
+    // the average time between the newest BLOCK_TIME_WINDOW blocks, None until there are two of them
+    pub block_time_average: Option<Duration>,
+    pub mode: NodeMode,
+    // the lowest block whose transactions can be looked up, above 0 on pruned nodes
+    pub lowest_block: u32,
+    // received messages dropped because their queue was full
+    pub dropped_messages: DropCounts,
+    // the background tasks by name
+    pub tasks: BTreeMap<String, TaskHealth>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    commands: mpsc::Sender<ServerCommand>,
+    quit: mpsc::Sender<()>,
+}
+
+impl ServerHandle {
+    pub fn new(commands: mpsc::Sender<ServerCommand>, quit: mpsc::Sender<()>) -> Self {
+        Self { commands, quit }
+    }
+
+    // Adds the transaction to the mem_pool and broadcasts it, fails if it's invalid. The error of a rejected
+    // transaction can be downcast to a TxRejection.
+    pub async fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        self.request(|result| ServerCommand::SubmitTransaction(Box::new(tx), result))
+            .await?
+    }
+
+    pub async fn get_height(&self) -> Result<u32> {
+        self.request(ServerCommand::GetHeight).await
+    }
+
+    pub async fn get_nonce(&self, address: Address) -> Result<u64> {
+        self.request(|result| ServerCommand::GetNonce(address, result))
+            .await
+    }
+
+    pub async fn get_peer_count(&self) -> Result<usize> {
+        self.request(ServerCommand::GetPeerCount).await
+    }
+
+    pub async fn get_node_info(&self) -> Result<NodeInfo> {
+        self.request(ServerCommand::GetNodeInfo).await
+    }
+
+    pub async fn get_peers(&self) -> Result<Vec<PeerInfo>> {
+        self.request(ServerCommand::GetPeers).await
+    }
+
+    pub async fn add_peer(&self, addr: NetAddr) -> Result<()> {
+        self.request(|result| ServerCommand::AddPeer(addr, result))
+            .await?
+    }
+
+    pub async fn remove_peer(&self, addr: NetAddr) -> Result<()> {
+        self.request(|result| ServerCommand::RemovePeer(addr, result))
+            .await?
+    }
+
+    pub async fn get_mempool(&self) -> Result<Vec<Transaction>> {
+        self.request(ServerCommand::GetMempool).await
+    }
+
+    pub async fn get_pool_content(&self) -> Result<BTreeMap<Address, Vec<Transaction>>> {
+        self.request(ServerCommand::GetPoolContent).await
+    }
+
+    pub async fn estimate_fee(&self, target_blocks: u32) -> Result<u64> {
+        self.request(|result| ServerCommand::EstimateFee(target_blocks, result))
+            .await
+    }
+
+    pub async fn get_tx_status(&self, hash: Hash) -> Result<TxStatus> {
+        self.request(|result| ServerCommand::GetTxStatus(hash, result))
+            .await
+    }
+
+    pub async fn get_receipt(&self, hash: Hash) -> Result<Option<Receipt>> {
+        self.request(|result| ServerCommand::GetReceipt(hash, result))
+            .await
+    }
+
+    pub async fn get_sync_status(&self) -> Result<SyncStatus> {
+        self.request(ServerCommand::GetSyncStatus).await
+    }
+
+    pub async fn get_balance(&self, address: Address, height: Option<u32>) -> Result<u64> {
+        self.request(|result| ServerCommand::GetBalance(address, height, result))
+            .await?
+    }
+
+    pub async fn get_storage(
+        &self,
+        contract: Address,
+        key: Vec<u8>,
+        height: Option<u32>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.request(|result| ServerCommand::GetStorage(contract, key, height, result))
+            .await?
+    }
+
+    // Makes the server stop, a server that already stopped is ignored
+    pub async fn shutdown(&self) {
+        let _ = self.quit.send(()).await;
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> ServerCommand,
+    ) -> Result<T> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.commands
+            .send(command(result_tx))
+            .await
+            .map_err(|_| anyhow!("the server is not running"))?;
+        result_rx
+            .await
+            .map_err(|_| anyhow!("the server stopped before answering"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        core::{RejectCode, TxRejection},
+        network::{BTransport, LocalTransport, Server},
+        test_utils::random_tx,
+    };
+
+    #[tokio::test]
+    async fn test_server_handle() -> Result<()> {
+        let tr_a: BTransport = Box::new(LocalTransport::new("A".into()));
+        let tr_b: BTransport = Box::new(LocalTransport::new("B".into()));
+        tr_a.connect(tr_b.clone()).await?;
+        tr_b.connect(tr_a.clone()).await?;
+
+        let mut b = Server::builder()
+            .with_transport(tr_b.clone())
+            .build()
+            .await?;
+        let handle = b.handle();
+        let server = tokio::task::spawn(async move { b.start().await });
+
+        assert_eq!(handle.get_height().await?, 0);
+        assert_eq!(handle.get_peer_count().await?, 0);
+
+        handle.submit_transaction(random_tx()).await?;
+        let err = handle
+            .submit_transaction(Transaction::new(vec![1]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TxRejection>().map(|r| r.code),
+            Some(RejectCode::InvalidSignature)
+        );
+
+        // A asks B for its status at the start and becomes its peer
+        let a = Server::builder().with_transport(tr_a).start().await?;
+        time::timeout(Duration::from_secs(2), async {
+            while handle.get_peer_count().await? == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await??;
+
+        handle.shutdown().await;
+        time::timeout(Duration::from_secs(1), server).await???;
+        assert!(handle.get_height().await.is_err());
+        a.shutdown().await
+    }
+}
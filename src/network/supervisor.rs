@@ -0,0 +1,249 @@
+/*
+TaskSupervisor runs the background tasks of a Server. Every task has a name, a task that fails or panics is logged
+and counted under its name instead of dying silently, the API reports the counts with the node_info.
+
+Critical loops, the transport readers and the validator loop, are restarted whenever they fail or return. The delay
+before a restart doubles from MIN_RESTART_DELAY up to MAX_RESTART_DELAY and starts over once a loop ran for longer
+than MAX_RESTART_DELAY. abort_all() stops every task when the server shuts down.
+*/
+
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{error, warn};
+
+pub const MIN_RESTART_DELAY: Duration = Duration::from_millis(100);
+pub const MAX_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+// What happened to the tasks with one name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskHealth {
+    pub running: usize,
+    pub failures: u64,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct TaskSupervisor {
+    id: String,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    health: BTreeMap<String, TaskHealth>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+// Aborts the task when the supervising task is aborted
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl TaskSupervisor {
+    // id is the server id used in the logs
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            ..Self::default()
+        }
+    }
+
+    // Runs the task once, an error or a panic is logged and recorded
+    pub fn spawn<F>(&self, name: &str, task: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.to_string();
+        self.started(&name);
+        let handle = tokio::task::spawn(async move {
+            let result = run(task).await;
+            supervisor.finished(&name, result);
+        });
+        self.track(handle);
+    }
+
+    // Runs the loop built by make_task and restarts it whenever it fails, panics or returns
+    pub fn spawn_critical<F, Fut>(&self, name: &str, make_task: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.to_string();
+        let handle = tokio::task::spawn(async move {
+            let mut delay = MIN_RESTART_DELAY;
+            loop {
+                supervisor.started(&name);
+                let start = Instant::now();
+                let result = run(make_task()).await;
+                supervisor.finished(&name, result.and(Err(anyhow!("stopped"))));
+
+                if start.elapsed() > MAX_RESTART_DELAY {
+                    delay = MIN_RESTART_DELAY;
+                }
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+                warn!("ID={} restarting {}", supervisor.id, name);
+                supervisor
+                    .lock()
+                    .health
+                    .entry(name.clone())
+                    .or_default()
+                    .restarts += 1;
+            }
+        });
+        self.track(handle);
+    }
+
+    // The health of the tasks by name
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        self.lock().health.clone()
+    }
+
+    // Stops every task, the health is kept
+    pub fn abort_all(&self) {
+        for handle in self.lock().handles.drain(..) {
+            handle.abort();
+        }
+    }
+
+    fn started(&self, name: &str) {
+        self.lock()
+            .health
+            .entry(name.to_string())
+            .or_default()
+            .running += 1;
+    }
+
+    fn finished(&self, name: &str, result: Result<()>) {
+        let mut inner = self.lock();
+        let health = inner.health.entry(name.to_string()).or_default();
+        health.running = health.running.saturating_sub(1);
+        if let Err(err) = result {
+            error!("ID={} task {} failed: {}", self.id, name, err);
+            health.failures += 1;
+            health.last_error = Some(err.to_string());
+        }
+    }
+
+    fn track(&self, handle: JoinHandle<()>) {
+        let mut inner = self.lock();
+        inner.handles.retain(|handle| !handle.is_finished());
+        inner.handles.push(handle);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("task supervisor lock poisoned")
+    }
+}
+
+// Runs the task in its own tokio task, so a panic is returned as an error
+async fn run<F>(task: F) -> Result<()>
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut task = AbortOnDrop(tokio::task::spawn(task));
+    match (&mut task.0).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => Err(anyhow!("panicked: {}", panic_message(err.into_panic()))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    async fn wait_for(
+        supervisor: &TaskSupervisor,
+        name: &str,
+        done: impl Fn(&TaskHealth) -> bool,
+    ) -> Result<TaskHealth> {
+        time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(health) = supervisor.health().get(name).filter(|h| done(h)) {
+                    return health.clone();
+                }
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("{name} didn't get there"))
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_recorded() -> Result<()> {
+        let supervisor = TaskSupervisor::new("A");
+        supervisor.spawn("ok", async { Ok(()) });
+        supervisor.spawn("error", async { Err(anyhow!("no peer")) });
+        supervisor.spawn("panic", async { panic!("boom") });
+
+        let ok = wait_for(&supervisor, "ok", |h| h.running == 0).await?;
+        assert_eq!(ok.failures, 0);
+        let error = wait_for(&supervisor, "error", |h| h.failures == 1).await?;
+        assert_eq!(error.last_error.as_deref(), Some("no peer"));
+        let panic = wait_for(&supervisor, "panic", |h| h.failures == 1).await?;
+        assert_eq!(panic.last_error.as_deref(), Some("panicked: boom"));
+        assert_eq!(panic.running, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_critical_loops_are_restarted() -> Result<()> {
+        let supervisor = TaskSupervisor::new("A");
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        // fails twice, then keeps running
+        let counter = runs.clone();
+        supervisor.spawn_critical("reader", move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run < 2 {
+                    return Err(anyhow!("transport closed"));
+                }
+                std::future::pending().await
+            }
+        });
+
+        let health = wait_for(&supervisor, "reader", |h| h.restarts == 2 && h.running == 1).await?;
+        assert_eq!(health.failures, 2);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        supervisor.abort_all();
+        time::sleep(MIN_RESTART_DELAY * 3).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+/*
+A node syncs from a peer once the peer's status or announcements tell it the peer is ahead: it asks for the missing
+blocks range by range until it reached the height the peer told it, see Server::process_status_message. SyncTargets
+holds that height for every peer we're syncing from. It's shared with the validator loop, which doesn't produce
+blocks on top of a chain it knows is behind, and with the peer events, a peer that disconnects is no target anymore.
+*/
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use super::NetAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    Synced,
+    // catching up from height current to target, with peers peers sending blocks
+    Syncing {
+        current: u32,
+        target: u32,
+        peers: usize,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncTargets(Arc<Mutex<HashMap<NetAddr, u32>>>);
+
+impl SyncTargets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, peer: NetAddr, target: u32) {
+        self.0.lock().unwrap().insert(peer, target);
+    }
+
+    pub fn remove(&self, peer: &NetAddr) {
+        self.0.lock().unwrap().remove(peer);
+    }
+
+    pub fn get(&self, peer: &NetAddr) -> Option<u32> {
+        self.0.lock().unwrap().get(peer).copied()
+    }
+
+    pub fn contains(&self, peer: &NetAddr) -> bool {
+        self.0.lock().unwrap().contains_key(peer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    // Syncing as long as one of the targets is above current, a target we reached by other peers doesn't count
+    pub fn status(&self, current: u32) -> SyncStatus {
+        let targets = self.0.lock().unwrap();
+        let ahead = targets.values().filter(|target| **target > current);
+        match ahead.clone().max() {
+            Some(&target) => SyncStatus::Syncing {
+                current,
+                target,
+                peers: ahead.count(),
+            },
+            None => SyncStatus::Synced,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_status() {
+        let targets = SyncTargets::new();
+        assert_eq!(targets.status(5), SyncStatus::Synced);
+
+        targets.insert("A".into(), 10);
+        targets.insert("B".into(), 12);
+        targets.insert("C".into(), 4);
+        assert_eq!(
+            targets.status(5),
+            SyncStatus::Syncing {
+                current: 5,
+                target: 12,
+                peers: 2,
+            }
+        );
+        assert_eq!(
+            targets.status(11),
+            SyncStatus::Syncing {
+                current: 11,
+                target: 12,
+                peers: 1,
+            }
+        );
+
+        targets.remove(&"B".into());
+        assert_eq!(targets.status(11), SyncStatus::Synced);
+    }
+}
@@ -3,9 +3,10 @@ In this file we define the Transport trait. This trait is used to abstract away
 The transport layer is responsible for sending and receiving messages. It is also responsible for connecting to other peers.
 */
 
-use super::RPC;
-use anyhow::Result;
+use super::{NetAddr, RPC};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
+use bytes::Bytes;
 use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use tokio::sync::{mpsc, Mutex, RwLock};
 
@@ -18,7 +19,9 @@ pub fn new_channel<T>(buffer_size: usize) -> Channel<T> {
     (tx, Arc::new(Mutex::new(rx)))
 }
 
-pub type NetAddr = String;
+// Payloads are shared between all the peers they're sent to instead of copied for each of them,
+// cloning and slicing one doesn't copy the bytes
+pub type Payload = Bytes;
 
 // Be very careful with rwlock, write can lock the whole program
 pub type BTransport = Box<dyn Transport>;
@@ -28,10 +31,80 @@ pub trait Transport: TransportClone + Send + Sync + Debug {
     fn consume(&self) -> Channel<RPC>;
     async fn recv(&self) -> Option<RPC>;
     async fn connect(&self, tr: Box<dyn Transport>) -> Result<()>;
-    async fn send_message(&self, to: &NetAddr, payload: Vec<u8>) -> Result<()>;
-    async fn broadcast(&self, payload: Vec<u8>) -> Result<()>;
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()>;
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()>;
+    async fn broadcast(&self, payload: Payload) -> Result<()>;
     async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>>;
     fn addr(&self) -> NetAddr;
+
+    // Every address the transport listens on, see MultiTransport
+    fn addrs(&self) -> Vec<NetAddr> {
+        vec![self.addr()]
+    }
+
+    // Sends to every peer but except, e.g. the one a relayed message came from
+    async fn broadcast_except(&self, payload: Payload, except: &NetAddr) -> Result<()> {
+        for addr in self.peers().await.into_keys().filter(|addr| addr != except) {
+            self.send_message(&addr, payload.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+// A peer only known by its address, e.g. a bootnode. Transports that connect by address, like the udp and quic
+// transports, can dial it. It can't send or receive anything itself.
+#[derive(Debug, Clone)]
+pub struct RemotePeer {
+    addr: NetAddr,
+    consume_channel: Channel<RPC>,
+}
+
+impl RemotePeer {
+    pub fn new(addr: NetAddr) -> Self {
+        Self {
+            addr,
+            consume_channel: new_channel(1),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for RemotePeer {
+    fn consume(&self) -> Channel<RPC> {
+        self.consume_channel.clone()
+    }
+
+    async fn recv(&self) -> Option<RPC> {
+        None
+    }
+
+    async fn connect(&self, tr: Box<dyn Transport>) -> Result<()> {
+        Err(anyhow!(
+            "remote peer {} can't connect to {}",
+            self.addr,
+            tr.addr()
+        ))
+    }
+
+    async fn disconnect(&self, _addr: &NetAddr) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send_message(&self, to: &NetAddr, _payload: Payload) -> Result<()> {
+        Err(anyhow!("remote peer {} can't send to {}", self.addr, to))
+    }
+
+    async fn broadcast(&self, _payload: Payload) -> Result<()> {
+        Err(anyhow!("remote peer {} can't broadcast", self.addr))
+    }
+
+    async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>> {
+        HashMap::new()
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.addr.clone()
+    }
 }
 
 pub trait TransportClone {
@@ -0,0 +1,60 @@
+/*
+Transactions are gossiped in batches. A transaction entering the mem_pool is queued instead of being broadcast on its
+own, the batch loop of the Server collects the queued transactions for batch_interval and broadcasts them in one
+TxBatchMessage. Under load this sends one message per window instead of one per transaction and peer.
+*/
+
+use std::time::Duration;
+
+use tokio::{sync::mpsc, time};
+
+use crate::core::Transaction;
+
+pub const DEFAULT_TX_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+// a full batch is sent right away, a received batch with more transactions is rejected
+pub const MAX_TX_BATCH_SIZE: usize = 256;
+
+// Waits for a transaction and collects the ones arriving within window after it, at most max_len of them.
+// None once the channel is closed and empty.
+pub async fn next_tx_batch(
+    rx: &mut mpsc::Receiver<Transaction>,
+    window: Duration,
+    max_len: usize,
+) -> Option<Vec<Transaction>> {
+    let mut batch = vec![rx.recv().await?];
+    let deadline = time::Instant::now() + window;
+    while batch.len() < max_len {
+        match time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(tx)) => batch.push(tx),
+            // the window passed or the channel was closed
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::random_tx;
+
+    #[tokio::test]
+    async fn test_next_tx_batch() {
+        let (tx, mut rx) = mpsc::channel(16);
+        for _ in 0..5 {
+            tx.send(random_tx()).await.unwrap();
+        }
+
+        // a full batch doesn't wait for the window
+        let window = Duration::from_secs(10);
+        let batch = time::timeout(Duration::from_secs(1), next_tx_batch(&mut rx, window, 3)).await;
+        assert_eq!(batch.unwrap().unwrap().len(), 3);
+
+        // the rest is sent once the window passed
+        let window = Duration::from_millis(20);
+        assert_eq!(next_tx_batch(&mut rx, window, 3).await.unwrap().len(), 2);
+
+        drop(tx);
+        assert!(next_tx_batch(&mut rx, window, 3).await.is_none());
+    }
+}
@@ -1,10 +1,41 @@
 use crate::{
-    core::{Transaction, TxHasher},
-    types::Hash,
+    core::{
+        BClock, Block, IncludedTxs, RejectCode, SigCache, State, SystemClock, Transaction,
+        TxHasher, TxKind, TxRejection, TxStatus,
+    },
+    types::{Address, Hash},
 };
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+};
+use tracing::debug;
+
+use super::{mempool_ages, MempoolAges};
+
+// number of recent blocks estimate_fee looks at
+pub const FEE_HISTORY_BLOCKS: usize = 20;
+
+// How much of the pool a single sender can fill. A sender at its limit makes room for a new transaction by
+// dropping its own transactions with the lowest fees, see TxPool::make_room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderLimits {
+    pub max_txs: usize,
+    // bytes of transaction data
+    pub max_bytes: usize,
+}
 
+impl Default for SenderLimits {
+    fn default() -> Self {
+        Self {
+            max_txs: 16,
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+// Orders transactions by their arrival in the pool, see TxPool::add
 pub struct TxMapSorter<'a> {
     transactions: Vec<&'a Transaction>,
 }
@@ -21,14 +52,14 @@ impl<'a> TxMapSorter<'a> {
     }
 
     pub fn sort(&mut self) {
-        self.transactions.sort_by_key(|a| a.first_seen());
+        self.transactions.sort_by_key(|a| a.sequence());
     }
 
     pub fn len(&self) -> usize {
         self.transactions.len()
     }
     pub fn less(&self, i: usize, j: usize) -> bool {
-        self.transactions[i].first_seen() < self.transactions[j].first_seen()
+        self.transactions[i].sequence() < self.transactions[j].sequence()
     }
 
     pub fn swap(&mut self, i: usize, j: usize) {
@@ -36,62 +67,520 @@ impl<'a> TxMapSorter<'a> {
     }
 }
 
+// Signed transactions are pending once the sender can send them, their nonce follows the account nonce without a
+// gap. The ones with a nonce further ahead are queued until the gap is filled, see reorganize. Only pending
+// transactions are put into blocks.
 pub struct TxPool {
     all: HashMap<Hash, Transaction>,
     pending: HashMap<Hash, Transaction>,
+    // the transaction of every sender and nonce, signed transactions with the same sender and nonce
+    // replace each other, see replace
+    by_nonce: HashMap<(Address, u64), Hash>,
+    // the pooled transactions of every sender
+    by_sender: HashMap<Address, HashSet<Hash>>,
+    // the nonce the chain expects next from the senders with pooled transactions, 0 if it isn't known
+    account_nonces: HashMap<Address, u64>,
     max_length: usize,
+    sender_limits: SenderLimits,
+    // why transactions left the pool without being mined, the oldest entries are forgotten
+    // once more than max_length transactions got dropped
+    dropped: HashMap<Hash, String>,
+    dropped_order: VecDeque<Hash>,
+    // first_seen of received transactions
+    clock: BClock,
+    // the sequence of the next transaction taken in, transactions are ordered by arrival with it
+    next_sequence: u64,
+    // verified signatures of the pooled transactions, shared with the Blockchain
+    sig_cache: SigCache,
+    // the transactions on the chain, shared with the Blockchain
+    included: IncludedTxs,
+    // the fees of the transactions of the last FEE_HISTORY_BLOCKS blocks, the oldest first
+    fee_history: VecDeque<Vec<u64>>,
 }
 
 impl TxPool {
     pub fn new(max_length: usize) -> Self {
+        Self::with_clock(max_length, SystemClock::shared())
+    }
+
+    pub fn with_clock(max_length: usize, clock: BClock) -> Self {
         Self {
             all: HashMap::new(),
             pending: HashMap::new(),
+            by_nonce: HashMap::new(),
+            by_sender: HashMap::new(),
+            account_nonces: HashMap::new(),
             max_length,
+            sender_limits: SenderLimits::default(),
+            dropped: HashMap::new(),
+            dropped_order: VecDeque::new(),
+            clock,
+            next_sequence: 0,
+            sig_cache: SigCache::default(),
+            included: IncludedTxs::new(),
+            fee_history: VecDeque::new(),
         }
     }
+
+    pub fn set_sig_cache(&mut self, sig_cache: SigCache) {
+        self.sig_cache = sig_cache;
+    }
+
+    // Transactions of the set are rejected, see Blockchain::included_txs
+    pub fn set_included_txs(&mut self, included: IncludedTxs) {
+        self.included = included;
+    }
+
+    pub fn set_sender_limits(&mut self, sender_limits: SenderLimits) {
+        self.sender_limits = sender_limits;
+    }
+
+    // Verifies the transaction, its signature is only checked once while it's in the pool
+    pub fn verify(&self, tx: &Transaction) -> Result<()> {
+        self.sig_cache
+            .verify(tx)
+            .map_err(|err| TxRejection::new(RejectCode::InvalidSignature, err.to_string()).into())
+    }
     pub fn len(&self) -> usize {
         self.all.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty()
+    }
     pub fn pending_count(&self) -> usize {
         self.pending.len()
     }
 
+    // Transactions waiting for a transaction with a lower nonce of their sender
+    pub fn queued_count(&self) -> usize {
+        self.all
+            .keys()
+            .filter(|hash| !self.pending.contains_key(hash))
+            .count()
+    }
+
+    // Size of the transaction data of all pending transactions
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.values().map(|tx| tx.data.len()).sum()
+    }
+
     pub fn clear_pending(&mut self) {
         self.pending.clear()
     }
 
-    // Add a transaction to the pool, the caller is responsible for checking if the transaction already exists
+    // Add a transaction to the pool, the caller is responsible for checking if the transaction already exists.
+    // A transaction with the sender and nonce of a pooled one has to replace it, see replace.
     pub fn add(&mut self, mut tx: Transaction) -> Result<()> {
         if !tx.has_cached_hash() {
             tx.calculate_and_cache_hash(Box::new(TxHasher))?;
         }
+        // a mined transaction can't be mined again
+        if self.included.contains(&tx.hash()) {
+            let message = format!("tx {} is on the chain already", tx.hash());
+            return Err(TxRejection::new(RejectCode::AlreadyIncluded, message).into());
+        }
+        if self.replaced_by(&tx).is_some() {
+            return self.replace(tx);
+        }
+        if let Some((sender, nonce)) = nonce_key(&tx) {
+            let account_nonce = self.account_nonce(&sender);
+            if nonce < account_nonce {
+                let message = format!(
+                    "tx {} has the nonce {nonce}, the next one of {sender} is {account_nonce}",
+                    tx.hash()
+                );
+                return Err(TxRejection::new(RejectCode::NonceTooLow, message).into());
+            }
+        }
+        if !self.has(&tx.hash()) {
+            self.make_room(&tx)?;
+        }
 
         if self.all.len() == self.max_length {
-            let oldest_hash = self
+            let oldest = self
                 .all()
                 .first()
-                .ok_or_else(|| anyhow!("could not find first block in all transactions"))?
-                .hash();
-            self.all.remove(&oldest_hash);
+                .copied()
+                .cloned()
+                .ok_or_else(|| anyhow!("could not find first block in all transactions"))?;
+            self.remove(&oldest.hash());
+            self.mark_dropped(oldest.hash(), "evicted from full mem_pool");
+            if let Some((sender, _)) = nonce_key(&oldest) {
+                self.reorganize(sender);
+            }
         }
 
         let tx_hash = tx.hash();
+        self.dropped.remove(&tx_hash);
 
         if !self.has(&tx_hash) {
-            self.all.insert(tx_hash, tx.clone());
-            self.pending.insert(tx_hash, tx);
+            tx.set_sequence(self.next_sequence);
+            self.next_sequence += 1;
+            match nonce_key(&tx) {
+                Some(key) => {
+                    self.by_nonce.insert(key, tx_hash);
+                    self.by_sender.entry(key.0).or_default().insert(tx_hash);
+                    self.all.insert(tx_hash, tx);
+                    self.reorganize(key.0);
+                }
+                // unsigned transactions have no nonce to wait for
+                None => {
+                    self.all.insert(tx_hash, tx.clone());
+                    self.pending.insert(tx_hash, tx);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Replaces the pooled transaction with the sender and nonce of tx, tx has to pay a higher fee.
+    // The replaced transaction is dropped.
+    pub fn replace(&mut self, mut tx: Transaction) -> Result<()> {
+        if !tx.has_cached_hash() {
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        }
+        let old_hash = self
+            .replaced_by(&tx)
+            .ok_or_else(|| anyhow!("tx {} doesn't replace a pooled transaction", tx.hash()))?;
+
+        let old_fee = self.all[&old_hash].fee;
+        if tx.fee <= old_fee {
+            let message = format!(
+                "tx {} with fee {} can't replace tx {}, the fee has to be higher than {}",
+                tx.hash(),
+                tx.fee,
+                old_hash,
+                old_fee
+            );
+            return Err(TxRejection::new(RejectCode::FeeTooLow, message).into());
         }
 
+        // checked before the replaced transaction is removed, so a rejected replacement leaves it in the pool
+        if self.included.contains(&tx.hash()) {
+            let message = format!("tx {} is on the chain already", tx.hash());
+            return Err(TxRejection::new(RejectCode::AlreadyIncluded, message).into());
+        }
+        self.room_for(&tx, Some(old_hash))?;
+
+        debug!("tx {} replaces tx {}", tx.hash(), old_hash);
+        self.remove(&old_hash);
+        self.mark_dropped(old_hash, &format!("replaced by tx {}", tx.hash()));
+        self.add(tx)
+    }
+
+    // Drops the transactions of the sender of tx with the lowest fees until tx fits into the SenderLimits. Fails
+    // without dropping anything if tx would have to pay more than one of them.
+    fn make_room(&mut self, tx: &Transaction) -> Result<()> {
+        for hash in self.room_for(tx, None)? {
+            debug!("tx {} evicts tx {} of the same sender", tx.hash(), hash);
+            self.remove(&hash);
+            self.mark_dropped(
+                hash,
+                &format!("evicted by tx {} of the same sender", tx.hash()),
+            );
+        }
         Ok(())
     }
 
+    // The transactions make_room drops for tx, without dropping them. The transaction except doesn't count
+    // towards the limits, e.g. the one tx replaces.
+    fn room_for(&self, tx: &Transaction, except: Option<Hash>) -> Result<Vec<Hash>> {
+        let Some((sender, _)) = nonce_key(tx) else {
+            return Ok(vec![]);
+        };
+        let limits = self.sender_limits;
+        if tx.data.len() > limits.max_bytes {
+            let message = format!(
+                "tx {} has {} bytes, a sender can have {} bytes pending",
+                tx.hash(),
+                tx.data.len(),
+                limits.max_bytes
+            );
+            return Err(TxRejection::new(RejectCode::SenderLimit, message).into());
+        }
+
+        let mut pooled: Vec<&Transaction> = self
+            .by_sender
+            .get(&sender)
+            .into_iter()
+            .flatten()
+            .filter(|hash| Some(**hash) != except)
+            .map(|hash| &self.all[hash])
+            .collect();
+        // the lowest fee first, of equal fees the newest
+        pooled.sort_by_key(|tx| (tx.fee, Reverse(tx.sequence())));
+
+        let pooled_count = pooled.len();
+        let pooled_bytes: usize = pooled.iter().map(|tx| tx.data.len()).sum();
+        let mut count = pooled_count + 1;
+        let mut bytes = pooled_bytes + tx.data.len();
+        let mut evicted = vec![];
+        for old in pooled {
+            if count <= limits.max_txs && bytes <= limits.max_bytes {
+                break;
+            }
+            if old.fee >= tx.fee {
+                let message = format!(
+                    "{sender} has {} pending transactions with {pooled_bytes} bytes, tx {} has to pay more than {}",
+                    pooled_count,
+                    tx.hash(),
+                    old.fee
+                );
+                return Err(TxRejection::new(RejectCode::SenderLimit, message).into());
+            }
+            count -= 1;
+            bytes -= old.data.len();
+            evicted.push(old.hash());
+        }
+        Ok(evicted)
+    }
+
+    // The pooled transaction with the sender and nonce of tx, unless it's tx itself
+    fn replaced_by(&self, tx: &Transaction) -> Option<Hash> {
+        let key = nonce_key(tx)?;
+        self.by_nonce
+            .get(&key)
+            .copied()
+            .filter(|hash| *hash != tx.hash())
+    }
+
+    // Adds a transaction that was just received, first_seen is set to the current time
+    pub fn add_received(&mut self, mut tx: Transaction) -> Result<()> {
+        tx.set_first_seen(self.clock.unix_nanos());
+        self.add(tx)
+    }
+
+    // Removes the given transactions from the pool, e.g. because they got included in a block. Transactions
+    // following a removed one of their sender are queued again.
+    pub fn remove_batch(&mut self, hashes: &[Hash]) {
+        let mut senders = HashSet::new();
+        for hash in hashes {
+            if let Some((sender, _)) = self.all.get(hash).and_then(nonce_key) {
+                senders.insert(sender);
+            }
+            self.remove(hash);
+        }
+        for sender in senders {
+            self.reorganize(sender);
+        }
+    }
+
+    pub fn account_nonce(&self, sender: &Address) -> u64 {
+        self.account_nonces.get(sender).copied().unwrap_or_default()
+    }
+
+    // Sets the nonce the chain expects next from the sender, e.g. from the state of the chain tip before one of
+    // its transactions is added. Its transactions are pending or queued by the new nonce.
+    pub fn set_account_nonce(&mut self, sender: Address, nonce: u64) {
+        self.account_nonces.insert(sender, nonce);
+        // a sender without transactions keeps it for the one being added
+        if self.by_sender.contains_key(&sender) {
+            self.reorganize(sender);
+        }
+    }
+
+    // Sorts the transactions of the sender into pending and queued. From the account nonce on, the transactions
+    // without a gap between their nonces are pending and the others are queued. Transactions with a nonce below
+    // the account nonce can't be mined anymore and are dropped.
+    fn reorganize(&mut self, sender: Address) {
+        let Some(hashes) = self.by_sender.get(&sender) else {
+            self.account_nonces.remove(&sender);
+            return;
+        };
+        let mut txx: Vec<(u64, Hash)> = hashes
+            .iter()
+            .map(|hash| (self.all[hash].nonce, *hash))
+            .collect();
+        txx.sort_unstable();
+
+        let account_nonce = self.account_nonce(&sender);
+        let mut next = account_nonce;
+        let mut stale = vec![];
+        for (nonce, hash) in txx {
+            if nonce < account_nonce {
+                stale.push(hash);
+            } else if nonce == next {
+                next += 1;
+                if !self.pending.contains_key(&hash) {
+                    self.pending.insert(hash, self.all[&hash].clone());
+                }
+            } else {
+                self.pending.remove(&hash);
+            }
+        }
+
+        for hash in stale {
+            self.remove(&hash);
+            self.mark_dropped(hash, "the nonce is used by a transaction in a block");
+        }
+        if !self.by_sender.contains_key(&sender) {
+            self.account_nonces.remove(&sender);
+        }
+    }
+
+    // Records a block added to the chain: its fees for estimate_fee, and the nonces of its transactions, the
+    // queued transactions following them become pending
+    pub fn record_block(&mut self, block: &Block) {
+        self.fee_history
+            .push_back(block.transactions.iter().map(|tx| tx.fee).collect());
+        if self.fee_history.len() > FEE_HISTORY_BLOCKS {
+            self.fee_history.pop_front();
+        }
+
+        let mut senders = BTreeMap::new();
+        for (sender, nonce) in block.transactions.iter().filter_map(nonce_key) {
+            let next = senders.entry(sender).or_insert(0);
+            *next = (*next).max(nonce.saturating_add(1));
+        }
+        for (sender, next) in senders {
+            if self.by_sender.contains_key(&sender) {
+                let nonce = self.account_nonce(&sender).max(next);
+                self.set_account_nonce(sender, nonce);
+            }
+        }
+    }
+
+    // Suggests a fee for a transaction to be included within target_blocks blocks. It pays at least the lowest fee
+    // that got into the recent blocks, taken from more of them for a shorter target: the median of the lowest
+    // fees for one block, the lower quartile for two, and so on. Blocks hold as many transactions as the fullest
+    // recent one, if more pending transactions pay the fee than fit into target_blocks blocks it has to outbid them.
+    pub fn estimate_fee(&self, target_blocks: u32) -> u64 {
+        let target = target_blocks.max(1) as usize;
+
+        let mut floors: Vec<u64> = self
+            .fee_history
+            .iter()
+            .map(|fees| fees.iter().copied().min().unwrap_or_default())
+            .collect();
+        floors.sort_unstable();
+        let history_fee = match floors.len() {
+            0 => 0,
+            n => floors[(n - 1) / (2 * target)],
+        };
+
+        let capacity = self.fee_history.iter().map(Vec::len).max().unwrap_or(0);
+        let mut pending: Vec<u64> = self.pending.values().map(|tx| tx.fee).collect();
+        pending.sort_unstable_by(|a, b| b.cmp(a));
+        let backlog_fee = match capacity.checked_mul(target) {
+            // the pending transaction that just fits into the target has to be outbid
+            Some(slots) if slots > 0 && pending.len() >= slots => pending[slots - 1] + 1,
+            _ => 0,
+        };
+
+        history_fee.max(backlog_fee)
+    }
+
+    fn remove(&mut self, hash: &Hash) {
+        self.sig_cache.remove(&[*hash]);
+        if let Some(tx) = self.all.remove(hash) {
+            if let Some(key) = nonce_key(&tx) {
+                self.by_nonce.remove(&key);
+                if let Some(hashes) = self.by_sender.get_mut(&key.0) {
+                    hashes.remove(hash);
+                    if hashes.is_empty() {
+                        self.by_sender.remove(&key.0);
+                    }
+                }
+            }
+        }
+        self.pending.remove(hash);
+    }
+
+    // Updates the pool after a reorg replaced the dropped blocks with the adopted ones.
+    // Transactions of the adopted blocks are mined now and get removed, transactions that only
+    // were in the dropped blocks are returned to the pool if they are still valid against state, the
+    // state of the chain after the reorg, together with the re-injected transactions before them. An invalid
+    // transaction is skipped, the ones after it are still re-injected. Returns the number of re-injected
+    // transactions.
+    pub fn apply_reorg(
+        &mut self,
+        dropped: &[Block],
+        adopted: &[Block],
+        state: &State,
+    ) -> Result<usize> {
+        let mut mined = HashSet::new();
+        for tx in adopted.iter().flat_map(|b| &b.transactions) {
+            let mut tx = tx.clone();
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            mined.insert(tx.hash());
+        }
+
+        let mined_hashes: Vec<Hash> = mined.iter().copied().collect();
+        self.remove_batch(&mined_hashes);
+
+        // the re-injected transactions are applied to it, the ones after them can't spend the same balance
+        let mut scratch = state.snapshot();
+        let mut reinjected = 0;
+        for tx in dropped.iter().flat_map(|b| &b.transactions) {
+            let mut tx = tx.clone();
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            let hash = tx.hash();
+
+            // a transaction stays included until the chain unwinds its block, see Blockchain::unindex_block
+            if mined.contains(&hash) || self.has(&hash) || self.included.contains(&hash) {
+                continue;
+            }
+
+            let checked = self
+                .verify(&tx)
+                .and_then(|_| Ok(scratch.check_transaction(&tx, &hash)?));
+            if let Err(err) = checked {
+                debug!("not re-injecting tx {}: {}", hash, err);
+                continue;
+            }
+            // pending or queued by the nonce of the sender after the reorg
+            let sender = tx.from.as_ref().map(|from| from.address());
+            if let Some(sender) = sender {
+                self.set_account_nonce(sender, state.nonce(&sender));
+            }
+            if let Err(err) = self.add(tx.clone()) {
+                debug!("not re-injecting tx {}: {}", hash, err);
+                if let Some(sender) = sender {
+                    self.reorganize(sender);
+                }
+                continue;
+            }
+            apply_to(&mut scratch, &tx);
+            reinjected += 1;
+        }
+
+        Ok(reinjected)
+    }
+
     pub fn has(&self, hash: &Hash) -> bool {
         self.all.contains_key(hash)
     }
 
+    // Pending, Dropped or Unknown, mined transactions are tracked by the Blockchain
+    pub fn status(&self, hash: &Hash) -> TxStatus {
+        if self.has(hash) {
+            return TxStatus::Pending;
+        }
+        match self.dropped.get(hash) {
+            Some(reason) => TxStatus::Dropped {
+                reason: reason.clone(),
+            },
+            None => TxStatus::Unknown,
+        }
+    }
+
+    fn mark_dropped(&mut self, hash: Hash, reason: &str) {
+        if self.dropped.insert(hash, reason.to_string()).is_none() {
+            self.dropped_order.push_back(hash);
+        }
+        while self.dropped_order.len() > self.max_length {
+            if let Some(oldest) = self.dropped_order.pop_front() {
+                self.dropped.remove(&oldest);
+            }
+        }
+    }
+
     pub fn flush(&mut self) {
         self.all = HashMap::new();
+        self.by_nonce = HashMap::new();
+        self.by_sender = HashMap::new();
+        self.account_nonces = HashMap::new();
     }
     pub fn pending(&self) -> Vec<&Transaction> {
         let s = TxMapSorter::new(&self.pending);
@@ -113,13 +602,66 @@ impl TxPool {
         let s = TxMapSorter::new(&self.all);
         s.transactions.into_iter().cloned().collect()
     }
+
+    // How long the pooled transactions have been waiting since they were received, None for an empty pool
+    pub fn ages(&self) -> Option<MempoolAges> {
+        mempool_ages(
+            self.clock.unix_nanos(),
+            self.all.values().map(|tx| tx.first_seen()),
+        )
+    }
+
+    // The pooled transactions grouped by sender and ordered by nonce. Unsigned transactions have no
+    // sender and are left out.
+    pub fn pool_content(&self) -> BTreeMap<Address, Vec<Transaction>> {
+        let mut content: BTreeMap<Address, Vec<Transaction>> = BTreeMap::new();
+        for tx in self.all.values() {
+            if let Some((sender, _)) = nonce_key(tx) {
+                content.entry(sender).or_default().push(tx.clone());
+            }
+        }
+        for txx in content.values_mut() {
+            txx.sort_by_key(|tx| tx.nonce);
+        }
+        content
+    }
+}
+
+fn nonce_key(tx: &Transaction) -> Option<(Address, u64)> {
+    tx.from.as_ref().map(|from| (from.address(), tx.nonce))
+}
+
+// Applies tx to the accounts of its sender as the chain would: the nonce is used and the fee is paid, a failing
+// stake, transfer or unstake only costs the fee. Calls run in the VM, the pool leaves them out.
+fn apply_to(state: &mut State, tx: &Transaction) {
+    let Some(from) = &tx.from else {
+        return;
+    };
+    let sender = from.address();
+    state.bump_nonce(sender, tx.nonce);
+    if state.charge_fee(&sender, tx.fee, None).is_err() {
+        return;
+    }
+    let _ = match &tx.kind {
+        TxKind::Stake { amount } => state.stake(from, *amount),
+        TxKind::Transfer { to, amount } => state.transfer(&sender, *to, *amount),
+        TxKind::Unstake => state.unstake(from),
+        TxKind::Call | TxKind::Evidence(_) => Ok(()),
+    };
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{sync::Arc, time::Duration};
+
     use rand::{thread_rng, Rng};
 
     use super::*;
+    use crate::{
+        core::{Header, ManualClock, TxKind},
+        crypto::PrivateKey,
+        test_utils::random_tx,
+    };
 
     #[test]
     fn test_tx_pool() {
@@ -133,7 +675,7 @@ mod tests {
         let n = 10;
 
         for i in 1..n {
-            let tx = Transaction::random_with_signature();
+            let tx = random_tx();
 
             p.add(tx.clone())?;
             p.add(tx)?;
@@ -146,23 +688,541 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_remove_batch() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let mut hashes = vec![];
+
+        for _ in 0..5 {
+            let mut tx = random_tx();
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            hashes.push(tx.hash());
+            p.add(tx)?;
+        }
+
+        p.remove_batch(&hashes[..3]);
+        assert_eq!(p.len(), 2);
+        assert_eq!(p.pending_count(), 2);
+        assert!(!p.has(&hashes[0]));
+        assert!(p.has(&hashes[4]));
+
+        // unknown hashes are ignored
+        p.remove_batch(&[Hash::random()]);
+        assert_eq!(p.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> Result<()> {
+        let mut p = TxPool::new(2);
+        let mut hashes = vec![];
+
+        for _ in 0..3 {
+            let mut tx = random_tx();
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            hashes.push(tx.hash());
+            p.add(tx)?;
+        }
+
+        assert!(matches!(p.status(&hashes[0]), TxStatus::Dropped { .. }));
+        assert_eq!(p.status(&hashes[1]), TxStatus::Pending);
+        assert_eq!(p.status(&hashes[2]), TxStatus::Pending);
+        assert_eq!(p.status(&Hash::random()), TxStatus::Unknown);
+        assert_eq!(p.pending_count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_reorg() -> Result<()> {
+        let mut p = TxPool::new(10);
+
+        let both = random_tx();
+        let only_dropped = random_tx();
+        let mut invalid = random_tx();
+        invalid.data = vec![1, 2, 3];
+        let pending = random_tx();
+
+        let dropped = Block::new(
+            Header::default(),
+            vec![both.clone(), only_dropped.clone(), invalid],
+        );
+        let adopted = Block::new(Header::default(), vec![both.clone(), pending.clone()]);
+
+        p.add(pending.clone())?;
+        assert_eq!(p.apply_reorg(&[dropped], &[adopted], &State::new())?, 1);
+
+        let mut only_dropped = only_dropped;
+        only_dropped.calculate_and_cache_hash(Box::new(TxHasher))?;
+        assert!(p.has(&only_dropped.hash()));
+        assert_eq!(p.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_reorg_rechecks_state() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let key = PrivateKey::generate();
+        let sender = key.public_key().address();
+
+        // after the reorg the sender used nonce 0 and has a balance of 5
+        let mut state = State::new();
+        state.bump_nonce(sender, 0);
+        state.credit(sender, 5);
+
+        let used_nonce = signed_tx(&key, 0, 1)?;
+        let mut too_expensive = Transaction::new(vec![]);
+        too_expensive.nonce = 1;
+        too_expensive.kind = TxKind::Transfer {
+            to: Address::default(),
+            amount: 10,
+        };
+        too_expensive.sign(&key);
+        let valid = [signed_tx(&key, 1, 1)?, signed_tx(&key, 2, 1)?];
+
+        // the invalid transactions come first, the valid ones after them are still re-injected
+        let dropped = Block::new(
+            Header::default(),
+            vec![
+                used_nonce.clone(),
+                too_expensive.clone(),
+                valid[0].clone(),
+                valid[1].clone(),
+            ],
+        );
+        assert_eq!(p.apply_reorg(&[dropped], &[], &state)?, 2);
+
+        too_expensive.calculate_and_cache_hash(Box::new(TxHasher))?;
+        assert!(!p.has(&used_nonce.hash()));
+        assert!(!p.has(&too_expensive.hash()));
+        assert_eq!(p.pending_count(), 2);
+        assert_eq!(p.account_nonce(&sender), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_reorg_spends_the_balance_once() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let key = PrivateKey::generate();
+        let sender = key.public_key().address();
+        let mut state = State::new();
+        state.credit(sender, 10);
+
+        // each transfer is covered by the balance, both together aren't
+        let transfer = |nonce: u64| {
+            let mut tx = Transaction::new_kind(TxKind::Transfer {
+                to: Address::default(),
+                amount: 6,
+            });
+            tx.nonce = nonce;
+            tx.sign(&key);
+            tx
+        };
+        let dropped = Block::new(Header::default(), vec![transfer(0), transfer(1)]);
+        assert_eq!(p.apply_reorg(&[dropped], &[], &state)?, 1);
+        assert_eq!(p.pending_count(), 1);
+        assert_eq!(p.account_nonce(&sender), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_reorg_queues_by_the_chain_nonce() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let key = PrivateKey::generate();
+        let sender = key.public_key().address();
+        let mut state = State::new();
+        for nonce in 0..3 {
+            state.bump_nonce(sender, nonce);
+        }
+
+        // the sender is new to the pool, its nonce comes from the chain
+        let dropped = Block::new(Header::default(), vec![signed_tx(&key, 3, 0)?]);
+        assert_eq!(p.apply_reorg(&[dropped], &[], &state)?, 1);
+        assert_eq!(p.pending_count(), 1);
+        assert_eq!(p.account_nonce(&sender), 3);
+
+        Ok(())
+    }
+
+    fn signed_tx(key: &PrivateKey, nonce: u64, fee: u64) -> Result<Transaction> {
+        let mut tx = Transaction::new(thread_rng().gen::<[u8; 8]>().to_vec());
+        tx.nonce = nonce;
+        tx.fee = fee;
+        tx.sign(key);
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        Ok(tx)
+    }
+
+    #[test]
+    fn test_replace() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let key = PrivateKey::generate();
+
+        let first = signed_tx(&key, 0, 10)?;
+        p.add(first.clone())?;
+        p.add(signed_tx(&key, 1, 10)?)?;
+
+        // the same nonce needs a higher fee
+        let err = p.add(signed_tx(&key, 0, 10)?).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TxRejection>().map(|r| r.code),
+            Some(RejectCode::FeeTooLow)
+        );
+        assert!(p.replace(signed_tx(&key, 0, 9)?).is_err());
+        assert!(p.has(&first.hash()));
+
+        let second = signed_tx(&key, 0, 11)?;
+        p.add(second.clone())?;
+        assert!(!p.has(&first.hash()));
+        assert!(p.has(&second.hash()));
+        assert_eq!(p.len(), 2);
+        assert_eq!(p.pending_count(), 2);
+        assert!(
+            matches!(p.status(&first.hash()), TxStatus::Dropped { reason } if reason.contains("replaced"))
+        );
+
+        let third = signed_tx(&key, 0, 12)?;
+        p.replace(third.clone())?;
+        assert!(p.has(&third.hash()));
+        assert_eq!(p.len(), 2);
+
+        // another sender has its own nonces, a new nonce replaces nothing
+        p.add(signed_tx(&PrivateKey::generate(), 0, 1)?)?;
+        assert_eq!(p.len(), 3);
+        assert!(p.replace(signed_tx(&key, 2, 20)?).is_err());
+
+        // once mined, the nonce can be used by a new transaction
+        p.remove_batch(&[third.hash()]);
+        p.add(signed_tx(&key, 0, 1)?)?;
+        assert_eq!(p.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejected_replacement_keeps_the_original() -> Result<()> {
+        let mut p = TxPool::new(10);
+        p.set_sender_limits(SenderLimits {
+            max_txs: 2,
+            max_bytes: 20,
+        });
+        let key = PrivateKey::generate();
+        let first = signed_tx(&key, 0, 1)?;
+        p.add(first.clone())?;
+        p.add(signed_tx(&key, 1, 10)?)?;
+
+        let replacement = |len: usize| -> Result<Transaction> {
+            let mut tx = Transaction::new(vec![0; len]);
+            tx.fee = 2;
+            tx.sign(&key);
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            Ok(tx)
+        };
+        // more bytes than a sender can have, and more than fit next to the transaction with nonce 1
+        for len in [21, 13] {
+            let err = p.add(replacement(len)?).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<TxRejection>().map(|r| r.code),
+                Some(RejectCode::SenderLimit)
+            );
+            assert!(p.has(&first.hash()));
+            assert_eq!(p.status(&first.hash()), TxStatus::Pending);
+            assert_eq!(p.len(), 2);
+        }
+
+        // the 8 bytes of first don't count, a replacement of 12 bytes fits
+        let fits = replacement(12)?;
+        p.add(fits.clone())?;
+        assert!(p.has(&fits.hash()) && !p.has(&first.hash()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queued() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let key = PrivateKey::generate();
+        let sender = key.public_key().address();
+        let txx = (0..4)
+            .map(|nonce| signed_tx(&key, nonce, 1))
+            .collect::<Result<Vec<_>>>()?;
+
+        // a gap at nonce 1 queues the transactions after it
+        p.add(txx[0].clone())?;
+        p.add(txx[2].clone())?;
+        p.add(txx[3].clone())?;
+        assert_eq!(p.pending_count(), 1);
+        assert_eq!(p.queued_count(), 2);
+        assert_eq!(p.status(&txx[3].hash()), TxStatus::Pending);
+
+        p.add(txx[1].clone())?;
+        assert_eq!(p.pending_count(), 4);
+        assert_eq!(p.queued_count(), 0);
+
+        // nonces 0 and 1 are mined, a transaction with nonce 1 of the block doesn't need to be in the pool
+        let block = Block::new(Header::default(), vec![txx[0].clone(), txx[1].clone()]);
+        p.remove_batch(&[txx[0].hash()]);
+        assert_eq!(p.pending_count(), 0);
+        p.remove_batch(&[txx[1].hash()]);
+        p.record_block(&block);
+        assert_eq!(p.account_nonce(&sender), 2);
+        let pending: Vec<u64> = p.pending().iter().map(|tx| tx.nonce).collect();
+        assert_eq!(pending.len(), 2);
+        assert!(pending.contains(&2) && pending.contains(&3));
+
+        // lower nonces are rejected, the chain moving past pooled ones drops them
+        let err = p.add(signed_tx(&key, 1, 5)?).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TxRejection>().map(|r| r.code),
+            Some(RejectCode::NonceTooLow)
+        );
+        p.set_account_nonce(sender, 3);
+        assert!(!p.has(&txx[2].hash()));
+        assert!(matches!(p.status(&txx[2].hash()), TxStatus::Dropped { .. }));
+        assert_eq!(p.pending_count(), 1);
+
+        // the account nonce is only kept while the sender has pooled transactions
+        p.remove_batch(&[txx[3].hash()]);
+        assert_eq!(p.account_nonce(&sender), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sender_limits() -> Result<()> {
+        let mut p = TxPool::new(10);
+        p.set_sender_limits(SenderLimits {
+            max_txs: 2,
+            max_bytes: 20,
+        });
+        let key = PrivateKey::generate();
+
+        // signed_tx has 8 bytes of data
+        let low = signed_tx(&key, 0, 1)?;
+        let high = signed_tx(&key, 1, 5)?;
+        p.add(low.clone())?;
+        p.add(high.clone())?;
+
+        // a third transaction evicts the one with the lowest fee
+        let third = signed_tx(&key, 2, 3)?;
+        p.add(third.clone())?;
+        assert!(!p.has(&low.hash()));
+        assert!(p.has(&high.hash()) && p.has(&third.hash()));
+        assert!(
+            matches!(p.status(&low.hash()), TxStatus::Dropped { reason } if reason.contains("evicted"))
+        );
+
+        // one paying less than the pooled ones is rejected
+        let err = p.add(signed_tx(&key, 3, 3)?).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TxRejection>().map(|r| r.code),
+            Some(RejectCode::SenderLimit)
+        );
+        assert_eq!(p.len(), 2);
+
+        // too many bytes evict as well, a transaction can't have more than the limit on its own
+        let mut big = Transaction::new(vec![0; 13]);
+        big.nonce = 4;
+        big.fee = 10;
+        big.sign(&key);
+        p.add(big)?;
+        assert_eq!(p.len(), 1);
+        let mut huge = Transaction::new(vec![0; 21]);
+        huge.fee = 100;
+        huge.sign(&key);
+        assert!(p.add(huge).is_err());
+
+        // other senders have their own limits
+        let other = PrivateKey::generate();
+        p.add(signed_tx(&other, 0, 1)?)?;
+        p.add(signed_tx(&other, 1, 1)?)?;
+        assert_eq!(p.len(), 3);
+        assert_eq!(p.by_sender[&key.public_key().address()].len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_fee() -> Result<()> {
+        let mut p = TxPool::new(10);
+        assert_eq!(p.estimate_fee(1), 0);
+
+        let key = PrivateKey::generate();
+        for fees in [[5, 7], [2, 9], [8, 10]] {
+            let txx = fees
+                .iter()
+                .map(|fee| signed_tx(&key, 0, *fee))
+                .collect::<Result<_>>()?;
+            p.record_block(&Block::new(Header::default(), txx));
+        }
+        // the lowest fees of the blocks are 2, 5 and 8
+        assert_eq!(p.estimate_fee(1), 5);
+        assert_eq!(p.estimate_fee(2), 2);
+        assert_eq!(p.estimate_fee(0), p.estimate_fee(1));
+
+        // blocks hold two transactions, the pending ones fill two blocks
+        for (nonce, fee) in [20, 15, 6, 1].into_iter().enumerate() {
+            p.add(signed_tx(&key, nonce as u64, fee)?)?;
+        }
+        assert_eq!(p.estimate_fee(1), 16);
+        assert_eq!(p.estimate_fee(2), 2);
+        assert_eq!(p.estimate_fee(3), 2);
+
+        // only the recent blocks count, empty ones take any fee
+        for _ in 0..FEE_HISTORY_BLOCKS {
+            p.record_block(&Block::new(Header::default(), vec![]));
+        }
+        assert_eq!(p.fee_history.len(), FEE_HISTORY_BLOCKS);
+        assert_eq!(p.estimate_fee(1), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_included_txs() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let included = IncludedTxs::new();
+        p.set_included_txs(included.clone());
+
+        let mut tx = random_tx();
+        tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+        included.insert([tx.hash()]);
+        let err = p.add(tx.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TxRejection>().map(|r| r.code),
+            Some(RejectCode::AlreadyIncluded)
+        );
+        assert!(!p.has(&tx.hash()));
+
+        // unwound in a reorg, it can be mined again
+        included.remove(&[tx.hash()]);
+        p.add(tx)?;
+        assert_eq!(p.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sig_cache() -> Result<()> {
+        let cache = SigCache::new(10);
+        let mut p = TxPool::new(10);
+        p.set_sig_cache(cache.clone());
+
+        let tx = signed_tx(&PrivateKey::generate(), 0, 1)?;
+        p.verify(&tx)?;
+        p.add(tx.clone())?;
+        assert_eq!(cache.len(), 1);
+
+        // a transaction leaving the pool leaves the cache
+        p.remove_batch(&[tx.hash()]);
+        assert!(cache.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_content() -> Result<()> {
+        let mut p = TxPool::new(10);
+        let a = PrivateKey::generate();
+        let b = PrivateKey::generate();
+
+        for nonce in [2, 0, 1] {
+            p.add(signed_tx(&a, nonce, 1)?)?;
+        }
+        p.add(signed_tx(&b, 5, 1)?)?;
+        // unsigned transactions have no sender
+        p.add(Transaction::new(vec![4]))?;
+
+        let content = p.pool_content();
+        assert_eq!(content.len(), 2);
+        let nonces: Vec<u64> = content[&a.public_key().address()]
+            .iter()
+            .map(|tx| tx.nonce)
+            .collect();
+        assert_eq!(nonces, vec![0, 1, 2]);
+        assert_eq!(content[&b.public_key().address()].len(), 1);
+
+        Ok(())
+    }
+
     #[test]
     fn test_sort_transaction() -> Result<()> {
         let tx_len: usize = 1000;
         let mut p = TxPool::new(tx_len);
 
+        let mut hashes = vec![];
         for i in 0..tx_len {
             let mut tx = Transaction::new(i.to_le_bytes().to_vec());
+            // the time a transaction was seen doesn't change the order it arrived in
             tx.set_first_seen((i * thread_rng().gen_range(1..1000)) as u128);
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            hashes.push(tx.hash());
             p.add(tx)?;
         }
         assert_eq!(tx_len, p.len());
 
-        let transactions = p.all();
-        for i in 0..tx_len - 1 {
-            assert!(transactions[i].first_seen() <= transactions[i + 1].first_seen());
+        let all: Vec<Hash> = p.all().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(all, hashes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_equal_fees_are_fifo() -> Result<()> {
+        let mut p = TxPool::new(10);
+        p.set_sender_limits(SenderLimits {
+            max_txs: 3,
+            ..SenderLimits::default()
+        });
+
+        // added at the same time with the same fee
+        let mut hashes = vec![];
+        for _ in 0..5 {
+            let tx = signed_tx(&PrivateKey::generate(), 0, 5)?;
+            hashes.push(tx.hash());
+            p.add(tx)?;
+        }
+        let pending: Vec<Hash> = p.pending().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(pending, hashes);
+
+        // a full sender drops the newest of its transactions with the lowest fee
+        let key = PrivateKey::generate();
+        let txx = (0..3)
+            .map(|nonce| signed_tx(&key, nonce, 5))
+            .collect::<Result<Vec<_>>>()?;
+        for tx in &txx {
+            p.add(tx.clone())?;
+        }
+        p.add(signed_tx(&key, 3, 6)?)?;
+        assert!(p.has(&txx[0].hash()) && p.has(&txx[1].hash()));
+        assert!(!p.has(&txx[2].hash()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_received() -> Result<()> {
+        let clock = Arc::new(ManualClock::new());
+        let mut p = TxPool::with_clock(10, clock.clone());
+
+        let mut hashes = vec![];
+        for _ in 0..3 {
+            clock.advance(Duration::from_millis(1));
+            let mut tx = random_tx();
+            tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+            hashes.push(tx.hash());
+            p.add_received(tx)?;
         }
 
+        // in the order they arrived in
+        let all: Vec<Hash> = p.all().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(all, hashes);
+        assert_eq!(p.all()[0].first_seen(), Duration::from_millis(1).as_nanos());
+
         Ok(())
     }
 }
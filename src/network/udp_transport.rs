@@ -0,0 +1,370 @@
+/*
+UdpTransport sends every message as a single datagram. It's meant for gossiping small messages (transaction
+announcements, ping/pong, status) with low latency, a lost message is simply missing. Anything larger than a
+datagram, e.g. a block, has to go over a stream based transport.
+
+Every datagram starts with the session of the sender and its sequence number in that session:
+
+    | session: u64 (big endian) | seq: u64 (big endian) | payload |
+
+With retransmits set every datagram is sent again after retransmit_interval, the receiver drops the
+copies it has already seen by their session and sequence number. The session is picked at random when
+the transport is bound, so a peer that restarts on the same port isn't taken for a retransmit.
+*/
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{net::UdpSocket, sync::RwLock, time};
+use tracing::{debug, info};
+
+use super::{new_channel, transport::Transport, Channel, NetAddr, Payload, RPC};
+
+// fits into an Ethernet frame, larger datagrams are fragmented and lost more often
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1472;
+
+const SESSION_SIZE: usize = 8;
+const SEQ_SIZE: usize = 8;
+const HEADER_SIZE: usize = SESSION_SIZE + SEQ_SIZE;
+// number of received sequence numbers remembered to drop retransmitted copies
+const SEEN_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UdpOpts {
+    // maximum size of a datagram including the session and the sequence number
+    pub max_datagram_size: usize,
+    // number of times every datagram is sent again, 0 disables retransmission
+    pub retransmits: u32,
+    pub retransmit_interval: Duration,
+}
+
+impl Default for UdpOpts {
+    fn default() -> Self {
+        Self {
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            retransmits: 0,
+            retransmit_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpTransport {
+    addr: NetAddr,
+    socket: Arc<UdpSocket>,
+    opts: UdpOpts,
+    session: u64,
+    seq: Arc<AtomicU64>,
+    consume_channel: Channel<RPC>,
+    peers: Arc<RwLock<HashMap<NetAddr, Box<dyn Transport>>>>,
+}
+
+impl UdpTransport {
+    // Binds the socket and starts receiving, a port of 0 picks a free port
+    pub async fn bind(addr: SocketAddr, opts: UdpOpts) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let addr = NetAddr::Socket(socket.local_addr()?);
+
+        let tr = Self {
+            addr,
+            socket,
+            opts,
+            session: rand::random(),
+            seq: Arc::new(AtomicU64::new(0)),
+            consume_channel: new_channel(1024),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+        };
+
+        let socket = tr.socket.clone();
+        let sender = tr.consume_channel.0.clone();
+        tokio::task::spawn(receive_loop(socket, sender, opts.max_datagram_size));
+
+        info!("UDP transport listening on {}", tr.addr);
+        Ok(tr)
+    }
+
+    fn datagram(&self, payload: &Payload) -> Result<Bytes> {
+        let size = HEADER_SIZE + payload.len();
+        if size > self.opts.max_datagram_size {
+            return Err(anyhow!(
+                "message of {} bytes doesn't fit into a datagram of {} bytes",
+                payload.len(),
+                self.opts.max_datagram_size
+            ));
+        }
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let mut datagram = Vec::with_capacity(size);
+        datagram.extend_from_slice(&self.session.to_be_bytes());
+        datagram.extend_from_slice(&seq.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        Ok(datagram.into())
+    }
+
+    async fn send_datagram(&self, to: SocketAddr, datagram: Bytes) -> Result<()> {
+        self.socket.send_to(&datagram, to).await?;
+
+        if self.opts.retransmits > 0 {
+            let socket = self.socket.clone();
+            let (retransmits, interval) = (self.opts.retransmits, self.opts.retransmit_interval);
+            tokio::task::spawn(async move {
+                for _ in 0..retransmits {
+                    time::sleep(interval).await;
+                    if let Err(err) = socket.send_to(&datagram, to).await {
+                        debug!("could not retransmit datagram to {to}: {err}");
+                        return;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn socket_addr(addr: &NetAddr) -> Result<SocketAddr> {
+    addr.socket_addr()
+        .ok_or_else(|| anyhow!("{addr} is not a socket address"))
+}
+
+// Forwards the received datagrams until the transport is dropped
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    sender: tokio::sync::mpsc::Sender<RPC>,
+    max_datagram_size: usize,
+) {
+    // one byte more than allowed, so a datagram that was too large can be detected
+    let mut buf = vec![0; max_datagram_size + 1];
+    let mut seen = HashSet::new();
+    let mut seen_order = VecDeque::new();
+
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = sender.closed() => return,
+        };
+        let (n, from) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("UDP receive error: {err}");
+                continue;
+            }
+        };
+
+        if n < HEADER_SIZE || n > max_datagram_size {
+            debug!("dropping datagram of {n} bytes from {from}");
+            continue;
+        }
+        let key = (
+            from,
+            be_u64(&buf[..SESSION_SIZE]),
+            be_u64(&buf[SESSION_SIZE..HEADER_SIZE]),
+        );
+        if !seen.insert(key) {
+            continue;
+        }
+        seen_order.push_back(key);
+        if seen_order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = seen_order.pop_front() {
+                seen.remove(&oldest);
+            }
+        }
+
+        let rpc = RPC {
+            from: NetAddr::Socket(from),
+            payload: Bytes::copy_from_slice(&buf[HEADER_SIZE..n]),
+        };
+        if sender.send(rpc).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    fn consume(&self) -> Channel<RPC> {
+        self.consume_channel.clone()
+    }
+
+    async fn recv(&self) -> Option<RPC> {
+        self.consume_channel.1.lock().await.recv().await
+    }
+
+    async fn connect(&self, tr: Box<dyn Transport>) -> Result<()> {
+        socket_addr(&tr.addr())?;
+        self.peers.write().await.insert(tr.addr(), tr);
+        Ok(())
+    }
+
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()> {
+        self.peers.write().await.remove(addr);
+        Ok(())
+    }
+
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()> {
+        if &self.addr == to {
+            return Ok(());
+        }
+        let datagram = self.datagram(&payload)?;
+        self.send_datagram(socket_addr(to)?, datagram).await
+    }
+
+    async fn broadcast(&self, payload: Payload) -> Result<()> {
+        let peers: Vec<NetAddr> = self.peers.read().await.keys().cloned().collect();
+
+        let datagram = self.datagram(&payload)?;
+        for peer in peers {
+            self.send_datagram(socket_addr(&peer)?, datagram.clone())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>> {
+        self.peers.read().await.clone()
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.addr.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{LocalTransport, RemotePeer};
+
+    async fn bind(opts: UdpOpts) -> Result<UdpTransport> {
+        UdpTransport::bind("127.0.0.1:0".parse()?, opts).await
+    }
+
+    async fn recv(tr: &UdpTransport) -> Option<RPC> {
+        time::timeout(Duration::from_millis(500), tr.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_send_message() -> Result<()> {
+        let a = bind(UdpOpts::default()).await?;
+        let b = bind(UdpOpts::default()).await?;
+        a.connect(Box::new(b.clone())).await?;
+
+        a.send_message(&b.addr(), Bytes::from_static(b"ping"))
+            .await?;
+        let rpc = recv(&b).await.unwrap();
+        assert_eq!(rpc.from, a.addr());
+        assert_eq!(rpc.payload, Bytes::from_static(b"ping"));
+
+        a.broadcast(Bytes::from_static(b"status")).await?;
+        assert_eq!(
+            recv(&b).await.unwrap().payload,
+            Bytes::from_static(b"status")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_size() -> Result<()> {
+        let opts = UdpOpts {
+            max_datagram_size: 64,
+            ..UdpOpts::default()
+        };
+        let a = bind(opts).await?;
+        let b = bind(opts).await?;
+
+        let payload = Bytes::from(vec![7; 64 - HEADER_SIZE]);
+        a.send_message(&b.addr(), payload.clone()).await?;
+        assert_eq!(recv(&b).await.unwrap().payload, payload);
+
+        assert!(a
+            .send_message(&b.addr(), Bytes::from(vec![7; 65 - HEADER_SIZE]))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retransmits_are_dropped() -> Result<()> {
+        let a = bind(UdpOpts {
+            retransmits: 2,
+            retransmit_interval: Duration::from_millis(10),
+            ..UdpOpts::default()
+        })
+        .await?;
+        let b = bind(UdpOpts::default()).await?;
+
+        a.send_message(&b.addr(), Bytes::from_static(b"tx")).await?;
+        a.send_message(&b.addr(), Bytes::from_static(b"tx")).await?;
+
+        // the same payload twice is two messages, their copies are dropped
+        assert!(recv(&b).await.is_some());
+        assert!(recv(&b).await.is_some());
+        assert!(recv(&b).await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restarted_peer() -> Result<()> {
+        let b = bind(UdpOpts::default()).await?;
+        let a = bind(UdpOpts::default()).await?;
+        let addr = socket_addr(&a.addr())?;
+        a.send_message(&b.addr(), Bytes::from_static(b"first"))
+            .await?;
+        assert!(recv(&b).await.is_some());
+
+        // the restarted peer starts over with its sequence numbers on the same port
+        drop(a);
+        // the receiver releases the socket once it notices
+        time::sleep(Duration::from_millis(50)).await;
+        let a = UdpTransport::bind(addr, UdpOpts::default()).await?;
+        a.send_message(&b.addr(), Bytes::from_static(b"again"))
+            .await?;
+        assert_eq!(
+            recv(&b).await.unwrap().payload,
+            Bytes::from_static(b"again")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_remote_peer() -> Result<()> {
+        let a = bind(UdpOpts::default()).await?;
+        let b = bind(UdpOpts::default()).await?;
+
+        // a bootnode is only known by its address
+        a.connect(Box::new(RemotePeer::new(b.addr()))).await?;
+        a.broadcast(Bytes::from_static(b"status")).await?;
+        assert_eq!(recv(&b).await.unwrap().from, a.addr());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_requires_socket_addr() -> Result<()> {
+        let a = bind(UdpOpts::default()).await?;
+        let local = LocalTransport::new("B".into());
+        assert!(a.connect(Box::new(local)).await.is_err());
+        Ok(())
+    }
+}
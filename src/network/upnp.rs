@@ -0,0 +1,79 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use igd_next::{
+    aio::{tokio::Tokio, Gateway},
+    PortMappingProtocol, SearchOptions,
+};
+
+use super::{PortMapper, Protocol};
+
+const DESCRIPTION: &str = "projectx";
+
+// Port mapping with an UPnP Internet Gateway Device
+pub struct Upnp {
+    gateway: Gateway<Tokio>,
+}
+
+impl Upnp {
+    // Searches the local network for the gateway
+    pub async fn discover() -> Result<Self> {
+        let gateway = igd_next::aio::tokio::search_gateway(SearchOptions::default())
+            .await
+            .map_err(|err| anyhow!("no UPnP gateway found: {err}"))?;
+        Ok(Self { gateway })
+    }
+
+    // The router forwards to a LAN address, not to the unspecified address a socket is bound to
+    fn lan_addr(&self, local: SocketAddr) -> Result<SocketAddr> {
+        if !local.ip().is_unspecified() {
+            return Ok(local);
+        }
+        let socket = UdpSocket::bind((local.ip(), 0))?;
+        socket.connect(self.gateway.addr)?;
+        Ok(SocketAddr::new(socket.local_addr()?.ip(), local.port()))
+    }
+}
+
+#[async_trait]
+impl PortMapper for Upnp {
+    async fn map_port(
+        &self,
+        protocol: Protocol,
+        local: SocketAddr,
+        lease: Duration,
+    ) -> Result<SocketAddr> {
+        let lease = lease.as_secs().clamp(1, u32::MAX as u64) as u32;
+        self.gateway
+            .add_port(
+                protocol.into(),
+                local.port(),
+                self.lan_addr(local)?,
+                lease,
+                DESCRIPTION,
+            )
+            .await?;
+        let ip = self.gateway.get_external_ip().await?;
+        Ok(SocketAddr::new(ip, local.port()))
+    }
+
+    async fn unmap_port(&self, protocol: Protocol, local: SocketAddr) -> Result<()> {
+        Ok(self
+            .gateway
+            .remove_port(protocol.into(), local.port())
+            .await?)
+    }
+}
+
+impl From<Protocol> for PortMappingProtocol {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Udp => PortMappingProtocol::UDP,
+            Protocol::Tcp => PortMappingProtocol::TCP,
+        }
+    }
+}
@@ -0,0 +1,13 @@
+/*
+The simulator runs a network of servers in one process. The nodes talk over SimTransport, which delays, drops
+and partitions messages as configured in the SimNetwork, so consensus and sync can be tested under bad network
+conditions without sockets.
+
+Drops and delays are drawn from a seeded rng, a failing run can be repeated with the same seed.
+*/
+
+mod network;
+mod simulation;
+
+pub use network::*;
+pub use simulation::*;
@@ -0,0 +1,366 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    sync::{mpsc, RwLock},
+    time,
+};
+
+use crate::network::{new_channel, Channel, NetAddr, Payload, Transport, RPC};
+
+// Conditions of the link from one node to another
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkOpts {
+    pub latency: Duration,
+    // a random delay of up to jitter is added to the latency, messages can overtake each other
+    pub jitter: Duration,
+    // probability that a message is lost, between 0 and 1
+    pub loss: f64,
+}
+
+type Peers = Arc<RwLock<HashMap<NetAddr, Box<dyn Transport>>>>;
+
+#[derive(Debug)]
+struct NetworkState {
+    link: LinkOpts,
+    // links that differ from link, by sender and receiver
+    links: HashMap<(NetAddr, NetAddr), LinkOpts>,
+    // group of every node while the network is partitioned
+    partitions: Option<HashMap<NetAddr, usize>>,
+    rng: StdRng,
+    // peers of every transport on the network, to connect and disconnect both ends
+    nodes: HashMap<NetAddr, Peers>,
+}
+
+// The conditions of the network shared by all SimTransports
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl SimNetwork {
+    pub fn new(link: LinkOpts, seed: u64) -> Result<Self> {
+        check_link(&link)?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(NetworkState {
+                link,
+                links: HashMap::new(),
+                partitions: None,
+                rng: StdRng::seed_from_u64(seed),
+                nodes: HashMap::new(),
+            })),
+        })
+    }
+
+    // Sets the conditions of all links that weren't set with set_link_between
+    pub fn set_link(&self, link: LinkOpts) -> Result<()> {
+        check_link(&link)?;
+        self.state.lock().unwrap().link = link;
+        Ok(())
+    }
+
+    // Sets the conditions of the links between a and b in both directions
+    pub fn set_link_between(&self, a: &NetAddr, b: &NetAddr, link: LinkOpts) -> Result<()> {
+        check_link(&link)?;
+        let mut state = self.state.lock().unwrap();
+        state.links.insert((a.clone(), b.clone()), link);
+        state.links.insert((b.clone(), a.clone()), link);
+        Ok(())
+    }
+
+    // Nodes only reach the nodes of their own group, nodes that aren't in any group reach nobody
+    pub fn partition(&self, groups: Vec<Vec<NetAddr>>) {
+        let partitions = groups
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.into_iter().map(move |addr| (addr, i)))
+            .collect();
+        self.state.lock().unwrap().partitions = Some(partitions);
+    }
+
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitions = None;
+    }
+
+    fn register(&self, addr: &NetAddr, peers: &Peers) {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .insert(addr.clone(), peers.clone());
+    }
+
+    fn peers_of(&self, addr: &NetAddr) -> Option<Peers> {
+        self.state.lock().unwrap().nodes.get(addr).cloned()
+    }
+
+    // The delay of a message from one node to another, None if the message is lost
+    fn delivery(&self, from: &NetAddr, to: &NetAddr) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(partitions) = &state.partitions {
+            match (partitions.get(from), partitions.get(to)) {
+                (Some(a), Some(b)) if a == b => {}
+                _ => return None,
+            }
+        }
+
+        let link = state
+            .links
+            .get(&(from.clone(), to.clone()))
+            .copied()
+            .unwrap_or(state.link);
+        if link.loss > 0.0 && state.rng.gen_bool(link.loss) {
+            return None;
+        }
+        let jitter = link.jitter.mul_f64(state.rng.gen());
+        Some(link.latency + jitter)
+    }
+}
+
+fn check_link(link: &LinkOpts) -> Result<()> {
+    if !(0.0..=1.0).contains(&link.loss) {
+        return Err(anyhow!("loss of {} is not a probability", link.loss));
+    }
+    Ok(())
+}
+
+// A LocalTransport that sends through a SimNetwork. Unlike LocalTransport a connection is open in both directions,
+// like a TCP connection, so a peer that connected to us can be answered.
+#[derive(Debug, Clone)]
+pub struct SimTransport {
+    addr: NetAddr,
+    network: SimNetwork,
+    consume_channel: Channel<RPC>,
+    peers: Peers,
+}
+
+impl SimTransport {
+    pub fn new(addr: NetAddr, network: SimNetwork) -> Self {
+        let tr = Self {
+            addr,
+            network,
+            consume_channel: new_channel(1024),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+        };
+        tr.network.register(&tr.addr, &tr.peers);
+        tr
+    }
+
+    async fn deliver(&self, to: &NetAddr, sender: mpsc::Sender<RPC>, payload: Payload) {
+        let Some(delay) = self.network.delivery(&self.addr, to) else {
+            return;
+        };
+        let rpc = RPC {
+            from: self.addr.clone(),
+            payload,
+        };
+
+        if delay.is_zero() {
+            let _ = sender.send(rpc).await;
+        } else {
+            tokio::task::spawn(async move {
+                time::sleep(delay).await;
+                let _ = sender.send(rpc).await;
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    fn consume(&self) -> Channel<RPC> {
+        self.consume_channel.clone()
+    }
+
+    async fn recv(&self) -> Option<RPC> {
+        self.consume_channel.1.lock().await.recv().await
+    }
+
+    async fn connect(&self, tr: Box<dyn Transport>) -> Result<()> {
+        let remote = self
+            .network
+            .peers_of(&tr.addr())
+            .ok_or(anyhow!("{} is not on the network", tr.addr()))?;
+        self.peers.write().await.insert(tr.addr(), tr);
+        remote
+            .write()
+            .await
+            .insert(self.addr.clone(), Box::new(self.clone()));
+        Ok(())
+    }
+
+    async fn disconnect(&self, addr: &NetAddr) -> Result<()> {
+        self.peers.write().await.remove(addr);
+        if let Some(remote) = self.network.peers_of(addr) {
+            remote.write().await.remove(&self.addr);
+        }
+        Ok(())
+    }
+
+    // A lost message is no error, like on a real network the sender doesn't notice
+    async fn send_message(&self, to: &NetAddr, payload: Payload) -> Result<()> {
+        if &self.addr == to {
+            return Ok(());
+        }
+        let sender = self
+            .peers
+            .read()
+            .await
+            .get(to)
+            .map(|peer| peer.consume().0)
+            .ok_or(anyhow!("{} could not send message to {}", self.addr, to))?;
+
+        self.deliver(to, sender, payload).await;
+        Ok(())
+    }
+
+    async fn broadcast(&self, payload: Payload) -> Result<()> {
+        let peers: Vec<_> = self
+            .peers
+            .read()
+            .await
+            .iter()
+            .map(|(addr, peer)| (addr.clone(), peer.consume().0))
+            .collect();
+
+        for (addr, sender) in peers {
+            self.deliver(&addr, sender, payload.clone()).await;
+        }
+        Ok(())
+    }
+
+    async fn peers(&self) -> HashMap<NetAddr, Box<dyn Transport>> {
+        self.peers.read().await.clone()
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.addr.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    async fn connected(network: &SimNetwork) -> Result<(SimTransport, SimTransport)> {
+        let a = SimTransport::new("A".into(), network.clone());
+        let b = SimTransport::new("B".into(), network.clone());
+        a.connect(Box::new(b.clone())).await?;
+        Ok((a, b))
+    }
+
+    async fn recv(tr: &SimTransport) -> Option<RPC> {
+        time::timeout(Duration::from_millis(200), tr.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_connection_is_symmetric() -> Result<()> {
+        let network = SimNetwork::new(LinkOpts::default(), 0)?;
+        let a = SimTransport::new("A".into(), network.clone());
+        let b = SimTransport::new("B".into(), network.clone());
+
+        a.connect(Box::new(b.clone())).await?;
+        b.send_message(&a.addr(), Bytes::from_static(b"hi")).await?;
+        assert_eq!(recv(&a).await.unwrap().from, b.addr());
+
+        b.disconnect(&a.addr()).await?;
+        assert!(a.peers().await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_latency() -> Result<()> {
+        let network = SimNetwork::new(
+            LinkOpts {
+                latency: Duration::from_millis(50),
+                ..LinkOpts::default()
+            },
+            0,
+        )?;
+        let (a, b) = connected(&network).await?;
+
+        let sent = time::Instant::now();
+        a.send_message(&b.addr(), Bytes::from_static(b"ping"))
+            .await?;
+        assert_eq!(recv(&b).await.unwrap().payload, Bytes::from_static(b"ping"));
+        assert!(sent.elapsed() >= Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_loss() -> Result<()> {
+        let network = SimNetwork::new(
+            LinkOpts {
+                loss: 1.0,
+                ..LinkOpts::default()
+            },
+            0,
+        )?;
+        let (a, b) = connected(&network).await?;
+
+        a.broadcast(Bytes::from_static(b"tx")).await?;
+        assert!(recv(&b).await.is_none());
+
+        assert!(network
+            .set_link(LinkOpts {
+                loss: 1.5,
+                ..LinkOpts::default()
+            })
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_link_between() -> Result<()> {
+        let network = SimNetwork::new(LinkOpts::default(), 0)?;
+        let (a, b) = connected(&network).await?;
+        let c = SimTransport::new("C".into(), network.clone());
+        c.connect(Box::new(b.clone())).await?;
+
+        let lossy = LinkOpts {
+            loss: 1.0,
+            ..LinkOpts::default()
+        };
+        network.set_link_between(&a.addr(), &b.addr(), lossy)?;
+        a.send_message(&b.addr(), Bytes::from_static(b"1")).await?;
+        b.send_message(&a.addr(), Bytes::from_static(b"2")).await?;
+        assert!(recv(&b).await.is_none());
+        assert!(recv(&a).await.is_none());
+
+        // the other links keep the conditions of the network
+        c.send_message(&b.addr(), Bytes::from_static(b"3")).await?;
+        assert_eq!(recv(&b).await.unwrap().payload, Bytes::from_static(b"3"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partition() -> Result<()> {
+        let network = SimNetwork::new(LinkOpts::default(), 0)?;
+        let (a, b) = connected(&network).await?;
+
+        network.partition(vec![vec![a.addr()], vec![b.addr()]]);
+        a.send_message(&b.addr(), Bytes::from_static(b"1")).await?;
+        assert!(recv(&b).await.is_none());
+
+        network.heal();
+        a.send_message(&b.addr(), Bytes::from_static(b"2")).await?;
+        assert_eq!(recv(&b).await.unwrap().payload, Bytes::from_static(b"2"));
+
+        Ok(())
+    }
+}
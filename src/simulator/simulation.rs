@@ -0,0 +1,255 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    sync::{mpsc, RwLock},
+    task::JoinHandle,
+    time,
+};
+
+use super::{LinkOpts, SimNetwork, SimTransport};
+use crate::{
+    consensus::{ConsensusOpts, Timeouts},
+    core::{Blockchain, ValidatorSet, DEFAULT_EPOCH_LENGTH},
+    crypto::PrivateKey,
+    network::{BTransport, ConnectionManagerOpts, NetAddr, Server},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone)]
+pub struct SimOpts {
+    // number of servers, the first validators of them are in the genesis validator set
+    pub nodes: usize,
+    pub validators: usize,
+    pub link: LinkOpts,
+    pub seed: u64,
+    pub epoch_length: u32,
+    pub timeouts: Timeouts,
+}
+
+impl Default for SimOpts {
+    fn default() -> Self {
+        Self {
+            nodes: 4,
+            validators: 4,
+            link: LinkOpts::default(),
+            seed: 0,
+            epoch_length: DEFAULT_EPOCH_LENGTH,
+            // much shorter than the defaults, there's no real network to wait for
+            timeouts: Timeouts {
+                propose: Duration::from_millis(500),
+                prevote: Duration::from_millis(200),
+                precommit: Duration::from_millis(200),
+                commit: Duration::from_millis(10),
+                round_increase: Duration::from_millis(100),
+            },
+        }
+    }
+}
+
+pub struct SimNode {
+    pub addr: NetAddr,
+    pub chain: Arc<RwLock<Blockchain>>,
+    quit: mpsc::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+// Servers named N0, N1, ... running consensus over a SimNetwork, every node is connected to every other
+pub struct Simulation {
+    pub nodes: Vec<SimNode>,
+    network: SimNetwork,
+}
+
+impl Simulation {
+    pub async fn start(opts: SimOpts) -> Result<Self> {
+        if opts.validators == 0 || opts.validators > opts.nodes {
+            return Err(anyhow!(
+                "{} validators don't fit {} nodes",
+                opts.validators,
+                opts.nodes
+            ));
+        }
+        let network = SimNetwork::new(opts.link, opts.seed)?;
+
+        let keys: Vec<PrivateKey> = (0..opts.validators)
+            .map(|_| PrivateKey::generate())
+            .collect();
+        let consensus = ConsensusOpts {
+            validators: ValidatorSet::new(keys.iter().map(|key| key.public_key()).collect()),
+            epoch_length: opts.epoch_length,
+            timeouts: opts.timeouts,
+        };
+
+        let transports: Vec<BTransport> = (0..opts.nodes)
+            .map(|i| -> BTransport {
+                Box::new(SimTransport::new(format!("N{i}").into(), network.clone()))
+            })
+            .collect();
+        for (i, a) in transports.iter().enumerate() {
+            for b in &transports[i + 1..] {
+                a.connect(b.clone()).await?;
+            }
+        }
+
+        let mut nodes = vec![];
+        for (i, tr) in transports.iter().enumerate() {
+            // Every other node is a persistent peer, peers that stop answering pings, e.g. while partitioned,
+            // are disconnected and re-dialed until they answer again
+            let connection_opts = ConnectionManagerOpts {
+                persistent_peers: transports
+                    .iter()
+                    .filter(|peer| peer.addr() != tr.addr())
+                    .cloned()
+                    .collect(),
+                dial_backoff: Duration::from_millis(100),
+                max_dial_backoff: Duration::from_secs(1),
+                ping_interval: Duration::from_millis(200),
+                pong_timeout: Duration::from_secs(1),
+                tick_interval: Duration::from_millis(50),
+                ..ConnectionManagerOpts::default()
+            };
+
+            let mut builder = Server::builder()
+                .with_transport(tr.clone())
+                .with_connection_opts(connection_opts)
+                .with_consensus(consensus.clone());
+            if let Some(key) = keys.get(i) {
+                builder = builder.with_validator_key(key.clone());
+            }
+            let mut s = builder.build().await?;
+
+            nodes.push(SimNode {
+                addr: tr.addr(),
+                chain: s.chain(),
+                quit: s.quit_sender(),
+                task: tokio::task::spawn(async move { s.start().await }),
+            });
+        }
+
+        Ok(Self { nodes, network })
+    }
+
+    pub fn network(&self) -> &SimNetwork {
+        &self.network
+    }
+
+    pub fn addrs(&self) -> Vec<NetAddr> {
+        self.nodes.iter().map(|node| node.addr.clone()).collect()
+    }
+
+    pub async fn heights(&self) -> Vec<u32> {
+        let mut heights = vec![];
+        for node in &self.nodes {
+            heights.push(node.chain.read().await.height().await);
+        }
+        heights
+    }
+
+    // Waits until every node has at least height blocks
+    pub async fn wait_for_height(&self, height: u32, timeout: Duration) -> Result<()> {
+        let reached = time::timeout(timeout, async {
+            while self.heights().await.iter().any(|h| *h < height) {
+                time::sleep(POLL_INTERVAL).await;
+            }
+        })
+        .await;
+
+        if reached.is_err() {
+            return Err(anyhow!(
+                "nodes didn't reach height {height} within {timeout:?}, heights: {:?}",
+                self.heights().await
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks that all nodes have the same headers up to the lowest height, returns that height
+    pub async fn assert_converged(&self) -> Result<u32> {
+        let height = self.heights().await.into_iter().min().unwrap_or_default();
+
+        for h in 1..=height {
+            let header = self.nodes[0].chain.read().await.get_header(h).await?;
+            for node in &self.nodes[1..] {
+                if node.chain.read().await.get_header(h).await? != header {
+                    return Err(anyhow!(
+                        "{} and {} have different blocks at height {h}",
+                        self.nodes[0].addr,
+                        node.addr
+                    ));
+                }
+            }
+        }
+        Ok(height)
+    }
+
+    pub async fn stop(self) -> Result<()> {
+        for node in self.nodes {
+            node.quit.send(()).await?;
+            node.task.await??;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_network() -> Result<()> {
+        let sim = Simulation::start(SimOpts {
+            nodes: 5,
+            link: LinkOpts {
+                latency: Duration::from_millis(20),
+                jitter: Duration::from_millis(30),
+                ..LinkOpts::default()
+            },
+            ..SimOpts::default()
+        })
+        .await?;
+
+        sim.wait_for_height(3, Duration::from_secs(20)).await?;
+        assert!(sim.assert_converged().await? >= 3);
+
+        sim.stop().await
+    }
+
+    #[tokio::test]
+    async fn test_partition() -> Result<()> {
+        let opts = SimOpts::default();
+        let sim = Simulation::start(SimOpts {
+            timeouts: Timeouts {
+                commit: Duration::from_secs(1),
+                ..opts.timeouts
+            },
+            ..opts
+        })
+        .await?;
+        sim.wait_for_height(1, Duration::from_secs(10)).await?;
+
+        // Partitioned while the nodes wait for the next height, so no commit is cut in half. Neither half has
+        // 2/3 of the validators, nobody commits until the network heals.
+        let mut heights = sim.heights().await;
+        while heights.iter().any(|h| *h != heights[0]) {
+            time::sleep(POLL_INTERVAL).await;
+            heights = sim.heights().await;
+        }
+        let addrs = sim.addrs();
+        sim.network()
+            .partition(vec![addrs[..2].to_vec(), addrs[2..].to_vec()]);
+        time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(sim.heights().await, heights);
+
+        sim.network().heal();
+        time::timeout(Duration::from_secs(20), async {
+            while sim.heights().await.iter().all(|h| *h == heights[0]) {
+                time::sleep(POLL_INTERVAL).await;
+            }
+        })
+        .await?;
+        sim.assert_converged().await?;
+
+        sim.stop().await
+    }
+}
@@ -0,0 +1,200 @@
+/*
+Helpers shared by the tests: builders for signed transactions, blocks and chains, and proptest strategies
+generating valid and invalid ones.
+
+Keys are generated with the thread rng, so proptest only shrinks the data, not the signer.
+*/
+
+use std::{future::Future, io::Cursor};
+
+use anyhow::Result;
+use proptest::prelude::*;
+use rand::{thread_rng, Rng};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    core::{
+        calculate_data_hash, BincodeDecoder, BincodeEncoder, Block, Blockchain, Decoder, Encoder,
+        Evidence, Header, Transaction, TxHasher,
+    },
+    crypto::PrivateKey,
+    types::Hash,
+};
+
+// A transaction running data, signed by a new key and with its hash cached
+pub fn signed_tx(data: Vec<u8>) -> Result<Transaction> {
+    let mut tx = Transaction::new(data);
+    tx.sign(&PrivateKey::generate());
+    tx.calculate_and_cache_hash(Box::new(TxHasher))?;
+    Ok(tx)
+}
+
+// A signed transaction with 32 random bytes as data, its hash is not cached yet
+pub fn random_tx() -> Transaction {
+    let mut tx = Transaction::new(thread_rng().gen::<[u8; 32]>().to_vec());
+    tx.sign(&PrivateKey::generate());
+    tx
+}
+
+// A signed block without transactions, prev_block_hash doesn't have to exist
+pub fn random_block(height: u32, prev_block_hash: Hash) -> Result<Block> {
+    let header = Header {
+        version: 1,
+        data_hash: calculate_data_hash(&[])?,
+        prev_block_hash: Some(prev_block_hash),
+        timestamp: thread_rng().gen(),
+        height,
+        epoch: 0,
+        state_root: Hash::default(),
+    };
+
+    let mut b = Block::new(header, vec![]);
+    b.sign(&PrivateKey::generate())?;
+    Ok(b)
+}
+
+// The signed block following prev with the given transactions
+pub fn next_block(prev: Header, txx: Vec<Transaction>) -> Result<Block> {
+    let mut b = Block::from_prev_header(prev, txx)?;
+    b.sign(&PrivateKey::generate())?;
+    Ok(b)
+}
+
+// Evidence of key signing two different headers with the same height
+pub fn double_sign_evidence(key: &PrivateKey) -> Result<Evidence> {
+    let a = random_block(1, Hash::random())?.header;
+    let b = random_block(1, Hash::random())?.header;
+    Ok(Evidence::DoubleSign {
+        validator: key.public_key(),
+        first: (a, key.sign(&a.bytes()?)),
+        second: (b, key.sign(&b.bytes()?)),
+    })
+}
+
+// Runs an async function from a sync test, e.g. inside proptest!
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("building runtime")
+        .block_on(f)
+}
+
+// A blockchain with a random genesis block and len empty blocks on top
+pub async fn chain(len: u32) -> Result<Blockchain> {
+    let mut bc = Blockchain::new("".into(), random_block(0, Hash::default())?).await?;
+    extend_chain(&mut bc, len).await?;
+    Ok(bc)
+}
+
+// Adds n empty blocks to the head of bc and returns them
+pub async fn extend_chain(bc: &mut Blockchain, n: u32) -> Result<Vec<Block>> {
+    let mut blocks = vec![];
+    for _ in 0..n {
+        let mut b = Block::from_prev_header(bc.get_header(bc.height().await).await?, vec![])?;
+        b.header.epoch = bc.epoch_of(b.header.height);
+        b.sign(&PrivateKey::generate())?;
+        bc.add_block(&mut b).await?;
+        blocks.push(b);
+    }
+    Ok(blocks)
+}
+
+pub fn encoded<T: Serialize>(t: &T) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    BincodeEncoder::new(&mut buf).encode(t)?;
+    Ok(buf)
+}
+
+// Encodes t and decodes it again into decoded, with the encoders used on the wire
+pub fn round_trip<T: Serialize + DeserializeOwned>(t: &T, mut decoded: T) -> Result<T> {
+    let buf = encoded(t)?;
+    BincodeDecoder::new(&mut Cursor::new(buf)).decode(&mut decoded)?;
+    Ok(decoded)
+}
+
+pub fn arb_hash() -> impl Strategy<Value = Hash> {
+    any::<[u8; 32]>().prop_map(Hash::from_digest)
+}
+
+pub fn arb_header() -> impl Strategy<Value = Header> {
+    (
+        any::<u32>(),
+        arb_hash(),
+        proptest::option::of(arb_hash()),
+        any::<u128>(),
+        any::<u32>(),
+        any::<u32>(),
+        arb_hash(),
+    )
+        .prop_map(
+            |(version, data_hash, prev_block_hash, timestamp, height, epoch, state_root)| Header {
+                version,
+                data_hash,
+                prev_block_hash,
+                timestamp,
+                height,
+                epoch,
+                state_root,
+            },
+        )
+}
+
+// A signed transaction with arbitrary code and call data
+pub fn arb_tx() -> impl Strategy<Value = Transaction> {
+    (
+        proptest::collection::vec(any::<u8>(), 0..64),
+        proptest::collection::vec(any::<u8>(), 0..16),
+    )
+        .prop_map(|(data, input)| {
+            let mut tx = Transaction::new_call(data, input);
+            tx.sign(&PrivateKey::generate());
+            tx
+        })
+}
+
+// A transaction that fails verification: unsigned, or changed after signing
+pub fn arb_invalid_tx() -> impl Strategy<Value = Transaction> {
+    prop_oneof![
+        proptest::collection::vec(any::<u8>(), 0..64).prop_map(Transaction::new),
+        (arb_tx(), any::<u8>()).prop_map(|(mut tx, b)| {
+            tx.data.push(b);
+            tx
+        }),
+    ]
+}
+
+// A signed block with up to 4 transactions and a matching data hash. The chain fields of the header are
+// arbitrary, use prop_map with next_block for blocks that extend a chain.
+pub fn arb_block() -> impl Strategy<Value = Block> {
+    (arb_header(), proptest::collection::vec(arb_tx(), 0..4)).prop_map(|(mut header, txx)| {
+        header.data_hash = calculate_data_hash(&txx).expect("hashing transactions");
+        let mut b = Block::new(header, txx);
+        b.sign(&PrivateKey::generate()).expect("signing block");
+        b
+    })
+}
+
+// A block that fails Block::verify
+pub fn arb_invalid_block() -> impl Strategy<Value = Block> {
+    prop_oneof![
+        // unsigned
+        (arb_header(), proptest::collection::vec(arb_tx(), 0..4))
+            .prop_map(|(header, txx)| Block::new(header, txx)),
+        // header changed after signing
+        (arb_block(), 1..u32::MAX).prop_map(|(mut b, n)| {
+            b.header.height = b.header.height.wrapping_add(n);
+            b
+        }),
+        // transaction that doesn't verify
+        (arb_block(), arb_invalid_tx()).prop_map(|(mut b, tx)| {
+            b.add_transaction(tx);
+            b
+        }),
+        // data hash doesn't match the transactions, signed anyway
+        (arb_block(), arb_tx()).prop_map(|(mut b, tx)| {
+            b.add_transaction(tx);
+            b.sign(&PrivateKey::generate()).expect("signing block");
+            b
+        }),
+    ]
+}
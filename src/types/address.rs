@@ -1,7 +1,13 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-use super::common::from_bytes;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 
+use super::common::try_from_bytes;
+
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
 pub struct Address([u8; 20]);
 
 impl Display for Address {
@@ -13,9 +19,31 @@ impl Display for Address {
     }
 }
 
+// The hex form of Display, with or without 0x
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|err| anyhow!("invalid address {s}: {err}"))?;
+        Self::try_from_bytes(&bytes).map_err(|err| anyhow!("invalid address {s}: {err}"))
+    }
+}
+
 impl Address {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let address = from_bytes::<20>(bytes);
+    pub fn into_bytes(&self) -> [u8; 20] {
+        self.0
+    }
+
+    // Fails unless there are exactly 20 bytes
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(try_from_bytes::<20>(bytes)?))
+    }
+
+    // The last 20 bytes of the digest of a public key
+    pub fn from_digest(digest: [u8; 32]) -> Self {
+        let mut address = [0; 20];
+        address.copy_from_slice(&digest[12..]);
         Self(address)
     }
 }
@@ -1,6 +1,7 @@
-use super::common::from_bytes;
-use std::fmt::Display;
+use super::common::try_from_bytes;
+use std::{fmt::Display, str::FromStr};
 
+use anyhow::{anyhow, Result};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
@@ -18,14 +19,30 @@ impl Display for Hash {
     }
 }
 
+// The hex form of Display, with or without 0x
+impl FromStr for Hash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim_start_matches("0x"))
+            .map_err(|err| anyhow!("invalid hash {s}: {err}"))?;
+        Self::try_from_bytes(&bytes).map_err(|err| anyhow!("invalid hash {s}: {err}"))
+    }
+}
+
 impl Hash {
     pub fn into_bytes(&self) -> [u8; 32] {
         self.0
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let hash = from_bytes::<32>(bytes);
-        Self(hash)
+    // Fails unless there are exactly 32 bytes, e.g. a hash decoded from the wire or parsed from hex
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(try_from_bytes::<32>(bytes)?))
+    }
+
+    // The output of a hash function, its size is always right
+    pub fn from_digest(digest: [u8; 32]) -> Self {
+        Self(digest)
     }
 
     pub fn is_zero(&self) -> bool {
@@ -44,3 +61,24 @@ impl Hash {
         Self(hash)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Address;
+
+    #[test]
+    fn test_try_from_bytes() -> Result<()> {
+        let hash = Hash::random();
+        assert_eq!(Hash::try_from_bytes(&hash.into_bytes())?, hash);
+
+        assert!(Hash::try_from_bytes(&[1; 31]).is_err());
+        assert!(Hash::try_from_bytes(&[1; 33]).is_err());
+        assert!(Hash::try_from_bytes(&[]).is_err());
+
+        Address::try_from_bytes(&[1; 20])?;
+        assert!(Address::try_from_bytes(&[1; 32]).is_err());
+
+        Ok(())
+    }
+}